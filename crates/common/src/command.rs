@@ -1,6 +1,9 @@
 use serde::{Deserialize, Serialize};
 
-use crate::db::Data;
+use crate::db::{
+    ChannelPattern, Data, IndexDefinition, Replay, SearchIndexDefinition, TableOptions,
+    TriggerDefinition, VectorIndexDefinition,
+};
 
 /// Commands sent from the Client to the Server
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -9,34 +12,452 @@ pub enum Command {
     /// This subscribes your client to the given channel
     ///
     /// If `subscribe_to_self` is true, you will receive messages sent to channels that were sent by yourself.
+    /// `since_seq`, `since_time` or `last_n` first sends the channel's recent messages from its history,
+    /// so a reconnecting client can catch up on what it missed.
     SubscribeBroadcast {
         channel: String,
 
         // Default this to false
         #[serde(default)]
         subscribe_to_self: bool,
+
+        #[serde(default, flatten)]
+        replay: Option<Replay>,
     },
 
     /// This unsubscribes your client from the given channel
     UnsubscribeBroadcast(String),
 
+    /// This subscribes your client to every channel matching the given pattern
+    ///
+    /// Messages to those channels are delivered as `PatternMessage`, with the pattern they matched.
+    #[serde(rename = "psubscribe")]
+    PSubscribe {
+        #[serde(flatten)]
+        pattern: ChannelPattern,
+
+        #[serde(default)]
+        subscribe_to_self: bool,
+    },
+
+    /// This unsubscribes your client from the given pattern
+    #[serde(rename = "punsubscribe")]
+    PUnsubscribe(ChannelPattern),
+
+    /// This unsubscribes your client from every channel and pattern
+    ClearSubscriptions,
+
     /// This sends a broadcast to the given channel
     ///
     /// If the channel is `general`, all clients will receive this message.
-    SendBroadcast {
-        channel: String,
-        message: String,
-    },
+    SendBroadcast { channel: String, message: String },
 
+    /// This returns how many clients, channels and patterns broadcasts are routed between,
+    /// and how many messages were delivered, dropped for slow clients or disconnected them
+    #[serde(rename = "pubsub_stats")]
+    PubSubStats,
+
+    /// This sets a value
+    ///
+    /// `table` defaults to the table chosen with `Select`.
     Set {
         key: String,
         value: Value,
+
+        #[serde(default)]
+        table: Option<String>,
     },
 
     /// This retrieves a value
+    ///
+    /// `table` defaults to the table chosen with `Select`.
     Get {
         key: String,
+
+        #[serde(default)]
+        table: Option<String>,
+
+        /// Reads from a snapshot taken with `Snapshot` instead of the table's current state
+        #[serde(default)]
+        snapshot_id: Option<String>,
+    },
+
+    /// This retrieves the values with keys from `start` up to but not including `end` from an ordered table
+    ///
+    /// Missing bounds are unbounded, and at most `limit` entries are returned.
+    /// Entries are in ascending key order, or descending if `reverse` is true.
+    Range {
+        #[serde(default)]
+        start: Option<String>,
+
+        #[serde(default)]
+        end: Option<String>,
+
+        #[serde(default)]
+        limit: Option<usize>,
+
+        #[serde(default)]
+        reverse: bool,
+
+        #[serde(default)]
+        table: Option<String>,
+
+        /// Reads from a snapshot taken with `Snapshot` instead of the table's current state
+        #[serde(default)]
+        snapshot_id: Option<String>,
+    },
+
+    /// This retrieves the values with keys starting with `prefix` from an ordered table, in key order
+    PrefixScan {
+        prefix: String,
+
+        #[serde(default)]
+        table: Option<String>,
+
+        /// Reads from a snapshot taken with `Snapshot` instead of the table's current state
+        #[serde(default)]
+        snapshot_id: Option<String>,
+    },
+
+    /// This removes a value
+    ///
+    /// `table` defaults to the table chosen with `Select`.
+    Delete {
+        key: String,
+
+        #[serde(default)]
+        table: Option<String>,
+    },
+
+    /// This creates a secondary index on a JSON field, indexing every matching key already in the table
+    CreateIndex {
+        #[serde(flatten)]
+        index: IndexDefinition,
+
+        #[serde(default)]
+        table: Option<String>,
+    },
+
+    /// This drops a secondary index
+    DropIndex {
+        name: String,
+
+        #[serde(default)]
+        table: Option<String>,
+    },
+
+    /// This retrieves the values whose indexed field matches `value` or falls within `range`
+    ///
+    /// Entries are ordered by the indexed field and then by key.
+    FindBy {
+        index: String,
+
+        #[serde(flatten)]
+        query: IndexQuery,
+
+        #[serde(default)]
+        table: Option<String>,
+    },
+
+    /// This creates a full-text index, indexing every matching key already in the table
+    CreateSearchIndex {
+        #[serde(flatten)]
+        index: SearchIndexDefinition,
+
+        #[serde(default)]
+        table: Option<String>,
+    },
+
+    /// This drops a full-text index
+    DropSearchIndex {
+        name: String,
+
+        #[serde(default)]
+        table: Option<String>,
+    },
+
+    /// This searches a full-text index, returning at most `limit` results, best first
+    ///
+    /// Every word must match, `"quoted words"` must appear together in order,
+    /// and `word*` matches words starting with `word`.
+    Search {
+        index: String,
+        query: String,
+
+        #[serde(default)]
+        limit: Option<usize>,
+
+        #[serde(default)]
+        table: Option<String>,
+    },
+
+    /// This creates a vector index, indexing every matching key already in the table
+    CreateVectorIndex {
+        #[serde(flatten)]
+        index: VectorIndexDefinition,
+
+        #[serde(default)]
+        table: Option<String>,
+    },
+
+    /// This drops a vector index
+    DropVectorIndex {
+        name: String,
+
+        #[serde(default)]
+        table: Option<String>,
+    },
+
+    /// This creates a trigger, which runs its action whenever a key matching its pattern is set, deleted or expires
+    CreateTrigger {
+        #[serde(flatten)]
+        trigger: Box<TriggerDefinition>,
+
+        #[serde(default)]
+        table: Option<String>,
+    },
+
+    /// This drops a trigger
+    DropTrigger {
+        name: String,
+
+        #[serde(default)]
+        table: Option<String>,
+    },
+
+    /// This lists a table's triggers
+    Triggers {
+        #[serde(default)]
+        table: Option<String>,
+    },
+
+    /// This sends a `KeyChanged` message whenever a key is set, deleted or expires, until the socket closes
+    ///
+    /// Either one `key` or every key matching a glob `pattern` is watched, and the new value of a set key is only
+    /// sent if `include_value` is true. `table` defaults to the table chosen with `Select`.
+    WatchKey {
+        #[serde(flatten)]
+        target: WatchTarget,
+
+        #[serde(default)]
+        include_value: bool,
+
+        #[serde(default)]
+        table: Option<String>,
+    },
+
+    /// This stops watching a key or pattern watched with `WatchKey`
+    UnwatchKey {
+        #[serde(flatten)]
+        target: WatchTarget,
+
+        #[serde(default)]
+        table: Option<String>,
+    },
+
+    /// This replies with the JSON values with keys starting with `prefix` that match `filter`, in key order, then sends
+    /// a `LiveQueryDiff` whenever a key is added to, updated in or removed from those results
+    ///
+    /// `filter` is written like the `where` of a `Query`, such as `.age > 30 and .city == "Oslo"`.
+    /// The query runs until `StopLiveQuery` or the socket closes, and `table` defaults to the table chosen with `Select`.
+    LiveQuery {
+        #[serde(default)]
+        prefix: String,
+
+        #[serde(default)]
+        filter: Option<String>,
+
+        #[serde(default)]
+        table: Option<String>,
+    },
+
+    /// This stops a live query started with `LiveQuery`
+    StopLiveQuery { query_id: String },
+
+    /// This finds the `k` keys with vectors nearest to `vector`, nearest first
+    ///
+    /// If `filter` is set, only keys matching that glob pattern are returned.
+    VectorSearch {
+        index: String,
+        vector: Vec<f32>,
+        k: usize,
+
+        #[serde(default)]
+        filter: Option<String>,
+
+        #[serde(default)]
+        table: Option<String>,
+    },
+
+    /// This filters, sorts and projects the JSON values in a table with a small query language
+    ///
+    /// For example `from "users/*" select .name where .age > 30 and .city == "Oslo" order by .age desc limit 10`.
+    /// Every part is optional, and secondary indexes are used where they help.
+    Query {
+        query: String,
+
+        #[serde(default)]
+        table: Option<String>,
+
+        /// Reads from a snapshot taken with `Snapshot` instead of the table's current state
+        #[serde(default)]
+        snapshot_id: Option<String>,
+    },
+
+    /// This pins the current state of a table, which `Get`, `Range`, `PrefixScan` and `Query` can read with the
+    /// returned `snapshot_id` while writers continue
+    ///
+    /// The snapshot is released by `ReleaseSnapshot`, when the socket closes, or after `timeout` seconds (60 by default).
+    Snapshot {
+        #[serde(default)]
+        table: Option<String>,
+
+        #[serde(default)]
+        timeout: Option<u64>,
+    },
+
+    /// This releases a snapshot taken with `Snapshot`
+    ReleaseSnapshot { snapshot_id: String },
+
+    /// This starts a transaction
+    ///
+    /// `Set`, `Get` and `Delete` are queued until `Exec` runs them as one atomic step, or `Discard` drops them.
+    Multi,
+
+    /// This runs the queued commands of a transaction, unless a watched key has changed since it was watched
+    Exec,
+
+    /// This drops the queued commands of a transaction and stops watching every key
+    Discard,
+
+    /// This aborts the next transaction if any of `keys` is set, deleted or expires before it runs
+    ///
+    /// `table` defaults to the table chosen with `Select`.
+    Watch {
+        keys: Vec<String>,
+
+        #[serde(default)]
+        table: Option<String>,
+    },
+
+    /// This stops watching every key
+    Unwatch,
+
+    /// This runs several commands sent in one frame, replying with one `Batch` of their replies in order
+    ///
+    /// Commands run one after another, as if sent separately. An `atomic` batch may only hold `Set`, `Get` and `Delete`,
+    /// and runs them as one atomic step like a transaction. A bare array of commands is a batch that isn't atomic.
+    Batch {
+        commands: Vec<Command>,
+
+        #[serde(default)]
+        atomic: bool,
+    },
+
+    /// This runs a Rhai script as one atomic step, caching it so it can be run again with `EvalSha`
+    ///
+    /// The script can only read and write `keys` of the table, through `db.get(key)`, `db.set(key, value)`,
+    /// `db.set(key, value, seconds)` and `db.delete(key)`, and can broadcast with `db.publish(channel, message)`.
+    /// It sees `keys` and `args` as arrays, and its value is the reply. `table` defaults to the table chosen with `Select`.
+    Eval {
+        script: String,
+
+        #[serde(default)]
+        keys: Vec<String>,
+
+        #[serde(default)]
+        args: Vec<serde_json::Value>,
+
+        #[serde(default)]
+        table: Option<String>,
     },
+
+    /// This runs a script cached by `Eval` or `ScriptLoad` by its SHA-1, like `Eval`
+    EvalSha {
+        sha: String,
+
+        #[serde(default)]
+        keys: Vec<String>,
+
+        #[serde(default)]
+        args: Vec<serde_json::Value>,
+
+        #[serde(default)]
+        table: Option<String>,
+    },
+
+    /// This compiles and caches a script without running it, replying with its SHA-1
+    ScriptLoad { script: String },
+
+    /// This chooses the table used by commands that don't name one, `default` until changed
+    Select { table: String },
+
+    /// This creates a new, empty table
+    CreateTable {
+        name: String,
+
+        #[serde(flatten)]
+        options: TableOptions,
+    },
+
+    /// This drops a table and everything in it
+    ///
+    /// The `default` table can't be dropped.
+    DropTable { name: String },
+
+    /// This saves a snapshot of the database to disk, replying once it has been written
+    Save,
+
+    /// This starts saving a snapshot of the database to disk in the background
+    BgSave,
+
+    /// This starts rewriting the append-only file from the current contents of the database
+    BgRewriteAof,
+
+    /// This writes every key matching one of `patterns` to a JSON Lines file on the server
+    ///
+    /// `path` is relative to the server's data directory. Every key is written if there are no patterns, and `table` defaults to the table chosen with `Select`.
+    Export {
+        path: String,
+
+        #[serde(default)]
+        patterns: Vec<String>,
+
+        #[serde(default)]
+        table: Option<String>,
+    },
+
+    /// This loads every key matching one of `patterns` from a JSON Lines file on the server
+    ///
+    /// `path` is relative to the server's data directory. Every key is loaded if there are no patterns, and `table` defaults to the table chosen with `Select`.
+    Import {
+        path: String,
+
+        #[serde(default)]
+        patterns: Vec<String>,
+
+        #[serde(default)]
+        table: Option<String>,
+    },
+
+    /// This runs a command registered by a plugin, passing it `args`
+    ///
+    /// It runs against the table chosen with `Select`.
+    Plugin(PluginCommand),
+}
+
+/// A command registered by a plugin, with whatever arguments the plugin takes
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct PluginCommand {
+    pub name: String,
+
+    #[serde(default)]
+    pub args: serde_json::Value,
+
+    /// The table to run against, instead of the one chosen with `Select`
+    #[serde(default)]
+    pub table: Option<String>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -45,3 +466,31 @@ pub struct Value {
     pub data: Data,
     pub expiry: Option<u32>,
 }
+
+/// The keys watched by `WatchKey`
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WatchTarget {
+    /// Exactly this key
+    Key(String),
+
+    /// Every key matching this glob pattern
+    Pattern(String),
+}
+
+/// What to look up in a secondary index
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IndexQuery {
+    /// Entries whose field equals this value
+    Value(serde_json::Value),
+
+    /// Entries whose field is at least `start` and less than `end`, either of which may be left out
+    Range {
+        #[serde(default)]
+        start: Option<serde_json::Value>,
+
+        #[serde(default)]
+        end: Option<serde_json::Value>,
+    },
+}
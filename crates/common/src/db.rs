@@ -2,6 +2,9 @@ use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use time::{Duration, OffsetDateTime};
 
+/// The table every server starts with, used by commands that don't name a table
+pub const DEFAULT_TABLE: &str = "default";
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub struct Value {
@@ -15,6 +18,169 @@ pub enum Data {
     String(String),
     Json(serde_json::Value),
     Int(i64),
+    /// An embedding, searchable through vector indexes
+    Vector(Vec<f32>),
+    /// A payload of a data type registered by a plugin, which checks it before it is stored
+    Custom {
+        kind: String,
+        data: serde_json::Value,
+    },
+}
+
+/// Settings for a named table
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct TableOptions {
+    /// Seconds until values set without an expiry expire
+    #[serde(default)]
+    pub default_ttl: Option<u32>,
+
+    /// The approximate number of bytes of keys and data the table may hold before writes are rejected
+    #[serde(default)]
+    pub max_memory: Option<u64>,
+
+    /// Keep keys sorted so the table supports range and prefix reads
+    #[serde(default)]
+    pub ordered: bool,
+}
+
+/// A secondary index on the value at a JSON pointer inside the JSON values of matching keys
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct IndexDefinition {
+    pub name: String,
+
+    /// A glob pattern, like `orders/*`, for the keys to index
+    pub pattern: String,
+
+    /// A JSON pointer, like `/status`, to the indexed value
+    pub pointer: String,
+}
+
+/// A full-text index over the string values and selected string fields of JSON values of matching keys
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct SearchIndexDefinition {
+    pub name: String,
+
+    /// A glob pattern, like `chat/*`, for the keys to index
+    pub pattern: String,
+
+    /// JSON pointers, like `/text`, to the string fields of JSON values to index
+    #[serde(default)]
+    pub fields: Vec<String>,
+}
+
+/// An approximate nearest neighbour index over the vector values of matching keys
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct VectorIndexDefinition {
+    pub name: String,
+
+    /// A glob pattern, like `embeddings/*`, for the keys to index
+    pub pattern: String,
+
+    /// The length of the indexed vectors, vectors of any other length are skipped
+    pub dimensions: usize,
+
+    #[serde(default)]
+    pub metric: Metric,
+}
+
+/// How the distance between two vectors is measured
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Metric {
+    #[default]
+    Cosine,
+    Dot,
+    L2,
+}
+
+/// What happened to a key
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyEvent {
+    Set,
+    Delete,
+    Expire,
+}
+
+/// Runs an action whenever a key matching a pattern changes
+///
+/// Actions run after the change is committed, and their own writes can run triggers too.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct TriggerDefinition {
+    pub name: String,
+
+    /// A glob pattern, like `orders/*`, for the keys to watch
+    pub pattern: String,
+
+    /// The events that run the action, every event if empty
+    #[serde(default)]
+    pub events: Vec<KeyEvent>,
+
+    pub action: TriggerAction,
+}
+
+/// What a trigger does
+///
+/// Templates have `{key}`, `{event}` and `{table}` replaced, and `{value}` replaced with the JSON of the key's data,
+/// which is the data it had for deletes and expirations.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TriggerAction {
+    /// Broadcasts a templated message to a templated channel
+    Publish { channel: String, message: String },
+
+    /// Adds `by` to the integer at the templated `key` in the same table, starting from zero
+    Increment {
+        key: String,
+
+        #[serde(default = "one")]
+        by: i64,
+    },
+
+    /// Runs a script with the changed key as `keys[0]` and the event as `args[0]`
+    Script { script: String },
+}
+
+fn one() -> i64 {
+    1
+}
+
+/// A value found by a vector search
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct VectorHit {
+    pub key: String,
+    pub value: Value,
+
+    /// The cosine similarity or dot product, where higher is nearer, or the L2 distance, where lower is nearer
+    pub score: f32,
+}
+
+/// A value found by a full-text search
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct SearchHit {
+    pub key: String,
+    pub value: Value,
+
+    /// The BM25 relevance of the value, higher is better
+    pub score: f64,
+
+    /// The indexed text that matched, with matching words wrapped in `<mark>` and `</mark>`
+    pub highlights: Vec<String>,
+}
+
+/// A key and its value, as returned by reads of many keys
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct KeyValue {
+    pub key: String,
+    pub value: Value,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -23,6 +189,70 @@ pub struct BroadcastMessage {
     pub client_id: String,
     pub channel: String,
     pub message: String,
+
+    /// The number of the message, which increases within its channel and is never reused while the server runs
+    #[serde(default)]
+    pub seq: u64,
+
+    /// When the message was sent, as a Unix timestamp in milliseconds
+    #[serde(with = "time::serde::timestamp::milliseconds")]
+    pub sent_at: OffsetDateTime,
+}
+
+/// Which of a channel's recent messages a new subscriber is sent before the ones that follow
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Replay {
+    /// The messages after this sequence number, like the last one a reconnecting client received
+    SinceSeq(u64),
+
+    /// The messages sent at or after this Unix timestamp in milliseconds
+    SinceTime(u64),
+
+    /// The last this many messages
+    LastN(usize),
+}
+
+/// The state of broadcast routing, as returned by a PubSubStats command
+///
+/// The counts of messages are since the server started.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct PubSubStats {
+    /// Connected clients, each with a mailbox
+    pub mailboxes: usize,
+
+    /// Channels with at least one subscriber
+    pub channels: usize,
+
+    /// Distinct patterns with at least one subscriber
+    pub patterns: usize,
+
+    /// Messages waiting in mailboxes to be sent
+    pub queued: usize,
+
+    /// Messages put in a mailbox
+    pub delivered: u64,
+
+    /// Messages dropped from full mailboxes under the drop oldest policy
+    pub dropped: u64,
+
+    /// Clients disconnected for falling behind under the disconnect or buffer policies
+    pub disconnected: u64,
+}
+
+/// A pattern matching the names of broadcast channels
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChannelPattern {
+    /// A glob pattern like `orders.*` or `chat.room.?`, where `*` matches anything including `.`
+    Glob(String),
+
+    /// `.` separated tokens like NATS subjects, where `*` matches one token and a final `>` matches one or more
+    Nats(String),
+
+    /// `/` separated levels like MQTT topics, where `+` matches one level and a final `#` matches any number of them
+    Mqtt(String),
 }
 
 #[derive(Error, Debug)]
@@ -31,6 +261,19 @@ pub enum Error {
     DoubleExpirationDefined,
 }
 
+impl BroadcastMessage {
+    /// A message sent now, numbered when it is published
+    pub fn new(client_id: String, channel: String, message: String) -> Self {
+        Self {
+            client_id,
+            channel,
+            message,
+            seq: 0,
+            sent_at: OffsetDateTime::now_utc(),
+        }
+    }
+}
+
 impl From<crate::command::Value> for Value {
     fn from(value: crate::command::Value) -> Self {
         let expiry = value.expiry.and_then(|seconds| {
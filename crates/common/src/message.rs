@@ -2,7 +2,10 @@ use serde::{Deserialize, Serialize};
 
 use crate::{
     command::Command,
-    db::{BroadcastMessage, Value},
+    db::{
+        BroadcastMessage, ChannelPattern, KeyEvent, KeyValue, PubSubStats, SearchHit,
+        TriggerDefinition, Value, VectorHit,
+    },
 };
 
 /// Messages sent from the Server to Clients
@@ -15,9 +18,103 @@ pub enum Message {
     /// This contains broadcast messages sent to your subscriptions or to the `general` channel
     BroadcastMessage(BroadcastMessage),
 
+    /// This contains broadcast messages sent to channels matching a pattern you subscribed to, with that pattern
+    ///
+    /// A message matching several of your patterns is delivered once for each of them.
+    PatternMessage {
+        pattern: ChannelPattern,
+        message: BroadcastMessage,
+    },
+
+    /// This is sent when your client fell behind and broadcast messages to it were dropped
+    ///
+    /// It comes before the messages sent after the ones that were dropped, with how many were.
+    Lagged { skipped: u64 },
+
     /// This contains the result of a GetString command
     Get(Option<Value>),
 
+    /// This contains the entries read by a Range, PrefixScan, FindBy or Query command
+    Entries(Vec<KeyValue>),
+
+    /// This is sent in reply to a Snapshot command with the id to read from it with
+    SnapshotId(String),
+
+    /// This is sent in reply to a command queued in a transaction
+    Queued,
+
+    /// This contains the reply to each command of a transaction in order,
+    /// or nothing if it was aborted because a watched key changed
+    Exec(Option<Vec<Message>>),
+
+    /// This contains the reply to each command of a Batch in order
+    Batch(Vec<Message>),
+
+    /// This contains the reply to a command registered by a plugin
+    PluginResult(serde_json::Value),
+
+    /// This contains the value an Eval or EvalSha script evaluated to
+    ScriptResult(serde_json::Value),
+
+    /// This contains the SHA-1 of a script cached by a ScriptLoad command
+    ScriptSha(String),
+
+    /// This contains the results of a Search command, best first
+    SearchResults(Vec<SearchHit>),
+
+    /// This contains the results of a VectorSearch command, nearest first
+    VectorResults(Vec<VectorHit>),
+
+    /// This is sent when a key watched with WatchKey is set, deleted or expires
+    ///
+    /// `value` is the new value of a set key if the watch asked for it, and `version` increases with every write to
+    /// the table.
+    KeyChanged {
+        table: String,
+        key: String,
+        op: KeyEvent,
+
+        #[serde(default)]
+        value: Option<Value>,
+        version: u64,
+    },
+
+    /// This is sent when your client fell behind and changes to a table you watch keys in were dropped
+    ///
+    /// `skipped` changes to the table were dropped, which may or may not have been to keys you watch.
+    WatchLagged { table: String, skipped: u64 },
+
+    /// This contains the id and initial results of a LiveQuery command
+    ///
+    /// It is sent again with the full results if the server fell behind and missed changes to them.
+    LiveQuery {
+        query_id: String,
+        entries: Vec<KeyValue>,
+    },
+
+    /// This is sent when a write changes the results of a live query
+    ///
+    /// Keys newly matching the query are `added`, keys that still match with a new value are `updated`, and keys that
+    /// were deleted, expired or no longer match are `removed`.
+    LiveQueryDiff {
+        query_id: String,
+
+        #[serde(default)]
+        added: Vec<KeyValue>,
+
+        #[serde(default)]
+        updated: Vec<KeyValue>,
+
+        #[serde(default)]
+        removed: Vec<String>,
+    },
+
+    /// This contains the triggers listed by a Triggers command
+    Triggers(Vec<TriggerDefinition>),
+
+    /// This contains the state of broadcast routing returned by a PubSubStats command
+    PubSubStats(PubSubStats),
+
     /// This contains an status state
     Status(StatusMessage),
 }
@@ -0,0 +1,138 @@
+//! Measures broadcast throughput through the websocket server as the number of channels grows
+//!
+//! Run with `cargo bench -p aether-db --bench channels`. The subscribers split the channels between them, so every
+//! channel has one subscriber, and a publisher sends to each channel in turn. A message only reaches the socket
+//! subscribed to its channel, so throughput should hold steady however many channels there are.
+
+use std::time::{Duration, Instant};
+
+use aether_common::{command::Command, message::Message};
+use futures::{SinkExt, StreamExt};
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+use common::{Client, Server};
+
+mod common;
+
+const MESSAGES: usize = 20_000;
+const CHANNELS: [usize; 4] = [10, 100, 1_000, 10_000];
+const SUBSCRIBERS: usize = 64;
+
+/// How long a subscriber waits for another message before giving up on the rest
+const IDLE: Duration = Duration::from_secs(2);
+
+#[tokio::main]
+async fn main() {
+    let server = Server::start();
+
+    println!(
+        "{:>8} {:>12} {:>12} {:>16}",
+        "channels", "subscribers", "delivered", "messages/second"
+    );
+    for channels in CHANNELS {
+        let subscribers = SUBSCRIBERS.min(channels);
+        let server = &server;
+        let clients = (0..subscribers).map(|subscriber| async move {
+            let client = server
+                .connect(format!("subscriber-{channels}-{subscriber}"))
+                .await;
+            subscribe(client, (subscriber..channels).step_by(subscribers)).await
+        });
+        let clients = futures::future::join_all(clients).await;
+        let mut publisher = server.connect(format!("publisher-{channels}")).await;
+
+        let start = Instant::now();
+        let receives = clients
+            .into_iter()
+            .enumerate()
+            .map(|(subscriber, client)| {
+                let expected = (0..MESSAGES)
+                    .filter(|message| message % channels % subscribers == subscriber)
+                    .count();
+                tokio::spawn(receive(client, expected))
+            })
+            .collect::<Vec<_>>();
+        for message in 0..MESSAGES {
+            let command = Command::SendBroadcast {
+                channel: channel(message % channels),
+                message: message.to_string(),
+            };
+            send(&mut publisher, &command).await;
+        }
+        let mut delivered = 0;
+        let mut finished = start;
+        for receive in futures::future::join_all(receives).await {
+            let (received, last) = receive.expect("subscriber panicked");
+            delivered += received;
+            finished = finished.max(last);
+        }
+        let _ = publisher.close(None).await;
+
+        let elapsed = finished.duration_since(start);
+        println!(
+            "{channels:>8} {subscribers:>12} {delivered:>12} {:>16.0}",
+            delivered as f64 / elapsed.as_secs_f64()
+        );
+    }
+    drop(server);
+}
+
+fn channel(number: usize) -> String {
+    format!("bench.{number}")
+}
+
+async fn send(client: &mut Client, command: &Command) {
+    let text = serde_json::to_string(command).expect("could not serialize command");
+    client
+        .send(WsMessage::Text(text))
+        .await
+        .expect("could not send command");
+}
+
+// Subscribes to the channels, then waits for a reply to a later command so the subscriptions are in place
+async fn subscribe(mut client: Client, channels: impl Iterator<Item = usize>) -> Client {
+    for number in channels {
+        let command = Command::SubscribeBroadcast {
+            channel: channel(number),
+            subscribe_to_self: false,
+            replay: None,
+        };
+        send(&mut client, &command).await;
+    }
+    let command = Command::Get {
+        key: "sync".to_string(),
+        table: None,
+        snapshot_id: None,
+    };
+    send(&mut client, &command).await;
+    while let Some(message) = client.next().await {
+        if let WsMessage::Text(text) = message.expect("could not read from socket") {
+            if let Message::Get(_) = serde_json::from_str(&text).expect("invalid message") {
+                break;
+            }
+        }
+    }
+    client
+}
+
+// Counts broadcast messages until all of them arrive or none arrive for a while, returning the count and when
+// the last one arrived
+async fn receive(mut client: Client, expected: usize) -> (usize, Instant) {
+    let mut received = 0;
+    let mut last = Instant::now();
+    while received < expected {
+        let Ok(Some(message)) = tokio::time::timeout(IDLE, client.next()).await else {
+            break;
+        };
+        let WsMessage::Text(text) = message.expect("could not read from socket") else {
+            continue;
+        };
+        if let Message::BroadcastMessage(_) = serde_json::from_str(&text).expect("invalid message")
+        {
+            received += 1;
+            last = Instant::now();
+        }
+    }
+    let _ = client.close(None).await;
+    (received, last)
+}
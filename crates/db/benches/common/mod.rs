@@ -0,0 +1,80 @@
+//! Starts the server and connects sockets to it, for the benchmarks
+
+use std::{
+    net::{SocketAddr, TcpListener, TcpStream},
+    process::{Child, Command as Process, Stdio},
+    time::{Duration, Instant},
+};
+
+use aether_common::message::Message;
+use futures::StreamExt;
+use tempfile::TempDir;
+use tokio_tungstenite::{connect_async, tungstenite::Message as WsMessage};
+
+pub type Client =
+    tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+/// A server running in its own process, killed when the benchmark ends, even if it panics
+pub struct Server {
+    address: SocketAddr,
+    child: Child,
+    _dir: TempDir,
+}
+
+impl Server {
+    pub fn start() -> Self {
+        let dir = tempfile::tempdir().expect("could not create a temporary directory");
+        let address = free_address();
+        let child = Process::new(env!("CARGO_BIN_EXE_aether-db"))
+            .arg("--bind")
+            .arg(address.to_string())
+            .arg("--dir")
+            .arg(dir.path())
+            .env("RUST_LOG", "error")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("could not start the server");
+        wait_for(address);
+        Self {
+            address,
+            child,
+            _dir: dir,
+        }
+    }
+
+    pub async fn connect(&self, client_id: String) -> Client {
+        let url = format!("ws://{}/ws?client_id={client_id}", self.address);
+        let (mut client, _) = connect_async(url).await.expect("could not connect");
+        // Wait for the client id so the socket is ready for commands
+        while let Some(message) = client.next().await {
+            if let WsMessage::Text(text) = message.expect("could not read from socket") {
+                if let Message::ClientId(_) = serde_json::from_str(&text).expect("invalid message")
+                {
+                    break;
+                }
+            }
+        }
+        client
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn free_address() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("could not find a free port");
+    listener.local_addr().expect("could not get local address")
+}
+
+fn wait_for(address: SocketAddr) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while TcpStream::connect(address).is_err() {
+        assert!(Instant::now() < deadline, "server did not start");
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
@@ -0,0 +1,120 @@
+//! Measures set throughput through the websocket server as the number of connected sockets grows
+//!
+//! Run with `cargo bench -p aether-db --bench sockets`. Each socket pipelines its sets to its own keys,
+//! so throughput is limited by how well the server handles concurrent writers.
+//!
+//! Each run is repeated with keys chosen so they all land in one of the table's shards. Every writer then waits
+//! on the same lock, as they would if the table weren't sharded, which gives a baseline to compare against.
+
+use std::time::Instant;
+
+use aether_common::{
+    command::{Command, Value},
+    db::Data,
+    message::{Message, StatusMessage},
+};
+use futures::{SinkExt, StreamExt};
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+use common::{Client, Server};
+use shard::shard_of;
+
+mod common;
+#[path = "../src/db/shard.rs"]
+mod shard;
+
+const SETS: usize = 100_000;
+const SOCKETS: [usize; 5] = [1, 4, 16, 64, 256];
+
+#[tokio::main]
+async fn main() {
+    let server = Server::start();
+
+    println!(
+        "{:>8} {:>10} {:>12} {:>14}",
+        "sockets", "shards", "sets", "sets/second"
+    );
+    for sockets in SOCKETS {
+        for one_shard in [false, true] {
+            let per_socket = SETS / sockets;
+            let clients: Vec<_> = (0..sockets)
+                .map(|socket| server.connect(format!("bench-{one_shard}-{socket}")))
+                .collect();
+            let clients = futures::future::join_all(clients).await;
+            let keys = (0..sockets).map(|socket| {
+                let prefix = format!("bench/{one_shard}/{socket}");
+                keys(&prefix, one_shard).take(per_socket).collect()
+            });
+
+            let start = Instant::now();
+            let runs = clients
+                .into_iter()
+                .zip(keys)
+                .map(|(client, keys)| tokio::spawn(run(client, keys)));
+            for run in futures::future::join_all(runs).await {
+                run.expect("client panicked");
+            }
+            let elapsed = start.elapsed();
+
+            let sets = per_socket * sockets;
+            let shards = if one_shard { "one" } else { "all" };
+            println!(
+                "{sockets:>8} {shards:>10} {sets:>12} {:>14.0}",
+                sets as f64 / elapsed.as_secs_f64()
+            );
+        }
+    }
+    drop(server);
+}
+
+// Keys under `prefix`, only those in the first shard if `one_shard` is set
+fn keys(prefix: &str, one_shard: bool) -> impl Iterator<Item = String> + '_ {
+    (0..)
+        .map(move |n| format!("{prefix}/{n}"))
+        .filter(move |key| !one_shard || shard_of(key) == 0)
+}
+
+// Sends every set without waiting, then waits for all of their statuses
+async fn run(client: Client, keys: Vec<String>) {
+    let sets = keys.len();
+    let (mut sender, mut receiver) = client.split();
+    let send = tokio::spawn(async move {
+        for key in keys {
+            let command = Command::Set {
+                key,
+                value: Value {
+                    data: Data::String("value".to_string()),
+                    expiry: None,
+                },
+                table: None,
+            };
+            let text = serde_json::to_string(&command).expect("could not serialize command");
+            sender
+                .send(WsMessage::Text(text))
+                .await
+                .expect("could not send command");
+        }
+        sender
+    });
+
+    let mut statuses = 0;
+    while statuses < sets {
+        let message = receiver
+            .next()
+            .await
+            .expect("socket closed")
+            .expect("could not read from socket");
+        let WsMessage::Text(text) = message else {
+            continue;
+        };
+        match serde_json::from_str(&text).expect("invalid message") {
+            Message::Status(StatusMessage::Ok) => statuses += 1,
+            Message::Status(status) => panic!("set failed: {status:?}"),
+            _ => {}
+        }
+    }
+    let sender = send.await.expect("sender panicked");
+    if let Ok(mut client) = sender.reunite(receiver) {
+        let _ = client.close(None).await;
+    }
+}
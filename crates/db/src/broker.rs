@@ -0,0 +1,825 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use aether_common::db::{BroadcastMessage, ChannelPattern, PubSubStats, Replay};
+use time::OffsetDateTime;
+use tokio::sync::Notify;
+use tracing::warn;
+
+use crate::{config::SlowConsumerPolicy, db::SubscriptionOptions, pattern::channel_match};
+
+/// Messages to this channel are delivered to every mailbox, subscribed or not
+pub const GLOBAL_CHANNEL: &str = "global";
+
+/// Routes broadcast messages to the mailboxes subscribed to their channel
+///
+/// Publishing looks up the channel's subscribers directly, so it costs the same however many other channels there
+/// are. Patterns are the exception, since every distinct pattern has to be checked against the channel.
+#[derive(Clone, Default)]
+pub struct Broker {
+    routes: Arc<RwLock<Routes>>,
+    // Each history is locked on its own, so publishes to different channels don't wait on each other
+    histories: Arc<RwLock<HashMap<String, Arc<Mutex<History>>>>>,
+    // Numbers every message, so a channel's numbers keep increasing even after its history is dropped
+    last_seq: Arc<AtomicU64>,
+    limits: MailboxLimits,
+    history_limits: HistoryLimits,
+    metrics: Arc<Metrics>,
+}
+
+/// How many messages each mailbox holds, and what happens when a client falls behind and fills it
+#[derive(Clone, Copy, Debug)]
+pub struct MailboxLimits {
+    /// The most messages a mailbox holds before the policy applies
+    pub size: usize,
+
+    pub policy: SlowConsumerPolicy,
+
+    /// The most bytes of messages a mailbox buffers under the buffer policy
+    pub max_bytes: usize,
+}
+
+/// How many of each channel's recent messages are kept for subscribers to replay
+#[derive(Clone, Copy, Debug)]
+pub struct HistoryLimits {
+    /// The most messages kept per channel
+    pub size: usize,
+
+    /// How long messages are kept, however few there are
+    pub window: Option<Duration>,
+
+    /// How long a channel's history is kept after its last message
+    pub idle: Duration,
+}
+
+/// A channel's recent messages, oldest first
+struct History {
+    messages: VecDeque<Arc<BroadcastMessage>>,
+    last_published: Instant,
+}
+
+// Counted since the server started
+#[derive(Default)]
+struct Metrics {
+    delivered: AtomicU64,
+    dropped: AtomicU64,
+    disconnected: AtomicU64,
+}
+
+#[derive(Default)]
+struct Routes {
+    next_id: u64,
+    mailboxes: HashMap<u64, Route>,
+    // The subscribers of each channel and pattern, by mailbox id
+    channels: HashMap<String, HashMap<u64, SubscriptionOptions>>,
+    patterns: HashMap<ChannelPattern, HashMap<u64, SubscriptionOptions>>,
+}
+
+/// Where a mailbox's messages go, and what it is subscribed to so it can be removed quickly
+struct Route {
+    client_id: String,
+    inbox: Arc<Inbox>,
+    channels: HashSet<String>,
+    patterns: HashSet<ChannelPattern>,
+}
+
+/// The messages waiting in a mailbox, shared between the broker delivering them and the mailbox receiving them
+#[derive(Default)]
+struct Inbox {
+    queue: Mutex<Queue>,
+    notify: Notify,
+}
+
+#[derive(Default)]
+struct Queue {
+    deliveries: VecDeque<Delivery>,
+    bytes: usize,
+    // Messages dropped since the client was last told about them
+    skipped: u64,
+    disconnected: bool,
+}
+
+/// A broadcast message delivered to a mailbox
+#[derive(Clone, Debug)]
+pub enum Delivery {
+    /// A message to a channel the mailbox is subscribed to, or to the global channel
+    Channel(Arc<BroadcastMessage>),
+
+    /// A message to a channel matching a pattern the mailbox is subscribed to
+    Pattern(ChannelPattern, Arc<BroadcastMessage>),
+
+    /// This many messages were dropped because the mailbox was full, before the ones that follow
+    Lagged(u64),
+}
+
+/// The messages delivered to one client, which stops receiving them when dropped
+pub struct Mailbox {
+    id: u64,
+    broker: Broker,
+    inbox: Arc<Inbox>,
+}
+
+impl Broker {
+    pub fn new(limits: MailboxLimits, history_limits: HistoryLimits) -> Self {
+        Self {
+            limits,
+            history_limits,
+            ..Self::default()
+        }
+    }
+
+    /// Opens a mailbox for a client, which receives the global channel until it subscribes to others
+    pub fn mailbox(&self, client_id: String) -> Mailbox {
+        let inbox = Arc::new(Inbox::default());
+        let mut routes = self.write();
+        let id = routes.next_id;
+        routes.next_id += 1;
+        routes.mailboxes.insert(
+            id,
+            Route {
+                client_id,
+                inbox: inbox.clone(),
+                channels: HashSet::new(),
+                patterns: HashSet::new(),
+            },
+        );
+        Mailbox {
+            id,
+            broker: self.clone(),
+            inbox,
+        }
+    }
+
+    /// Delivers a message to every mailbox subscribed to its channel, returning how many it was delivered to
+    ///
+    /// Senders only receive their own messages if they subscribed with `subscribe_to_self`, except on the global
+    /// channel. What happens when a mailbox is full depends on the slow consumer policy.
+    pub fn publish(&self, message: BroadcastMessage) -> usize {
+        let routes = self.routes.read().unwrap_or_else(PoisonError::into_inner);
+        let history = self.history(&message.channel);
+        // Held until the message is delivered, so each channel's messages arrive in the order they are numbered
+        let mut history = history.lock().unwrap_or_else(PoisonError::into_inner);
+        let message = self.record(&mut history, message);
+        if message.channel == GLOBAL_CHANNEL {
+            return routes
+                .mailboxes
+                .values()
+                .filter(|route| self.deliver(route, Delivery::Channel(message.clone())))
+                .count();
+        }
+
+        let mut delivered = 0;
+        for (id, options) in routes.channels.get(&message.channel).into_iter().flatten() {
+            if let Some(route) = routes
+                .mailboxes
+                .get(id)
+                .filter(|route| route.wants(&message, options))
+            {
+                delivered += usize::from(self.deliver(route, Delivery::Channel(message.clone())));
+            }
+        }
+        for (pattern, subscribers) in &routes.patterns {
+            if !channel_match(pattern, &message.channel) {
+                continue;
+            }
+            for (id, options) in subscribers {
+                if let Some(route) = routes
+                    .mailboxes
+                    .get(id)
+                    .filter(|route| route.wants(&message, options))
+                {
+                    let delivery = Delivery::Pattern(pattern.clone(), message.clone());
+                    delivered += usize::from(self.deliver(route, delivery));
+                }
+            }
+        }
+        delivered
+    }
+
+    /// Expires old messages from every channel's history, dropping the histories that are left empty or are idle
+    pub fn expire_histories(&self) {
+        let mut histories = self
+            .histories
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        histories.retain(|_, history| {
+            // A publisher is about to record into it
+            if Arc::strong_count(history) > 1 {
+                return true;
+            }
+            let mut history = history.lock().unwrap_or_else(PoisonError::into_inner);
+            history.expire(&self.history_limits);
+            !history.messages.is_empty()
+                && history.last_published.elapsed() < self.history_limits.idle
+        });
+    }
+
+    /// The number of mailboxes, subscriptions and queued messages, and what happened to the messages published
+    pub fn stats(&self) -> PubSubStats {
+        let routes = self.routes.read().unwrap_or_else(PoisonError::into_inner);
+        PubSubStats {
+            mailboxes: routes.mailboxes.len(),
+            channels: routes.channels.len(),
+            patterns: routes.patterns.len(),
+            queued: routes
+                .mailboxes
+                .values()
+                .map(|route| route.inbox.lock().deliveries.len())
+                .sum(),
+            delivered: self.metrics.delivered.load(Ordering::Relaxed),
+            dropped: self.metrics.dropped.load(Ordering::Relaxed),
+            disconnected: self.metrics.disconnected.load(Ordering::Relaxed),
+        }
+    }
+
+    // Returns whether the message was delivered, applying the slow consumer policy if the mailbox is full
+    fn deliver(&self, route: &Route, delivery: Delivery) -> bool {
+        let size = delivery.size();
+        let mut queue = route.inbox.lock();
+        if queue.disconnected {
+            return false;
+        }
+        if queue.deliveries.len() >= self.limits.size {
+            match self.limits.policy {
+                SlowConsumerPolicy::DropOldest => {
+                    if queue.skipped == 0 {
+                        warn!(
+                            client_id = route.client_id,
+                            "Mailbox is full, dropping the oldest messages"
+                        );
+                    }
+                    if let Some(oldest) = queue.deliveries.pop_front() {
+                        queue.bytes -= oldest.size();
+                    }
+                    queue.skipped += 1;
+                    self.metrics.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                SlowConsumerPolicy::Buffer if queue.bytes + size <= self.limits.max_bytes => {}
+                SlowConsumerPolicy::Disconnect | SlowConsumerPolicy::Buffer => {
+                    warn!(
+                        client_id = route.client_id,
+                        queued = queue.deliveries.len(),
+                        "Mailbox is full, disconnecting slow consumer"
+                    );
+                    queue.disconnect();
+                    drop(queue);
+                    route.inbox.notify.notify_one();
+                    self.metrics.disconnected.fetch_add(1, Ordering::Relaxed);
+                    return false;
+                }
+            }
+        }
+        queue.deliveries.push_back(delivery);
+        queue.bytes += size;
+        drop(queue);
+        route.inbox.notify.notify_one();
+        self.metrics.delivered.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    fn write(&self) -> std::sync::RwLockWriteGuard<'_, Routes> {
+        self.routes.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Numbers a message and keeps it in its channel's history, which must be locked
+    fn record(
+        &self,
+        history: &mut History,
+        mut message: BroadcastMessage,
+    ) -> Arc<BroadcastMessage> {
+        message.seq = self.last_seq.fetch_add(1, Ordering::Relaxed) + 1;
+        message.sent_at = OffsetDateTime::now_utc();
+        let message = Arc::new(message);
+        history.record(message.clone(), &self.history_limits);
+        message
+    }
+
+    fn history(&self, channel: &str) -> Arc<Mutex<History>> {
+        let histories = self
+            .histories
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(history) = histories.get(channel) {
+            return history.clone();
+        }
+        drop(histories);
+        let mut histories = self
+            .histories
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        histories
+            .entry(channel.to_string())
+            .or_insert_with(|| Arc::new(Mutex::new(History::new())))
+            .clone()
+    }
+}
+
+impl Route {
+    fn wants(&self, message: &BroadcastMessage, options: &SubscriptionOptions) -> bool {
+        message.client_id != self.client_id || options.subscribe_to_self
+    }
+}
+
+impl Mailbox {
+    pub fn subscribe(&self, channel: String, options: SubscriptionOptions) {
+        let mut routes = self.broker.write();
+        routes.subscribe(self.id, channel, options);
+    }
+
+    /// Subscribes to a channel after delivering the messages from its history that the replay asks for,
+    /// returning how many were delivered
+    ///
+    /// Nothing can be published in between, so the subscriber receives every message once and in order.
+    pub fn subscribe_with_replay(
+        &self,
+        channel: String,
+        options: SubscriptionOptions,
+        replay: &Replay,
+    ) -> usize {
+        let mut routes = self.broker.write();
+        let mut delivered = 0;
+        if let Some(route) = routes.mailboxes.get(&self.id) {
+            let histories = self
+                .broker
+                .histories
+                .read()
+                .unwrap_or_else(PoisonError::into_inner);
+            if let Some(history) = histories.get(&channel) {
+                let mut history = history.lock().unwrap_or_else(PoisonError::into_inner);
+                history.expire(&self.broker.history_limits);
+                for message in history.replay(replay) {
+                    if route.wants(message, &options) {
+                        let delivery = Delivery::Channel(message.clone());
+                        delivered += usize::from(self.broker.deliver(route, delivery));
+                    }
+                }
+            }
+        }
+        routes.subscribe(self.id, channel, options);
+        delivered
+    }
+
+    pub fn unsubscribe(&self, channel: &str) {
+        let mut routes = self.broker.write();
+        if let Some(route) = routes.mailboxes.get_mut(&self.id) {
+            route.channels.remove(channel);
+        }
+        routes.unsubscribe(self.id, channel);
+    }
+
+    pub fn psubscribe(&self, pattern: ChannelPattern, options: SubscriptionOptions) {
+        let mut routes = self.broker.write();
+        if let Some(route) = routes.mailboxes.get_mut(&self.id) {
+            route.patterns.insert(pattern.clone());
+        }
+        routes
+            .patterns
+            .entry(pattern)
+            .or_default()
+            .insert(self.id, options);
+    }
+
+    pub fn punsubscribe(&self, pattern: &ChannelPattern) {
+        let mut routes = self.broker.write();
+        if let Some(route) = routes.mailboxes.get_mut(&self.id) {
+            route.patterns.remove(pattern);
+        }
+        routes.punsubscribe(self.id, pattern);
+    }
+
+    /// Unsubscribes from every channel and pattern
+    pub fn unsubscribe_all(&self) {
+        let mut routes = self.broker.write();
+        let Some(route) = routes.mailboxes.get_mut(&self.id) else {
+            return;
+        };
+        let channels = std::mem::take(&mut route.channels);
+        let patterns = std::mem::take(&mut route.patterns);
+        for channel in &channels {
+            routes.unsubscribe(self.id, channel);
+        }
+        for pattern in &patterns {
+            routes.punsubscribe(self.id, pattern);
+        }
+    }
+
+    /// Waits for the next message delivered to the mailbox
+    ///
+    /// Dropped messages are reported with a `Lagged` delivery before the messages after them. Returns `None` once
+    /// the slow consumer policy disconnected the mailbox.
+    pub async fn recv(&mut self) -> Option<Delivery> {
+        loop {
+            // Delivering stores a permit if nothing is waiting, so nothing delivered after the check is missed
+            let notified = self.inbox.notify.notified();
+            {
+                let mut queue = self.inbox.lock();
+                if queue.disconnected {
+                    return None;
+                }
+                if queue.skipped > 0 {
+                    return Some(Delivery::Lagged(std::mem::take(&mut queue.skipped)));
+                }
+                if let Some(delivery) = queue.deliveries.pop_front() {
+                    queue.bytes -= delivery.size();
+                    return Some(delivery);
+                }
+            }
+            notified.await;
+        }
+    }
+}
+
+impl Drop for Mailbox {
+    fn drop(&mut self) {
+        let mut routes = self.broker.write();
+        let Some(route) = routes.mailboxes.remove(&self.id) else {
+            return;
+        };
+        for channel in &route.channels {
+            routes.unsubscribe(self.id, channel);
+        }
+        for pattern in &route.patterns {
+            routes.punsubscribe(self.id, pattern);
+        }
+    }
+}
+
+impl Routes {
+    fn subscribe(&mut self, id: u64, channel: String, options: SubscriptionOptions) {
+        if let Some(route) = self.mailboxes.get_mut(&id) {
+            route.channels.insert(channel.clone());
+        }
+        self.channels
+            .entry(channel)
+            .or_default()
+            .insert(id, options);
+    }
+
+    // Removes channels and patterns once nothing subscribes to them, so they don't build up
+    fn unsubscribe(&mut self, id: u64, channel: &str) {
+        if let Some(subscribers) = self.channels.get_mut(channel) {
+            subscribers.remove(&id);
+            if subscribers.is_empty() {
+                self.channels.remove(channel);
+            }
+        }
+    }
+
+    fn punsubscribe(&mut self, id: u64, pattern: &ChannelPattern) {
+        if let Some(subscribers) = self.patterns.get_mut(pattern) {
+            subscribers.remove(&id);
+            if subscribers.is_empty() {
+                self.patterns.remove(pattern);
+            }
+        }
+    }
+}
+
+impl History {
+    fn new() -> Self {
+        Self {
+            messages: VecDeque::new(),
+            last_published: Instant::now(),
+        }
+    }
+
+    fn record(&mut self, message: Arc<BroadcastMessage>, limits: &HistoryLimits) {
+        self.last_published = Instant::now();
+        self.messages.push_back(message);
+        while self.messages.len() > limits.size {
+            self.messages.pop_front();
+        }
+        self.expire(limits);
+    }
+
+    fn expire(&mut self, limits: &HistoryLimits) {
+        let Some(window) = limits.window else {
+            return;
+        };
+        let oldest = OffsetDateTime::now_utc() - window;
+        while self
+            .messages
+            .front()
+            .is_some_and(|message| message.sent_at < oldest)
+        {
+            self.messages.pop_front();
+        }
+    }
+
+    fn replay(&self, replay: &Replay) -> impl Iterator<Item = &Arc<BroadcastMessage>> {
+        let skip = match *replay {
+            Replay::SinceSeq(seq) => self.messages.partition_point(|message| message.seq <= seq),
+            Replay::SinceTime(millis) => {
+                let time = i128::from(millis) * 1_000_000;
+                self.messages
+                    .partition_point(|message| message.sent_at.unix_timestamp_nanos() < time)
+            }
+            Replay::LastN(count) => self.messages.len().saturating_sub(count),
+        };
+        self.messages.iter().skip(skip)
+    }
+}
+
+impl Inbox {
+    fn lock(&self) -> MutexGuard<'_, Queue> {
+        self.queue.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Queue {
+    fn disconnect(&mut self) {
+        self.disconnected = true;
+        self.deliveries.clear();
+        self.bytes = 0;
+    }
+}
+
+impl Delivery {
+    pub fn message(&self) -> Option<&BroadcastMessage> {
+        match self {
+            Self::Channel(message) | Self::Pattern(_, message) => Some(message),
+            Self::Lagged(_) => None,
+        }
+    }
+
+    // Roughly how much memory the message holds, counted against the buffer limit
+    fn size(&self) -> usize {
+        self.message().map_or(0, |message| {
+            message.client_id.len() + message.channel.len() + message.message.len()
+        })
+    }
+}
+
+impl Default for HistoryLimits {
+    fn default() -> Self {
+        Self {
+            size: 100,
+            window: None,
+            idle: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+impl Default for MailboxLimits {
+    fn default() -> Self {
+        Self {
+            size: crate::CHANNEL_SIZE,
+            policy: SlowConsumerPolicy::default(),
+            max_bytes: 64 * 1024 * 1024,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::FutureExt;
+
+    use super::*;
+
+    fn message(client_id: &str, channel: &str) -> BroadcastMessage {
+        BroadcastMessage::new(
+            client_id.to_string(),
+            channel.to_string(),
+            "message".to_string(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_routing() {
+        let broker = Broker::default();
+        let others = SubscriptionOptions {
+            subscribe_to_self: false,
+        };
+        let everyone = SubscriptionOptions {
+            subscribe_to_self: true,
+        };
+        let mut orders = broker.mailbox("orders".to_string());
+        orders.subscribe("orders.eu".to_string(), others.clone());
+        orders.psubscribe(
+            ChannelPattern::Nats("orders.*.created".to_string()),
+            others.clone(),
+        );
+        orders.psubscribe(ChannelPattern::Glob("orders.*".to_string()), everyone);
+        let mut chat = broker.mailbox("chat".to_string());
+        chat.subscribe("chat".to_string(), others);
+
+        assert_eq!(broker.publish(message("other", "orders.eu")), 2);
+        assert!(matches!(orders.recv().await, Some(Delivery::Channel(_))));
+        assert!(matches!(
+            orders.recv().await,
+            Some(Delivery::Pattern(ChannelPattern::Glob(_), _))
+        ));
+        assert_eq!(broker.publish(message("other", "orders.eu.created")), 2);
+        // Only the pattern subscribed with subscribe_to_self sees the sender's own message
+        assert_eq!(broker.publish(message("orders", "orders.eu.created")), 1);
+        assert_eq!(broker.publish(message("other", "returns")), 0);
+        assert_eq!(broker.publish(message("chat", GLOBAL_CHANNEL)), 2);
+        let delivery = chat.recv().await.unwrap();
+        assert_eq!(delivery.message().unwrap().channel, GLOBAL_CHANNEL);
+
+        drop(orders);
+        assert_eq!(broker.publish(message("other", "orders.eu.created")), 0);
+        let routes = broker.routes.read().unwrap();
+        assert!(routes.patterns.is_empty());
+        assert_eq!(routes.channels.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_slow_consumers() {
+        let limits = |policy| MailboxLimits {
+            size: 2,
+            policy,
+            // Each message below counts as 18 bytes
+            max_bytes: 100,
+        };
+
+        let broker = Broker::new(
+            limits(SlowConsumerPolicy::DropOldest),
+            HistoryLimits::default(),
+        );
+        let mut mailbox = broker.mailbox("client".to_string());
+        for _ in 0..5 {
+            assert_eq!(broker.publish(message("other", GLOBAL_CHANNEL)), 1);
+        }
+        assert!(matches!(mailbox.recv().await, Some(Delivery::Lagged(3))));
+        assert!(matches!(mailbox.recv().await, Some(Delivery::Channel(_))));
+        assert!(matches!(mailbox.recv().await, Some(Delivery::Channel(_))));
+        let stats = broker.stats();
+        assert_eq!((stats.delivered, stats.dropped, stats.queued), (5, 3, 0));
+
+        let broker = Broker::new(
+            limits(SlowConsumerPolicy::Disconnect),
+            HistoryLimits::default(),
+        );
+        let mut mailbox = broker.mailbox("client".to_string());
+        for _ in 0..2 {
+            assert_eq!(broker.publish(message("other", GLOBAL_CHANNEL)), 1);
+        }
+        assert_eq!(broker.publish(message("other", GLOBAL_CHANNEL)), 0);
+        assert!(mailbox.recv().await.is_none());
+        assert_eq!(broker.stats().disconnected, 1);
+
+        // Buffering goes past the mailbox size until the messages reach the byte limit
+        let broker = Broker::new(limits(SlowConsumerPolicy::Buffer), HistoryLimits::default());
+        let mut mailbox = broker.mailbox("client".to_string());
+        let published = (0..10)
+            .map(|_| broker.publish(message("other", GLOBAL_CHANNEL)))
+            .sum::<usize>();
+        assert_eq!(published, 5);
+        assert_eq!(broker.stats().disconnected, 1);
+        assert!(mailbox.recv().await.is_none());
+    }
+
+    #[test]
+    fn test_history() {
+        let broker = Broker::new(
+            MailboxLimits::default(),
+            HistoryLimits {
+                size: 3,
+                window: None,
+                idle: Duration::from_secs(60),
+            },
+        );
+        for _ in 0..5 {
+            broker.publish(message("other", "chat"));
+        }
+        broker.publish(message("client", "chat"));
+        broker.publish(message("other", "news"));
+
+        let replayed = |replay| {
+            let mut mailbox = broker.mailbox("client".to_string());
+            let options = SubscriptionOptions {
+                subscribe_to_self: true,
+            };
+            let count = mailbox.subscribe_with_replay("chat".to_string(), options, &replay);
+            // Replayed messages come before the ones published after subscribing
+            broker.publish(message("other", "chat"));
+            (0..=count)
+                .map(|_| {
+                    let delivery = mailbox.recv().now_or_never().flatten().unwrap();
+                    delivery.message().unwrap().seq
+                })
+                .collect::<Vec<_>>()
+        };
+        // Only the last 3 of the channel's messages are kept, and 7 went to the other channel
+        assert_eq!(replayed(Replay::SinceSeq(0)), [4, 5, 6, 8]);
+        assert_eq!(replayed(Replay::SinceSeq(5)), [6, 8, 9]);
+        assert_eq!(replayed(Replay::LastN(1)), [9, 10]);
+        assert_eq!(replayed(Replay::SinceSeq(100)), [11]);
+        let past = OffsetDateTime::now_utc() - Duration::from_secs(60);
+        let past = (past.unix_timestamp_nanos() / 1_000_000) as u64;
+        assert_eq!(replayed(Replay::SinceTime(past)), [9, 10, 11, 12]);
+
+        // Without subscribe_to_self the client's own messages are not replayed
+        broker.publish(message("client", "chat"));
+        let mailbox = broker.mailbox("client".to_string());
+        let count = mailbox.subscribe_with_replay(
+            "chat".to_string(),
+            SubscriptionOptions::default(),
+            &Replay::LastN(3),
+        );
+        assert_eq!(count, 2);
+
+        let broker = Broker::new(
+            MailboxLimits::default(),
+            HistoryLimits {
+                size: 3,
+                window: Some(Duration::ZERO),
+                idle: Duration::from_secs(60),
+            },
+        );
+        broker.publish(message("other", "chat"));
+        std::thread::sleep(Duration::from_millis(5));
+        let mailbox = broker.mailbox("client".to_string());
+        let count = mailbox.subscribe_with_replay(
+            "chat".to_string(),
+            SubscriptionOptions::default(),
+            &Replay::SinceSeq(0),
+        );
+        assert_eq!(count, 0);
+    }
+
+    #[test]
+    fn test_ordered_delivery() {
+        let broker = Broker::new(
+            MailboxLimits {
+                size: 4000,
+                ..MailboxLimits::default()
+            },
+            HistoryLimits {
+                size: 0,
+                ..HistoryLimits::default()
+            },
+        );
+        let mut mailbox = broker.mailbox("client".to_string());
+        mailbox.subscribe("chat".to_string(), SubscriptionOptions::default());
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..1000 {
+                        broker.publish(message("other", "chat"));
+                    }
+                });
+            }
+        });
+
+        // Each channel's messages arrive in the order they were numbered, even without a history
+        let seqs: Vec<u64> = (0..4000)
+            .map(|_| {
+                let delivery = mailbox.recv().now_or_never().flatten().unwrap();
+                delivery.message().unwrap().seq
+            })
+            .collect();
+        assert!(seqs.windows(2).all(|pair| pair[0] < pair[1]));
+        broker.expire_histories();
+        assert!(broker.histories.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_expire_histories() {
+        let limits = |window, idle| HistoryLimits {
+            size: 3,
+            window,
+            idle,
+        };
+        let histories = |broker: &Broker| broker.histories.read().unwrap().len();
+
+        let broker = Broker::new(
+            MailboxLimits::default(),
+            limits(None, Duration::from_secs(60)),
+        );
+        broker.publish(message("other", "chat"));
+        broker.expire_histories();
+        assert_eq!(histories(&broker), 1);
+
+        // Histories whose messages have all expired are dropped
+        let broker = Broker::new(
+            MailboxLimits::default(),
+            limits(Some(Duration::ZERO), Duration::from_secs(60)),
+        );
+        broker.publish(message("other", "chat"));
+        std::thread::sleep(Duration::from_millis(5));
+        broker.expire_histories();
+        assert_eq!(histories(&broker), 0);
+
+        // So are idle ones, and the channel's numbers keep increasing afterwards
+        let broker = Broker::new(MailboxLimits::default(), limits(None, Duration::ZERO));
+        broker.publish(message("other", "chat"));
+        broker.expire_histories();
+        assert_eq!(histories(&broker), 0);
+        broker.publish(message("other", "chat"));
+        let mailbox = broker.mailbox("client".to_string());
+        let count = mailbox.subscribe_with_replay(
+            "chat".to_string(),
+            SubscriptionOptions::default(),
+            &Replay::SinceSeq(1),
+        );
+        assert_eq!(count, 1);
+    }
+}
@@ -0,0 +1,299 @@
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
+
+use aether_common::db::DEFAULT_TABLE;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+
+use crate::{
+    broker::{HistoryLimits, MailboxLimits},
+    persistence::aof::RewritePolicy,
+    plugin::PluginLimits,
+    script::ScriptLimits,
+};
+
+/// Command line interface
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    #[command(flatten)]
+    pub config: Config,
+
+    #[command(subcommand)]
+    pub command: Option<CliCommand>,
+}
+
+/// What to do, serving the database if no command is given
+#[derive(Debug, Subcommand)]
+pub enum CliCommand {
+    /// Serve the database over websockets
+    Serve,
+
+    /// Write the persisted keyspace as JSON Lines
+    ///
+    /// This reads the persistence files directly, so run it against a stopped server
+    /// or use the `export` command to export from a running one.
+    Export {
+        /// The file to write to, standard output if omitted
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+
+        /// Only export keys matching one of these glob patterns
+        #[arg(long = "pattern", short)]
+        patterns: Vec<String>,
+
+        /// The table to export
+        #[arg(long, short, default_value = DEFAULT_TABLE)]
+        table: String,
+    },
+
+    /// Load JSON Lines into the persisted keyspace
+    ///
+    /// This writes the persistence files directly, so run it against a stopped server
+    /// or use the `import` command to import into a running one.
+    Import {
+        /// The file to read from, standard input if omitted
+        #[arg(long, short)]
+        input: Option<PathBuf>,
+
+        /// Only import keys matching one of these glob patterns
+        #[arg(long = "pattern", short)]
+        patterns: Vec<String>,
+
+        /// The table to import
+        #[arg(long, short, default_value = DEFAULT_TABLE)]
+        table: String,
+    },
+
+    /// Load a Redis RDB dump into the persisted keyspace, printing a report of what was loaded
+    ///
+    /// Strings become strings or ints, while lists, sets, sorted sets and hashes become JSON.
+    /// Keys from Redis database 0 are loaded into the table, and from database N into `<table>-N`, which is created if
+    /// it doesn't exist.
+    /// This writes the persistence files directly, so run it against a stopped server.
+    ImportRdb {
+        /// The RDB file to read
+        input: PathBuf,
+
+        /// The table to import Redis database 0 into
+        #[arg(long, short, default_value = DEFAULT_TABLE)]
+        table: String,
+    },
+}
+
+/// Server configuration
+///
+/// Every option may also be set through an `AETHER_*` environment variable.
+#[derive(Clone, Debug, Args)]
+pub struct Config {
+    /// The address the websocket server listens on
+    #[arg(long, env = "AETHER_BIND", default_value = "127.0.0.1:3000")]
+    pub bind: SocketAddr,
+
+    /// The directory persistence files are read from and written to
+    #[arg(long, env = "AETHER_DIR", default_value = ".")]
+    pub dir: PathBuf,
+
+    /// The name of the snapshot file within `dir`
+    #[arg(long, env = "AETHER_DBFILENAME", default_value = "dump.adb")]
+    pub dbfilename: String,
+
+    /// Save a snapshot in the background every this many seconds
+    #[arg(long, env = "AETHER_SAVE", value_name = "SECONDS")]
+    pub save: Option<u64>,
+
+    /// Log every mutating command to the append-only file
+    #[arg(long, env = "AETHER_APPENDONLY")]
+    pub appendonly: bool,
+
+    /// The name of the append-only file within `dir`
+    #[arg(long, env = "AETHER_APPENDFILENAME", default_value = "appendonly.aof")]
+    pub appendfilename: String,
+
+    /// How often the append-only file is flushed to disk
+    #[arg(long, env = "AETHER_APPENDFSYNC", value_enum, default_value_t = FsyncPolicy::Everysec)]
+    pub appendfsync: FsyncPolicy,
+
+    /// Rewrite the append-only file once it has grown by this percentage since the last rewrite
+    ///
+    /// Zero disables automatic rewrites.
+    #[arg(
+        long,
+        env = "AETHER_AUTO_AOF_REWRITE_PERCENTAGE",
+        default_value_t = 100
+    )]
+    pub auto_aof_rewrite_percentage: u64,
+
+    /// Never automatically rewrite an append-only file smaller than this many bytes
+    #[arg(long, env = "AETHER_AUTO_AOF_REWRITE_MIN_SIZE", default_value_t = 64 * 1024 * 1024)]
+    pub auto_aof_rewrite_min_size: u64,
+
+    /// Stop scripts after they have run this many operations
+    ///
+    /// Zero removes the limit.
+    #[arg(
+        long,
+        env = "AETHER_SCRIPT_MAX_OPERATIONS",
+        default_value_t = 1_000_000
+    )]
+    pub script_max_operations: u64,
+
+    /// Stop scripts after they have run for this long, as they hold their keys locked while running
+    ///
+    /// With the append-only file enabled, every write waits for a running script.
+    #[arg(
+        long,
+        env = "AETHER_SCRIPT_TIMEOUT",
+        value_name = "MILLISECONDS",
+        default_value_t = 100
+    )]
+    pub script_timeout: u64,
+
+    /// WebAssembly plugin modules to load at startup, which can add commands and data types
+    #[arg(
+        long = "plugin",
+        env = "AETHER_PLUGINS",
+        value_name = "PATH",
+        value_delimiter = ','
+    )]
+    pub plugins: Vec<PathBuf>,
+
+    /// The most linear memory, in bytes, each plugin call may use
+    #[arg(long, env = "AETHER_PLUGIN_MAX_MEMORY", default_value_t = 16 * 1024 * 1024)]
+    pub plugin_max_memory: usize,
+
+    /// The fuel each plugin call is given, roughly the number of WebAssembly instructions it may run
+    #[arg(long, env = "AETHER_PLUGIN_FUEL", default_value_t = 10_000_000)]
+    pub plugin_fuel: u64,
+
+    /// How many broadcast messages each client's mailbox holds before the slow consumer policy applies
+    #[arg(long, env = "AETHER_MAILBOX_SIZE", default_value_t = crate::CHANNEL_SIZE)]
+    pub mailbox_size: usize,
+
+    /// What happens when a client falls so far behind that its mailbox is full
+    #[arg(long, env = "AETHER_SLOW_CONSUMER_POLICY", value_enum, default_value_t = SlowConsumerPolicy::DropOldest)]
+    pub slow_consumer_policy: SlowConsumerPolicy,
+
+    /// The most bytes of broadcast messages a mailbox may buffer under the `buffer` policy before disconnecting
+    #[arg(long, env = "AETHER_MAILBOX_MAX_BYTES", default_value_t = 64 * 1024 * 1024)]
+    pub mailbox_max_bytes: usize,
+
+    /// How many recent messages each broadcast channel keeps for subscribers to replay
+    ///
+    /// Zero keeps none.
+    #[arg(long, env = "AETHER_CHANNEL_HISTORY", default_value_t = 100)]
+    pub channel_history: usize,
+
+    /// Only keep channel messages sent within this many seconds for replay, however few there are
+    #[arg(long, env = "AETHER_CHANNEL_HISTORY_WINDOW", value_name = "SECONDS")]
+    pub channel_history_window: Option<u64>,
+
+    /// Drop a channel's history once nothing has been sent to it for this many seconds
+    #[arg(
+        long,
+        env = "AETHER_CHANNEL_HISTORY_IDLE",
+        value_name = "SECONDS",
+        default_value_t = 60 * 60
+    )]
+    pub channel_history_idle: u64,
+}
+
+/// When the append-only file is fsynced
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum FsyncPolicy {
+    /// Fsync after every write, before the write is acknowledged
+    Always,
+
+    /// Fsync once per second in the background
+    #[default]
+    Everysec,
+
+    /// Never fsync, leaving it to the operating system
+    No,
+}
+
+/// What happens to a client whose mailbox is full when another broadcast message arrives for it
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum SlowConsumerPolicy {
+    /// Drop the oldest message to make room, telling the client how many it missed
+    #[default]
+    DropOldest,
+
+    /// Disconnect the client, which can reconnect and catch up from the current state
+    Disconnect,
+
+    /// Keep buffering messages past the mailbox size, disconnecting the client once they reach `mailbox_max_bytes`
+    Buffer,
+}
+
+impl Config {
+    pub fn aof_path(&self) -> PathBuf {
+        self.dir.join(&self.appendfilename)
+    }
+
+    pub fn snapshot_path(&self) -> PathBuf {
+        self.dir.join(&self.dbfilename)
+    }
+
+    pub fn rewrite_policy(&self) -> RewritePolicy {
+        RewritePolicy {
+            percentage: self.auto_aof_rewrite_percentage,
+            min_size: self.auto_aof_rewrite_min_size,
+        }
+    }
+
+    pub fn script_limits(&self) -> ScriptLimits {
+        ScriptLimits {
+            max_operations: self.script_max_operations,
+            timeout: Duration::from_millis(self.script_timeout),
+        }
+    }
+
+    pub fn mailbox_limits(&self) -> MailboxLimits {
+        MailboxLimits {
+            size: self.mailbox_size,
+            policy: self.slow_consumer_policy,
+            max_bytes: self.mailbox_max_bytes,
+        }
+    }
+
+    pub fn history_limits(&self) -> HistoryLimits {
+        HistoryLimits {
+            size: self.channel_history,
+            window: self.channel_history_window.map(Duration::from_secs),
+            idle: Duration::from_secs(self.channel_history_idle),
+        }
+    }
+
+    pub fn plugin_limits(&self) -> PluginLimits {
+        PluginLimits {
+            max_memory: self.plugin_max_memory,
+            fuel: self.plugin_fuel,
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind: SocketAddr::from(([127, 0, 0, 1], 3000)),
+            dir: PathBuf::from("."),
+            dbfilename: "dump.adb".to_string(),
+            save: None,
+            appendonly: false,
+            appendfilename: "appendonly.aof".to_string(),
+            appendfsync: FsyncPolicy::default(),
+            auto_aof_rewrite_percentage: 100,
+            auto_aof_rewrite_min_size: 64 * 1024 * 1024,
+            script_max_operations: 1_000_000,
+            script_timeout: 100,
+            plugins: Vec::new(),
+            plugin_max_memory: 16 * 1024 * 1024,
+            plugin_fuel: 10_000_000,
+            mailbox_size: crate::CHANNEL_SIZE,
+            slow_consumer_policy: SlowConsumerPolicy::default(),
+            mailbox_max_bytes: 64 * 1024 * 1024,
+            channel_history: 100,
+            channel_history_window: None,
+            channel_history_idle: 60 * 60,
+        }
+    }
+}
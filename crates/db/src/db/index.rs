@@ -0,0 +1,285 @@
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet},
+    ops::Bound,
+};
+
+use aether_common::{
+    command::IndexQuery,
+    db::{Data, IndexDefinition, Value},
+};
+
+use crate::pattern::glob_match;
+
+/// A secondary index from the value at a JSON pointer to the keys holding that value
+///
+/// Only JSON values under keys matching the definition's pattern are indexed,
+/// and only when the pointer resolves to `null`, a boolean, a number or a string.
+pub struct Index {
+    definition: IndexDefinition,
+    keys: BTreeMap<IndexKey, BTreeSet<String>>,
+}
+
+impl Index {
+    pub fn new(definition: IndexDefinition) -> Self {
+        Self {
+            definition,
+            keys: BTreeMap::new(),
+        }
+    }
+
+    pub fn definition(&self) -> &IndexDefinition {
+        &self.definition
+    }
+
+    pub fn insert(&mut self, key: &str, value: &Value) {
+        if let Some(index_key) = self.index_key(key, value) {
+            self.keys
+                .entry(index_key)
+                .or_default()
+                .insert(key.to_string());
+        }
+    }
+
+    pub fn remove(&mut self, key: &str, value: &Value) {
+        let Some(index_key) = self.index_key(key, value) else {
+            return;
+        };
+        if let Some(keys) = self.keys.get_mut(&index_key) {
+            keys.remove(key);
+            if keys.is_empty() {
+                self.keys.remove(&index_key);
+            }
+        }
+    }
+
+    /// Returns the keys matching `query`, ordered by the indexed value and then by key
+    ///
+    /// Ranges include `start` and exclude `end`, and only cover values of the same type as their bounds,
+    /// so a range of numbers never returns strings.
+    pub fn find<'a>(&'a self, query: &IndexQuery) -> Box<dyn Iterator<Item = &'a str> + 'a> {
+        let bounds = match query {
+            IndexQuery::Value(value) => match IndexKey::from_json(value) {
+                Some(key) => (Bound::Included(key.clone()), Bound::Included(key)),
+                None => return Box::new(std::iter::empty()),
+            },
+            IndexQuery::Range { start, end } => {
+                let (Some(start), Some(end)) = (bound(start, true), bound(end, false)) else {
+                    return Box::new(std::iter::empty());
+                };
+                // An open end stops at the edge of the other end's type
+                let (start, end) = match (start, end) {
+                    (Bound::Unbounded, Bound::Excluded(end)) => {
+                        (end.first_of_type(), Bound::Excluded(end))
+                    }
+                    (Bound::Included(start), Bound::Unbounded) => {
+                        let end = start.last_of_type();
+                        (Bound::Included(start), end)
+                    }
+                    bounds => bounds,
+                };
+                match (&start, &end) {
+                    (Bound::Included(start), Bound::Excluded(end))
+                        if std::mem::discriminant(start) != std::mem::discriminant(end) =>
+                    {
+                        return Box::new(std::iter::empty());
+                    }
+                    // `BTreeMap::range` panics on inverted bounds, and they can't match anything anyway
+                    (Bound::Included(start), Bound::Excluded(end) | Bound::Included(end))
+                        if start > end =>
+                    {
+                        return Box::new(std::iter::empty());
+                    }
+                    _ => {}
+                }
+                (start, end)
+            }
+        };
+        Box::new(
+            self.keys
+                .range(bounds)
+                .flat_map(|(_, keys)| keys.iter().map(String::as_str)),
+        )
+    }
+
+    fn index_key(&self, key: &str, value: &Value) -> Option<IndexKey> {
+        if !glob_match(&self.definition.pattern, key) {
+            return None;
+        }
+        let Data::Json(json) = &value.data else {
+            return None;
+        };
+        IndexKey::from_json(json.pointer(&self.definition.pointer)?)
+    }
+}
+
+// Converts one end of a range, returning `None` if it can't be indexed so nothing matches
+fn bound(value: &Option<serde_json::Value>, start: bool) -> Option<Bound<IndexKey>> {
+    let Some(value) = value else {
+        return Some(Bound::Unbounded);
+    };
+    let key = IndexKey::from_json(value)?;
+    Some(match start {
+        true => Bound::Included(key),
+        false => Bound::Excluded(key),
+    })
+}
+
+/// An indexable JSON value
+///
+/// Values of different types sort as `null < booleans < numbers < strings`.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum IndexKey {
+    Null,
+    Bool(bool),
+    Number(Number),
+    String(String),
+}
+
+impl IndexKey {
+    fn from_json(value: &serde_json::Value) -> Option<Self> {
+        match value {
+            serde_json::Value::Null => Some(Self::Null),
+            serde_json::Value::Bool(bool) => Some(Self::Bool(*bool)),
+            serde_json::Value::Number(number) => number.as_f64().map(|number| {
+                // Negative zero would otherwise sort separately from zero
+                Self::Number(Number(if number == 0.0 { 0.0 } else { number }))
+            }),
+            serde_json::Value::String(string) => Some(Self::String(string.clone())),
+            serde_json::Value::Array(_) | serde_json::Value::Object(_) => None,
+        }
+    }
+
+    // The bound at or before the first value of this type
+    fn first_of_type(&self) -> Bound<Self> {
+        Bound::Included(match self {
+            Self::Null => Self::Null,
+            Self::Bool(_) => Self::Bool(false),
+            Self::Number(_) => Self::Number(Number(f64::NEG_INFINITY)),
+            Self::String(_) => Self::String(String::new()),
+        })
+    }
+
+    // The bound at or after the last value of this type
+    fn last_of_type(&self) -> Bound<Self> {
+        match self {
+            Self::Null => Bound::Included(Self::Null),
+            Self::Bool(_) => Bound::Included(Self::Bool(true)),
+            Self::Number(_) => Bound::Included(Self::Number(Number(f64::INFINITY))),
+            // Strings sort last
+            Self::String(_) => Bound::Unbounded,
+        }
+    }
+}
+
+/// A JSON number with a total order
+#[derive(Clone, Copy, Debug)]
+struct Number(f64);
+
+impl PartialEq for Number {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Number {}
+
+impl PartialOrd for Number {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Number {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    fn value(json: serde_json::Value) -> Value {
+        Value {
+            data: Data::Json(json),
+            expiry: None,
+        }
+    }
+
+    fn find(index: &Index, query: IndexQuery) -> Vec<String> {
+        index.find(&query).map(str::to_string).collect()
+    }
+
+    #[test]
+    fn test_index() {
+        let mut index = Index::new(IndexDefinition {
+            name: "total".to_string(),
+            pattern: "orders/*".to_string(),
+            pointer: "/total".to_string(),
+        });
+        index.insert("orders/1", &value(json!({ "total": 5 })));
+        index.insert("orders/2", &value(json!({ "total": 12.5 })));
+        index.insert("orders/3", &value(json!({ "total": 5 })));
+        index.insert("orders/4", &value(json!({ "total": "unknown" })));
+        index.insert("orders/5", &value(json!({ "items": [] })));
+        index.insert("users/1", &value(json!({ "total": 5 })));
+
+        assert_eq!(
+            find(&index, IndexQuery::Value(json!(5))),
+            ["orders/1", "orders/3"]
+        );
+        assert_eq!(
+            find(
+                &index,
+                IndexQuery::Range {
+                    start: Some(json!(6)),
+                    end: None,
+                }
+            ),
+            ["orders/2"]
+        );
+        assert_eq!(
+            find(
+                &index,
+                IndexQuery::Range {
+                    start: None,
+                    end: Some(json!("z")),
+                }
+            ),
+            ["orders/4"]
+        );
+        assert!(find(
+            &index,
+            IndexQuery::Range {
+                start: Some(json!(1)),
+                end: Some(json!("z")),
+            }
+        )
+        .is_empty());
+        assert_eq!(
+            find(
+                &index,
+                IndexQuery::Range {
+                    start: None,
+                    end: Some(json!(12.5)),
+                }
+            ),
+            ["orders/1", "orders/3"]
+        );
+        assert!(find(
+            &index,
+            IndexQuery::Range {
+                start: Some(json!(10)),
+                end: Some(json!(1)),
+            }
+        )
+        .is_empty());
+        assert!(find(&index, IndexQuery::Value(json!({ "total": 5 }))).is_empty());
+
+        index.remove("orders/1", &value(json!({ "total": 5 })));
+        assert_eq!(find(&index, IndexQuery::Value(json!(5))), ["orders/3"]);
+    }
+}
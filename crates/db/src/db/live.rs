@@ -0,0 +1,84 @@
+use std::collections::HashSet;
+
+use aether_common::db::{KeyEvent, KeyValue, Value};
+
+use super::table::Change;
+use crate::query::Query;
+
+/// The keys with a prefix whose values match a filter, kept up to date by the changes to their table
+///
+/// Only the keys are kept, so it costs little more than the keys themselves however large the values are.
+pub struct LiveQuery {
+    table: String,
+    prefix: String,
+    query: Query,
+    // Changes up to this version were already included in the initial results
+    since: u64,
+    // The keys currently in the results
+    keys: HashSet<String>,
+}
+
+/// How a change moved a key into, within or out of a live query's results
+#[derive(Debug)]
+pub enum Delta {
+    Added(KeyValue),
+    Updated(KeyValue),
+    Removed(String),
+}
+
+impl LiveQuery {
+    pub(super) fn new(
+        table: String,
+        prefix: String,
+        query: Query,
+        since: u64,
+        results: &[KeyValue],
+    ) -> Self {
+        Self {
+            table,
+            prefix,
+            query,
+            since,
+            keys: results.iter().map(|entry| entry.key.clone()).collect(),
+        }
+    }
+
+    pub fn table(&self) -> &str {
+        &self.table
+    }
+
+    pub(super) fn query(&self) -> &Query {
+        &self.query
+    }
+
+    /// Replaces the results with ones read at `since`, for when changes to them were missed
+    pub(super) fn reset(&mut self, since: u64, results: &[KeyValue]) {
+        self.since = since;
+        self.keys = results.iter().map(|entry| entry.key.clone()).collect();
+    }
+
+    /// Updates the results with a change to the table, returning how they changed if they did
+    pub fn apply(&mut self, change: &Change) -> Option<Delta> {
+        if change.version <= self.since || !change.key.starts_with(&self.prefix) {
+            return None;
+        }
+        let matches =
+            change.event == KeyEvent::Set && self.query.matches(&change.key, &change.value);
+        let entry = || KeyValue {
+            key: change.key.clone(),
+            value: Value::clone(&change.value),
+        };
+        match (matches, self.keys.contains(&change.key)) {
+            (true, false) => {
+                self.keys.insert(change.key.clone());
+                Some(Delta::Added(entry()))
+            }
+            (true, true) => Some(Delta::Updated(entry())),
+            (false, true) => {
+                self.keys.remove(&change.key);
+                Some(Delta::Removed(change.key.clone()))
+            }
+            (false, false) => None,
+        }
+    }
+}
@@ -1,50 +1,1255 @@
-use aether_common::db::BroadcastMessage;
-use std::{collections::HashMap, sync::Arc};
+use aether_common::{
+    command::IndexQuery,
+    db::{
+        BroadcastMessage, ChannelPattern, IndexDefinition, KeyValue, SearchHit,
+        SearchIndexDefinition, TableOptions, TriggerAction, TriggerDefinition, Value, VectorHit,
+        VectorIndexDefinition, DEFAULT_TABLE,
+    },
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
-use table::Table;
-use tokio::sync::{broadcast, RwLock};
+pub use live::{Delta, LiveQuery};
+use serde::{Deserialize, Serialize};
+pub use table::{Change, Watch};
+use table::{Table, TableSnapshot};
+use time::OffsetDateTime;
+use tokio::sync::{broadcast, mpsc, Mutex, RwLock};
+use tracing::{debug, error, info, warn};
 
+use crate::{
+    broker::Broker,
+    config::Config,
+    pattern,
+    persistence::{
+        self,
+        aof::Aof,
+        export,
+        snapshot::{Contents, EntryBatches, Snapshotter, TableContents},
+        Entry,
+    },
+    plugin::{self, Plugins},
+    query,
+    script::{self, Scripts},
+};
+
+mod index;
+mod live;
+mod search;
+mod shard;
 mod table;
+mod trigger;
+mod vector;
+
+/// How many hits a search returns when no limit is given
+const DEFAULT_SEARCH_LIMIT: usize = 10;
+
+/// How many batches of entries are copied ahead of a save or rewrite writing them
+const COPY_BUFFER: usize = 4;
+
+/// How often old broadcast messages and idle channel histories are dropped
+const HISTORY_EXPIRY_INTERVAL: Duration = Duration::from_secs(10);
 
 #[derive(Clone)]
 pub struct Database {
     // Data
-    pub db: Table,
-    pub broadcast_channel: broadcast::Sender<BroadcastMessage>,
+    tables: Arc<RwLock<HashMap<String, Table>>>,
+    pub broker: Broker,
     // TODO: Add get current subscriptions command
-    // TODO: Add clear all subscriptions command
-    subscriptions: Arc<RwLock<HashMap<String, HashMap<String, SubscriptionOptions>>>>,
+    subscriptions: Arc<RwLock<Subscriptions>>,
+    pattern_subscriptions: Arc<RwLock<PatternSubscriptions>>,
+    // Held while a table, index or trigger is created or dropped,
+    // so checking whether it exists and logging the change can't interleave with another
+    schema: Arc<Mutex<()>>,
+    aof: Option<Aof>,
+    snapshotter: Snapshotter,
+    // Exports and imports are confined to this directory
+    dir: PathBuf,
+    scripts: Scripts,
+    plugins: Plugins,
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub struct SubscriptionOptions {
     pub subscribe_to_self: bool,
 }
 
+/// Every client's subscriptions, keyed by client id and then channel
+pub type Subscriptions = HashMap<String, HashMap<String, SubscriptionOptions>>;
+
+/// Every client's pattern subscriptions, keyed by client id and then pattern
+pub type PatternSubscriptions = HashMap<String, HashMap<ChannelPattern, SubscriptionOptions>>;
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
-    #[error("could not send on broadcast channel")]
-    BroadcastSendMessage(#[from] broadcast::error::SendError<BroadcastMessage>),
+    #[error("the append-only file is not enabled")]
+    AofDisabled,
+
+    #[error("table {0} does not exist")]
+    NoSuchTable(String),
+
+    #[error("table {0} already exists")]
+    TableExists(String),
+
+    #[error("the default table can't be dropped")]
+    DropDefaultTable,
+
+    #[error("table {0} is over its memory limit")]
+    OutOfMemory(String),
+
+    #[error("table {0} is not ordered")]
+    Unordered(String),
+
+    #[error("index {0} does not exist")]
+    NoSuchIndex(String),
+
+    #[error("index {0} already exists")]
+    IndexExists(String),
+
+    #[error("search index {0} does not exist")]
+    NoSuchSearchIndex(String),
+
+    #[error("search index {0} already exists")]
+    SearchIndexExists(String),
+
+    #[error("vector index {0} does not exist")]
+    NoSuchVectorIndex(String),
+
+    #[error("vector index {0} already exists")]
+    VectorIndexExists(String),
+
+    #[error("trigger {0} does not exist")]
+    NoSuchTrigger(String),
+
+    #[error("trigger {0} already exists")]
+    TriggerExists(String),
+
+    #[error("{0} is not a relative path within the data directory")]
+    InvalidPath(String),
+
+    #[error("snapshot {0} does not exist")]
+    NoSuchSnapshot(String),
+
+    #[error("live query {0} does not exist")]
+    NoSuchLiveQuery(String),
+
+    #[error("no transaction has been started")]
+    NoTransaction,
+
+    #[error("a transaction has already been started")]
+    TransactionStarted,
+
+    #[error("only set, get and delete can be used in a transaction")]
+    NotTransactional,
+
+    #[error("the transaction was discarded because a command could not be queued")]
+    TransactionDiscarded,
+
+    #[error("expected a vector with {expected} dimensions, got {actual}")]
+    VectorDimensions { expected: usize, actual: usize },
+
+    #[error("script {0} does not exist")]
+    NoSuchScript(String),
+
+    #[error(transparent)]
+    Plugin(#[from] plugin::Error),
+
+    #[error(transparent)]
+    Query(#[from] query::Error),
+
+    #[error(transparent)]
+    Script(#[from] script::Error),
+
+    #[error(transparent)]
+    Persistence(#[from] persistence::Error),
 }
 
 impl Database {
+    /// Opens the database
+    ///
+    /// The snapshot is loaded first, then the append-only file is replayed on top of it if it is enabled.
+    pub async fn open(config: &Config) -> Result<Self, Error> {
+        let mut database = Self {
+            snapshotter: Snapshotter::new(config.snapshot_path()),
+            dir: config.dir.clone(),
+            scripts: Scripts::new(config.script_limits()),
+            plugins: Plugins::load(&config.plugins, config.plugin_limits()).await?,
+            broker: Broker::new(config.mailbox_limits(), config.history_limits()),
+            ..Self::default()
+        };
+        let contents = database.snapshotter.load().await?;
+        {
+            let mut tables = database.tables.write().await;
+            for (name, contents) in contents.tables {
+                let table = tables
+                    .entry(name)
+                    .or_insert_with(|| Table::with_options(contents.options));
+                table.extend(contents.entries).await;
+                for index in contents.indexes {
+                    table.create_index(index).await;
+                }
+                for index in contents.search_indexes {
+                    table.create_search_index(index).await;
+                }
+                for index in contents.vector_indexes {
+                    table.create_vector_index(index).await;
+                }
+                for trigger in contents.triggers {
+                    table.create_trigger(trigger);
+                }
+            }
+        }
+        *database.subscriptions.write().await = contents.subscriptions;
+        *database.pattern_subscriptions.write().await = contents.pattern_subscriptions;
+        if config.appendonly {
+            let (aof, entries) = Aof::open(
+                &config.aof_path(),
+                config.appendfsync,
+                config.rewrite_policy(),
+            )
+            .await?;
+            database.apply_all(entries).await;
+            database.aof = Some(aof);
+        }
+        // Triggers only start once everything is loaded, so replaying writes doesn't run them again
+        let tables: Vec<(String, Table)> =
+            database.tables.read().await.clone().into_iter().collect();
+        for (name, table) in tables {
+            if !table.triggers().is_empty() {
+                database.start_triggers(name, &table);
+            }
+        }
+        tokio::spawn(expire_histories_periodically(database.broker.clone()));
+        if let Some(seconds) = config.save {
+            tokio::spawn(save_periodically(
+                database.clone(),
+                Duration::from_secs(seconds),
+            ));
+        }
+        Ok(database)
+    }
+
+    /// Returns the table named `name`
+    pub async fn table(&self, name: &str) -> Result<Table, Error> {
+        let tables = self.tables.read().await;
+        tables
+            .get(name)
+            .cloned()
+            .ok_or_else(|| Error::NoSuchTable(name.to_string()))
+    }
+
+    /// Creates an empty table, logging it to the append-only file first if it is enabled
+    pub async fn create_table(&self, name: String, options: TableOptions) -> Result<(), Error> {
+        let _schema = self.schema.lock().await;
+        if self.tables.read().await.contains_key(&name) {
+            return Err(Error::TableExists(name));
+        }
+        self.log_and_apply(vec![Entry::CreateTable { name, options }])
+            .await
+    }
+
+    /// Drops a table and everything in it, logging it to the append-only file first if it is enabled
+    pub async fn drop_table(&self, name: String) -> Result<(), Error> {
+        if name == DEFAULT_TABLE {
+            return Err(Error::DropDefaultTable);
+        }
+        let _schema = self.schema.lock().await;
+        if !self.tables.read().await.contains_key(&name) {
+            return Err(Error::NoSuchTable(name));
+        }
+        self.log_and_apply(vec![Entry::DropTable { name }]).await
+    }
+
+    /// Gets a value from a table
+    pub async fn get(&self, table: &str, key: &str) -> Result<Option<Value>, Error> {
+        Ok(self.table(table).await?.get(key).await)
+    }
+
+    /// Gets the entries with keys from `start` up to but not including `end` from an ordered table
+    pub async fn range(
+        &self,
+        table: &str,
+        start: Option<&str>,
+        end: Option<&str>,
+        limit: Option<usize>,
+        reverse: bool,
+    ) -> Result<Vec<KeyValue>, Error> {
+        let entries = self
+            .table(table)
+            .await?
+            .range(start, end, limit, reverse)
+            .await
+            .ok_or_else(|| Error::Unordered(table.to_string()))?;
+        Ok(key_values(entries))
+    }
+
+    /// Gets the entries with keys starting with `prefix` from an ordered table
+    pub async fn prefix_scan(&self, table: &str, prefix: &str) -> Result<Vec<KeyValue>, Error> {
+        let entries = self
+            .table(table)
+            .await?
+            .prefix_scan(prefix)
+            .await
+            .ok_or_else(|| Error::Unordered(table.to_string()))?;
+        Ok(key_values(entries))
+    }
+
+    /// Gets the entries found by `query` on an index, ordered by the indexed value and then by key
+    pub async fn find_by(
+        &self,
+        table: &str,
+        index: &str,
+        query: &IndexQuery,
+    ) -> Result<Vec<KeyValue>, Error> {
+        let entries = self
+            .table(table)
+            .await?
+            .find_by(index, query)
+            .await
+            .ok_or_else(|| Error::NoSuchIndex(index.to_string()))?;
+        Ok(key_values(entries))
+    }
+
+    /// Pins the current state of a table for consistent reads while writers continue
+    pub async fn snapshot(&self, table: &str) -> Result<Snapshot, Error> {
+        Ok(Snapshot {
+            table: table.to_string(),
+            inner: self.table(table).await?.snapshot(),
+        })
+    }
+
+    /// Parses and runs a query over the JSON values in a table
+    pub async fn query(&self, table: &str, query: &str) -> Result<Vec<KeyValue>, Error> {
+        let query = query::parse(query)?;
+        let entries = self.table(table).await?.query(&query).await;
+        Ok(key_values(entries))
+    }
+
+    /// Creates a secondary index, logging it to the append-only file first if it is enabled
+    pub async fn create_index(&self, table: &str, index: IndexDefinition) -> Result<(), Error> {
+        let _schema = self.schema.lock().await;
+        let handle = self.table(table).await?;
+        if handle
+            .indexes()
+            .await
+            .iter()
+            .any(|existing| existing.name == index.name)
+        {
+            return Err(Error::IndexExists(index.name));
+        }
+        self.log_and_apply(vec![Entry::CreateIndex {
+            table: table.to_string(),
+            index,
+        }])
+        .await
+    }
+
+    /// Drops a secondary index, logging it to the append-only file first if it is enabled
+    pub async fn drop_index(&self, table: &str, name: String) -> Result<(), Error> {
+        let _schema = self.schema.lock().await;
+        let handle = self.table(table).await?;
+        if !handle
+            .indexes()
+            .await
+            .iter()
+            .any(|existing| existing.name == name)
+        {
+            return Err(Error::NoSuchIndex(name));
+        }
+        self.log_and_apply(vec![Entry::DropIndex {
+            table: table.to_string(),
+            name,
+        }])
+        .await
+    }
+
+    /// Searches a full-text index, returning the best `limit` hits first
+    pub async fn search(
+        &self,
+        table: &str,
+        index: &str,
+        query: &str,
+        limit: Option<usize>,
+    ) -> Result<Vec<SearchHit>, Error> {
+        let search = search::Search::parse(query);
+        let hits = self
+            .table(table)
+            .await?
+            .search(index, &search, limit.unwrap_or(DEFAULT_SEARCH_LIMIT))
+            .await
+            .ok_or_else(|| Error::NoSuchSearchIndex(index.to_string()))?;
+        Ok(hits
+            .into_iter()
+            .map(|(hit, value, highlights)| SearchHit {
+                key: hit.key,
+                value,
+                score: hit.score,
+                highlights,
+            })
+            .collect())
+    }
+
+    /// Creates a full-text index, logging it to the append-only file first if it is enabled
+    pub async fn create_search_index(
+        &self,
+        table: &str,
+        index: SearchIndexDefinition,
+    ) -> Result<(), Error> {
+        let _schema = self.schema.lock().await;
+        let handle = self.table(table).await?;
+        if handle
+            .search_indexes()
+            .await
+            .iter()
+            .any(|existing| existing.name == index.name)
+        {
+            return Err(Error::SearchIndexExists(index.name));
+        }
+        self.log_and_apply(vec![Entry::CreateSearchIndex {
+            table: table.to_string(),
+            index,
+        }])
+        .await
+    }
+
+    /// Drops a full-text index, logging it to the append-only file first if it is enabled
+    pub async fn drop_search_index(&self, table: &str, name: String) -> Result<(), Error> {
+        let _schema = self.schema.lock().await;
+        let handle = self.table(table).await?;
+        if !handle
+            .search_indexes()
+            .await
+            .iter()
+            .any(|existing| existing.name == name)
+        {
+            return Err(Error::NoSuchSearchIndex(name));
+        }
+        self.log_and_apply(vec![Entry::DropSearchIndex {
+            table: table.to_string(),
+            name,
+        }])
+        .await
+    }
+
+    /// Finds the `k` values with vectors nearest to `vector`, nearest first
+    ///
+    /// Only keys matching the `filter` glob pattern are returned if it is set.
+    pub async fn vector_search(
+        &self,
+        table: &str,
+        index: &str,
+        vector: &[f32],
+        k: usize,
+        filter: Option<&str>,
+    ) -> Result<Vec<VectorHit>, Error> {
+        let handle = self.table(table).await?;
+        let definition = handle
+            .vector_indexes()
+            .await
+            .into_iter()
+            .find(|definition| definition.name == index)
+            .ok_or_else(|| Error::NoSuchVectorIndex(index.to_string()))?;
+        if vector.len() != definition.dimensions {
+            return Err(Error::VectorDimensions {
+                expected: definition.dimensions,
+                actual: vector.len(),
+            });
+        }
+        let neighbours = handle
+            .vector_search(index, vector, k, filter)
+            .await
+            .ok_or_else(|| Error::NoSuchVectorIndex(index.to_string()))?;
+        Ok(neighbours
+            .into_iter()
+            .map(|(neighbour, value)| VectorHit {
+                key: neighbour.key,
+                value,
+                score: neighbour.score,
+            })
+            .collect())
+    }
+
+    /// Creates a vector index, logging it to the append-only file first if it is enabled
+    pub async fn create_vector_index(
+        &self,
+        table: &str,
+        index: VectorIndexDefinition,
+    ) -> Result<(), Error> {
+        let _schema = self.schema.lock().await;
+        let handle = self.table(table).await?;
+        if handle
+            .vector_indexes()
+            .await
+            .iter()
+            .any(|existing| existing.name == index.name)
+        {
+            return Err(Error::VectorIndexExists(index.name));
+        }
+        self.log_and_apply(vec![Entry::CreateVectorIndex {
+            table: table.to_string(),
+            index,
+        }])
+        .await
+    }
+
+    /// Drops a vector index, logging it to the append-only file first if it is enabled
+    pub async fn drop_vector_index(&self, table: &str, name: String) -> Result<(), Error> {
+        let _schema = self.schema.lock().await;
+        let handle = self.table(table).await?;
+        if !handle
+            .vector_indexes()
+            .await
+            .iter()
+            .any(|existing| existing.name == name)
+        {
+            return Err(Error::NoSuchVectorIndex(name));
+        }
+        self.log_and_apply(vec![Entry::DropVectorIndex {
+            table: table.to_string(),
+            name,
+        }])
+        .await
+    }
+
+    /// Creates a trigger, logging it to the append-only file first if it is enabled
+    pub async fn create_trigger(
+        &self,
+        table: &str,
+        trigger: TriggerDefinition,
+    ) -> Result<(), Error> {
+        let _schema = self.schema.lock().await;
+        let handle = self.table(table).await?;
+        if handle
+            .triggers()
+            .iter()
+            .any(|existing| existing.name == trigger.name)
+        {
+            return Err(Error::TriggerExists(trigger.name));
+        }
+        if let TriggerAction::Script { script } = &trigger.action {
+            self.script_load(script)?;
+        }
+        self.log_and_apply(vec![Entry::CreateTrigger {
+            table: table.to_string(),
+            trigger,
+        }])
+        .await?;
+        self.start_triggers(table.to_string(), &handle);
+        Ok(())
+    }
+
+    /// Drops a trigger, logging it to the append-only file first if it is enabled
+    pub async fn drop_trigger(&self, table: &str, name: String) -> Result<(), Error> {
+        let _schema = self.schema.lock().await;
+        let handle = self.table(table).await?;
+        if !handle
+            .triggers()
+            .iter()
+            .any(|existing| existing.name == name)
+        {
+            return Err(Error::NoSuchTrigger(name));
+        }
+        self.log_and_apply(vec![Entry::DropTrigger {
+            table: table.to_string(),
+            name,
+        }])
+        .await
+    }
+
+    /// Receives every change committed to a table from now on, until the table is dropped
+    pub async fn changes(&self, table: &str) -> Result<broadcast::Receiver<Change>, Error> {
+        Ok(self.table(table).await?.changes())
+    }
+
+    /// Starts a live query over the JSON values with keys starting with `prefix` that match `filter`
+    ///
+    /// This returns the query, its initial results in key order, and the changes to the table that keep it up to
+    /// date from then on.
+    pub async fn live_query(
+        &self,
+        table: &str,
+        prefix: String,
+        filter: Option<&str>,
+    ) -> Result<(LiveQuery, Vec<KeyValue>, broadcast::Receiver<Change>), Error> {
+        let query = query::Query {
+            pattern: Some(format!("{}*", pattern::escape(&prefix))),
+            filter: filter.map(query::parse_filter).transpose()?,
+            order: Vec::new(),
+            projection: Vec::new(),
+            limit: None,
+        };
+        let handle = self.table(table).await?;
+        // Listening before reading means every change the results don't include is received
+        let changes = handle.changes();
+        let snapshot = handle.snapshot();
+        let results = key_values(snapshot.query(&query).await);
+        let live_query = LiveQuery::new(
+            table.to_string(),
+            prefix,
+            query,
+            snapshot.version(),
+            &results,
+        );
+        Ok((live_query, results, changes))
+    }
+
+    /// Runs a live query again from the current state of its table, for when changes to it were missed
+    ///
+    /// This returns its new results in key order, and changes already included in them are ignored from then on.
+    pub async fn refresh_live_query(
+        &self,
+        live_query: &mut LiveQuery,
+    ) -> Result<Vec<KeyValue>, Error> {
+        let snapshot = self.table(live_query.table()).await?.snapshot();
+        let results = key_values(snapshot.query(live_query.query()).await);
+        live_query.reset(snapshot.version(), &results);
+        Ok(results)
+    }
+
+    /// Lists a table's triggers, by name
+    pub async fn triggers(&self, table: &str) -> Result<Vec<TriggerDefinition>, Error> {
+        Ok(self.table(table).await?.triggers())
+    }
+
+    /// Starts running a table's triggers on every change to it, unless they are already running
+    fn start_triggers(&self, name: String, table: &Table) {
+        if let Some(changes) = table.start_triggers() {
+            tokio::spawn(trigger::run(self.clone(), name, table.downgrade(), changes));
+        }
+    }
+
+    /// Removes a value, logging it to the append-only file first if it is enabled
+    pub async fn delete(&self, table: &str, key: String) -> Result<(), Error> {
+        self.table(table).await?;
+        self.log_and_apply(vec![Entry::Delete {
+            table: table.to_string(),
+            key,
+        }])
+        .await
+    }
+
+    /// Has `watch` notice the next time any of `keys` in a table is set, removed or expires
+    pub async fn watch(&self, table: &str, keys: &[String], watch: &Watch) -> Result<(), Error> {
+        let handle = self.table(table).await?;
+        for key in keys {
+            handle.watch(key, watch).await;
+        }
+        Ok(())
+    }
+
+    /// Runs every operation as one atomic step, logging the writes to the append-only file first if it is enabled
+    ///
+    /// Nothing is run and `None` is returned if a key `watch` is watching has changed.
+    /// Otherwise the value read by each `Get` is returned in order, with `None` for the writes.
+    pub async fn exec(
+        &self,
+        operations: Vec<Operation>,
+        watch: &Watch,
+    ) -> Result<Option<Vec<Option<Value>>>, Error> {
+        // Tables are locked in name order so concurrent transactions can't deadlock
+        let mut tables = BTreeMap::new();
+        for operation in &operations {
+            let name = operation.table();
+            if !tables.contains_key(name) {
+                tables.insert(name.to_string(), self.table(name).await?);
+            }
+        }
+        let mut checked = Vec::with_capacity(operations.len());
+        for operation in operations {
+            checked.push(match operation {
+                Operation::Set { table, key, value } => Operation::Set {
+                    value: with_default_ttl(tables[&table].options(), self.check(value).await?),
+                    table,
+                    key,
+                },
+                operation => operation,
+            });
+        }
+        let operations = checked;
+        for (name, handle) in &tables {
+            let values: Vec<(&str, &Value)> = operations
+                .iter()
+                .filter_map(|operation| match operation {
+                    Operation::Set { table, key, value } if table == name => {
+                        Some((key.as_str(), value))
+                    }
+                    _ => None,
+                })
+                .collect();
+            if !handle.fits(values).await {
+                return Err(Error::OutOfMemory(name.clone()));
+            }
+        }
+
+        // The log is locked before the tables, like every other write
+        let mut guard = match &self.aof {
+            Some(aof) => Some(aof.lock().await),
+            None => None,
+        };
+        let mut transactions = BTreeMap::new();
+        for (name, handle) in &tables {
+            let keys: Vec<&str> = operations
+                .iter()
+                .filter(|operation| operation.table() == name)
+                .map(Operation::key)
+                .collect();
+            transactions.insert(name.as_str(), handle.lock(keys).await);
+        }
+        if watch.changed() {
+            return Ok(None);
+        }
+
+        let entries: Vec<Entry> = operations
+            .iter()
+            .filter_map(|operation| match operation {
+                Operation::Get { .. } => None,
+                Operation::Set { table, key, value } => Some(Entry::Set {
+                    table: table.clone(),
+                    key: key.clone(),
+                    value: value.clone(),
+                }),
+                Operation::Delete { table, key } => Some(Entry::Delete {
+                    table: table.clone(),
+                    key: key.clone(),
+                }),
+            })
+            .collect();
+        if let (Some(guard), false) = (&mut guard, entries.is_empty()) {
+            guard.append(&entries).await?;
+        }
+
+        let results = operations
+            .into_iter()
+            .map(|operation| {
+                let transaction = transactions
+                    .get_mut(operation.table())
+                    .expect("every table is locked");
+                match operation {
+                    Operation::Get { key, .. } => transaction.get(&key),
+                    Operation::Set { key, value, .. } => {
+                        transaction.set(key, value);
+                        None
+                    }
+                    Operation::Delete { key, .. } => {
+                        transaction.remove(&key);
+                        None
+                    }
+                }
+            })
+            .collect();
+        drop(transactions);
+
+        if guard.is_some_and(|guard| guard.needs_rewrite()) {
+            match self.background_rewrite_aof().await {
+                Ok(()) => info!("Started automatic append-only file rewrite"),
+                Err(err) => debug!(?err, "Could not start automatic append-only file rewrite"),
+            }
+        }
+        Ok(Some(results))
+    }
+
+    /// Runs every operation as one atomic step like [`Database::exec`], without watching any keys
+    pub async fn exec_all(&self, operations: Vec<Operation>) -> Result<Vec<Option<Value>>, Error> {
+        let results = self.exec(operations, &Watch::default()).await?;
+        // Nothing can change a watch that isn't watching any keys
+        Ok(results.unwrap_or_default())
+    }
+
+    /// Compiles and caches a script, returning the SHA-1 to run it by with [`Database::eval_sha`]
+    pub fn script_load(&self, source: &str) -> Result<String, Error> {
+        Ok(self.scripts.load(source)?)
+    }
+
+    /// Compiles, caches and runs a script, like [`Database::eval_sha`]
+    pub async fn eval(
+        &self,
+        table: &str,
+        source: &str,
+        keys: &[String],
+        args: &[serde_json::Value],
+        client_id: &str,
+    ) -> Result<serde_json::Value, Error> {
+        let sha = self.script_load(source)?;
+        self.eval_sha(table, &sha, keys, args, client_id).await
+    }
+
+    /// Runs a cached script as one atomic step over `keys`, the only keys in the table it can read and write
+    ///
+    /// Its writes are logged to the append-only file first if it is enabled, and nothing is written if it fails.
+    /// Its changes are tagged with `client_id` as their origin, and what it broadcasts is sent as `client_id` once its
+    /// writes are applied.
+    ///
+    /// The script runs on a blocking thread so it doesn't hold up other tasks. It holds its keys locked while it runs,
+    /// and the append-only file too if it is enabled, so then every write to the database waits for it.
+    pub async fn eval_sha(
+        &self,
+        table: &str,
+        sha: &str,
+        keys: &[String],
+        args: &[serde_json::Value],
+        client_id: &str,
+    ) -> Result<serde_json::Value, Error> {
+        let script = self
+            .scripts
+            .get(sha)
+            .ok_or_else(|| Error::NoSuchScript(sha.to_string()))?;
+        let handle = self.table(table).await?;
+
+        // The log is locked before the table, like every other write
+        let mut guard = match &self.aof {
+            Some(aof) => Some(aof.lock().await),
+            None => None,
+        };
+        let mut transaction = handle.lock(keys.iter().map(String::as_str)).await;
+        let values = keys
+            .iter()
+            .map(|key| (key.clone(), transaction.get(key)))
+            .collect();
+        let scripts = self.scripts.clone();
+        let (script_keys, script_args) = (keys.to_vec(), args.to_vec());
+        let outcome = tokio::task::spawn_blocking(move || {
+            scripts.run(&script, values, &script_keys, &script_args)
+        })
+        .await
+        .unwrap_or_else(|err| std::panic::resume_unwind(err.into_panic()))?;
+
+        let writes: Vec<(String, Option<Value>)> = outcome
+            .writes
+            .into_iter()
+            .map(|(key, value)| {
+                let value = value.map(|value| with_default_ttl(handle.options(), value));
+                (key, value)
+            })
+            .collect();
+        let sets = writes
+            .iter()
+            .filter_map(|(key, value)| Some((key.as_str(), value.as_ref()?)));
+        if !transaction.fits(sets) {
+            return Err(Error::OutOfMemory(table.to_string()));
+        }
+        let entries: Vec<Entry> = writes
+            .iter()
+            .map(|(key, value)| match value {
+                Some(value) => Entry::Set {
+                    table: table.to_string(),
+                    key: key.clone(),
+                    value: value.clone(),
+                },
+                None => Entry::Delete {
+                    table: table.to_string(),
+                    key: key.clone(),
+                },
+            })
+            .collect();
+        if let (Some(guard), false) = (&mut guard, entries.is_empty()) {
+            guard.append(&entries).await?;
+        }
+        transaction.set_origin(client_id);
+        for (key, value) in writes {
+            match value {
+                Some(value) => transaction.set(key, value),
+                None => {
+                    transaction.remove(&key);
+                }
+            }
+        }
+        drop(transaction);
+
+        for (channel, message) in outcome.broadcasts {
+            let message = BroadcastMessage::new(client_id.to_string(), channel, message);
+            self.broker.publish(message);
+        }
+        if guard.is_some_and(|guard| guard.needs_rewrite()) {
+            match self.background_rewrite_aof().await {
+                Ok(()) => info!("Started automatic append-only file rewrite"),
+                Err(err) => debug!(?err, "Could not start automatic append-only file rewrite"),
+            }
+        }
+        Ok(outcome.result)
+    }
+
+    /// Runs a command registered by a plugin against `table`, broadcasting what it publishes as `client_id`
+    pub async fn plugin_command(
+        &self,
+        table: &str,
+        name: &str,
+        args: &serde_json::Value,
+        client_id: &str,
+    ) -> Result<serde_json::Value, Error> {
+        self.table(table).await?;
+        Ok(self
+            .plugins
+            .command(self, table, name, args, client_id)
+            .await?)
+    }
+
+    /// Sets a value, logging it to the append-only file first if it is enabled
+    pub async fn set(&self, table: &str, key: String, value: Value) -> Result<(), Error> {
+        self.set_many(table, vec![(key, value)]).await
+    }
+
+    /// Sets many values at once, logging them to the append-only file in a single write if it is enabled
+    ///
+    /// Values without an expiry are given the table's default TTL,
+    /// and nothing is set if the values would put the table over its memory limit.
+    pub async fn set_many(&self, table: &str, values: Vec<(String, Value)>) -> Result<(), Error> {
+        let handle = self.table(table).await?;
+        let mut checked = Vec::with_capacity(values.len());
+        for (key, value) in values {
+            let value = self.check(value).await?;
+            checked.push((key, with_default_ttl(handle.options(), value)));
+        }
+        let values = checked;
+        if !handle
+            .fits(values.iter().map(|(key, value)| (key.as_str(), value)))
+            .await
+        {
+            return Err(Error::OutOfMemory(table.to_string()));
+        }
+
+        let entries = values
+            .into_iter()
+            .map(|(key, value)| Entry::Set {
+                table: table.to_string(),
+                key,
+                value,
+            })
+            .collect();
+        self.log_and_apply(entries).await
+    }
+
+    /// Writes every key in `table` matching one of `patterns` to a JSON Lines file, returning how many were written
+    ///
+    /// `path` is relative to the data directory.
+    pub async fn export(
+        &self,
+        table: &str,
+        path: &str,
+        patterns: Vec<String>,
+    ) -> Result<usize, Error> {
+        let path = self.data_path(path)?;
+        let entries = self.table(table).await?.entries().await;
+        let written = tokio::task::spawn_blocking(move || {
+            let file = BufWriter::new(File::create(path)?);
+            export::write_records(file, entries, &patterns)
+        })
+        .await
+        .map_err(persistence::Error::from)??;
+        Ok(written)
+    }
+
+    /// Loads every key matching one of `patterns` from a JSON Lines file into `table`, returning how many were loaded
+    ///
+    /// `path` is relative to the data directory.
+    pub async fn import(
+        &self,
+        table: &str,
+        path: &str,
+        patterns: Vec<String>,
+    ) -> Result<usize, Error> {
+        let path = self.data_path(path)?;
+        let records = tokio::task::spawn_blocking(move || {
+            let file = BufReader::new(File::open(path)?);
+            export::read_records(file, &patterns)
+        })
+        .await
+        .map_err(persistence::Error::from)??;
+        let count = records.len();
+        self.set_many(
+            table,
+            records
+                .into_iter()
+                .map(|record| (record.key, record.value))
+                .collect(),
+        )
+        .await?;
+        Ok(count)
+    }
+
+    // Resolves a path a client gave within the data directory, so clients can't read or write anywhere else
+    fn data_path(&self, path: &str) -> Result<PathBuf, Error> {
+        let relative = Path::new(path);
+        let within = relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+        if !within || relative.file_name().is_none() {
+            return Err(Error::InvalidPath(path.to_string()));
+        }
+        Ok(self.dir.join(relative))
+    }
+
+    /// Has a plugin check a value of a custom data type before it is stored
+    async fn check(&self, value: Value) -> Result<Value, Error> {
+        Ok(Value {
+            data: self.plugins.check(value.data).await?,
+            ..value
+        })
+    }
+
+    async fn log_and_apply(&self, entries: Vec<Entry>) -> Result<(), Error> {
+        // Keep the log locked until the entries are applied so the log and table agree on ordering
+        let guard = match &self.aof {
+            Some(aof) => Some(aof.append_all(&entries).await?),
+            None => None,
+        };
+        self.apply_all(entries).await;
+
+        if guard.is_some_and(|guard| guard.needs_rewrite()) {
+            match self.background_rewrite_aof().await {
+                Ok(()) => info!("Started automatic append-only file rewrite"),
+                Err(err) => debug!(?err, "Could not start automatic append-only file rewrite"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Saves a snapshot, waiting until it has been written
+    pub async fn save(&self) -> Result<(), Error> {
+        let guard = self.snapshotter.begin_save()?;
+        let contents = self.pin_contents().await;
+        self.snapshotter.save(guard, contents).await?;
+        Ok(())
+    }
+
+    /// Starts saving a snapshot in the background
+    ///
+    /// The tables are pinned as of the moment the save starts and their values streamed to disk a batch at a time,
+    /// so writers only wait for a batch to be copied and the tables are never copied whole.
+    pub async fn background_save(&self) -> Result<(), Error> {
+        let guard = self.snapshotter.begin_save()?;
+        let database = self.clone();
+        tokio::spawn(async move {
+            let contents = database.pin_contents().await;
+            if let Err(err) = database.snapshotter.save(guard, contents).await {
+                error!(?err, "Could not save snapshot in the background");
+            }
+        });
+        Ok(())
+    }
+
+    /// Starts rewriting the append-only file from the current contents of the tables
+    ///
+    /// Writes only wait while the tables are pinned, and continue while their entries are streamed to the new file.
+    /// Those writes are copied into it before it replaces the old one.
+    pub async fn background_rewrite_aof(&self) -> Result<(), Error> {
+        let Some(aof) = self.aof.clone() else {
+            return Err(Error::AofDisabled);
+        };
+        let guard = aof.begin_rewrite().await?;
+        let contents = self.pin_contents().await;
+        drop(guard);
+
+        let (sender, entries) = mpsc::channel(COPY_BUFFER);
+        // Sending only fails once the rewrite has given up, which it logs itself
+        tokio::spawn(async move { log_entries(contents, sender).await.ok() });
+        tokio::spawn(async move {
+            if let Err(err) = aof.finish_rewrite(entries).await {
+                error!(?err, "Could not rewrite append-only file");
+            }
+        });
+        Ok(())
+    }
+
+    /// Pins the tables and copies everything but their entries, which are copied a batch at a time as they're read
+    async fn pin_contents(&self) -> Contents<EntryBatches> {
+        let tables = self.tables.read().await.clone();
+        let mut pinned = HashMap::with_capacity(tables.len());
+        for (name, table) in tables {
+            let (sender, entries) = mpsc::channel(COPY_BUFFER);
+            tokio::spawn(copy_entries(table.snapshot(), sender));
+            let contents = TableContents {
+                options: table.options().clone(),
+                entries,
+                indexes: table.indexes().await,
+                search_indexes: table.search_indexes().await,
+                vector_indexes: table.vector_indexes().await,
+                triggers: table.triggers(),
+            };
+            pinned.insert(name, contents);
+        }
+        Contents {
+            tables: pinned,
+            subscriptions: self.subscriptions.read().await.clone(),
+            pattern_subscriptions: self.pattern_subscriptions.read().await.clone(),
+        }
+    }
+
+    async fn apply_all(&self, entries: Vec<Entry>) {
+        let mut values = Vec::new();
+        for entry in entries {
+            match entry {
+                Entry::Set { table, key, value } => values.push((table, key, value)),
+                Entry::CreateTable { name, options } => {
+                    // Earlier sets may belong to a table of the same name that is about to be replaced
+                    self.set_values(std::mem::take(&mut values)).await;
+                    let mut tables = self.tables.write().await;
+                    tables
+                        .entry(name)
+                        .or_insert_with(|| Table::with_options(options));
+                }
+                Entry::DropTable { name } => {
+                    self.set_values(std::mem::take(&mut values)).await;
+                    let mut tables = self.tables.write().await;
+                    tables.remove(&name);
+                }
+                Entry::Delete { table, key } => {
+                    self.set_values(std::mem::take(&mut values)).await;
+                    if let Some(table) = self.existing_table(&table).await {
+                        table.remove(&key).await;
+                    }
+                }
+                Entry::CreateIndex { table, index } => {
+                    self.set_values(std::mem::take(&mut values)).await;
+                    if let Some(table) = self.existing_table(&table).await {
+                        table.create_index(index).await;
+                    }
+                }
+                Entry::DropIndex { table, name } => {
+                    if let Some(table) = self.existing_table(&table).await {
+                        table.drop_index(&name).await;
+                    }
+                }
+                Entry::CreateSearchIndex { table, index } => {
+                    self.set_values(std::mem::take(&mut values)).await;
+                    if let Some(table) = self.existing_table(&table).await {
+                        table.create_search_index(index).await;
+                    }
+                }
+                Entry::DropSearchIndex { table, name } => {
+                    if let Some(table) = self.existing_table(&table).await {
+                        table.drop_search_index(&name).await;
+                    }
+                }
+                Entry::CreateVectorIndex { table, index } => {
+                    self.set_values(std::mem::take(&mut values)).await;
+                    if let Some(table) = self.existing_table(&table).await {
+                        table.create_vector_index(index).await;
+                    }
+                }
+                Entry::DropVectorIndex { table, name } => {
+                    if let Some(table) = self.existing_table(&table).await {
+                        table.drop_vector_index(&name).await;
+                    }
+                }
+                Entry::CreateTrigger { table, trigger } => {
+                    self.set_values(std::mem::take(&mut values)).await;
+                    if let Some(table) = self.existing_table(&table).await {
+                        table.create_trigger(trigger);
+                    }
+                }
+                Entry::DropTrigger { table, name } => {
+                    self.set_values(std::mem::take(&mut values)).await;
+                    if let Some(table) = self.existing_table(&table).await {
+                        table.drop_trigger(&name);
+                    }
+                }
+                Entry::Subscribe {
+                    client_id,
+                    channel,
+                    options,
+                } => {
+                    let mut subscriptions = self.subscriptions.write().await;
+                    subscriptions
+                        .entry(client_id)
+                        .or_default()
+                        .insert(channel, options);
+                }
+                Entry::Unsubscribe { client_id, channel } => {
+                    let mut subscriptions = self.subscriptions.write().await;
+                    if let Some(client_subscriptions) = subscriptions.get_mut(&client_id) {
+                        client_subscriptions.remove(&channel);
+                    }
+                }
+                Entry::PSubscribe {
+                    client_id,
+                    pattern,
+                    options,
+                } => {
+                    let mut patterns = self.pattern_subscriptions.write().await;
+                    patterns
+                        .entry(client_id)
+                        .or_default()
+                        .insert(pattern, options);
+                }
+                Entry::PUnsubscribe { client_id, pattern } => {
+                    let mut patterns = self.pattern_subscriptions.write().await;
+                    if let Some(client_patterns) = patterns.get_mut(&client_id) {
+                        client_patterns.remove(&pattern);
+                    }
+                }
+                Entry::ClearSubscriptions { client_id } => {
+                    self.subscriptions.write().await.remove(&client_id);
+                    self.pattern_subscriptions.write().await.remove(&client_id);
+                }
+            }
+        }
+        self.set_values(values).await;
+    }
+
+    // Looks up a table while replaying, where a missing table is only worth a warning
+    async fn existing_table(&self, name: &str) -> Option<Table> {
+        let table = self.tables.read().await.get(name).cloned();
+        if table.is_none() {
+            warn!(
+                table = name,
+                "Skipping change to a table that does not exist"
+            );
+        }
+        table
+    }
+
+    async fn set_values(&self, values: Vec<(String, String, Value)>) {
+        let mut by_table: HashMap<String, Vec<(String, Value)>> = HashMap::new();
+        for (table, key, value) in values {
+            by_table.entry(table).or_default().push((key, value));
+        }
+
+        let tables = self.tables.read().await;
+        for (name, mut values) in by_table {
+            let Some(table) = tables.get(&name) else {
+                warn!(
+                    table = name,
+                    "Skipping values for a table that does not exist"
+                );
+                continue;
+            };
+            // Bulk inserts skip the per-key expiration bookkeeping of `Table::set`
+            if values.len() == 1 {
+                let (key, value) = values.remove(0);
+                table.set(key, value).await;
+            } else {
+                table.extend(values).await;
+            }
+        }
+    }
+
     pub async fn add_subscription(
         &self,
         client_id: String,
         channel: String,
         subscription: SubscriptionOptions,
-    ) {
-        let mut subscriptions = self.subscriptions.write().await;
-        subscriptions
-            .entry(client_id)
-            .or_default()
-            .insert(channel, subscription);
+    ) -> Result<(), Error> {
+        self.log_and_apply(vec![Entry::Subscribe {
+            client_id,
+            channel,
+            options: subscription,
+        }])
+        .await
     }
 
-    pub async fn remove_subscription(&self, client_id: String, channel: &str) {
-        let mut subscriptions = self.subscriptions.write().await;
-        let client_subscriptions = subscriptions.entry(client_id).or_default();
-        client_subscriptions.remove(channel);
+    pub async fn remove_subscription(&self, client_id: String, channel: &str) -> Result<(), Error> {
+        self.log_and_apply(vec![Entry::Unsubscribe {
+            client_id,
+            channel: channel.to_string(),
+        }])
+        .await
     }
 
     pub async fn get_subscriptions(&self, client_id: &str) -> HashMap<String, SubscriptionOptions> {
@@ -52,18 +1257,270 @@ impl Database {
         subscriptions.get(client_id).cloned().unwrap_or_default()
     }
 
-    pub async fn clear_subscriptions(&self, client_id: &str) {
-        let mut subscriptions = self.subscriptions.write().await;
-        subscriptions.remove(client_id);
+    pub async fn add_pattern_subscription(
+        &self,
+        client_id: String,
+        pattern: ChannelPattern,
+        subscription: SubscriptionOptions,
+    ) -> Result<(), Error> {
+        self.log_and_apply(vec![Entry::PSubscribe {
+            client_id,
+            pattern,
+            options: subscription,
+        }])
+        .await
+    }
+
+    pub async fn remove_pattern_subscription(
+        &self,
+        client_id: String,
+        pattern: ChannelPattern,
+    ) -> Result<(), Error> {
+        self.log_and_apply(vec![Entry::PUnsubscribe { client_id, pattern }])
+            .await
+    }
+
+    pub async fn get_pattern_subscriptions(
+        &self,
+        client_id: &str,
+    ) -> HashMap<ChannelPattern, SubscriptionOptions> {
+        let patterns = self.pattern_subscriptions.read().await;
+        patterns.get(client_id).cloned().unwrap_or_default()
+    }
+
+    pub async fn clear_subscriptions(&self, client_id: &str) -> Result<(), Error> {
+        self.log_and_apply(vec![Entry::ClearSubscriptions {
+            client_id: client_id.to_string(),
+        }])
+        .await
     }
 }
 
 impl Default for Database {
     fn default() -> Self {
         Self {
-            broadcast_channel: broadcast::Sender::new(crate::CHANNEL_SIZE),
+            broker: Broker::default(),
             subscriptions: Arc::new(RwLock::new(HashMap::new())),
-            db: Table::new(),
+            pattern_subscriptions: Arc::new(RwLock::new(HashMap::new())),
+            schema: Arc::default(),
+            tables: Arc::new(RwLock::new(HashMap::from([(
+                DEFAULT_TABLE.to_string(),
+                Table::new(),
+            )]))),
+            aof: None,
+            snapshotter: Snapshotter::new(Config::default().snapshot_path()),
+            dir: Config::default().dir,
+            scripts: Scripts::new(Config::default().script_limits()),
+            plugins: Plugins::default(),
+        }
+    }
+}
+
+/// A read or write queued in a transaction
+#[derive(Clone, Debug)]
+pub enum Operation {
+    Get {
+        table: String,
+        key: String,
+    },
+    Set {
+        table: String,
+        key: String,
+        value: Value,
+    },
+    Delete {
+        table: String,
+        key: String,
+    },
+}
+
+impl Operation {
+    fn table(&self) -> &str {
+        match self {
+            Operation::Get { table, .. }
+            | Operation::Set { table, .. }
+            | Operation::Delete { table, .. } => table,
+        }
+    }
+
+    fn key(&self) -> &str {
+        match self {
+            Operation::Get { key, .. }
+            | Operation::Set { key, .. }
+            | Operation::Delete { key, .. } => key,
+        }
+    }
+}
+
+/// A read-only view of a table as it was when the snapshot was taken
+///
+/// Writes keep the values they replace while a snapshot is open, so it should be dropped as soon as it is done with.
+pub struct Snapshot {
+    table: String,
+    inner: TableSnapshot,
+}
+
+impl Snapshot {
+    pub async fn get(&self, key: &str) -> Option<Value> {
+        self.inner.get(key).await
+    }
+
+    /// Like [`Database::range`], as of the snapshot
+    pub async fn range(
+        &self,
+        start: Option<&str>,
+        end: Option<&str>,
+        limit: Option<usize>,
+        reverse: bool,
+    ) -> Result<Vec<KeyValue>, Error> {
+        let entries = self
+            .inner
+            .range(start, end, limit, reverse)
+            .await
+            .ok_or_else(|| Error::Unordered(self.table.clone()))?;
+        Ok(key_values(entries))
+    }
+
+    /// Like [`Database::prefix_scan`], as of the snapshot
+    pub async fn prefix_scan(&self, prefix: &str) -> Result<Vec<KeyValue>, Error> {
+        let entries = self
+            .inner
+            .prefix_scan(prefix)
+            .await
+            .ok_or_else(|| Error::Unordered(self.table.clone()))?;
+        Ok(key_values(entries))
+    }
+
+    /// Like [`Database::query`], as of the snapshot
+    pub async fn query(&self, query: &str) -> Result<Vec<KeyValue>, Error> {
+        let query = query::parse(query)?;
+        Ok(key_values(self.inner.query(&query).await))
+    }
+}
+
+fn key_values(entries: Vec<(String, Value)>) -> Vec<KeyValue> {
+    entries
+        .into_iter()
+        .map(|(key, value)| KeyValue { key, value })
+        .collect()
+}
+
+/// Gives a value without an expiry the table's default TTL
+fn with_default_ttl(options: &TableOptions, mut value: Value) -> Value {
+    if let (None, Some(seconds)) = (value.expiry, options.default_ttl) {
+        value.expiry =
+            OffsetDateTime::now_utc().checked_add(time::Duration::seconds(seconds.into()));
+    }
+    value
+}
+
+/// Sends the entries of `snapshot` a batch at a time until they have all been sent or nothing is receiving them
+async fn copy_entries(snapshot: TableSnapshot, sender: mpsc::Sender<Vec<(String, Value)>>) {
+    let mut batches = snapshot.batches();
+    while let Some(batch) = batches.next().await {
+        if sender.send(batch).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Sends the log entries that recreate `contents` a batch at a time
+async fn log_entries(
+    contents: Contents<EntryBatches>,
+    sender: mpsc::Sender<Vec<Entry>>,
+) -> Result<(), mpsc::error::SendError<Vec<Entry>>> {
+    for (name, mut table) in contents.tables {
+        if name != DEFAULT_TABLE {
+            sender
+                .send(vec![Entry::CreateTable {
+                    name: name.clone(),
+                    options: table.options,
+                }])
+                .await?;
+        }
+        while let Some(batch) = table.entries.recv().await {
+            let batch = batch
+                .into_iter()
+                .map(|(key, value)| Entry::Set {
+                    table: name.clone(),
+                    key,
+                    value,
+                })
+                .collect();
+            sender.send(batch).await?;
+        }
+        let mut entries = Vec::new();
+        entries.extend(table.indexes.into_iter().map(|index| Entry::CreateIndex {
+            table: name.clone(),
+            index,
+        }));
+        entries.extend(
+            table
+                .search_indexes
+                .into_iter()
+                .map(|index| Entry::CreateSearchIndex {
+                    table: name.clone(),
+                    index,
+                }),
+        );
+        entries.extend(
+            table
+                .vector_indexes
+                .into_iter()
+                .map(|index| Entry::CreateVectorIndex {
+                    table: name.clone(),
+                    index,
+                }),
+        );
+        entries.extend(
+            table
+                .triggers
+                .into_iter()
+                .map(|trigger| Entry::CreateTrigger {
+                    table: name.clone(),
+                    trigger,
+                }),
+        );
+        sender.send(entries).await?;
+    }
+    let mut entries = Vec::new();
+    for (client_id, subscriptions) in contents.subscriptions {
+        for (channel, options) in subscriptions {
+            entries.push(Entry::Subscribe {
+                client_id: client_id.clone(),
+                channel,
+                options,
+            });
+        }
+    }
+    for (client_id, patterns) in contents.pattern_subscriptions {
+        for (pattern, options) in patterns {
+            entries.push(Entry::PSubscribe {
+                client_id: client_id.clone(),
+                pattern,
+                options,
+            });
+        }
+    }
+    sender.send(entries).await
+}
+
+async fn expire_histories_periodically(broker: Broker) {
+    let mut interval = tokio::time::interval(HISTORY_EXPIRY_INTERVAL);
+    loop {
+        interval.tick().await;
+        broker.expire_histories();
+    }
+}
+
+async fn save_periodically(database: Database, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    // The first tick completes immediately and there is nothing new to save at startup
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        if let Err(err) = database.background_save().await {
+            error!(?err, "Could not start periodic save");
         }
     }
 }
@@ -86,15 +1543,1139 @@ mod tests {
                 subscription.clone(),
                 subscription_options.clone(),
             )
-            .await;
+            .await
+            .unwrap();
         let subscriptions = database.get_subscriptions(&client_id).await;
         assert_eq!(
             subscriptions.get(&subscription),
             Some(&subscription_options)
         );
 
-        database.clear_subscriptions(&client_id).await;
+        database.clear_subscriptions(&client_id).await.unwrap();
         let subscriptions = database.get_subscriptions(&client_id).await;
         assert_eq!(subscriptions.len(), 0);
     }
+
+    #[tokio::test]
+    async fn test_export_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            dir: dir.path().to_path_buf(),
+            ..Config::default()
+        };
+        let database = Database::open(&config).await.unwrap();
+        let value = Value {
+            data: aether_common::db::Data::Int(1),
+            expiry: None,
+        };
+        database
+            .set(DEFAULT_TABLE, "key".to_string(), value)
+            .await
+            .unwrap();
+
+        let exported = database.export(DEFAULT_TABLE, "backups/keys.jsonl", Vec::new());
+        assert!(matches!(exported.await, Err(Error::Persistence(_))));
+        std::fs::create_dir(dir.path().join("backups")).unwrap();
+        let exported = database.export(DEFAULT_TABLE, "backups/keys.jsonl", Vec::new());
+        assert_eq!(exported.await.unwrap(), 1);
+        assert!(dir.path().join("backups/keys.jsonl").exists());
+        let imported = database.import(DEFAULT_TABLE, "./backups/keys.jsonl", Vec::new());
+        assert_eq!(imported.await.unwrap(), 1);
+
+        for path in [
+            "/etc/passwd",
+            "../keys.jsonl",
+            "backups/../../keys.jsonl",
+            "",
+        ] {
+            let exported = database.export(DEFAULT_TABLE, path, Vec::new()).await;
+            assert!(matches!(exported, Err(Error::InvalidPath(_))), "{path}");
+            let imported = database.import(DEFAULT_TABLE, path, Vec::new()).await;
+            assert!(matches!(imported, Err(Error::InvalidPath(_))), "{path}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_open_replays_aof() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            dir: dir.path().to_path_buf(),
+            appendonly: true,
+            ..Config::default()
+        };
+        let value = Value {
+            data: aether_common::db::Data::String("value".to_string()),
+            expiry: None,
+        };
+
+        let database = Database::open(&config).await.unwrap();
+        database
+            .set(DEFAULT_TABLE, "key".to_string(), value)
+            .await
+            .unwrap();
+        drop(database);
+
+        let database = Database::open(&config).await.unwrap();
+        assert!(database.get(DEFAULT_TABLE, "key").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_atomic_batch_torn_by_crash() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            dir: dir.path().to_path_buf(),
+            appendonly: true,
+            ..Config::default()
+        };
+        let set = |key: &str| Operation::Set {
+            table: DEFAULT_TABLE.to_string(),
+            key: key.to_string(),
+            value: Value {
+                data: aether_common::db::Data::String("value".to_string()),
+                expiry: None,
+            },
+        };
+
+        let database = Database::open(&config).await.unwrap();
+        database.exec_all(vec![set("a"), set("b")]).await.unwrap();
+        drop(database);
+
+        // Simulate a crash after the first set of the batch was written but before the second
+        let len = std::fs::metadata(config.aof_path()).unwrap().len();
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(config.aof_path())
+            .unwrap();
+        file.set_len(len - 20).unwrap();
+        drop(file);
+
+        let database = Database::open(&config).await.unwrap();
+        assert!(database.get(DEFAULT_TABLE, "a").await.unwrap().is_none());
+        assert!(database.get(DEFAULT_TABLE, "b").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_open_loads_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            dir: dir.path().to_path_buf(),
+            ..Config::default()
+        };
+        let value = Value {
+            data: aether_common::db::Data::Int(1),
+            expiry: None,
+        };
+
+        let database = Database::open(&config).await.unwrap();
+        database
+            .set(DEFAULT_TABLE, "key".to_string(), value)
+            .await
+            .unwrap();
+        database.save().await.unwrap();
+        drop(database);
+
+        let database = Database::open(&config).await.unwrap();
+        assert!(database.get(DEFAULT_TABLE, "key").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_subscriptions_survive_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let aof_config = Config {
+            dir: dir.path().join("aof"),
+            appendonly: true,
+            ..Config::default()
+        };
+        let snapshot_config = Config {
+            dir: dir.path().join("snapshot"),
+            ..Config::default()
+        };
+        let options = SubscriptionOptions {
+            subscribe_to_self: true,
+        };
+
+        for config in [aof_config, snapshot_config] {
+            std::fs::create_dir(&config.dir).unwrap();
+            let database = Database::open(&config).await.unwrap();
+            for channel in ["kept", "dropped"] {
+                database
+                    .add_subscription("client".to_string(), channel.to_string(), options.clone())
+                    .await
+                    .unwrap();
+            }
+            database
+                .remove_subscription("client".to_string(), "dropped")
+                .await
+                .unwrap();
+            let pattern = ChannelPattern::Mqtt("sensors/#".to_string());
+            database
+                .add_pattern_subscription("client".to_string(), pattern.clone(), options.clone())
+                .await
+                .unwrap();
+            if !config.appendonly {
+                database.save().await.unwrap();
+            }
+            drop(database);
+
+            let database = Database::open(&config).await.unwrap();
+            let subscriptions = database.get_subscriptions("client").await;
+            assert_eq!(
+                subscriptions,
+                HashMap::from([("kept".to_string(), options.clone())])
+            );
+            let patterns = database.get_pattern_subscriptions("client").await;
+            assert_eq!(patterns, HashMap::from([(pattern, options.clone())]));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tables() {
+        let database = Database::default();
+        let value = || Value {
+            data: aether_common::db::Data::String("value".to_string()),
+            expiry: None,
+        };
+        let options = TableOptions {
+            default_ttl: Some(60),
+            max_memory: Some(16),
+            ordered: false,
+        };
+
+        database
+            .create_table("team".to_string(), options.clone())
+            .await
+            .unwrap();
+        assert!(matches!(
+            database.create_table("team".to_string(), options).await,
+            Err(Error::TableExists(_))
+        ));
+
+        database
+            .set("team", "key".to_string(), value())
+            .await
+            .unwrap();
+        let stored = database.get("team", "key").await.unwrap().unwrap();
+        assert!(stored.expiry.is_some());
+        assert!(database.get(DEFAULT_TABLE, "key").await.unwrap().is_none());
+
+        assert!(matches!(
+            database.set("team", "other".to_string(), value()).await,
+            Err(Error::OutOfMemory(_))
+        ));
+
+        assert!(matches!(
+            database.drop_table(DEFAULT_TABLE.to_string()).await,
+            Err(Error::DropDefaultTable)
+        ));
+        database.drop_table("team".to_string()).await.unwrap();
+        assert!(matches!(
+            database.get("team", "key").await,
+            Err(Error::NoSuchTable(_))
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_schema_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            dir: dir.path().to_path_buf(),
+            appendonly: true,
+            ..Config::default()
+        };
+        let database = Database::open(&config).await.unwrap();
+
+        let creates = (0..8).map(|_| {
+            let database = database.clone();
+            tokio::spawn(async move {
+                database
+                    .create_table("team".to_string(), TableOptions::default())
+                    .await
+            })
+        });
+        let mut created = 0;
+        for create in creates.collect::<Vec<_>>() {
+            match create.await.unwrap() {
+                Ok(()) => created += 1,
+                Err(err) => assert!(matches!(err, Error::TableExists(_))),
+            }
+        }
+        assert_eq!(created, 1);
+
+        let drops = (0..8).map(|_| {
+            let database = database.clone();
+            tokio::spawn(async move { database.drop_table("team".to_string()).await })
+        });
+        let mut dropped = 0;
+        for drop in drops.collect::<Vec<_>>() {
+            match drop.await.unwrap() {
+                Ok(()) => dropped += 1,
+                Err(err) => assert!(matches!(err, Error::NoSuchTable(_))),
+            }
+        }
+        assert_eq!(dropped, 1);
+
+        // Only the changes that were made are logged
+        let log = std::fs::read_to_string(config.aof_path()).unwrap();
+        assert_eq!(log.matches("create_table").count(), 1);
+        assert_eq!(log.matches("drop_table").count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_tables_survive_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let aof_config = Config {
+            dir: dir.path().join("aof"),
+            appendonly: true,
+            ..Config::default()
+        };
+        let snapshot_config = Config {
+            dir: dir.path().join("snapshot"),
+            ..Config::default()
+        };
+        let options = TableOptions {
+            default_ttl: None,
+            max_memory: Some(1024),
+            ordered: false,
+        };
+        let value = Value {
+            data: aether_common::db::Data::Int(1),
+            expiry: None,
+        };
+
+        for config in [aof_config, snapshot_config] {
+            std::fs::create_dir(&config.dir).unwrap();
+            let database = Database::open(&config).await.unwrap();
+            for name in ["kept", "dropped"] {
+                database
+                    .create_table(name.to_string(), options.clone())
+                    .await
+                    .unwrap();
+                database
+                    .set(name, "key".to_string(), value.clone())
+                    .await
+                    .unwrap();
+            }
+            database.drop_table("dropped".to_string()).await.unwrap();
+            if !config.appendonly {
+                database.save().await.unwrap();
+            }
+            drop(database);
+
+            let database = Database::open(&config).await.unwrap();
+            let table = database.table("kept").await.unwrap();
+            assert_eq!(table.options(), &options);
+            assert!(table.get("key").await.is_some());
+            assert!(database.table("dropped").await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_indexes() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            dir: dir.path().to_path_buf(),
+            appendonly: true,
+            ..Config::default()
+        };
+        let order = |status: &str| Value {
+            data: aether_common::db::Data::Json(serde_json::json!({ "status": status })),
+            expiry: None,
+        };
+        let pending = IndexQuery::Value(serde_json::json!("pending"));
+        let found = |entries: Vec<KeyValue>| -> Vec<String> {
+            entries.into_iter().map(|entry| entry.key).collect()
+        };
+
+        let database = Database::open(&config).await.unwrap();
+        database
+            .set(DEFAULT_TABLE, "orders/1".to_string(), order("pending"))
+            .await
+            .unwrap();
+        database
+            .create_index(
+                DEFAULT_TABLE,
+                IndexDefinition {
+                    name: "status".to_string(),
+                    pattern: "orders/*".to_string(),
+                    pointer: "/status".to_string(),
+                },
+            )
+            .await
+            .unwrap();
+        for (key, status) in [("orders/2", "pending"), ("orders/3", "shipped")] {
+            database
+                .set(DEFAULT_TABLE, key.to_string(), order(status))
+                .await
+                .unwrap();
+        }
+        database
+            .set(DEFAULT_TABLE, "orders/1".to_string(), order("shipped"))
+            .await
+            .unwrap();
+        assert_eq!(
+            found(
+                database
+                    .find_by(DEFAULT_TABLE, "status", &pending)
+                    .await
+                    .unwrap()
+            ),
+            ["orders/2"]
+        );
+
+        database
+            .delete(DEFAULT_TABLE, "orders/2".to_string())
+            .await
+            .unwrap();
+        drop(database);
+
+        // The index and the changes made after it was created are replayed from the append-only file
+        let database = Database::open(&config).await.unwrap();
+        let shipped = IndexQuery::Value(serde_json::json!("shipped"));
+        assert!(database
+            .find_by(DEFAULT_TABLE, "status", &pending)
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            found(
+                database
+                    .find_by(DEFAULT_TABLE, "status", &shipped)
+                    .await
+                    .unwrap()
+            ),
+            ["orders/1", "orders/3"]
+        );
+        assert!(matches!(
+            database.find_by(DEFAULT_TABLE, "missing", &shipped).await,
+            Err(Error::NoSuchIndex(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_search_indexes() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            dir: dir.path().to_path_buf(),
+            appendonly: true,
+            ..Config::default()
+        };
+        let message = |text: &str| Value {
+            data: aether_common::db::Data::Json(serde_json::json!({ "text": text })),
+            expiry: None,
+        };
+
+        let database = Database::open(&config).await.unwrap();
+        database
+            .set(
+                DEFAULT_TABLE,
+                "chat/1".to_string(),
+                message("Deploying the release today"),
+            )
+            .await
+            .unwrap();
+        database
+            .create_search_index(
+                DEFAULT_TABLE,
+                SearchIndexDefinition {
+                    name: "chat".to_string(),
+                    pattern: "chat/*".to_string(),
+                    fields: vec!["/text".to_string()],
+                },
+            )
+            .await
+            .unwrap();
+        database
+            .set(
+                DEFAULT_TABLE,
+                "chat/2".to_string(),
+                message("Lunch after the deploy?"),
+            )
+            .await
+            .unwrap();
+        database.save().await.unwrap();
+        database
+            .delete(DEFAULT_TABLE, "chat/1".to_string())
+            .await
+            .unwrap();
+        drop(database);
+
+        // The index comes back from the snapshot and the delete is replayed on top of it
+        let database = Database::open(&config).await.unwrap();
+        let hits = database
+            .search(DEFAULT_TABLE, "chat", "deployed", None)
+            .await
+            .unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].key, "chat/2");
+        assert_eq!(hits[0].highlights, ["Lunch after the <mark>deploy</mark>?"]);
+        assert!(matches!(
+            database
+                .search(DEFAULT_TABLE, "missing", "deploy", None)
+                .await,
+            Err(Error::NoSuchSearchIndex(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_vector_indexes() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            dir: dir.path().to_path_buf(),
+            appendonly: true,
+            ..Config::default()
+        };
+        let embedding = |vector: Vec<f32>| Value {
+            data: aether_common::db::Data::Vector(vector),
+            expiry: None,
+        };
+
+        let database = Database::open(&config).await.unwrap();
+        database
+            .create_vector_index(
+                DEFAULT_TABLE,
+                VectorIndexDefinition {
+                    name: "embeddings".to_string(),
+                    pattern: "cache/*".to_string(),
+                    dimensions: 2,
+                    metric: aether_common::db::Metric::Cosine,
+                },
+            )
+            .await
+            .unwrap();
+        for (key, vector) in [
+            ("cache/en/1", vec![1.0, 0.0]),
+            ("cache/en/2", vec![0.0, 1.0]),
+            ("cache/fr/1", vec![1.0, 0.1]),
+        ] {
+            database
+                .set(DEFAULT_TABLE, key.to_string(), embedding(vector))
+                .await
+                .unwrap();
+        }
+        drop(database);
+
+        // The index is rebuilt as the append-only file is replayed
+        let database = Database::open(&config).await.unwrap();
+        let found =
+            |hits: Vec<VectorHit>| -> Vec<String> { hits.into_iter().map(|hit| hit.key).collect() };
+        let hits = database
+            .vector_search(DEFAULT_TABLE, "embeddings", &[2.0, 0.0], 2, None)
+            .await
+            .unwrap();
+        assert_eq!(found(hits), ["cache/en/1", "cache/fr/1"]);
+        let hits = database
+            .vector_search(
+                DEFAULT_TABLE,
+                "embeddings",
+                &[2.0, 0.0],
+                2,
+                Some("cache/en/*"),
+            )
+            .await
+            .unwrap();
+        assert_eq!(found(hits), ["cache/en/1", "cache/en/2"]);
+        assert!(matches!(
+            database
+                .vector_search(DEFAULT_TABLE, "embeddings", &[1.0], 2, None)
+                .await,
+            Err(Error::VectorDimensions {
+                expected: 2,
+                actual: 1
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_transactions() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            dir: dir.path().to_path_buf(),
+            appendonly: true,
+            ..Config::default()
+        };
+        let int = |n| Value {
+            data: aether_common::db::Data::Int(n),
+            expiry: None,
+        };
+        let int_of = |value: &Option<Value>| match value.as_ref().map(|value| &value.data) {
+            Some(aether_common::db::Data::Int(n)) => Some(*n),
+            _ => None,
+        };
+        let transfer = |amount: i64, from: i64, to: i64| {
+            vec![
+                Operation::Set {
+                    table: DEFAULT_TABLE.to_string(),
+                    key: "counter/a".to_string(),
+                    value: int(from - amount),
+                },
+                Operation::Set {
+                    table: DEFAULT_TABLE.to_string(),
+                    key: "counter/b".to_string(),
+                    value: int(to + amount),
+                },
+                Operation::Get {
+                    table: DEFAULT_TABLE.to_string(),
+                    key: "counter/a".to_string(),
+                },
+            ]
+        };
+        let keys = ["counter/a".to_string(), "counter/b".to_string()];
+
+        let database = Database::open(&config).await.unwrap();
+        database
+            .set_many(
+                DEFAULT_TABLE,
+                vec![
+                    ("counter/a".to_string(), int(100)),
+                    ("counter/b".to_string(), int(0)),
+                ],
+            )
+            .await
+            .unwrap();
+
+        let watch = Watch::default();
+        database.watch(DEFAULT_TABLE, &keys, &watch).await.unwrap();
+        let results = database
+            .exec(transfer(30, 100, 0), &watch)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(results.len(), 3);
+        assert_eq!(int_of(&results[2]), Some(70));
+
+        // Another write to a watched key between the read and the transaction aborts it
+        let watch = Watch::default();
+        database.watch(DEFAULT_TABLE, &keys, &watch).await.unwrap();
+        database
+            .set(DEFAULT_TABLE, "counter/b".to_string(), int(50))
+            .await
+            .unwrap();
+        assert!(database
+            .exec(transfer(30, 70, 30), &watch)
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            int_of(&database.get(DEFAULT_TABLE, "counter/a").await.unwrap()),
+            Some(70)
+        );
+
+        // So does a watched key expiring
+        let expiring = Value {
+            expiry: OffsetDateTime::now_utc().checked_add(time::Duration::milliseconds(100)),
+            ..int(1)
+        };
+        database
+            .set(DEFAULT_TABLE, "counter/a".to_string(), expiring)
+            .await
+            .unwrap();
+        let watch = Watch::default();
+        database.watch(DEFAULT_TABLE, &keys, &watch).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(database
+            .exec(transfer(1, 1, 50), &watch)
+            .await
+            .unwrap()
+            .is_none());
+
+        assert!(matches!(
+            database
+                .exec(
+                    vec![Operation::Delete {
+                        table: "missing".to_string(),
+                        key: "counter/a".to_string(),
+                    }],
+                    &Watch::default(),
+                )
+                .await,
+            Err(Error::NoSuchTable(_))
+        ));
+        database
+            .exec(transfer(20, 0, 50), &Watch::default())
+            .await
+            .unwrap()
+            .unwrap();
+        drop(database);
+
+        let database = Database::open(&config).await.unwrap();
+        assert_eq!(
+            int_of(&database.get(DEFAULT_TABLE, "counter/a").await.unwrap()),
+            Some(-20)
+        );
+        assert_eq!(
+            int_of(&database.get(DEFAULT_TABLE, "counter/b").await.unwrap()),
+            Some(70)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_scripts() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            dir: dir.path().to_path_buf(),
+            appendonly: true,
+            ..Config::default()
+        };
+        let keys = ["counter/a".to_string(), "counter/b".to_string()];
+        let transfer = r#"
+            let from = db.get(keys[0]) ?? 0;
+            if from < args[0] {
+                throw "insufficient funds";
+            }
+            db.set(keys[0], from - args[0]);
+            db.set(keys[1], (db.get(keys[1]) ?? 0) + args[0]);
+            db.publish("transfers", `${args[0]}`);
+            from - args[0]
+        "#;
+
+        let database = Database::open(&config).await.unwrap();
+        let mut receiver = database.broker.mailbox("listener".to_string());
+        receiver.subscribe("transfers".to_string(), SubscriptionOptions::default());
+        database
+            .set(
+                DEFAULT_TABLE,
+                "counter/a".to_string(),
+                Value {
+                    data: aether_common::db::Data::Int(100),
+                    expiry: None,
+                },
+            )
+            .await
+            .unwrap();
+        let result = database
+            .eval(
+                DEFAULT_TABLE,
+                transfer,
+                &keys,
+                &[serde_json::json!(30)],
+                "client",
+            )
+            .await
+            .unwrap();
+        assert_eq!(result, serde_json::json!(70));
+        let delivery = receiver.recv().await.unwrap();
+        let message = delivery.message().unwrap();
+        assert_eq!(
+            (message.client_id.as_str(), message.channel.as_str()),
+            ("client", "transfers")
+        );
+        assert_eq!(message.message, "30");
+
+        // A failing script writes nothing
+        let sha = database.script_load(transfer).unwrap();
+        assert!(matches!(
+            database
+                .eval_sha(
+                    DEFAULT_TABLE,
+                    &sha,
+                    &keys,
+                    &[serde_json::json!(80)],
+                    "client"
+                )
+                .await,
+            Err(Error::Script(script::Error::Run(_)))
+        ));
+        assert!(matches!(
+            database
+                .eval_sha(DEFAULT_TABLE, "missing", &keys, &[], "client")
+                .await,
+            Err(Error::NoSuchScript(_))
+        ));
+        drop(database);
+
+        let database = Database::open(&config).await.unwrap();
+        let int_of = |value: Option<Value>| match value.map(|value| value.data) {
+            Some(aether_common::db::Data::Int(n)) => Some(n),
+            _ => None,
+        };
+        assert_eq!(
+            int_of(database.get(DEFAULT_TABLE, "counter/a").await.unwrap()),
+            Some(70)
+        );
+        assert_eq!(
+            int_of(database.get(DEFAULT_TABLE, "counter/b").await.unwrap()),
+            Some(30)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_triggers() {
+        use aether_common::db::KeyEvent;
+
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            dir: dir.path().to_path_buf(),
+            appendonly: true,
+            ..Config::default()
+        };
+        let string = |s: &str| Value {
+            data: aether_common::db::Data::String(s.to_string()),
+            expiry: None,
+        };
+        let count = |database: Database| async move {
+            // Triggers run in the background, so wait for the counter to settle
+            let mut last = None;
+            for _ in 0..10 {
+                tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+                last = match database.get(DEFAULT_TABLE, "stats/users").await.unwrap() {
+                    Some(Value {
+                        data: aether_common::db::Data::Int(n),
+                        ..
+                    }) => Some(n),
+                    _ => None,
+                };
+            }
+            last
+        };
+
+        let database = Database::open(&config).await.unwrap();
+        let mut receiver = database.broker.mailbox("listener".to_string());
+        for channel in [DEFAULT_TABLE, "audit"] {
+            receiver.subscribe(channel.to_string(), SubscriptionOptions::default());
+        }
+        database
+            .create_trigger(
+                DEFAULT_TABLE,
+                TriggerDefinition {
+                    name: "announce".to_string(),
+                    pattern: "users/*".to_string(),
+                    events: vec![KeyEvent::Set],
+                    action: TriggerAction::Publish {
+                        channel: "{table}".to_string(),
+                        message: "{event} {key} {value}".to_string(),
+                    },
+                },
+            )
+            .await
+            .unwrap();
+        database
+            .create_trigger(
+                DEFAULT_TABLE,
+                TriggerDefinition {
+                    name: "count".to_string(),
+                    pattern: "users/*".to_string(),
+                    events: vec![KeyEvent::Set],
+                    action: TriggerAction::Increment {
+                        key: "stats/users".to_string(),
+                        by: 1,
+                    },
+                },
+            )
+            .await
+            .unwrap();
+        database
+            .create_trigger(
+                DEFAULT_TABLE,
+                TriggerDefinition {
+                    name: "audit".to_string(),
+                    pattern: "users/*".to_string(),
+                    events: vec![KeyEvent::Delete],
+                    action: TriggerAction::Script {
+                        script: r#"db.publish("audit", `${args[0]} ${keys[0]}`)"#.to_string(),
+                    },
+                },
+            )
+            .await
+            .unwrap();
+        assert!(matches!(
+            database
+                .create_trigger(
+                    DEFAULT_TABLE,
+                    TriggerDefinition {
+                        name: "count".to_string(),
+                        pattern: "*".to_string(),
+                        events: Vec::new(),
+                        action: TriggerAction::Increment {
+                            key: "other".to_string(),
+                            by: 1,
+                        },
+                    },
+                )
+                .await,
+            Err(Error::TriggerExists(_))
+        ));
+
+        database
+            .set(DEFAULT_TABLE, "users/ada".to_string(), string("Ada"))
+            .await
+            .unwrap();
+        database
+            .set(DEFAULT_TABLE, "posts/1".to_string(), string("Hello"))
+            .await
+            .unwrap();
+        let delivery = receiver.recv().await.unwrap();
+        let message = delivery.message().unwrap();
+        assert_eq!(
+            (message.channel.as_str(), message.message.as_str()),
+            (DEFAULT_TABLE, r#"set users/ada {"string":"Ada"}"#)
+        );
+        database
+            .delete(DEFAULT_TABLE, "users/ada".to_string())
+            .await
+            .unwrap();
+        let delivery = receiver.recv().await.unwrap();
+        let message = delivery.message().unwrap();
+        assert_eq!(
+            (message.channel.as_str(), message.message.as_str()),
+            ("audit", "delete users/ada")
+        );
+        assert_eq!(count(database.clone()).await, Some(1));
+        drop(database);
+
+        // Triggers are logged, but replaying the log doesn't run them again
+        let database = Database::open(&config).await.unwrap();
+        assert_eq!(database.triggers(DEFAULT_TABLE).await.unwrap().len(), 3);
+        assert_eq!(count(database.clone()).await, Some(1));
+        database
+            .set(DEFAULT_TABLE, "users/grace".to_string(), string("Grace"))
+            .await
+            .unwrap();
+        assert_eq!(count(database.clone()).await, Some(2));
+
+        database
+            .drop_trigger(DEFAULT_TABLE, "count".to_string())
+            .await
+            .unwrap();
+        database
+            .set(DEFAULT_TABLE, "users/alan".to_string(), string("Alan"))
+            .await
+            .unwrap();
+        assert_eq!(count(database.clone()).await, Some(2));
+        assert!(matches!(
+            database
+                .drop_trigger(DEFAULT_TABLE, "count".to_string())
+                .await,
+            Err(Error::NoSuchTrigger(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_triggers_after_burst() {
+        let database = Database::default();
+        database
+            .create_trigger(
+                DEFAULT_TABLE,
+                TriggerDefinition {
+                    name: "count".to_string(),
+                    pattern: "users/*".to_string(),
+                    events: Vec::new(),
+                    action: TriggerAction::Increment {
+                        key: "stats/users".to_string(),
+                        by: 1,
+                    },
+                },
+            )
+            .await
+            .unwrap();
+        // More changes at once than the change broadcast holds
+        let sets = (0..1500)
+            .map(|n| Operation::Set {
+                table: DEFAULT_TABLE.to_string(),
+                key: format!("users/{n}"),
+                value: Value {
+                    data: aether_common::db::Data::Int(n),
+                    expiry: None,
+                },
+            })
+            .collect();
+        database.exec_all(sets).await.unwrap();
+
+        let mut count = None;
+        for _ in 0..100 {
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            count = match database.get(DEFAULT_TABLE, "stats/users").await.unwrap() {
+                Some(Value {
+                    data: aether_common::db::Data::Int(n),
+                    ..
+                }) => Some(n),
+                _ => None,
+            };
+            if count == Some(1500) {
+                break;
+            }
+        }
+        assert_eq!(count, Some(1500));
+    }
+
+    #[tokio::test]
+    async fn test_trigger_writing_its_own_key() {
+        let database = Database::default();
+        database
+            .create_trigger(
+                DEFAULT_TABLE,
+                TriggerDefinition {
+                    name: "bump".to_string(),
+                    pattern: "counters/*".to_string(),
+                    events: Vec::new(),
+                    action: TriggerAction::Script {
+                        script: "db.set(keys[0], db.get(keys[0]) + 1)".to_string(),
+                    },
+                },
+            )
+            .await
+            .unwrap();
+        let zero = Value {
+            data: aether_common::db::Data::Int(0),
+            expiry: None,
+        };
+        database
+            .set(DEFAULT_TABLE, "counters/a".to_string(), zero)
+            .await
+            .unwrap();
+
+        // The script's own write doesn't fire it again
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        let value = database.get(DEFAULT_TABLE, "counters/a").await.unwrap();
+        assert!(matches!(
+            value.unwrap().data,
+            aether_common::db::Data::Int(1)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_trigger_chains() {
+        let database = Database::default();
+        let increment = |name: &str, pattern: &str, key: &str| TriggerDefinition {
+            name: name.to_string(),
+            pattern: pattern.to_string(),
+            events: Vec::new(),
+            action: TriggerAction::Increment {
+                key: key.to_string(),
+                by: 1,
+            },
+        };
+        // Two triggers that count changes to each other's keys, and a line of triggers each setting off the next
+        let mut triggers = vec![
+            increment("ping", "ping/*", "pong/count"),
+            increment("pong", "pong/*", "ping/count"),
+        ];
+        triggers.extend((0..10).map(|n| {
+            increment(
+                &n.to_string(),
+                &format!("line/{n}"),
+                &format!("line/{}", n + 1),
+            )
+        }));
+        for trigger in triggers {
+            database
+                .create_trigger(DEFAULT_TABLE, trigger)
+                .await
+                .unwrap();
+        }
+        let one = Value {
+            data: aether_common::db::Data::Int(1),
+            expiry: None,
+        };
+        database
+            .set(DEFAULT_TABLE, "ping/1".to_string(), one.clone())
+            .await
+            .unwrap();
+        database
+            .set(DEFAULT_TABLE, "line/0".to_string(), one)
+            .await
+            .unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+
+        let int = |value: Option<Value>| match value.map(|value| value.data) {
+            Some(aether_common::db::Data::Int(n)) => Some(n),
+            _ => None,
+        };
+        // Each trigger fires once, rather than the two setting each other off forever
+        assert_eq!(
+            int(database.get(DEFAULT_TABLE, "pong/count").await.unwrap()),
+            Some(1)
+        );
+        assert_eq!(
+            int(database.get(DEFAULT_TABLE, "ping/count").await.unwrap()),
+            Some(1)
+        );
+        // Only the first 8 triggers of the line fire
+        assert_eq!(
+            int(database.get(DEFAULT_TABLE, "line/8").await.unwrap()),
+            Some(1)
+        );
+        assert!(database
+            .get(DEFAULT_TABLE, "line/9")
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_live_queries() {
+        let database = Database::default();
+        let json = |json: serde_json::Value| Value {
+            data: aether_common::db::Data::Json(json),
+            expiry: None,
+        };
+        let set = |key: &str, value: serde_json::Value| {
+            database.set(DEFAULT_TABLE, key.to_string(), json(value))
+        };
+        set("users/ada", serde_json::json!({"age": 36}))
+            .await
+            .unwrap();
+        set("users/alan", serde_json::json!({"age": 41}))
+            .await
+            .unwrap();
+        set("users/kid", serde_json::json!({"age": 9}))
+            .await
+            .unwrap();
+        set("posts/1", serde_json::json!({"age": 50}))
+            .await
+            .unwrap();
+
+        let (mut live_query, results, mut changes) = database
+            .live_query(DEFAULT_TABLE, "users/".to_string(), Some(".age >= 18"))
+            .await
+            .unwrap();
+        let keys: Vec<&str> = results.iter().map(|entry| entry.key.as_str()).collect();
+        assert_eq!(keys, ["users/ada", "users/alan"]);
+
+        set("users/kid", serde_json::json!({"age": 18}))
+            .await
+            .unwrap();
+        set("users/ada", serde_json::json!({"age": 37}))
+            .await
+            .unwrap();
+        set("users/alan", serde_json::json!({"age": 4}))
+            .await
+            .unwrap();
+        set("posts/2", serde_json::json!({"age": 60}))
+            .await
+            .unwrap();
+        database
+            .delete(DEFAULT_TABLE, "users/kid".to_string())
+            .await
+            .unwrap();
+        let mut deltas = Vec::new();
+        while let Ok(change) = changes.try_recv() {
+            deltas.extend(live_query.apply(&change));
+        }
+        assert!(matches!(
+            deltas.as_slice(),
+            [
+                Delta::Added(added),
+                Delta::Updated(updated),
+                Delta::Removed(alan),
+                Delta::Removed(kid),
+            ] if added.key == "users/kid" && updated.key == "users/ada" && alan == "users/alan" && kid == "users/kid"
+        ));
+
+        // Refreshing after missing changes gives the current results, and ignores the changes they include
+        set("users/grace", serde_json::json!({"age": 45}))
+            .await
+            .unwrap();
+        let results = database.refresh_live_query(&mut live_query).await.unwrap();
+        let keys: Vec<&str> = results.iter().map(|entry| entry.key.as_str()).collect();
+        assert_eq!(keys, ["users/ada", "users/grace"]);
+        let change = changes.try_recv().unwrap();
+        assert!(live_query.apply(&change).is_none());
+
+        assert!(matches!(
+            database
+                .live_query(DEFAULT_TABLE, String::new(), Some(".age >"))
+                .await,
+            Err(Error::Query(_))
+        ));
+    }
 }
@@ -0,0 +1,436 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    ops::Bound,
+};
+
+use aether_common::db::{Data, SearchIndexDefinition, Value};
+use rust_stemmers::{Algorithm, Stemmer};
+
+use crate::pattern::glob_match;
+
+// BM25 parameters, the usual defaults
+const K1: f64 = 1.2;
+const B: f64 = 0.75;
+
+const HIGHLIGHT_START: &str = "<mark>";
+const HIGHLIGHT_END: &str = "</mark>";
+
+/// A full-text index over string values and selected string fields of JSON values
+///
+/// Text is split into lowercase alphanumeric words and stemmed as English.
+pub struct SearchIndex {
+    definition: SearchIndexDefinition,
+    // Term to document key to the positions the term appears at, in order
+    postings: BTreeMap<String, HashMap<String, Vec<u32>>>,
+    documents: HashMap<String, Document>,
+    total_length: usize,
+}
+
+struct Document {
+    length: usize,
+    terms: HashSet<String>,
+}
+
+/// A key matched by a search, before its value is highlighted
+pub struct Hit {
+    pub key: String,
+    pub score: f64,
+}
+
+/// A parsed search query
+///
+/// Words must all appear, `"quoted words"` must appear next to each other in order,
+/// and `word*` matches any word starting with `word`.
+pub struct Search {
+    clauses: Vec<Clause>,
+}
+
+enum Clause {
+    Term(String),
+    Phrase(Vec<String>),
+    Prefix(String),
+}
+
+impl SearchIndex {
+    pub fn new(definition: SearchIndexDefinition) -> Self {
+        Self {
+            definition,
+            postings: BTreeMap::new(),
+            documents: HashMap::new(),
+            total_length: 0,
+        }
+    }
+
+    pub fn definition(&self) -> &SearchIndexDefinition {
+        &self.definition
+    }
+
+    pub fn insert(&mut self, key: &str, value: &Value) {
+        self.remove(key);
+        if !glob_match(&self.definition.pattern, key) {
+            return;
+        }
+
+        let stemmer = Stemmer::create(Algorithm::English);
+        let mut position = 0;
+        let mut length = 0;
+        let mut terms = HashSet::new();
+        let texts: Vec<Vec<String>> = self
+            .texts(value)
+            .map(|text| tokenize(&stemmer, text).map(|(_, term)| term).collect())
+            .collect();
+        for text in texts {
+            for term in text {
+                let positions = self
+                    .postings
+                    .entry(term.clone())
+                    .or_default()
+                    .entry(key.to_string())
+                    .or_default();
+                positions.push(position);
+                terms.insert(term);
+                position += 1;
+                length += 1;
+            }
+            // Leave a gap so phrases can't span two fields
+            position += 1;
+        }
+        if terms.is_empty() {
+            return;
+        }
+
+        self.total_length += length;
+        self.documents
+            .insert(key.to_string(), Document { length, terms });
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        let Some(document) = self.documents.remove(key) else {
+            return;
+        };
+        self.total_length -= document.length;
+        for term in document.terms {
+            if let Some(postings) = self.postings.get_mut(&term) {
+                postings.remove(key);
+                if postings.is_empty() {
+                    self.postings.remove(&term);
+                }
+            }
+        }
+    }
+
+    /// Returns up to `limit` keys matching `search`, best first
+    pub fn search(&self, search: &Search, limit: usize) -> Vec<Hit> {
+        let mut scores: Option<HashMap<&str, f64>> = None;
+        for clause in &search.clauses {
+            let clause_scores = self.clause_scores(clause);
+            // Every clause must match, so only keep keys matched by all of them
+            scores = Some(match scores {
+                None => clause_scores,
+                Some(scores) => scores
+                    .into_iter()
+                    .filter_map(|(key, score)| Some((key, score + clause_scores.get(key)?)))
+                    .collect(),
+            });
+        }
+
+        let mut hits: Vec<Hit> = scores
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(key, score)| Hit {
+                key: key.to_string(),
+                score,
+            })
+            .collect();
+        hits.sort_by(|a, b| b.score.total_cmp(&a.score).then_with(|| a.key.cmp(&b.key)));
+        hits.truncate(limit);
+        hits
+    }
+
+    /// Returns the indexed text of `value` with every word matched by `search` marked
+    ///
+    /// Only texts with at least one match are returned.
+    pub fn highlight(&self, search: &Search, value: &Value) -> Vec<String> {
+        let stemmer = Stemmer::create(Algorithm::English);
+        self.texts(value)
+            .filter_map(|text| {
+                let mut highlighted = String::with_capacity(text.len());
+                let mut last = 0;
+                for ((start, end), term) in tokenize(&stemmer, text) {
+                    let word = text[start..end].to_lowercase();
+                    if search.matches(&term, &word) {
+                        highlighted.push_str(&text[last..start]);
+                        highlighted.push_str(HIGHLIGHT_START);
+                        highlighted.push_str(&text[start..end]);
+                        highlighted.push_str(HIGHLIGHT_END);
+                        last = end;
+                    }
+                }
+                (last > 0).then(|| {
+                    highlighted.push_str(&text[last..]);
+                    highlighted
+                })
+            })
+            .collect()
+    }
+
+    fn texts<'a>(&'a self, value: &'a Value) -> Box<dyn Iterator<Item = &'a str> + 'a> {
+        match &value.data {
+            Data::String(string) => Box::new(std::iter::once(string.as_str())),
+            Data::Json(json) => Box::new(
+                self.definition
+                    .fields
+                    .iter()
+                    .filter_map(|pointer| json.pointer(pointer)?.as_str()),
+            ),
+            Data::Int(_) | Data::Vector(_) | Data::Custom { .. } => Box::new(std::iter::empty()),
+        }
+    }
+
+    fn clause_scores(&self, clause: &Clause) -> HashMap<&str, f64> {
+        match clause {
+            Clause::Term(term) => self.term_scores(term),
+            Clause::Prefix(prefix) => {
+                let mut scores: HashMap<&str, f64> = HashMap::new();
+                let terms = self
+                    .postings
+                    .range::<str, _>((Bound::Included(prefix.as_str()), Bound::Unbounded))
+                    .take_while(|(term, _)| term.starts_with(prefix.as_str()));
+                for (term, _) in terms {
+                    for (key, score) in self.term_scores(term) {
+                        *scores.entry(key).or_default() += score;
+                    }
+                }
+                scores
+            }
+            Clause::Phrase(terms) => {
+                let frequencies = self.phrase_frequencies(terms);
+                let idf = self.idf(frequencies.len());
+                frequencies
+                    .into_iter()
+                    .map(|(key, frequency)| (key, self.bm25(idf, frequency, key)))
+                    .collect()
+            }
+        }
+    }
+
+    fn term_scores(&self, term: &str) -> HashMap<&str, f64> {
+        let Some(postings) = self.postings.get(term) else {
+            return HashMap::new();
+        };
+        let idf = self.idf(postings.len());
+        postings
+            .iter()
+            .map(|(key, positions)| (key.as_str(), self.bm25(idf, positions.len(), key)))
+            .collect()
+    }
+
+    // Counts how many times the terms appear next to each other in order in each document
+    fn phrase_frequencies(&self, terms: &[String]) -> HashMap<&str, usize> {
+        let Some(postings) = terms
+            .iter()
+            .map(|term| self.postings.get(term))
+            .collect::<Option<Vec<_>>>()
+        else {
+            return HashMap::new();
+        };
+        let Some((first, rest)) = postings.split_first() else {
+            return HashMap::new();
+        };
+
+        first
+            .iter()
+            .filter_map(|(key, positions)| {
+                let frequency = positions
+                    .iter()
+                    .filter(|&&start| {
+                        rest.iter().zip(1..).all(|(postings, offset)| {
+                            postings.get(key).is_some_and(|positions| {
+                                positions.binary_search(&(start + offset)).is_ok()
+                            })
+                        })
+                    })
+                    .count();
+                (frequency > 0).then_some((key.as_str(), frequency))
+            })
+            .collect()
+    }
+
+    fn idf(&self, document_frequency: usize) -> f64 {
+        let documents = self.documents.len() as f64;
+        let document_frequency = document_frequency as f64;
+        ((documents - document_frequency + 0.5) / (document_frequency + 0.5) + 1.0).ln()
+    }
+
+    fn bm25(&self, idf: f64, frequency: usize, key: &str) -> f64 {
+        let length = self
+            .documents
+            .get(key)
+            .map_or(0, |document| document.length) as f64;
+        let average_length = self.total_length as f64 / self.documents.len().max(1) as f64;
+        let frequency = frequency as f64;
+        idf * frequency * (K1 + 1.0)
+            / (frequency + K1 * (1.0 - B + B * length / average_length.max(1.0)))
+    }
+}
+
+impl Search {
+    pub fn parse(query: &str) -> Self {
+        let stemmer = Stemmer::create(Algorithm::English);
+        let mut clauses = Vec::new();
+        // Text outside quotes is at even indices and phrases at odd ones, so an unterminated quote is still a phrase
+        for (index, part) in query.split('"').enumerate() {
+            if index % 2 == 1 {
+                let terms: Vec<String> = tokenize(&stemmer, part).map(|(_, term)| term).collect();
+                match terms.len() {
+                    0 => {}
+                    1 => clauses.extend(terms.into_iter().map(Clause::Term)),
+                    _ => clauses.push(Clause::Phrase(terms)),
+                }
+                continue;
+            }
+            for word in part.split_whitespace() {
+                match word.strip_suffix('*') {
+                    Some(prefix) => {
+                        let prefix = prefix.to_lowercase();
+                        if prefix.chars().all(char::is_alphanumeric) && !prefix.is_empty() {
+                            clauses.push(Clause::Prefix(prefix));
+                        }
+                    }
+                    None => {
+                        clauses.extend(tokenize(&stemmer, word).map(|(_, term)| Clause::Term(term)))
+                    }
+                }
+            }
+        }
+        Self { clauses }
+    }
+
+    // Whether a word, given both stemmed and as written, was searched for
+    fn matches(&self, term: &str, word: &str) -> bool {
+        self.clauses.iter().any(|clause| match clause {
+            Clause::Term(searched) => searched == term,
+            Clause::Phrase(terms) => terms.iter().any(|searched| searched == term),
+            Clause::Prefix(prefix) => {
+                word.starts_with(prefix.as_str()) || term.starts_with(prefix.as_str())
+            }
+        })
+    }
+}
+
+/// Splits text into words, yielding each word's byte range and stemmed, lowercase term
+fn tokenize<'a>(
+    stemmer: &'a Stemmer,
+    text: &'a str,
+) -> impl Iterator<Item = ((usize, usize), String)> + 'a {
+    let mut chars = text.char_indices().peekable();
+    std::iter::from_fn(move || {
+        // Skip to the start of the next word
+        while chars.next_if(|(_, c)| !c.is_alphanumeric()).is_some() {}
+        let (start, _) = *chars.peek()?;
+        let mut end = start;
+        while let Some((index, c)) = chars.next_if(|(_, c)| c.is_alphanumeric()) {
+            end = index + c.len_utf8();
+        }
+        let word = text[start..end].to_lowercase();
+        Some(((start, end), stemmer.stem(&word).into_owned()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(text: &str) -> Value {
+        Value {
+            data: Data::String(text.to_string()),
+            expiry: None,
+        }
+    }
+
+    fn index() -> SearchIndex {
+        let mut index = SearchIndex::new(SearchIndexDefinition {
+            name: "chat".to_string(),
+            pattern: "chat/*".to_string(),
+            fields: vec!["/text".to_string()],
+        });
+        index.insert("chat/1", &value("Running the deploy tonight"));
+        index.insert(
+            "chat/2",
+            &value("The deploy is running, deploy logs are green"),
+        );
+        index.insert("chat/3", &value("Lunch is ready"));
+        index.insert(
+            "chat/4",
+            &Value {
+                data: Data::Json(
+                    serde_json::json!({ "text": "Tonight we deploy", "user": "running" }),
+                ),
+                expiry: None,
+            },
+        );
+        index.insert("other/1", &value("deploy"));
+        index
+    }
+
+    // The matching keys in key order, ignoring rank
+    fn keys(index: &SearchIndex, query: &str) -> Vec<String> {
+        let mut keys: Vec<String> = index
+            .search(&Search::parse(query), 10)
+            .into_iter()
+            .map(|hit| hit.key)
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn test_search() {
+        let mut index = index();
+        // Stemming matches "running" with "runs"
+        assert_eq!(keys(&index, "runs deploy"), ["chat/1", "chat/2"]);
+        assert_eq!(keys(&index, "\"deploy tonight\""), ["chat/1"]);
+        assert_eq!(keys(&index, "lun*"), ["chat/3"]);
+        assert!(keys(&index, "\"tonight deploy\" lunch").is_empty());
+
+        index.remove("chat/2");
+        assert_eq!(keys(&index, "deploy"), ["chat/1", "chat/4"]);
+
+        // Replacing a value replaces what it is indexed under
+        index.insert("chat/1", &value("Lunch tonight"));
+        assert_eq!(keys(&index, "lunch"), ["chat/1", "chat/3"]);
+        assert_eq!(keys(&index, "deploy"), ["chat/4"]);
+    }
+
+    #[test]
+    fn test_search_ranking() {
+        let mut index = SearchIndex::new(SearchIndexDefinition {
+            name: "chat".to_string(),
+            pattern: "*".to_string(),
+            fields: Vec::new(),
+        });
+        index.insert("once", &value("deploy lunch today"));
+        index.insert("twice", &value("deploy deploy today"));
+        index.insert(
+            "long",
+            &value("deploy after a very long and winding lunch today"),
+        );
+        index.insert("none", &value("lunch today"));
+
+        let hits = index.search(&Search::parse("deploy"), 2);
+        let keys: Vec<&str> = hits.iter().map(|hit| hit.key.as_str()).collect();
+        assert_eq!(keys, ["twice", "once"]);
+        assert!(hits[0].score > hits[1].score);
+    }
+
+    #[test]
+    fn test_highlight() {
+        let index = index();
+        let search = Search::parse("run dep*");
+        assert_eq!(
+            index.highlight(&search, &value("The deploy is running!")),
+            ["The <mark>deploy</mark> is <mark>running</mark>!"]
+        );
+        assert!(index.highlight(&search, &value("Lunch")).is_empty());
+    }
+}
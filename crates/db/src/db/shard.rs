@@ -0,0 +1,15 @@
+//! How a table's keys are split between its shards
+//!
+//! This has no dependencies on the rest of the crate, so the sockets benchmark includes it to pick keys the same way.
+
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+/// How many independently locked parts each table's entries are split into
+pub const SHARDS: usize = 16;
+
+/// Picks the shard a key belongs to
+pub fn shard_of(key: &str) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish() as usize % SHARDS
+}
@@ -1,95 +1,1297 @@
-use aether_common::db::Value;
-use std::{cmp::max, collections::HashMap, sync::Arc, time::Duration};
+use aether_common::{
+    command::IndexQuery,
+    db::{
+        Data, IndexDefinition, KeyEvent, SearchIndexDefinition, TableOptions, TriggerDefinition,
+        Value, VectorIndexDefinition,
+    },
+};
+use std::{
+    cmp::max,
+    collections::{BTreeMap, HashMap},
+    ops::{Bound, RangeBounds},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock, PoisonError, Weak,
+    },
+    time::Duration,
+};
 
 use time::OffsetDateTime;
 use tokio::{
     select,
-    sync::{Notify, RwLock},
+    sync::{broadcast, mpsc, Notify, RwLock, RwLockReadGuard, RwLockWriteGuard},
 };
 use tracing::debug;
 
+use super::{
+    index::Index,
+    search::{Hit, Search, SearchIndex},
+    shard::{shard_of, SHARDS},
+    vector::{Neighbour, VectorIndex},
+};
+use crate::{pattern::glob_match, query::Query};
+
+/// How many values are copied each time a shard is locked while copying every entry
+const COPY_BATCH: usize = 1024;
+
 #[derive(Clone)]
 pub struct Table {
     store: Arc<Store>,
 }
 
+/// A table that may have been dropped, which doesn't keep its entries alive
+pub struct WeakTable {
+    store: Weak<Store>,
+}
+
+/// A committed change to a key
+#[derive(Clone, Debug)]
+pub struct Change {
+    pub key: String,
+    pub event: KeyEvent,
+
+    /// The new value of a set key, or the value a deleted or expired key had
+    ///
+    /// It is shared so every listener doesn't have to copy it.
+    pub value: Arc<Value>,
+
+    /// The version of the write that made the change, which increases with every write to the table
+    pub version: u64,
+
+    /// Who made the change, if the write was tagged with [`Transaction::set_origin`]
+    pub origin: Option<Arc<str>>,
+}
+
+/// A table's entries, split into shards by key hash so writers to different shards don't wait on each other
+///
+/// Locks are always taken in the same order to avoid deadlocks: shards by ascending number, then the indexes.
 struct Store {
-    data: RwLock<HashMap<String, Value>>,
-    background_task: Notify,
+    shards: Box<[RwLock<Shard>]>,
+    indexes: RwLock<Indexes>,
+    // Whether there are any indexes, so writes to tables without them don't wait on the index lock.
+    // Only changed while holding the index lock, and only set while also holding every shard lock.
+    indexed: AtomicBool,
+    // Approximate bytes of keys and data across every shard
+    memory: AtomicUsize,
+    // Counts writes, so snapshots can tell which happened after them
+    version: AtomicU64,
+    // How many snapshots are open at each version
+    snapshots: Mutex<BTreeMap<u64, usize>>,
+    // The total of `snapshots`, so writers can check for open snapshots without taking its lock
+    open_snapshots: AtomicUsize,
+    options: TableOptions,
+    // Shared with the expiration task, which only holds a weak reference to the store so it stops once the table is dropped
+    background_task: Arc<Notify>,
+    // Changes are only collected while something is listening
+    changes: broadcast::Sender<Change>,
+    triggers: Mutex<BTreeMap<String, TriggerDefinition>>,
+    // Every change for the triggers, set once something has started running them.
+    // Unlike `changes` it never drops any, so no trigger is skipped however many writes there are at once.
+    trigger_changes: OnceLock<mpsc::UnboundedSender<Change>>,
 }
 
 impl Table {
     pub fn new() -> Table {
-        let db = Table {
-            store: Arc::new(Store::new()),
+        Self::with_options(TableOptions::default())
+    }
+
+    pub fn with_options(options: TableOptions) -> Table {
+        let store = Arc::new(Store::new(options));
+        tokio::spawn(remove_expired_entries(
+            Arc::downgrade(&store),
+            store.background_task.clone(),
+        ));
+        Table { store }
+    }
+
+    pub fn options(&self) -> &TableOptions {
+        &self.store.options
+    }
+
+    /// Returns `true` if setting every key to its value would keep the table within its `max_memory`
+    pub async fn fits<'a>(&self, values: impl IntoIterator<Item = (&'a str, &'a Value)>) -> bool {
+        let Some(max_memory) = self.store.options.max_memory else {
+            return true;
         };
-        tokio::spawn(remove_expired_entries(db.store.clone()));
-        db
+        let values: Vec<_> = values.into_iter().collect();
+        let shards = self
+            .store
+            .read(values.iter().map(|(key, _)| shard_of(key)))
+            .await;
+        self.store.memory_after(values, |key| shards.get(key)) <= max_memory
     }
 
     pub async fn get(&self, key: &str) -> Option<Value> {
-        let data = self.store.data.read().await;
-        data.get(key).cloned()
+        self.store.get(key, None).await
     }
 
     pub async fn set(&self, key: String, value: Value) {
-        // Get next expiration to see if notification is necessary
-        let next_expiration = self.store.next_expiration().await;
-
         // Check to see if new expiration is the newest
         // If it is, notify the expiration checker
-        let should_notify = match (next_expiration, value.expiry) {
-            // No expirations at all => No notification
-            (None, None) => false,
-            // No current expirations, but there is an expiration in the new value => Notify
-            (None, Some(_)) => true,
-            // Current expirations exist, but no new expiration => No notification
-            (Some(_), None) => false,
-            // Both exist => Only notify if next expiration is the first to occur
-            (Some(next_expiration), Some(expiration)) => expiration < next_expiration,
+        let should_notify = match value.expiry {
+            // No expiration in the new value => No notification, and no need to look through every shard
+            None => false,
+            // Only notify if there are no current expirations or the new one is the first to occur
+            Some(expiration) => self
+                .store
+                .next_expiration()
+                .await
+                .is_none_or(|next_expiration| expiration < next_expiration),
         };
 
         // Insert the new data
-        let mut data = self.store.data.write().await;
-        data.insert(key, value);
+        let mut writer = self.store.write([shard_of(&key)]).await;
+        writer.insert(key, value);
 
-        // Drop data when we're done mutating state.
-        drop(data);
+        // Drop the locks when we're done mutating state.
+        drop(writer);
 
         if should_notify {
             self.store.background_task.notify_one();
         }
     }
+
+    /// Inserts many values at once, notifying the expiration checker at most once
+    pub async fn extend(&self, values: impl IntoIterator<Item = (String, Value)>) {
+        let values: Vec<_> = values.into_iter().collect();
+        let mut writer = self
+            .store
+            .write(values.iter().map(|(key, _)| shard_of(key)))
+            .await;
+        let mut has_expiry = false;
+        for (key, value) in values {
+            has_expiry |= value.expiry.is_some();
+            writer.insert(key, value);
+        }
+        drop(writer);
+
+        if has_expiry {
+            self.store.background_task.notify_one();
+        }
+    }
+
+    /// Clones every entry as of one moment
+    ///
+    /// The table is pinned like a snapshot, so a write to several shards is either wholly in the copy or not at all,
+    /// even though each shard's read lock is only held while it is copied.
+    pub async fn entries(&self) -> HashMap<String, Value> {
+        self.snapshot().entries().await
+    }
+
+    /// Pins the table's current state, which the snapshot reads until it is dropped
+    pub fn snapshot(&self) -> TableSnapshot {
+        TableSnapshot {
+            version: self.store.pin(),
+            store: self.store.clone(),
+        }
+    }
+
+    /// Removes a value, returning it if there was one
+    pub async fn remove(&self, key: &str) -> Option<Value> {
+        let mut writer = self.store.write([shard_of(key)]).await;
+        writer.remove(key)
+    }
+
+    /// Has `watch` notice the next time `key` is set, removed or expires
+    pub async fn watch(&self, key: &str, watch: &Watch) {
+        let mut writer = self.store.write([shard_of(key)]).await;
+        let watchers = writer
+            .shard(key)
+            .watchers
+            .entry(key.to_string())
+            .or_default();
+        watchers.retain(|changed| changed.strong_count() > 0);
+        watchers.push(Arc::downgrade(&watch.changed));
+    }
+
+    /// Locks the shards holding `keys` so they can be read and written as one atomic step
+    ///
+    /// Only `keys` can be used through the returned transaction.
+    pub async fn lock<'a>(&self, keys: impl IntoIterator<Item = &'a str>) -> Transaction<'_> {
+        Transaction {
+            writer: self.store.write(keys.into_iter().map(shard_of)).await,
+            has_expiry: false,
+        }
+    }
+
+    /// Listens for changes committed from now on, which are sent as each write finishes
+    pub fn changes(&self) -> broadcast::Receiver<Change> {
+        self.store.changes.subscribe()
+    }
+
+    pub fn downgrade(&self) -> WeakTable {
+        WeakTable {
+            store: Arc::downgrade(&self.store),
+        }
+    }
+
+    /// Adds a trigger, returning `false` without changing anything if there is already one with the same name
+    pub fn create_trigger(&self, definition: TriggerDefinition) -> bool {
+        let mut triggers = self
+            .store
+            .triggers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if triggers.contains_key(&definition.name) {
+            return false;
+        }
+        triggers.insert(definition.name.clone(), definition);
+        true
+    }
+
+    /// Removes a trigger, returning `false` if there was no trigger with that name
+    pub fn drop_trigger(&self, name: &str) -> bool {
+        let mut triggers = self
+            .store
+            .triggers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        triggers.remove(name).is_some()
+    }
+
+    pub fn triggers(&self) -> Vec<TriggerDefinition> {
+        let triggers = self
+            .store
+            .triggers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        triggers.values().cloned().collect()
+    }
+
+    /// Returns every change committed from now on the first time it is called, so only one task runs the table's
+    /// triggers
+    pub fn start_triggers(&self) -> Option<mpsc::UnboundedReceiver<Change>> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.store.trigger_changes.set(sender).ok()?;
+        Some(receiver)
+    }
+
+    /// Adds an index, built from every entry already in the table
+    ///
+    /// Returns `false` without changing anything if there is already an index with the same name.
+    pub async fn create_index(&self, definition: IndexDefinition) -> bool {
+        let mut writer = self.store.write_all().await;
+        let mut index = Index::new(definition);
+        for (key, value) in writer.iter() {
+            index.insert(key, value);
+        }
+        writer.add_index(
+            |indexes| &mut indexes.indexes,
+            index.definition().name.clone(),
+            index,
+        )
+    }
+
+    /// Removes an index, returning `false` if there was no index with that name
+    pub async fn drop_index(&self, name: &str) -> bool {
+        self.store
+            .drop_index(|indexes| indexes.indexes.remove(name).is_some())
+            .await
+    }
+
+    pub async fn indexes(&self) -> Vec<IndexDefinition> {
+        let indexes = self.store.indexes.read().await;
+        indexes
+            .indexes
+            .values()
+            .map(|index| index.definition().clone())
+            .collect()
+    }
+
+    /// Adds a full-text index, built from every entry already in the table
+    ///
+    /// Returns `false` without changing anything if there is already a full-text index with the same name.
+    pub async fn create_search_index(&self, definition: SearchIndexDefinition) -> bool {
+        let mut writer = self.store.write_all().await;
+        let mut index = SearchIndex::new(definition);
+        for (key, value) in writer.iter() {
+            index.insert(key, value);
+        }
+        writer.add_index(
+            |indexes| &mut indexes.search_indexes,
+            index.definition().name.clone(),
+            index,
+        )
+    }
+
+    /// Removes a full-text index, returning `false` if there was no full-text index with that name
+    pub async fn drop_search_index(&self, name: &str) -> bool {
+        self.store
+            .drop_index(|indexes| indexes.search_indexes.remove(name).is_some())
+            .await
+    }
+
+    pub async fn search_indexes(&self) -> Vec<SearchIndexDefinition> {
+        let indexes = self.store.indexes.read().await;
+        indexes
+            .search_indexes
+            .values()
+            .map(|index| index.definition().clone())
+            .collect()
+    }
+
+    /// Searches a full-text index, returning up to `limit` hits with their values and highlights, best first
+    ///
+    /// `None` is returned if there is no full-text index with that name.
+    pub async fn search(
+        &self,
+        index: &str,
+        search: &Search,
+        limit: usize,
+    ) -> Option<Vec<(Hit, Value, Vec<String>)>> {
+        let reader = self.store.read_all().await;
+        let index = reader.indexes.search_indexes.get(index)?;
+        let hits = index
+            .search(search, limit)
+            .into_iter()
+            .filter_map(|hit| {
+                let value = reader.shards.get(&hit.key)?.clone();
+                let highlights = index.highlight(search, &value);
+                Some((hit, value, highlights))
+            })
+            .collect();
+        Some(hits)
+    }
+
+    /// Adds a vector index, built from every entry already in the table
+    ///
+    /// Returns `false` without changing anything if there is already a vector index with the same name.
+    pub async fn create_vector_index(&self, definition: VectorIndexDefinition) -> bool {
+        let mut writer = self.store.write_all().await;
+        let mut index = VectorIndex::new(definition);
+        for (key, value) in writer.iter() {
+            index.insert(key, value);
+        }
+        writer.add_index(
+            |indexes| &mut indexes.vector_indexes,
+            index.definition().name.clone(),
+            index,
+        )
+    }
+
+    /// Removes a vector index, returning `false` if there was no vector index with that name
+    pub async fn drop_vector_index(&self, name: &str) -> bool {
+        self.store
+            .drop_index(|indexes| indexes.vector_indexes.remove(name).is_some())
+            .await
+    }
+
+    pub async fn vector_indexes(&self) -> Vec<VectorIndexDefinition> {
+        let indexes = self.store.indexes.read().await;
+        indexes
+            .vector_indexes
+            .values()
+            .map(|index| index.definition().clone())
+            .collect()
+    }
+
+    /// Finds up to `k` of the keys nearest to `vector`, and their values, nearest first
+    ///
+    /// Only keys matching the `filter` glob pattern are returned if it is set.
+    /// `None` is returned if there is no vector index with that name.
+    pub async fn vector_search(
+        &self,
+        index: &str,
+        vector: &[f32],
+        k: usize,
+        filter: Option<&str>,
+    ) -> Option<Vec<(Neighbour, Value)>> {
+        let reader = self.store.read_all().await;
+        let index = reader.indexes.vector_indexes.get(index)?;
+        let neighbours = index
+            .search(vector, k, |key| {
+                filter.is_none_or(|pattern| glob_match(pattern, key))
+            })
+            .into_iter()
+            .filter_map(|neighbour| {
+                let value = reader.shards.get(&neighbour.key)?.clone();
+                Some((neighbour, value))
+            })
+            .collect();
+        Some(neighbours)
+    }
+
+    /// Runs a query, narrowing down the entries to look at with an index if the query can use one
+    pub async fn query(&self, query: &Query) -> Vec<(String, Value)> {
+        let reader = self.store.read_all().await;
+        let indexes = &reader.indexes.indexes;
+        let lookup = query
+            .index_lookup(indexes.values().map(Index::definition))
+            .and_then(|(name, index_query)| Some((indexes.get(&name)?, index_query)));
+        match lookup {
+            Some((index, index_query)) => {
+                let keys = index.find(&index_query);
+                query.execute(keys.filter_map(|key| Some((key, reader.shards.get(key)?))))
+            }
+            None => query.execute(
+                reader
+                    .shards
+                    .iter(None)
+                    .map(|(key, value)| (key.as_str(), value)),
+            ),
+        }
+    }
+
+    /// Returns the entries found by `query` on an index, ordered by the indexed value and then by key
+    ///
+    /// `None` is returned if there is no index with that name.
+    pub async fn find_by(&self, index: &str, query: &IndexQuery) -> Option<Vec<(String, Value)>> {
+        let reader = self.store.read_all().await;
+        let index = reader.indexes.indexes.get(index)?;
+        let entries = index
+            .find(query)
+            .filter_map(|key| {
+                let value = reader.shards.get(key)?;
+                Some((key.to_string(), value.clone()))
+            })
+            .collect();
+        Some(entries)
+    }
+
+    /// Returns the entries with keys from `start` up to but not including `end` in key order
+    ///
+    /// Missing bounds are unbounded. `None` is returned if the table isn't ordered.
+    pub async fn range(
+        &self,
+        start: Option<&str>,
+        end: Option<&str>,
+        limit: Option<usize>,
+        reverse: bool,
+    ) -> Option<Vec<(String, Value)>> {
+        self.store.range(start, end, limit, reverse, None).await
+    }
+
+    /// Returns the entries with keys starting with `prefix` in key order
+    ///
+    /// `None` is returned if the table isn't ordered.
+    pub async fn prefix_scan(&self, prefix: &str) -> Option<Vec<(String, Value)>> {
+        self.store.prefix_scan(prefix, None).await
+    }
+}
+
+impl WeakTable {
+    pub fn upgrade(&self) -> Option<Table> {
+        Some(Table {
+            store: self.store.upgrade()?,
+        })
+    }
+}
+
+/// Notices changes to the keys it watches, for optimistic transactions
+///
+/// Dropping a watch stops it watching every key.
+#[derive(Default)]
+pub struct Watch {
+    changed: Arc<AtomicBool>,
+}
+
+impl Watch {
+    /// Whether any watched key has been set, removed or expired since it was watched
+    pub fn changed(&self) -> bool {
+        self.changed.load(Ordering::SeqCst)
+    }
+}
+
+/// Some keys of a table, locked so they can be read and written as one atomic step
+pub struct Transaction<'a> {
+    writer: Writer<'a>,
+    has_expiry: bool,
+}
+
+impl Transaction<'_> {
+    pub fn get(&self, key: &str) -> Option<Value> {
+        self.writer.get(key).cloned()
+    }
+
+    pub fn set(&mut self, key: String, value: Value) {
+        self.has_expiry |= value.expiry.is_some();
+        self.writer.insert(key, value);
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<Value> {
+        self.writer.remove(key)
+    }
+
+    /// Tags the changes made from now on with who made them
+    pub fn set_origin(&mut self, origin: &str) {
+        self.writer.origin = Some(origin.into());
+    }
+
+    /// Like [`Table::fits`], for keys locked by the transaction
+    pub fn fits<'a>(&self, values: impl IntoIterator<Item = (&'a str, &'a Value)>) -> bool {
+        let store = self.writer.store;
+        store.options.max_memory.is_none_or(|max_memory| {
+            store.memory_after(values, |key| self.writer.get(key)) <= max_memory
+        })
+    }
+}
+
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        if self.has_expiry {
+            self.writer.store.background_task.notify_one();
+        }
+    }
+}
+
+/// A read-only view of a table as it was when the snapshot was taken, unaffected by later writes
+///
+/// While any snapshot of a table is open, writes keep the values they replace so snapshots can still read them.
+/// Those are let go once no open snapshot needs them, so snapshots should be dropped as soon as they are done with.
+pub struct TableSnapshot {
+    store: Arc<Store>,
+    version: u64,
+}
+
+impl TableSnapshot {
+    /// The version of the last write the snapshot includes
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub async fn get(&self, key: &str) -> Option<Value> {
+        self.store.get(key, Some(self.version)).await
+    }
+
+    /// Like [`Table::range`], as of the snapshot
+    pub async fn range(
+        &self,
+        start: Option<&str>,
+        end: Option<&str>,
+        limit: Option<usize>,
+        reverse: bool,
+    ) -> Option<Vec<(String, Value)>> {
+        self.store
+            .range(start, end, limit, reverse, Some(self.version))
+            .await
+    }
+
+    /// Like [`Table::prefix_scan`], as of the snapshot
+    pub async fn prefix_scan(&self, prefix: &str) -> Option<Vec<(String, Value)>> {
+        self.store.prefix_scan(prefix, Some(self.version)).await
+    }
+
+    /// Clones every entry as of the snapshot
+    pub async fn entries(&self) -> HashMap<String, Value> {
+        let mut entries = HashMap::new();
+        let mut batches = self.batches();
+        while let Some(batch) = batches.next().await {
+            entries.extend(batch);
+        }
+        entries
+    }
+
+    /// Clones every entry as of the snapshot a batch at a time, so they never all have to be held at once
+    pub fn batches(&self) -> Batches<'_> {
+        Batches {
+            snapshot: self,
+            shard: 0,
+            keys: Vec::new(),
+        }
+    }
+
+    /// Runs a query over every entry as of the snapshot
+    ///
+    /// Indexes only reflect the current state, so they aren't used.
+    pub async fn query(&self, query: &Query) -> Vec<(String, Value)> {
+        let shards = self.store.read(0..SHARDS).await;
+        query.execute(
+            shards
+                .iter(Some(self.version))
+                .map(|(key, value)| (key.as_str(), value)),
+        )
+    }
+}
+
+/// The entries of a snapshot, copied a batch at a time
+///
+/// Only the keys are copied while a shard is first locked, then the values a batch at a time, so writers never
+/// wait for more than a batch to be copied however large the table is.
+pub struct Batches<'a> {
+    snapshot: &'a TableSnapshot,
+    // The next shard to copy the keys of
+    shard: usize,
+    // The keys of the previous shard whose values haven't been copied yet
+    keys: Vec<String>,
+}
+
+impl Batches<'_> {
+    pub async fn next(&mut self) -> Option<Vec<(String, Value)>> {
+        let version = Some(self.snapshot.version);
+        while self.keys.is_empty() {
+            let shard = self.snapshot.store.shards.get(self.shard)?.read().await;
+            self.keys = shard.iter(version).map(|(key, _)| key.clone()).collect();
+            self.shard += 1;
+        }
+        let batch = self
+            .keys
+            .split_off(self.keys.len().saturating_sub(COPY_BATCH));
+        let shard = self.snapshot.store.shards[self.shard - 1].read().await;
+        Some(
+            batch
+                .into_iter()
+                .filter_map(|key| {
+                    let value = shard.get(&key, version)?.clone();
+                    Some((key, value))
+                })
+                .collect(),
+        )
+    }
+}
+
+impl Drop for TableSnapshot {
+    fn drop(&mut self) {
+        self.store.unpin(self.version);
+    }
+}
+
+/// Everything derived from a table's entries
+#[derive(Default)]
+struct Indexes {
+    indexes: HashMap<String, Index>,
+    search_indexes: HashMap<String, SearchIndex>,
+    vector_indexes: HashMap<String, VectorIndex>,
+}
+
+impl Indexes {
+    fn is_empty(&self) -> bool {
+        self.indexes.is_empty() && self.search_indexes.is_empty() && self.vector_indexes.is_empty()
+    }
+
+    fn insert(&mut self, key: &str, value: &Value) {
+        for index in self.indexes.values_mut() {
+            index.insert(key, value);
+        }
+        for index in self.search_indexes.values_mut() {
+            index.insert(key, value);
+        }
+        for index in self.vector_indexes.values_mut() {
+            index.insert(key, value);
+        }
+    }
+
+    fn remove(&mut self, key: &str, value: &Value) {
+        for index in self.indexes.values_mut() {
+            index.remove(key, value);
+        }
+        for index in self.search_indexes.values_mut() {
+            index.remove(key);
+        }
+        for index in self.vector_indexes.values_mut() {
+            index.remove(key);
+        }
+    }
+}
+
+/// Read locks on some of a table's shards
+struct Shards<'a> {
+    // Kept sorted by shard number
+    numbers: Vec<usize>,
+    shards: Vec<RwLockReadGuard<'a, Shard>>,
+}
+
+impl Shards<'_> {
+    /// Gets a value, which must be in one of the locked shards
+    fn get(&self, key: &str) -> Option<&Value> {
+        let position = self.numbers.binary_search(&shard_of(key)).ok()?;
+        self.shards[position].get(key, None)
+    }
+
+    /// Every entry in the locked shards, as of `version` if there is one
+    fn iter(&self, version: Option<u64>) -> impl Iterator<Item = (&String, &Value)> {
+        self.shards
+            .iter()
+            .flat_map(move |shard| shard.iter(version))
+    }
+}
+
+/// Read locks on every shard and the indexes, so reads through an index see the entries it was built from
+struct Reader<'a> {
+    shards: Shards<'a>,
+    indexes: RwLockReadGuard<'a, Indexes>,
+}
+
+/// Write locks on some of a table's shards, and the indexes if there are any
+///
+/// Every change to the entries goes through here so the indexes and memory count are kept up to date.
+struct Writer<'a> {
+    store: &'a Store,
+    // Kept sorted by shard number
+    numbers: Vec<usize>,
+    shards: Vec<RwLockWriteGuard<'a, Shard>>,
+    indexes: Option<RwLockWriteGuard<'a, Indexes>>,
+    // The version of the writes made through this writer
+    version: u64,
+    // Whether there are open snapshots that may need the values replaced by these writes
+    recording: bool,
+    // The changes to send once the shards are unlocked, if anything is listening for them
+    changes: Option<Vec<Change>>,
+    // Who the changes are made by, if anyone said
+    origin: Option<Arc<str>>,
+}
+
+impl Writer<'_> {
+    fn shard(&mut self, key: &str) -> &mut Shard {
+        let position = self
+            .numbers
+            .binary_search(&shard_of(key))
+            .expect("key's shard is not locked");
+        &mut self.shards[position]
+    }
+
+    fn insert(&mut self, key: String, value: Value) {
+        self.report(&key, KeyEvent::Set, &value);
+        self.shard(&key).touch(&key);
+        let old = self.shard(&key).entries.remove(&key);
+        if let Some(old) = &old {
+            self.forget(&key, old);
+        }
+        if self.recording {
+            self.record(&key, old);
+        }
+        self.store
+            .memory
+            .fetch_add(size_of(&key, &value), Ordering::Relaxed);
+        if let Some(indexes) = &mut self.indexes {
+            indexes.insert(&key, &value);
+        }
+        self.shard(&key).entries.insert(key, value);
+    }
+
+    fn remove(&mut self, key: &str) -> Option<Value> {
+        let value = self.shard(key).entries.remove(key)?;
+        self.report(key, KeyEvent::Delete, &value);
+        self.shard(key).touch(key);
+        self.forget(key, &value);
+        if self.recording {
+            self.record(key, Some(value.clone()));
+        }
+        Some(value)
+    }
+
+    /// Expires the entries in the locked shards `f` returns `false` for
+    fn retain(&mut self, mut f: impl FnMut(&str, &Value) -> bool) {
+        let mut removed = Vec::new();
+        for shard in &mut self.shards {
+            shard.entries.retain(|key, value| {
+                let keep = f(key, value);
+                if !keep {
+                    removed.push((key.clone(), value.clone()));
+                }
+                keep
+            });
+        }
+        for (key, value) in removed {
+            self.report(&key, KeyEvent::Expire, &value);
+            self.shard(&key).touch(&key);
+            self.forget(&key, &value);
+            if self.recording {
+                self.record(&key, Some(value));
+            }
+        }
+    }
+
+    /// Gets a value, which must be in one of the locked shards
+    fn get(&self, key: &str) -> Option<&Value> {
+        let position = self
+            .numbers
+            .binary_search(&shard_of(key))
+            .expect("key's shard is not locked");
+        self.shards[position].get(key, None)
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (&String, &Value)> {
+        self.shards.iter().flat_map(|shard| shard.iter(None))
+    }
+
+    fn report(&mut self, key: &str, event: KeyEvent, value: &Value) {
+        if let Some(changes) = &mut self.changes {
+            changes.push(Change {
+                key: key.to_string(),
+                event,
+                value: Arc::new(value.clone()),
+                version: self.version,
+                origin: self.origin.clone(),
+            });
+        }
+    }
+
+    // Keeps the value a key had before this write for the open snapshots
+    fn record(&mut self, key: &str, old: Option<Value>) {
+        let version = self.version;
+        self.shard(key)
+            .history
+            .entry(key.to_string())
+            .or_default()
+            .push((version, old));
+    }
+
+    /// Forgets the watches in the locked shards that have been dropped
+    fn prune_watchers(&mut self) {
+        for shard in &mut self.shards {
+            shard.watchers.retain(|_, watchers| {
+                watchers.retain(|changed| changed.strong_count() > 0);
+                !watchers.is_empty()
+            });
+        }
+    }
+
+    /// Lets go of the replaced values in the locked shards that no snapshot at `oldest` or later needs
+    ///
+    /// Everything is let go if there are no open snapshots.
+    fn prune_history(&mut self, oldest: Option<u64>) {
+        for shard in &mut self.shards {
+            match oldest {
+                None => shard.history.clear(),
+                Some(oldest) => shard.history.retain(|_, history| {
+                    history.retain(|(version, _)| *version > oldest);
+                    !history.is_empty()
+                }),
+            }
+        }
+    }
+
+    /// Adds a built index, returning `false` without changing anything if there is already one with the same name
+    ///
+    /// Every shard must be locked, so no entry can change between building the index and adding it.
+    fn add_index<I>(
+        &mut self,
+        kind: impl FnOnce(&mut Indexes) -> &mut HashMap<String, I>,
+        name: String,
+        index: I,
+    ) -> bool {
+        let indexes = self
+            .indexes
+            .as_mut()
+            .expect("indexes must be locked to add one");
+        let indexes = kind(indexes);
+        if indexes.contains_key(&name) {
+            return false;
+        }
+        indexes.insert(name, index);
+        self.store.indexed.store(true, Ordering::Release);
+        true
+    }
+
+    // Removes a value that is no longer in its shard from the indexes and memory count
+    fn forget(&mut self, key: &str, value: &Value) {
+        self.store
+            .memory
+            .fetch_sub(size_of(key, value), Ordering::Relaxed);
+        if let Some(indexes) = &mut self.indexes {
+            indexes.remove(key, value);
+        }
+    }
+}
+
+impl Drop for Writer<'_> {
+    fn drop(&mut self) {
+        for change in self.changes.take().into_iter().flatten() {
+            // Sending only fails when nothing is listening any more
+            if let Some(triggers) = self.store.trigger_changes.get() {
+                let _ = triggers.send(change.clone());
+            }
+            let _ = self.store.changes.send(change);
+        }
+    }
+}
+
+/// One independently locked part of a table
+struct Shard {
+    entries: Entries,
+    // Values replaced or removed while snapshots were open, oldest first, with the version of the write that replaced them
+    history: HashMap<String, Vec<(u64, Option<Value>)>>,
+    // The watches to tell when each key next changes
+    watchers: HashMap<String, Vec<Weak<AtomicBool>>>,
+}
+
+impl Shard {
+    fn new(ordered: bool) -> Self {
+        Self {
+            entries: Entries::new(ordered),
+            history: HashMap::new(),
+            watchers: HashMap::new(),
+        }
+    }
+
+    /// Tells the watches on a key that it changed
+    ///
+    /// A watch only needs telling once, so they are forgotten afterwards.
+    fn touch(&mut self, key: &str) {
+        let Some(watchers) = self.watchers.remove(key) else {
+            return;
+        };
+        for changed in watchers.iter().filter_map(Weak::upgrade) {
+            changed.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Gets a value as it was at `version`, or as it is now if there is no version
+    fn get(&self, key: &str, version: Option<u64>) -> Option<&Value> {
+        let Some(version) = version else {
+            return self.entries.get(key);
+        };
+        // The first write after the snapshot replaced the value the snapshot sees
+        let replaced = self
+            .history
+            .get(key)
+            .and_then(|history| history.iter().find(|(replaced, _)| *replaced > version));
+        match replaced {
+            Some((_, old)) => old.as_ref(),
+            None => self.entries.get(key),
+        }
+    }
+
+    /// Every entry as it was at `version`, or as it is now if there is no version
+    fn iter(&self, version: Option<u64>) -> Box<dyn Iterator<Item = (&String, &Value)> + '_> {
+        if version.is_none() {
+            return self.entries.iter();
+        }
+        let removed = self
+            .history
+            .keys()
+            .filter(|key| self.entries.get(key).is_none());
+        Box::new(
+            self.entries
+                .iter()
+                .map(|(key, _)| key)
+                .chain(removed)
+                .filter_map(move |key| Some((key, self.get(key, version)?))),
+        )
+    }
+
+    /// The entries within `bounds` in key order, as they were at `version` or as they are now if there is no version
+    ///
+    /// `None` is returned if the shard isn't ordered.
+    fn range<'a>(
+        &'a self,
+        bounds: (Bound<&'a str>, Bound<&'a str>),
+        version: Option<u64>,
+    ) -> Option<Box<dyn DoubleEndedIterator<Item = (&'a String, &'a Value)> + 'a>> {
+        let Entries::Ordered(map) = &self.entries else {
+            return None;
+        };
+        let range = map.range::<str, _>(bounds);
+        if version.is_none() {
+            return Some(Box::new(range));
+        }
+        let removed = self.history.keys().filter(|key| {
+            RangeBounds::<str>::contains(&bounds, key.as_str()) && !map.contains_key(*key)
+        });
+        let mut keys: Vec<&String> = range.map(|(key, _)| key).chain(removed).collect();
+        keys.sort_unstable();
+        Some(Box::new(
+            keys.into_iter()
+                .filter_map(move |key| Some((key, self.get(key, version)?))),
+        ))
+    }
+}
+
+/// Where a shard's entries are kept
+///
+/// Ordered tables trade slower point reads and writes for range and prefix reads.
+enum Entries {
+    Hashed(HashMap<String, Value>),
+    Ordered(BTreeMap<String, Value>),
+}
+
+impl Entries {
+    fn new(ordered: bool) -> Self {
+        match ordered {
+            true => Self::Ordered(BTreeMap::new()),
+            false => Self::Hashed(HashMap::new()),
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Self::Hashed(map) => map.get(key),
+            Self::Ordered(map) => map.get(key),
+        }
+    }
+
+    fn insert(&mut self, key: String, value: Value) -> Option<Value> {
+        match self {
+            Self::Hashed(map) => map.insert(key, value),
+            Self::Ordered(map) => map.insert(key, value),
+        }
+    }
+
+    fn remove(&mut self, key: &str) -> Option<Value> {
+        match self {
+            Self::Hashed(map) => map.remove(key),
+            Self::Ordered(map) => map.remove(key),
+        }
+    }
+
+    fn retain(&mut self, f: impl FnMut(&String, &mut Value) -> bool) {
+        match self {
+            Self::Hashed(map) => map.retain(f),
+            Self::Ordered(map) => map.retain(f),
+        }
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        match self {
+            Self::Hashed(map) => map.len(),
+            Self::Ordered(map) => map.len(),
+        }
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (&String, &Value)> + '_> {
+        match self {
+            Self::Hashed(map) => Box::new(map.iter()),
+            Self::Ordered(map) => Box::new(map.iter()),
+        }
+    }
+}
+
+fn collect<'a>(
+    entries: impl Iterator<Item = (&'a String, &'a Value)>,
+    limit: usize,
+) -> Vec<(String, Value)> {
+    entries
+        .take(limit)
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect()
 }
 
 impl Store {
-    fn new() -> Store {
+    fn new(options: TableOptions) -> Store {
         Store {
-            data: RwLock::new(HashMap::new()),
-            background_task: Notify::new(),
+            shards: (0..SHARDS)
+                .map(|_| RwLock::new(Shard::new(options.ordered)))
+                .collect(),
+            indexes: RwLock::new(Indexes::default()),
+            indexed: AtomicBool::new(false),
+            memory: AtomicUsize::new(0),
+            version: AtomicU64::new(0),
+            snapshots: Mutex::new(BTreeMap::new()),
+            open_snapshots: AtomicUsize::new(0),
+            options,
+            background_task: Arc::new(Notify::new()),
+            changes: broadcast::Sender::new(crate::CHANNEL_SIZE),
+            triggers: Mutex::new(BTreeMap::new()),
+            trigger_changes: OnceLock::new(),
+        }
+    }
+
+    /// Read locks the given shards in ascending order
+    async fn read(&self, numbers: impl IntoIterator<Item = usize>) -> Shards<'_> {
+        let numbers = sorted(numbers);
+        let mut shards = Vec::with_capacity(numbers.len());
+        for &number in &numbers {
+            shards.push(self.shards[number].read().await);
+        }
+        Shards { numbers, shards }
+    }
+
+    async fn read_all(&self) -> Reader<'_> {
+        let shards = self.read(0..SHARDS).await;
+        let indexes = self.indexes.read().await;
+        Reader { shards, indexes }
+    }
+
+    /// Write locks the given shards in ascending order, then the indexes if there are any
+    async fn write(&self, numbers: impl IntoIterator<Item = usize>) -> Writer<'_> {
+        self.lock_for_writing(sorted(numbers), false).await
+    }
+
+    /// Write locks every shard and the indexes
+    async fn write_all(&self) -> Writer<'_> {
+        self.lock_for_writing((0..SHARDS).collect(), true).await
+    }
+
+    async fn lock_for_writing(&self, numbers: Vec<usize>, lock_indexes: bool) -> Writer<'_> {
+        let mut shards = Vec::with_capacity(numbers.len());
+        for &number in &numbers {
+            shards.push(self.shards[number].write().await);
+        }
+        // Indexes can only be added while every shard is locked, so this can't change until the shards are unlocked
+        let indexes = match lock_indexes || self.indexed.load(Ordering::Acquire) {
+            true => Some(self.indexes.write().await),
+            false => None,
+        };
+        // Snapshots take the version after counting themselves as open, and writers check for them after taking
+        // a version, so a writer either sees a snapshot it needs to record for or is already included in it
+        let version = self.version.fetch_add(1, Ordering::SeqCst) + 1;
+        let recording = self.open_snapshots.load(Ordering::SeqCst) > 0;
+        let listening = self.changes.receiver_count() > 0 || self.trigger_changes.get().is_some();
+        let changes = listening.then(Vec::new);
+        Writer {
+            store: self,
+            numbers,
+            shards,
+            indexes,
+            version,
+            recording,
+            changes,
+            origin: None,
+        }
+    }
+
+    /// The memory the table would use after setting every key to its value, given how to get a key's current value
+    fn memory_after<'a, 'b>(
+        &self,
+        values: impl IntoIterator<Item = (&'a str, &'a Value)>,
+        current: impl Fn(&str) -> Option<&'b Value>,
+    ) -> u64 {
+        let mut memory = self.memory.load(Ordering::Relaxed);
+        for (key, value) in values {
+            let replaced = current(key).map_or(0, |old| size_of(key, old));
+            memory = memory.saturating_sub(replaced) + size_of(key, value);
+        }
+        memory as u64
+    }
+
+    /// Opens a snapshot at the current version
+    fn pin(&self) -> u64 {
+        let mut snapshots = self
+            .snapshots
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        self.open_snapshots.fetch_add(1, Ordering::SeqCst);
+        let version = self.version.load(Ordering::SeqCst);
+        *snapshots.entry(version).or_default() += 1;
+        version
+    }
+
+    fn unpin(&self, version: u64) {
+        let mut snapshots = self
+            .snapshots
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(count) = snapshots.get_mut(&version) {
+            *count -= 1;
+            if *count == 0 {
+                snapshots.remove(&version);
+            }
+        }
+        self.open_snapshots.fetch_sub(1, Ordering::SeqCst);
+        drop(snapshots);
+
+        // The expiration task also lets go of replaced values no snapshot needs anymore
+        self.background_task.notify_one();
+    }
+
+    fn oldest_snapshot(&self) -> Option<u64> {
+        let snapshots = self
+            .snapshots
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        snapshots.keys().next().copied()
+    }
+
+    async fn get(&self, key: &str, version: Option<u64>) -> Option<Value> {
+        let shard = self.shards[shard_of(key)].read().await;
+        shard.get(key, version).cloned()
+    }
+
+    /// Returns the entries of an ordered table with keys from `start` up to but not including `end` in key order,
+    /// as of `version` if there is one
+    async fn range(
+        &self,
+        start: Option<&str>,
+        end: Option<&str>,
+        limit: Option<usize>,
+        reverse: bool,
+        version: Option<u64>,
+    ) -> Option<Vec<(String, Value)>> {
+        if !self.options.ordered {
+            return None;
+        }
+        // `BTreeMap::range` panics on inverted bounds, and they can't match anything anyway
+        if let (Some(start), Some(end)) = (start, end) {
+            if start > end {
+                return Some(Vec::new());
+            }
         }
+
+        let bounds = (
+            start.map_or(Bound::Unbounded, Bound::Included),
+            end.map_or(Bound::Unbounded, Bound::Excluded),
+        );
+        let limit = limit.unwrap_or(usize::MAX);
+        let shards = self.read(0..SHARDS).await;
+        // Each shard is in key order, so the first `limit` overall are among the first `limit` of each shard
+        let mut entries = Vec::new();
+        for shard in &shards.shards {
+            let range = shard.range(bounds, version)?;
+            if reverse {
+                entries.extend(range.rev().take(limit));
+            } else {
+                entries.extend(range.take(limit));
+            }
+        }
+        entries.sort_unstable_by(|(a, _), (b, _)| match reverse {
+            true => b.cmp(a),
+            false => a.cmp(b),
+        });
+        Some(collect(entries.into_iter(), limit))
+    }
+
+    /// Returns the entries of an ordered table with keys starting with `prefix` in key order,
+    /// as of `version` if there is one
+    async fn prefix_scan(
+        &self,
+        prefix: &str,
+        version: Option<u64>,
+    ) -> Option<Vec<(String, Value)>> {
+        if !self.options.ordered {
+            return None;
+        }
+        let shards = self.read(0..SHARDS).await;
+        let mut entries = Vec::new();
+        for shard in &shards.shards {
+            entries.extend(
+                shard
+                    .range((Bound::Included(prefix), Bound::Unbounded), version)?
+                    .take_while(|(key, _)| key.starts_with(prefix)),
+            );
+        }
+        entries.sort_unstable_by_key(|(key, _)| *key);
+        Some(collect(entries.into_iter(), usize::MAX))
+    }
+
+    /// Removes an index with `remove`, which returns whether there was one
+    async fn drop_index(&self, remove: impl FnOnce(&mut Indexes) -> bool) -> bool {
+        let mut indexes = self.indexes.write().await;
+        let removed = remove(&mut indexes);
+        self.indexed.store(!indexes.is_empty(), Ordering::Release);
+        removed
     }
 
     async fn next_expiration(&self) -> Option<OffsetDateTime> {
-        let state = self.data.read().await;
-        let next_expiration = state
-            .iter()
-            .filter(|(_, value)| value.expiry.is_some())
-            .min_by_key(|(_, value)| value.expiry);
-        next_expiration.and_then(|(_, value)| value.expiry)
+        let mut next_expiration = None;
+        for shard in self.shards.iter() {
+            let shard = shard.read().await;
+            let next_in_shard = shard
+                .entries
+                .iter()
+                .filter_map(|(_, value)| value.expiry)
+                .min();
+            next_expiration = match (next_expiration, next_in_shard) {
+                (Some(next), Some(next_in_shard)) => Some(next_in_shard.min(next)),
+                (next, next_in_shard) => next.or(next_in_shard),
+            };
+        }
+        next_expiration
     }
 
     async fn remove_expired_values(&self) -> Option<Duration> {
-        let mut state = self.data.write().await;
         let now = OffsetDateTime::now_utc();
         debug!("removing expired values");
 
-        // Remove expired values
+        // Remove expired values one shard at a time, so only writers to that shard wait,
+        // and let go of replaced values no open snapshot needs while each shard is locked
         // TODO: This could be more efficient by caching expirations
-        state.retain(|_, value| value.expiry.map_or(true, |expiry| now <= expiry));
-
-        // Drop state when unneeded and prevent deadlock with next_expiration read
-        drop(state);
+        for shard in 0..SHARDS {
+            let mut writer = self.write([shard]).await;
+            writer.prune_history(self.oldest_snapshot());
+            writer.prune_watchers();
+            writer.retain(|_, value| value.expiry.is_none_or(|expiry| now <= expiry));
+        }
 
         // Get the duration until the next expiration for tokio::time::sleep
         // TODO: This could be more efficient by caching expirations
@@ -99,21 +1301,68 @@ impl Store {
             max(time::Duration::new(0, 0), expiration_offset).unsigned_abs()
         })
     }
+
+    #[cfg(test)]
+    async fn len(&self) -> usize {
+        let mut len = 0;
+        for shard in self.shards.iter() {
+            len += shard.read().await.entries.len();
+        }
+        len
+    }
+}
+
+// Sorts and deduplicates shard numbers into the order their locks are taken in
+fn sorted(numbers: impl IntoIterator<Item = usize>) -> Vec<usize> {
+    let mut numbers: Vec<usize> = numbers.into_iter().collect();
+    numbers.sort_unstable();
+    numbers.dedup();
+    numbers
+}
+
+impl Drop for Store {
+    fn drop(&mut self) {
+        // Wake the expiration task so it notices the store is gone and exits
+        self.background_task.notify_one();
+    }
 }
 
-async fn remove_expired_entries(data: Arc<Store>) {
+/// Approximates the memory used by a key and its data
+fn size_of(key: &str, value: &Value) -> usize {
+    key.len() + data_size(&value.data)
+}
+
+fn data_size(data: &Data) -> usize {
+    match data {
+        Data::String(string) => string.len(),
+        Data::Int(_) => size_of_val(&0i64),
+        Data::Json(json) => json.to_string().len(),
+        Data::Vector(vector) => size_of_val(vector.as_slice()),
+        Data::Custom { kind, data } => kind.len() + data.to_string().len(),
+    }
+}
+
+async fn remove_expired_entries(data: Weak<Store>, background_task: Arc<Notify>) {
     loop {
-        if let Some(instant) = data.remove_expired_values().await {
+        let Some(store) = data.upgrade() else {
+            debug!("table dropped, stopping expiration task");
+            return;
+        };
+        let next_expiration = store.remove_expired_values().await;
+        // Don't keep the store alive while waiting
+        drop(store);
+
+        if let Some(instant) = next_expiration {
             select! {
                 // Hope to switch this call to `sleep_until` as it seems cleaner.
                 // This depends on better time handling.
                 _ = tokio::time::sleep(instant) => {}
-                _ = data.background_task.notified() => {}
+                _ = background_task.notified() => {}
             }
         } else {
             // There are no keys expiring in the future. Wait until the task is
             // notified.
-            data.background_task.notified().await;
+            background_task.notified().await;
         }
     }
 }
@@ -158,7 +1407,7 @@ mod tests {
                 },
             )
             .await;
-        assert_eq!(database.store.data.read().await.len(), 2);
+        assert_eq!(database.store.len().await, 2);
 
         // Insert another value that expires first
         database
@@ -170,16 +1419,16 @@ mod tests {
                 },
             )
             .await;
-        assert_eq!(database.store.data.read().await.len(), 3);
+        assert_eq!(database.store.len().await, 3);
 
         // Advance time until expiration occurs
         tokio::time::sleep(StdDuration::from_secs(time_to_jump)).await;
-        assert_eq!(database.store.data.read().await.len(), 2);
+        assert_eq!(database.store.len().await, 2);
 
         // Advance time until expiration occurs
         tokio::time::sleep(StdDuration::from_secs(time_to_jump)).await;
 
-        assert_eq!(database.store.data.read().await.len(), 1);
+        assert_eq!(database.store.len().await, 1);
     }
 
     #[tokio::test]
@@ -191,11 +1440,11 @@ mod tests {
             OffsetDateTime::now_utc().checked_sub(Duration::new(expiration_time_jump, 0));
         let future_instant =
             OffsetDateTime::now_utc().checked_add(Duration::new(expiration_time_jump, 0));
-        let store = Store::new();
+        let store = Store::new(TableOptions::default());
 
         // Insert test data within block to drop write guard when done
         {
-            let mut data = store.data.write().await;
+            let mut data = store.write_all().await;
             data.insert(
                 "expired".to_string(),
                 Value {
@@ -227,7 +1476,7 @@ mod tests {
         }
 
         store.remove_expired_values().await;
-        let len = store.data.read().await.len();
+        let len = store.len().await;
         assert_eq!(len, 3);
 
         // Jump ahead to test expirations
@@ -236,7 +1485,351 @@ mod tests {
         let next_expiration = store.remove_expired_values().await;
         assert_eq!(next_expiration, None);
 
-        let len = store.data.read().await.len();
+        let len = store.len().await;
         assert_eq!(len, 1);
     }
+
+    // The `skip`th of the keys `key/0`, `key/1`... that belong to `shard`
+    fn key_in_shard(shard: usize, skip: usize) -> String {
+        (0..)
+            .map(|n| format!("key/{n}"))
+            .filter(|key| shard_of(key) == shard)
+            .nth(skip)
+            .unwrap()
+    }
+
+    fn int(n: i64) -> Value {
+        Value {
+            data: Data::Int(n),
+            expiry: None,
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_writes_across_shards() {
+        let table = Table::new();
+        let (first, second) = (key_in_shard(1, 0), key_in_shard(9, 0));
+        let mut changes = table.changes();
+
+        let mut transaction = table.lock([second.as_str(), first.as_str()]).await;
+        assert_eq!(transaction.writer.numbers, [1, 9]);
+        transaction.set(first.clone(), int(1));
+
+        // A reader of every shard waits for the whole write, so it never sees only half of it
+        let reader = tokio::spawn({
+            let table = table.clone();
+            let (first, second) = (first.clone(), second.clone());
+            async move {
+                let reader = table.store.read_all().await;
+                (
+                    reader.shards.get(&first).cloned(),
+                    reader.shards.get(&second).cloned(),
+                )
+            }
+        });
+        tokio::time::sleep(StdDuration::from_millis(50)).await;
+        assert!(!reader.is_finished());
+        transaction.set(second.clone(), int(2));
+        drop(transaction);
+
+        let (first_value, second_value) = reader.await.unwrap();
+        assert!(matches!(first_value.unwrap().data, Data::Int(1)));
+        assert!(matches!(second_value.unwrap().data, Data::Int(2)));
+        let (first_change, second_change) =
+            (changes.recv().await.unwrap(), changes.recv().await.unwrap());
+        assert_eq!((first_change.key, second_change.key), (first, second));
+        assert_eq!(first_change.version, second_change.version);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_lock_order() {
+        let table = Table::new();
+        let (first, second) = (key_in_shard(2, 0), key_in_shard(14, 0));
+        assert_eq!(table.store.write([14, 2, 14]).await.numbers, [2, 14]);
+
+        // Locking the same shards in opposite orders would deadlock if the locks weren't sorted first
+        let writers = [[first.clone(), second.clone()], [second, first]].map(|keys| {
+            let table = table.clone();
+            tokio::spawn(async move {
+                for n in 0..1000 {
+                    let mut transaction = table.lock(keys.iter().map(String::as_str)).await;
+                    for key in &keys {
+                        transaction.set(key.clone(), int(n));
+                    }
+                }
+            })
+        });
+        let writers = futures::future::join_all(writers);
+        for writer in tokio::time::timeout(StdDuration::from_secs(10), writers)
+            .await
+            .expect("writers deadlocked")
+        {
+            writer.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shard_expiration() {
+        let store = Store::new(TableOptions::default());
+        let mut changes = store.changes.subscribe();
+        let expired = Value {
+            data: Data::Int(0),
+            expiry: OffsetDateTime::now_utc().checked_sub(Duration::new(10, 0)),
+        };
+        let forever = key_in_shard(3, 1);
+        {
+            let mut writer = store.write_all().await;
+            for shard in 0..SHARDS {
+                writer.insert(key_in_shard(shard, 0), expired.clone());
+            }
+            writer.insert(forever.clone(), int(1));
+        }
+        for _ in 0..=SHARDS {
+            changes.recv().await.unwrap();
+        }
+
+        // Expiring through a writer of one shard leaves the others alone
+        store
+            .write([3])
+            .await
+            .retain(|_, value| value.expiry.is_none());
+        assert_eq!(store.len().await, SHARDS);
+        let change = changes.recv().await.unwrap();
+        assert_eq!(
+            (change.key, change.event),
+            (key_in_shard(3, 0), KeyEvent::Expire)
+        );
+        assert!(store.get(&key_in_shard(4, 0), None).await.is_some());
+
+        assert_eq!(store.remove_expired_values().await, None);
+        assert_eq!(store.len().await, 1);
+        assert!(store.get(&forever, None).await.is_some());
+        for _ in 1..SHARDS {
+            assert_eq!(changes.recv().await.unwrap().event, KeyEvent::Expire);
+        }
+        assert_eq!(
+            store.memory.load(Ordering::Relaxed),
+            size_of(&forever, &int(1))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_max_memory() {
+        let table = Table::with_options(TableOptions {
+            default_ttl: None,
+            max_memory: Some(10),
+            ordered: false,
+        });
+        let value = |string: &str| Value {
+            data: Data::String(string.to_string()),
+            expiry: None,
+        };
+
+        table.set("key".to_string(), value("1234")).await;
+        assert_eq!(table.store.memory.load(Ordering::Relaxed), 7);
+
+        // Replacing a value only counts the difference
+        assert!(table.fits([("key", &value("1234567"))]).await);
+        assert!(!table.fits([("key", &value("12345678"))]).await);
+        assert!(!table.fits([("other", &value("1"))]).await);
+
+        table.set("key".to_string(), value("1")).await;
+        assert_eq!(table.store.memory.load(Ordering::Relaxed), 4);
+    }
+
+    #[tokio::test]
+    async fn test_ordered_reads() {
+        let table = Table::with_options(TableOptions {
+            ordered: true,
+            ..TableOptions::default()
+        });
+        let keys = [
+            "tenant/1/users/1",
+            "tenant/1/users/2",
+            "tenant/12/users/1",
+            "tenant/2/users/1",
+        ];
+        table
+            .extend(keys.iter().map(|key| {
+                (
+                    key.to_string(),
+                    Value {
+                        data: Data::Int(1),
+                        expiry: None,
+                    },
+                )
+            }))
+            .await;
+        let keys_of = |entries: Option<Vec<(String, Value)>>| -> Vec<String> {
+            entries.unwrap().into_iter().map(|(key, _)| key).collect()
+        };
+
+        assert_eq!(
+            keys_of(table.prefix_scan("tenant/1/").await),
+            ["tenant/1/users/1", "tenant/1/users/2"]
+        );
+        assert_eq!(
+            keys_of(
+                table
+                    .range(Some("tenant/1/"), Some("tenant/2"), None, false)
+                    .await
+            ),
+            ["tenant/1/users/1", "tenant/1/users/2", "tenant/12/users/1"]
+        );
+        assert_eq!(
+            keys_of(table.range(None, None, Some(2), true).await),
+            ["tenant/2/users/1", "tenant/12/users/1"]
+        );
+        assert!(keys_of(table.range(Some("z"), Some("a"), None, false).await).is_empty());
+
+        assert!(Table::new().prefix_scan("tenant/").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_query_with_index() {
+        let table = Table::new();
+        table
+            .extend((0..10).map(|n| {
+                let value = Value {
+                    data: Data::Json(serde_json::json!({ "n": n })),
+                    expiry: None,
+                };
+                (format!("numbers/{n}"), value)
+            }))
+            .await;
+        let query = crate::query::parse("where .n >= 7 and .n != 8 order by .n desc").unwrap();
+        let without_index = table.query(&query).await;
+
+        table
+            .create_index(IndexDefinition {
+                name: "n".to_string(),
+                pattern: "*".to_string(),
+                pointer: "/n".to_string(),
+            })
+            .await;
+        let with_index = table.query(&query).await;
+
+        let keys = |entries: Vec<(String, Value)>| -> Vec<String> {
+            entries.into_iter().map(|(key, _)| key).collect()
+        };
+        assert_eq!(keys(without_index), ["numbers/9", "numbers/7"]);
+        assert_eq!(keys(with_index), ["numbers/9", "numbers/7"]);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_writers() {
+        let table = Table::new();
+        table
+            .create_index(IndexDefinition {
+                name: "writer".to_string(),
+                pattern: "*".to_string(),
+                pointer: "/writer".to_string(),
+            })
+            .await;
+        let writers = (0..8).map(|writer| {
+            let table = table.clone();
+            tokio::spawn(async move {
+                for n in 0..100 {
+                    let value = Value {
+                        data: Data::Json(serde_json::json!({ "writer": writer })),
+                        expiry: None,
+                    };
+                    if n % 2 == 0 {
+                        table.set(format!("{writer}/{n}"), value).await;
+                    } else {
+                        // Keys spread over every shard, so these lock several at once
+                        table
+                            .extend((0..4).map(|i| (format!("{writer}/{n}/{i}"), value.clone())))
+                            .await;
+                    }
+                }
+            })
+        });
+        for writer in futures::future::join_all(writers).await {
+            writer.unwrap();
+        }
+
+        assert_eq!(table.store.len().await, 8 * (50 + 50 * 4));
+        let found = table
+            .find_by("writer", &IndexQuery::Value(serde_json::json!(3)))
+            .await
+            .unwrap();
+        assert_eq!(found.len(), 50 + 50 * 4);
+        let memory: usize = table
+            .entries()
+            .await
+            .iter()
+            .map(|(key, value)| size_of(key, value))
+            .sum();
+        assert_eq!(table.store.memory.load(Ordering::Relaxed), memory);
+    }
+
+    #[tokio::test]
+    async fn test_snapshots() {
+        let table = Table::with_options(TableOptions {
+            ordered: true,
+            ..TableOptions::default()
+        });
+        let int = |n: i64| Value {
+            data: Data::Json(serde_json::json!(n)),
+            expiry: None,
+        };
+        let int_of = |value: Option<Value>| match value?.data {
+            Data::Json(json) => json.as_i64(),
+            _ => None,
+        };
+        let ints = |entries: Vec<(String, Value)>| -> Vec<(String, i64)> {
+            entries
+                .into_iter()
+                .map(|(key, value)| (key, int_of(Some(value)).unwrap()))
+                .collect()
+        };
+        table
+            .extend((0..4).map(|n| (format!("report/{n}"), int(n))))
+            .await;
+
+        let snapshot = table.snapshot();
+        table.set("report/0".to_string(), int(10)).await;
+        table.set("report/0".to_string(), int(20)).await;
+        table.remove("report/1").await;
+        table.set("report/4".to_string(), int(4)).await;
+
+        assert_eq!(int_of(snapshot.get("report/0").await), Some(0));
+        assert!(snapshot.get("report/4").await.is_none());
+        assert_eq!(int_of(table.get("report/0").await), Some(20));
+        let expected: Vec<(String, i64)> = (0..4).map(|n| (format!("report/{n}"), n)).collect();
+        assert_eq!(
+            ints(snapshot.prefix_scan("report/").await.unwrap()),
+            expected
+        );
+        assert_eq!(
+            ints(snapshot.range(None, None, Some(2), true).await.unwrap()),
+            [("report/3".to_string(), 3), ("report/2".to_string(), 2)]
+        );
+        let mut queried = ints(
+            snapshot
+                .query(&crate::query::parse("from \"report/*\"").unwrap())
+                .await,
+        );
+        queried.sort();
+        assert_eq!(queried, expected);
+        let mut copied = ints(snapshot.entries().await.into_iter().collect());
+        copied.sort();
+        assert_eq!(copied, expected);
+
+        // A second snapshot sees the writes made before it, and the first one still needs the old values
+        let later = table.snapshot();
+        table.set("report/2".to_string(), int(12)).await;
+        drop(snapshot);
+        table.store.remove_expired_values().await;
+        assert_eq!(int_of(later.get("report/0").await), Some(20));
+        assert_eq!(int_of(later.get("report/2").await), Some(2));
+        assert!(later.get("report/1").await.is_none());
+
+        drop(later);
+        table.store.remove_expired_values().await;
+        for shard in table.store.shards.iter() {
+            assert!(shard.read().await.history.is_empty());
+        }
+    }
 }
@@ -0,0 +1,157 @@
+use aether_common::db::{BroadcastMessage, KeyEvent, TriggerAction, TriggerDefinition};
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+use super::{
+    table::{Change, WeakTable},
+    Database, Error,
+};
+use crate::pattern::glob_match;
+
+// Increments run as a script so they are atomic and logged like any other write
+const INCREMENT: &str = r#"
+    let count = db.get(keys[0]) ?? 0;
+    if type_of(count) != "i64" {
+        throw `${keys[0]} is not an integer`;
+    }
+    db.set(keys[0], count + args[0]);
+"#;
+
+/// How many triggers can fire one after another from a single write, so triggers setting each other off stop
+const MAX_CHAIN: usize = 8;
+
+// Separates the triggers in the origin of a write made by a trigger
+const CHAIN_SEPARATOR: &str = " > ";
+
+/// Runs a table's triggers on every change to it, until the table is dropped
+pub async fn run(
+    database: Database,
+    table_name: String,
+    table: WeakTable,
+    mut changes: mpsc::UnboundedReceiver<Change>,
+) {
+    while let Some(change) = changes.recv().await {
+        let Some(triggers) = table.upgrade().map(|table| table.triggers()) else {
+            break;
+        };
+        let chain = chain(&change);
+        for trigger in triggers.iter().filter(|trigger| matches(trigger, &change)) {
+            // A trigger whose actions led to this change would otherwise fire itself forever
+            if chain.contains(&origin(trigger).as_str()) {
+                debug!(
+                    trigger = trigger.name,
+                    key = change.key,
+                    "Not firing a trigger for a change it led to"
+                );
+                continue;
+            }
+            if chain.len() >= MAX_CHAIN {
+                warn!(
+                    trigger = trigger.name,
+                    key = change.key,
+                    ?chain,
+                    "Not firing a trigger at the end of a long chain of triggers"
+                );
+                continue;
+            }
+            if let Err(err) = fire(&database, &table_name, trigger, &change, &chain).await {
+                warn!(
+                    ?err,
+                    trigger = trigger.name,
+                    key = change.key,
+                    "Trigger failed"
+                );
+            }
+        }
+    }
+    debug!(table = table_name, "table dropped, stopping trigger task");
+}
+
+fn matches(trigger: &TriggerDefinition, change: &Change) -> bool {
+    glob_match(&trigger.pattern, &change.key)
+        && (trigger.events.is_empty() || trigger.events.contains(&change.event))
+}
+
+async fn fire(
+    database: &Database,
+    table: &str,
+    trigger: &TriggerDefinition,
+    change: &Change,
+    chain: &[&str],
+) -> Result<(), Error> {
+    // Writes are tagged with every trigger that led to them
+    let client_id = chain
+        .iter()
+        .copied()
+        .chain([origin(trigger).as_str()])
+        .collect::<Vec<_>>()
+        .join(CHAIN_SEPARATOR);
+    match &trigger.action {
+        TriggerAction::Publish { channel, message } => {
+            let message = BroadcastMessage::new(
+                origin(trigger),
+                render(channel, table, change),
+                render(message, table, change),
+            );
+            database.broker.publish(message);
+        }
+        TriggerAction::Increment { key, by } => {
+            let key = render(key, table, change);
+            // Counting changes to the counter itself would never stop
+            if glob_match(&trigger.pattern, &key) {
+                warn!(
+                    trigger = trigger.name,
+                    key, "Not incrementing a key the trigger watches"
+                );
+                return Ok(());
+            }
+            let args = [serde_json::json!(by)];
+            database
+                .eval(table, INCREMENT, &[key], &args, &client_id)
+                .await?;
+        }
+        TriggerAction::Script { script } => {
+            let args = [serde_json::json!(event_name(change.event))];
+            database
+                .eval(
+                    table,
+                    script,
+                    std::slice::from_ref(&change.key),
+                    &args,
+                    &client_id,
+                )
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Who a trigger's actions run as, which tags the changes they make
+fn origin(trigger: &TriggerDefinition) -> String {
+    format!("trigger:{}", trigger.name)
+}
+
+// The triggers whose actions led to a change, oldest first, which is empty for changes made by clients
+fn chain(change: &Change) -> Vec<&str> {
+    match change.origin.as_deref() {
+        Some(origin) if origin.starts_with("trigger:") => origin.split(CHAIN_SEPARATOR).collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn render(template: &str, table: &str, change: &Change) -> String {
+    let value = serde_json::to_string(&change.value.data).expect("data always serializes");
+    template
+        .replace("{key}", &change.key)
+        .replace("{event}", event_name(change.event))
+        .replace("{table}", table)
+        .replace("{value}", &value)
+}
+
+fn event_name(event: KeyEvent) -> &'static str {
+    match event {
+        KeyEvent::Set => "set",
+        KeyEvent::Delete => "delete",
+        KeyEvent::Expire => "expire",
+    }
+}
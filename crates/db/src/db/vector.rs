@@ -0,0 +1,487 @@
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::{BinaryHeap, HashMap, HashSet},
+    hash::{DefaultHasher, Hash, Hasher},
+};
+
+use aether_common::db::{Data, Metric, Value, VectorIndexDefinition};
+
+use crate::pattern::glob_match;
+
+// HNSW parameters: neighbours kept per node on upper layers and on the bottom layer,
+// and how many candidates are tracked while inserting and searching
+const M: usize = 16;
+const M0: usize = 2 * M;
+const EF_CONSTRUCTION: usize = 100;
+const EF_SEARCH: usize = 64;
+const MAX_LEVEL: usize = 16;
+/// How many vectors are copied into the new graph with each write while a rebuild is in progress
+const REBUILD_STEP: usize = 4;
+
+/// An approximate nearest neighbour index over vector values, using a hierarchical navigable small world graph
+///
+/// Only vector values under keys matching the definition's pattern and with its number of dimensions are indexed.
+/// Removed vectors stay in the graph to keep it connected and are skipped by searches. Once more than half of
+/// the graph is removed a new one is built from the rest, a few vectors with each write so no write waits long.
+pub struct VectorIndex {
+    definition: VectorIndexDefinition,
+    graph: Graph,
+    rebuild: Option<Rebuild>,
+}
+
+struct Graph {
+    metric: Metric,
+    nodes: Vec<Node>,
+    // Every vector still in the graph, by key
+    keys: HashMap<String, usize>,
+    entry_point: Option<usize>,
+}
+
+struct Node {
+    key: String,
+    vector: Vec<f32>,
+    // Neighbours on each layer, from the bottom layer up to the node's level
+    neighbours: Vec<Vec<usize>>,
+    removed: bool,
+}
+
+/// A graph being built from the vectors still in the searched one, which replaces it once every node is copied
+///
+/// Vectors added meanwhile are appended to the searched graph so they are copied too,
+/// and vectors removed meanwhile are removed from both.
+struct Rebuild {
+    graph: Graph,
+    // The next node of the searched graph to copy
+    next: usize,
+}
+
+/// A key found by a vector search, before its value is looked up
+pub struct Neighbour {
+    pub key: String,
+    pub score: f32,
+}
+
+impl VectorIndex {
+    pub fn new(definition: VectorIndexDefinition) -> Self {
+        Self {
+            graph: Graph::new(definition.metric),
+            definition,
+            rebuild: None,
+        }
+    }
+
+    pub fn definition(&self) -> &VectorIndexDefinition {
+        &self.definition
+    }
+
+    pub fn insert(&mut self, key: &str, value: &Value) {
+        self.forget(key);
+        if glob_match(&self.definition.pattern, key) {
+            if let Data::Vector(vector) = &value.data {
+                if let Some(vector) = self.prepare(vector) {
+                    self.graph.add(key.to_string(), vector);
+                }
+            }
+        }
+        self.continue_rebuild();
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        self.forget(key);
+        self.continue_rebuild();
+    }
+
+    /// Returns up to `k` of the keys nearest to `vector` that `filter` accepts, nearest first
+    ///
+    /// Nothing is found if `vector` has the wrong number of dimensions.
+    pub fn search(
+        &self,
+        vector: &[f32],
+        k: usize,
+        filter: impl Fn(&str) -> bool,
+    ) -> Vec<Neighbour> {
+        let graph = &self.graph;
+        let (Some(query), Some(entry_point)) = (self.prepare(vector), graph.entry_point) else {
+            return Vec::new();
+        };
+        let entry_point = graph.descend(&query, entry_point, 0);
+        // Removed nodes are walked through but never kept as results, so they don't crowd out the `k` wanted
+        graph
+            .search_layer(&query, entry_point, k.max(EF_SEARCH), 0, |node| {
+                !node.removed && filter(&node.key)
+            })
+            .into_iter()
+            .take(k)
+            .map(|(distance, id)| Neighbour {
+                key: graph.nodes[id].key.clone(),
+                score: self.score(distance),
+            })
+            .collect()
+    }
+
+    // Checks the dimensions of a vector and normalizes it if the metric is cosine,
+    // returning `None` if it can't be indexed or searched for
+    fn prepare(&self, vector: &[f32]) -> Option<Vec<f32>> {
+        if vector.len() != self.definition.dimensions {
+            return None;
+        }
+        match self.definition.metric {
+            Metric::Cosine => {
+                let norm = dot(vector, vector).sqrt();
+                (norm > 0.0).then(|| vector.iter().map(|x| x / norm).collect())
+            }
+            Metric::Dot | Metric::L2 => Some(vector.to_vec()),
+        }
+    }
+
+    // Removes a key from the searched graph and from the one being built
+    fn forget(&mut self, key: &str) {
+        self.graph.remove(key);
+        if let Some(rebuild) = &mut self.rebuild {
+            rebuild.graph.remove(key);
+        }
+    }
+
+    // Copies a few more nodes into the graph being built, starting a rebuild if too much of the graph is removed
+    fn continue_rebuild(&mut self) {
+        if self.rebuild.is_none() && self.graph.removed() > self.graph.keys.len() {
+            self.rebuild = Some(Rebuild {
+                graph: Graph::new(self.definition.metric),
+                next: 0,
+            });
+        }
+        let Some(rebuild) = &mut self.rebuild else {
+            return;
+        };
+        let mut copied = 0;
+        while copied < REBUILD_STEP && rebuild.next < self.graph.nodes.len() {
+            let node = &self.graph.nodes[rebuild.next];
+            rebuild.next += 1;
+            if !node.removed {
+                rebuild.graph.add(node.key.clone(), node.vector.clone());
+                copied += 1;
+            }
+        }
+        if rebuild.next == self.graph.nodes.len() {
+            self.graph = self.rebuild.take().unwrap().graph;
+        }
+    }
+
+    fn score(&self, distance: Distance) -> f32 {
+        match self.definition.metric {
+            Metric::Cosine => 1.0 - distance.0,
+            Metric::Dot => -distance.0,
+            Metric::L2 => distance.0.sqrt(),
+        }
+    }
+}
+
+impl Graph {
+    fn new(metric: Metric) -> Self {
+        Self {
+            metric,
+            nodes: Vec::new(),
+            keys: HashMap::new(),
+            entry_point: None,
+        }
+    }
+
+    // How many nodes have been removed but are still in the graph
+    fn removed(&self) -> usize {
+        self.nodes.len() - self.keys.len()
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(id) = self.keys.remove(key) {
+            self.nodes[id].removed = true;
+        }
+    }
+
+    fn add(&mut self, key: String, vector: Vec<f32>) {
+        let id = self.nodes.len();
+        let level = random_level(&key, id);
+        self.keys.insert(key.clone(), id);
+        self.nodes.push(Node {
+            key,
+            vector,
+            neighbours: vec![Vec::new(); level + 1],
+            removed: false,
+        });
+        let Some(entry_point) = self.entry_point else {
+            self.entry_point = Some(id);
+            return;
+        };
+
+        let vector = self.nodes[id].vector.clone();
+        let top = self.nodes[entry_point].neighbours.len() - 1;
+        let mut entry_point = self.descend(&vector, entry_point, level + 1);
+        for layer in (0..=level.min(top)).rev() {
+            let candidates =
+                self.search_layer(&vector, entry_point, EF_CONSTRUCTION, layer, |_| true);
+            entry_point = candidates[0].1;
+            let limit = if layer == 0 { M0 } else { M };
+            let neighbours: Vec<usize> = candidates
+                .into_iter()
+                .take(limit)
+                .map(|(_, neighbour)| neighbour)
+                .collect();
+            for &neighbour in &neighbours {
+                self.nodes[neighbour].neighbours[layer].push(id);
+                if self.nodes[neighbour].neighbours[layer].len() > limit {
+                    self.prune(neighbour, layer, limit);
+                }
+            }
+            self.nodes[id].neighbours[layer] = neighbours;
+        }
+        if level > top {
+            self.entry_point = Some(id);
+        }
+    }
+
+    // Keeps only a node's `limit` nearest neighbours on a layer
+    fn prune(&mut self, id: usize, layer: usize, limit: usize) {
+        let node = &self.nodes[id];
+        let mut neighbours: Vec<(Distance, usize)> = node.neighbours[layer]
+            .iter()
+            .map(|&neighbour| (self.distance(&node.vector, neighbour), neighbour))
+            .collect();
+        neighbours.sort();
+        self.nodes[id].neighbours[layer] = neighbours
+            .into_iter()
+            .take(limit)
+            .map(|(_, neighbour)| neighbour)
+            .collect();
+    }
+
+    // Greedily walks down the layers above `bottom` to the node nearest to `vector`
+    fn descend(&self, vector: &[f32], mut entry_point: usize, bottom: usize) -> usize {
+        let top = self.nodes[entry_point].neighbours.len() - 1;
+        for layer in (bottom..=top).rev() {
+            entry_point = self.search_layer(vector, entry_point, 1, layer, |_| true)[0].1;
+        }
+        entry_point
+    }
+
+    // Finds up to `ef` of the nodes nearest to `vector` on one layer that `accept` accepts, nearest first
+    //
+    // Nodes that aren't accepted are still walked through, so a strict filter makes the search more exhaustive.
+    fn search_layer(
+        &self,
+        vector: &[f32],
+        entry_point: usize,
+        ef: usize,
+        layer: usize,
+        accept: impl Fn(&Node) -> bool,
+    ) -> Vec<(Distance, usize)> {
+        let distance = self.distance(vector, entry_point);
+        let mut visited = HashSet::from([entry_point]);
+        let mut candidates = BinaryHeap::from([Reverse((distance, entry_point))]);
+        let mut results = BinaryHeap::new();
+        if accept(&self.nodes[entry_point]) {
+            results.push((distance, entry_point));
+        }
+
+        while let Some(Reverse((distance, id))) = candidates.pop() {
+            if results.len() >= ef
+                && results
+                    .peek()
+                    .is_some_and(|&(furthest, _)| distance > furthest)
+            {
+                break;
+            }
+            let Some(neighbours) = self.nodes[id].neighbours.get(layer) else {
+                continue;
+            };
+            for &neighbour in neighbours {
+                if !visited.insert(neighbour) {
+                    continue;
+                }
+                let distance = self.distance(vector, neighbour);
+                if results.len() < ef
+                    || results
+                        .peek()
+                        .is_some_and(|&(furthest, _)| distance < furthest)
+                {
+                    candidates.push(Reverse((distance, neighbour)));
+                    if accept(&self.nodes[neighbour]) {
+                        results.push((distance, neighbour));
+                        if results.len() > ef {
+                            results.pop();
+                        }
+                    }
+                }
+            }
+        }
+        results.into_sorted_vec()
+    }
+
+    fn distance(&self, vector: &[f32], id: usize) -> Distance {
+        let other = &self.nodes[id].vector;
+        Distance(match self.metric {
+            // Vectors are normalized, so their dot product is the cosine similarity
+            Metric::Cosine => 1.0 - dot(vector, other),
+            Metric::Dot => -dot(vector, other),
+            Metric::L2 => vector
+                .iter()
+                .zip(other)
+                .map(|(a, b)| (a - b) * (a - b))
+                .sum(),
+        })
+    }
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(a, b)| a * b).sum()
+}
+
+// Picks the top layer of a new node, with each layer holding about 1/M of the nodes of the one below
+//
+// The level comes from a hash of the key and position so rebuilding the same data gives the same graph.
+fn random_level(key: &str, id: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    (key, id).hash(&mut hasher);
+    // A uniform number in (0, 1]
+    let uniform = ((hasher.finish() >> 11) + 1) as f64 / (1u64 << 53) as f64;
+    let level = -uniform.ln() / (M as f64).ln();
+    (level as usize).min(MAX_LEVEL)
+}
+
+/// A distance with a total order, smaller is nearer
+#[derive(Clone, Copy, Debug)]
+struct Distance(f32);
+
+impl PartialEq for Distance {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Distance {}
+
+impl PartialOrd for Distance {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Distance {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(vector: Vec<f32>) -> Value {
+        Value {
+            data: Data::Vector(vector),
+            expiry: None,
+        }
+    }
+
+    fn index(metric: Metric) -> VectorIndex {
+        VectorIndex::new(VectorIndexDefinition {
+            name: "embeddings".to_string(),
+            pattern: "docs/*".to_string(),
+            dimensions: 2,
+            metric,
+        })
+    }
+
+    fn keys(neighbours: Vec<Neighbour>) -> Vec<String> {
+        neighbours
+            .into_iter()
+            .map(|neighbour| neighbour.key)
+            .collect()
+    }
+
+    #[test]
+    fn test_metrics() {
+        let mut cosine = index(Metric::Cosine);
+        let mut dot = index(Metric::Dot);
+        let mut l2 = index(Metric::L2);
+        for index in [&mut cosine, &mut dot, &mut l2] {
+            index.insert("docs/near", &value(vec![1.0, 0.1]));
+            index.insert("docs/long", &value(vec![10.0, 10.0]));
+            index.insert("docs/opposite", &value(vec![-1.0, 0.0]));
+            index.insert("docs/short", &value(vec![1.0]));
+            index.insert("other/1", &value(vec![1.0, 0.0]));
+        }
+
+        let query = [1.0, 0.0];
+        let found = cosine.search(&query, 3, |_| true);
+        assert!((found[0].score - 0.995).abs() < 0.001);
+        assert_eq!(keys(found), ["docs/near", "docs/long", "docs/opposite"]);
+        let found = dot.search(&query, 3, |_| true);
+        assert_eq!(found[0].score, 10.0);
+        assert_eq!(keys(found), ["docs/long", "docs/near", "docs/opposite"]);
+        let found = l2.search(&query, 3, |_| true);
+        assert!((found[0].score - 0.1).abs() < 0.001);
+        assert_eq!(keys(found), ["docs/near", "docs/opposite", "docs/long"]);
+
+        assert!(cosine.search(&[1.0], 3, |_| true).is_empty());
+        assert!(cosine.search(&[0.0, 0.0], 3, |_| true).is_empty());
+    }
+
+    #[test]
+    fn test_search_many() {
+        let mut index = index(Metric::L2);
+        // Points on a grid, so the nearest neighbours of any point are known
+        for x in 0..40 {
+            for y in 0..40 {
+                index.insert(&format!("docs/{x}/{y}"), &value(vec![x as f32, y as f32]));
+            }
+        }
+        assert_eq!(
+            keys(index.search(&[10.2, 20.1], 4, |_| true)),
+            ["docs/10/20", "docs/11/20", "docs/10/21", "docs/10/19"]
+        );
+
+        let found = keys(index.search(&[10.2, 20.1], 2, |key| key.starts_with("docs/30/")));
+        assert_eq!(found, ["docs/30/20", "docs/30/21"]);
+
+        // Removing most of the graph rebuilds it without losing the rest
+        for x in 0..40 {
+            for y in 0..30 {
+                index.remove(&format!("docs/{x}/{y}"));
+            }
+        }
+        assert!(index.rebuild.is_none());
+        assert_eq!(index.graph.keys.len(), 400);
+        assert!(index.graph.nodes.len() < 800);
+        let found = keys(index.search(&[10.2, 20.1], 1, |_| true));
+        assert_eq!(found, ["docs/10/30"]);
+    }
+
+    #[test]
+    fn test_rebuild_while_writing() {
+        let mut index = index(Metric::L2);
+        for x in 0..100 {
+            index.insert(&format!("docs/{x}"), &value(vec![x as f32, 0.0]));
+        }
+        for x in 0..51 {
+            index.remove(&format!("docs/{x}"));
+        }
+        assert!(index.rebuild.is_some());
+        // Every remaining vector is still found, however many removed ones are in the way
+        assert_eq!(index.search(&[0.0, 0.0], 49, |_| true).len(), 49);
+
+        // Writes during the rebuild reach the new graph, whether or not the key was copied yet
+        index.remove("docs/51");
+        index.remove("docs/99");
+        index.insert("docs/98", &value(vec![-1.0, 0.0]));
+        index.insert("docs/200", &value(vec![-2.0, 0.0]));
+        while index.rebuild.is_some() {
+            index.remove("other");
+        }
+        // Only the key removed after it was copied is left behind
+        assert_eq!(index.graph.removed(), 1);
+        let found = keys(index.search(&[0.0, 0.0], 100, |_| true));
+        assert_eq!(found.len(), 48);
+        assert_eq!(found[..3], ["docs/98", "docs/200", "docs/52"]);
+        assert!(!found.contains(&"docs/51".to_string()));
+        assert!(!found.contains(&"docs/99".to_string()));
+    }
+}
@@ -1,12 +1,30 @@
+use aether_common::db::TableOptions;
+use anyhow::Context;
 use axum::{routing::get, Router};
+use clap::Parser;
+use config::{Cli, CliCommand, Config};
 use db::Database;
+use persistence::{export, rdb};
 use serde::Deserialize;
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter},
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Arc,
+};
 use tower_http::trace::{DefaultMakeSpan, TraceLayer};
-use tracing::debug;
+use tracing::{debug, info};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
+mod broker;
+mod config;
 mod db;
+mod pattern;
+mod persistence;
+mod plugin;
+mod query;
+mod script;
 mod ws;
 
 // TODO: Make this configurable
@@ -24,7 +42,9 @@ struct ClientID {
 }
 
 #[tokio::main]
-async fn main() {
+async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+
     // set up tracing subsciber
     // this let's us get our logs
     tracing_subscriber::registry()
@@ -33,13 +53,34 @@ async fn main() {
                 format!("{}=debug,tower_http=debug", env!("CARGO_CRATE_NAME")).into()
             }),
         )
-        .with(tracing_subscriber::fmt::layer())
+        // log to stderr so exports can be written to stdout
+        .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
         .init();
 
+    match cli.command.unwrap_or(CliCommand::Serve) {
+        CliCommand::Serve => serve(cli.config).await,
+        CliCommand::Export {
+            output,
+            patterns,
+            table,
+        } => export(cli.config, output, patterns, table).await?,
+        CliCommand::Import {
+            input,
+            patterns,
+            table,
+        } => import(cli.config, input, patterns, table).await?,
+        CliCommand::ImportRdb { input, table } => import_rdb(cli.config, input, table).await?,
+    }
+    Ok(())
+}
+
+async fn serve(config: Config) {
     // set up our app state
     // this contains our runtime data and configs
     let app_state = Arc::new(AppState {
-        data_store: Database::default(),
+        data_store: Database::open(&config)
+            .await
+            .expect("could not open database"),
     });
 
     // set up routing and middleware
@@ -49,7 +90,7 @@ async fn main() {
         .with_state(app_state);
 
     // run the server
-    let listener = tokio::net::TcpListener::bind("127.0.0.1:3000")
+    let listener = tokio::net::TcpListener::bind(config.bind)
         .await
         .expect("could not bind to address");
     debug!(
@@ -63,3 +104,84 @@ async fn main() {
     .await
     .expect("could not start server");
 }
+
+async fn export(
+    config: Config,
+    output: Option<PathBuf>,
+    patterns: Vec<String>,
+    table: String,
+) -> anyhow::Result<()> {
+    let database = Database::open(&config)
+        .await
+        .context("could not open database")?;
+    let entries = database.table(&table).await?.entries().await;
+    let written = match output {
+        Some(path) => {
+            let file = File::create(&path).with_context(|| format!("could not create {path:?}"))?;
+            export::write_records(BufWriter::new(file), entries, &patterns)?
+        }
+        None => export::write_records(std::io::stdout().lock(), entries, &patterns)?,
+    };
+    info!(written, "Exported keys");
+    Ok(())
+}
+
+async fn import(
+    config: Config,
+    input: Option<PathBuf>,
+    patterns: Vec<String>,
+    table: String,
+) -> anyhow::Result<()> {
+    let database = Database::open(&config)
+        .await
+        .context("could not open database")?;
+    let records = match input {
+        Some(path) => {
+            let file = File::open(&path).with_context(|| format!("could not open {path:?}"))?;
+            export::read_records(BufReader::new(file), &patterns)?
+        }
+        None => export::read_records(std::io::stdin().lock(), &patterns)?,
+    };
+    let count = records.len();
+    database
+        .set_many(
+            &table,
+            records
+                .into_iter()
+                .map(|record| (record.key, record.value))
+                .collect(),
+        )
+        .await?;
+
+    // Without the append-only file, the import is only persisted by a snapshot
+    if !config.appendonly {
+        database.save().await?;
+    }
+    info!(count, "Imported keys");
+    Ok(())
+}
+
+async fn import_rdb(config: Config, input: PathBuf, table: String) -> anyhow::Result<()> {
+    let database = Database::open(&config)
+        .await
+        .context("could not open database")?;
+    let file = File::open(&input).with_context(|| format!("could not open {input:?}"))?;
+    let mut import = rdb::read(BufReader::new(file))?;
+    for (db, entries) in import.entries {
+        let name = rdb::table_name(&table, db);
+        if database.table(&name).await.is_err() {
+            database
+                .create_table(name.clone(), TableOptions::default())
+                .await?;
+        }
+        database.set_many(&name, entries).await?;
+        import.report.tables.insert(db, name);
+    }
+
+    // Without the append-only file, the import is only persisted by a snapshot
+    if !config.appendonly {
+        database.save().await?;
+    }
+    println!("{}", serde_json::to_string_pretty(&import.report)?);
+    Ok(())
+}
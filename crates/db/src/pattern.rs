@@ -0,0 +1,204 @@
+use aether_common::db::ChannelPattern;
+
+/// Matches `text` against a glob `pattern`
+///
+/// Supports `*`, `?`, `[abc]`, `[a-z]`, `[^abc]` and `\` escapes, like Redis' `KEYS`.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    let mut p = 0;
+    let mut t = 0;
+    // Where to resume after the most recent `*` if the rest fails to match
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p + 1, t));
+            p += 1;
+            continue;
+        }
+        if p < pattern.len() {
+            if let Some(next) = match_one(&pattern, p, text[t]) {
+                p = next;
+                t += 1;
+                continue;
+            }
+        }
+        // Let the last `*` swallow one more character and try again
+        match star {
+            Some((star_p, star_t)) => {
+                p = star_p;
+                t = star_t + 1;
+                star = Some((star_p, star_t + 1));
+            }
+            None => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Returns `true` if `text` matches any of `patterns`, or if there are no patterns at all
+pub fn matches_any(patterns: &[String], text: &str) -> bool {
+    patterns.is_empty() || patterns.iter().any(|pattern| glob_match(pattern, text))
+}
+
+/// Matches a broadcast channel's name against a channel pattern
+pub fn channel_match(pattern: &ChannelPattern, channel: &str) -> bool {
+    match pattern {
+        ChannelPattern::Glob(pattern) => glob_match(pattern, channel),
+        ChannelPattern::Nats(pattern) => segments_match(pattern, channel, '.', "*", ">", false),
+        ChannelPattern::Mqtt(pattern) => segments_match(pattern, channel, '/', "+", "#", true),
+    }
+}
+
+// Matches `separator` separated segments, where `one` matches any one segment and a final `rest` matches the
+// remaining segments, of which there must be at least one unless `rest_matches_none`
+fn segments_match(
+    pattern: &str,
+    text: &str,
+    separator: char,
+    one: &str,
+    rest: &str,
+    rest_matches_none: bool,
+) -> bool {
+    let mut pattern = pattern.split(separator).peekable();
+    let mut text = text.split(separator);
+    loop {
+        match (pattern.next(), text.next()) {
+            (Some(segment), next) if segment == rest && pattern.peek().is_none() => {
+                return next.is_some() || rest_matches_none;
+            }
+            (Some(segment), Some(next)) if segment == one || segment == next => continue,
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// Escapes `text` so it only matches itself as a glob pattern
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+// Matches a single non-`*` token, returning the index of the next token
+fn match_one(pattern: &[char], p: usize, c: char) -> Option<usize> {
+    match pattern[p] {
+        '?' => Some(p + 1),
+        '\\' if p + 1 < pattern.len() => (pattern[p + 1] == c).then_some(p + 2),
+        '[' => match_class(pattern, p, c),
+        literal => (literal == c).then_some(p + 1),
+    }
+}
+
+fn match_class(pattern: &[char], start: usize, c: char) -> Option<usize> {
+    let mut p = start + 1;
+    let negate = matches!(pattern.get(p), Some('^' | '!'));
+    if negate {
+        p += 1;
+    }
+
+    let mut matched = false;
+    loop {
+        match pattern.get(p) {
+            // An unterminated class is just a literal `[`
+            None => return (c == '[').then_some(start + 1),
+            Some(']') => break,
+            Some('\\') if p + 1 < pattern.len() => {
+                matched |= pattern[p + 1] == c;
+                p += 2;
+            }
+            Some(&low)
+                if pattern.get(p + 1) == Some(&'-')
+                    && p + 2 < pattern.len()
+                    && pattern[p + 2] != ']' =>
+            {
+                let high = pattern[p + 2];
+                matched |= low.min(high) <= c && c <= low.max(high);
+                p += 3;
+            }
+            Some(&literal) => {
+                matched |= literal == c;
+                p += 1;
+            }
+        }
+    }
+
+    (matched != negate).then_some(p + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("orders/*", "orders/1"));
+        assert!(!glob_match("orders/*", "users/1"));
+        assert!(glob_match("h?llo", "hello"));
+        assert!(!glob_match("h?llo", "hllo"));
+        assert!(glob_match("h*llo", "heeeello"));
+        assert!(glob_match("*a*b*", "xxaxxbxx"));
+        assert!(!glob_match("*a*b", "xxaxxbxx"));
+        assert!(glob_match("h[ae]llo", "hallo"));
+        assert!(!glob_match("h[ae]llo", "hillo"));
+        assert!(glob_match("h[^e]llo", "hallo"));
+        assert!(!glob_match("h[^e]llo", "hello"));
+        assert!(glob_match("h[a-c]llo", "hbllo"));
+        assert!(!glob_match("h[a-c]llo", "hdllo"));
+        assert!(glob_match("h\\*llo", "h*llo"));
+        assert!(!glob_match("h\\*llo", "hello"));
+        assert!(glob_match("[unterminated", "[unterminated"));
+    }
+
+    #[test]
+    fn test_channel_match() {
+        let glob = ChannelPattern::Glob("orders.*".to_string());
+        assert!(channel_match(&glob, "orders.eu.1"));
+        assert!(!channel_match(&glob, "returns.1"));
+        let glob = ChannelPattern::Glob("chat.room.?".to_string());
+        assert!(channel_match(&glob, "chat.room.1"));
+        assert!(!channel_match(&glob, "chat.room.10"));
+
+        let nats = ChannelPattern::Nats("orders.*.created".to_string());
+        assert!(channel_match(&nats, "orders.eu.created"));
+        assert!(!channel_match(&nats, "orders.eu.1.created"));
+        let nats = ChannelPattern::Nats("orders.>".to_string());
+        assert!(channel_match(&nats, "orders.eu.1"));
+        assert!(!channel_match(&nats, "orders"));
+
+        let mqtt = ChannelPattern::Mqtt("sensors/+/temperature".to_string());
+        assert!(channel_match(&mqtt, "sensors/kitchen/temperature"));
+        assert!(!channel_match(&mqtt, "sensors/kitchen/humidity"));
+        let mqtt = ChannelPattern::Mqtt("sensors/#".to_string());
+        assert!(channel_match(&mqtt, "sensors/kitchen/temperature"));
+        assert!(channel_match(&mqtt, "sensors"));
+        assert!(!channel_match(&mqtt, "actuators/kitchen"));
+    }
+
+    #[test]
+    fn test_escape() {
+        let pattern = format!("{}*", escape("a*[b]?\\"));
+        assert!(glob_match(&pattern, "a*[b]?\\/1"));
+        assert!(!glob_match(&pattern, "aa[b]?\\/1"));
+        assert!(!glob_match(&pattern, "a*b?\\/1"));
+    }
+
+    #[test]
+    fn test_matches_any() {
+        assert!(matches_any(&[], "key"));
+        let patterns = vec!["a*".to_string(), "b*".to_string()];
+        assert!(matches_any(&patterns, "apple"));
+        assert!(matches_any(&patterns, "banana"));
+        assert!(!matches_any(&patterns, "cherry"));
+    }
+}
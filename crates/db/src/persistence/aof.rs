@@ -0,0 +1,493 @@
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::{Arc, Weak},
+    time::Duration,
+};
+
+use tokio::{
+    fs::{File, OpenOptions},
+    io::AsyncWriteExt,
+    sync::{mpsc, Mutex, MutexGuard},
+};
+use tracing::{debug, error, info, warn};
+
+use super::{Entry, Error};
+use crate::config::FsyncPolicy;
+
+/// The append-only file
+///
+/// Every mutation is appended as a single line of JSON before it is applied. Mutations that are applied together
+/// are appended as one line holding an array of them, so a crash can't leave only some of them in the log.
+#[derive(Clone)]
+pub struct Aof {
+    writer: Arc<Mutex<Writer>>,
+    policy: FsyncPolicy,
+    rewrite_policy: RewritePolicy,
+}
+
+/// When the append-only file is automatically rewritten
+#[derive(Clone, Copy, Debug)]
+pub struct RewritePolicy {
+    /// How much the file must have grown since the last rewrite, as a percentage of its size then
+    ///
+    /// Zero disables automatic rewrites.
+    pub percentage: u64,
+
+    /// The smallest file size worth rewriting
+    pub min_size: u64,
+}
+
+struct Writer {
+    path: PathBuf,
+    file: File,
+    len: u64,
+    // The length after the last rewrite, or at startup
+    base_len: u64,
+    // Set when there are writes that have not been fsynced yet
+    dirty: bool,
+    // Records appended while a rewrite is running, copied to the new file before it is swapped in
+    rewrite_buffer: Option<Vec<u8>>,
+}
+
+/// Keeps the append-only file locked
+///
+/// Hold this until the logged mutation has been applied, so mutations are applied in log order.
+pub struct AppendGuard<'a> {
+    aof: &'a Aof,
+    writer: MutexGuard<'a, Writer>,
+    needs_rewrite: bool,
+}
+
+impl AppendGuard<'_> {
+    /// Whether the file has grown enough that it should be rewritten
+    pub fn needs_rewrite(&self) -> bool {
+        self.needs_rewrite
+    }
+
+    /// Appends `entries` as a single record, which is replayed whole or not at all
+    pub async fn append(&mut self, entries: &[Entry]) -> Result<(), Error> {
+        let mut line = Vec::new();
+        match entries {
+            [] => return Ok(()),
+            [entry] => serde_json::to_writer(&mut line, entry)?,
+            entries => serde_json::to_writer(&mut line, entries)?,
+        }
+        line.push(b'\n');
+
+        let writer = &mut *self.writer;
+        if let Err(err) = writer.write(&line).await {
+            // Drop the partial record so later appends don't land after garbage
+            let len = writer.len;
+            if let Err(err) = writer.file.set_len(len).await {
+                error!(?err, path = ?writer.path, "Could not truncate partial record");
+            }
+            return Err(err.into());
+        }
+
+        match self.aof.policy {
+            FsyncPolicy::Always => writer.file.sync_data().await?,
+            FsyncPolicy::Everysec => writer.dirty = true,
+            FsyncPolicy::No => {}
+        }
+
+        if let Some(buffer) = &mut writer.rewrite_buffer {
+            buffer.extend_from_slice(&line);
+        }
+
+        self.needs_rewrite = self.aof.needs_rewrite(writer);
+        Ok(())
+    }
+}
+
+impl Aof {
+    /// Opens the append-only file at `path`, returning it with the entries to replay
+    ///
+    /// A torn record at the end of the file is discarded and truncated away.
+    pub async fn open(
+        path: &Path,
+        policy: FsyncPolicy,
+        rewrite_policy: RewritePolicy,
+    ) -> Result<(Aof, Vec<Entry>), Error> {
+        let (entries, len) = load(path).await?;
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        let writer = Arc::new(Mutex::new(Writer {
+            path: path.to_path_buf(),
+            file,
+            len,
+            base_len: len,
+            dirty: false,
+            rewrite_buffer: None,
+        }));
+
+        if policy == FsyncPolicy::Everysec {
+            tokio::spawn(fsync_every_second(Arc::downgrade(&writer)));
+        }
+
+        let aof = Aof {
+            writer,
+            policy,
+            rewrite_policy,
+        };
+        Ok((aof, entries))
+    }
+
+    /// Appends every entry in a single write, fsyncing them if the policy requires it
+    pub async fn append_all(&self, entries: &[Entry]) -> Result<AppendGuard<'_>, Error> {
+        let mut guard = self.lock().await;
+        guard.append(entries).await?;
+        Ok(guard)
+    }
+
+    /// Locks the log without appending anything yet, for mutations that decide what to log once they hold it
+    pub async fn lock(&self) -> AppendGuard<'_> {
+        AppendGuard {
+            aof: self,
+            writer: self.writer.lock().await,
+            needs_rewrite: false,
+        }
+    }
+
+    /// Starts a rewrite, buffering every append from now on until it finishes
+    ///
+    /// The log stays locked until the returned guard is dropped,
+    /// so the state captured while holding it matches the log exactly.
+    pub async fn begin_rewrite(&self) -> Result<AppendGuard<'_>, Error> {
+        let mut writer = self.writer.lock().await;
+        if writer.rewrite_buffer.is_some() {
+            return Err(Error::RewriteInProgress);
+        }
+        writer.rewrite_buffer = Some(Vec::new());
+        Ok(AppendGuard {
+            aof: self,
+            writer,
+            needs_rewrite: false,
+        })
+    }
+
+    /// Writes `entries` as they arrive and everything buffered since the rewrite began to a new file,
+    /// then swaps it in for the current one
+    pub async fn finish_rewrite(&self, entries: mpsc::Receiver<Vec<Entry>>) -> Result<(), Error> {
+        let path = self.writer.lock().await.path.clone();
+        let mut temporary_path = path.as_os_str().to_owned();
+        temporary_path.push(".rewrite");
+        let temporary_path = PathBuf::from(temporary_path);
+
+        let result = self.rewrite(&path, &temporary_path, entries).await;
+        if result.is_err() {
+            self.writer.lock().await.rewrite_buffer = None;
+            let _ = tokio::fs::remove_file(&temporary_path).await;
+        }
+        result
+    }
+
+    async fn rewrite(
+        &self,
+        path: &Path,
+        temporary_path: &Path,
+        mut entries: mpsc::Receiver<Vec<Entry>>,
+    ) -> Result<(), Error> {
+        // The bulk of the file is written without holding the lock so appends can continue
+        let mut file = File::create(temporary_path).await?;
+        let mut buffer = Vec::new();
+        while let Some(batch) = entries.recv().await {
+            for entry in batch {
+                serde_json::to_writer(&mut buffer, &entry)?;
+                buffer.push(b'\n');
+            }
+            if buffer.len() >= REWRITE_CHUNK_SIZE {
+                file.write_all(&buffer).await?;
+                buffer.clear();
+            }
+        }
+        file.write_all(&buffer).await?;
+
+        // Append everything logged in the meantime and swap the files while appends are blocked
+        let mut writer = self.writer.lock().await;
+        let buffered = writer.rewrite_buffer.take().unwrap_or_default();
+        file.write_all(&buffered).await?;
+        file.flush().await?;
+        file.sync_all().await?;
+        let len = file.metadata().await?.len();
+        drop(file);
+
+        tokio::fs::rename(temporary_path, path).await?;
+        writer.file = OpenOptions::new().append(true).open(path).await?;
+        writer.len = len;
+        writer.base_len = len;
+        writer.dirty = false;
+        info!(?path, len, "Rewrote append-only file");
+        Ok(())
+    }
+
+    fn needs_rewrite(&self, writer: &Writer) -> bool {
+        let RewritePolicy {
+            percentage,
+            min_size,
+        } = self.rewrite_policy;
+        percentage > 0
+            && writer.rewrite_buffer.is_none()
+            && writer.len >= min_size
+            && writer.len >= writer.base_len + writer.base_len * percentage / 100
+    }
+}
+
+// How much of a rewrite is buffered in memory before it is written out
+const REWRITE_CHUNK_SIZE: usize = 64 * 1024;
+
+impl Writer {
+    async fn write(&mut self, line: &[u8]) -> std::io::Result<()> {
+        self.file.write_all(line).await?;
+        // Flushing a tokio file waits for the write to reach the operating system
+        self.file.flush().await?;
+        self.len += line.len() as u64;
+        Ok(())
+    }
+}
+
+async fn load(path: &Path) -> Result<(Vec<Entry>, u64), Error> {
+    let bytes = match tokio::fs::read(path).await {
+        Ok(bytes) => bytes,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok((Vec::new(), 0)),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while let Some(end) = bytes[offset..].iter().position(|byte| *byte == b'\n') {
+        let line = &bytes[offset..offset + end];
+        let record = match line.first() {
+            Some(b'[') => serde_json::from_slice::<Vec<Entry>>(line),
+            _ => serde_json::from_slice(line).map(|entry| vec![entry]),
+        };
+        match record {
+            Ok(record) => entries.extend(record),
+            // The final record may have been torn by a crash mid-write
+            Err(_) if offset + end + 1 == bytes.len() => break,
+            Err(source) => return Err(Error::CorruptLog { offset, source }),
+        }
+        offset += end + 1;
+    }
+
+    if offset < bytes.len() {
+        warn!(
+            ?path,
+            discarded = bytes.len() - offset,
+            "Truncating torn record at the end of the append-only file"
+        );
+        let file = OpenOptions::new().write(true).open(path).await?;
+        file.set_len(offset as u64).await?;
+        file.sync_all().await?;
+    }
+
+    debug!(?path, entries = entries.len(), "Loaded append-only file");
+    Ok((entries, offset as u64))
+}
+
+async fn fsync_every_second(writer: Weak<Mutex<Writer>>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+
+        // Stop once the log has been dropped
+        let Some(writer) = writer.upgrade() else {
+            return;
+        };
+        // Appends are already flushed to the operating system, so the lock is only held to clone the file
+        // and appends don't wait for the fsync
+        let (file, path) = {
+            let mut writer = writer.lock().await;
+            if !writer.dirty {
+                continue;
+            }
+            match writer.file.try_clone().await {
+                Ok(file) => {
+                    writer.dirty = false;
+                    (file, writer.path.clone())
+                }
+                Err(err) => {
+                    error!(?err, path = ?writer.path, "Could not fsync append-only file");
+                    continue;
+                }
+            }
+        };
+        if let Err(err) = file.sync_data().await {
+            error!(?err, ?path, "Could not fsync append-only file");
+            // Try again next time
+            writer.lock().await.dirty = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use aether_common::db::{Data, Value, DEFAULT_TABLE};
+
+    const NEVER_REWRITE: RewritePolicy = RewritePolicy {
+        percentage: 0,
+        min_size: 0,
+    };
+
+    fn entry(key: &str, value: i64) -> Entry {
+        Entry::Set {
+            table: DEFAULT_TABLE.to_string(),
+            key: key.to_string(),
+            value: Value {
+                data: Data::Int(value),
+                expiry: None,
+            },
+        }
+    }
+
+    fn key(entry: &Entry) -> &str {
+        match entry {
+            Entry::Set { key, .. } => key,
+            other => panic!("expected a set, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_aof_replay() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("appendonly.aof");
+
+        let (aof, entries) = Aof::open(&path, FsyncPolicy::Always, NEVER_REWRITE)
+            .await
+            .unwrap();
+        assert!(entries.is_empty());
+        drop(aof.append_all(&[entry("first", 1)]).await.unwrap());
+        drop(aof.append_all(&[entry("second", 2)]).await.unwrap());
+        drop(aof);
+
+        let (_, entries) = Aof::open(&path, FsyncPolicy::No, NEVER_REWRITE)
+            .await
+            .unwrap();
+        let keys: Vec<&str> = entries.iter().map(key).collect();
+        assert_eq!(keys, vec!["first", "second"]);
+    }
+
+    #[tokio::test]
+    async fn test_aof_truncated_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("appendonly.aof");
+
+        let (aof, _) = Aof::open(&path, FsyncPolicy::Always, NEVER_REWRITE)
+            .await
+            .unwrap();
+        drop(aof.append_all(&[entry("first", 1)]).await.unwrap());
+        drop(aof);
+
+        // Simulate a crash halfway through writing a record
+        let mut file = OpenOptions::new().append(true).open(&path).await.unwrap();
+        file.write_all(br#"{"set":{"key":"sec"#).await.unwrap();
+        drop(file);
+
+        let (aof, entries) = Aof::open(&path, FsyncPolicy::Always, NEVER_REWRITE)
+            .await
+            .unwrap();
+        assert_eq!(entries.len(), 1);
+        drop(aof.append_all(&[entry("third", 3)]).await.unwrap());
+        drop(aof);
+
+        let (_, entries) = Aof::open(&path, FsyncPolicy::Always, NEVER_REWRITE)
+            .await
+            .unwrap();
+        let keys: Vec<&str> = entries.iter().map(key).collect();
+        assert_eq!(keys, vec!["first", "third"]);
+    }
+
+    #[tokio::test]
+    async fn test_aof_truncated_batch() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("appendonly.aof");
+
+        let (aof, _) = Aof::open(&path, FsyncPolicy::Always, NEVER_REWRITE)
+            .await
+            .unwrap();
+        drop(aof.append_all(&[entry("first", 1)]).await.unwrap());
+        drop(
+            aof.append_all(&[entry("second", 2), entry("third", 3)])
+                .await
+                .unwrap(),
+        );
+        drop(aof);
+        let (_, entries) = Aof::open(&path, FsyncPolicy::Always, NEVER_REWRITE)
+            .await
+            .unwrap();
+        let keys: Vec<&str> = entries.iter().map(key).collect();
+        assert_eq!(keys, vec!["first", "second", "third"]);
+
+        // Simulate a crash after the first entry of the batch was written but before the second
+        let len = std::fs::metadata(&path).unwrap().len();
+        let file = OpenOptions::new().write(true).open(&path).await.unwrap();
+        file.set_len(len - 20).await.unwrap();
+        drop(file);
+
+        let (_, entries) = Aof::open(&path, FsyncPolicy::Always, NEVER_REWRITE)
+            .await
+            .unwrap();
+        let keys: Vec<&str> = entries.iter().map(key).collect();
+        assert_eq!(keys, vec!["first"]);
+    }
+
+    #[tokio::test]
+    async fn test_aof_rewrite() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("appendonly.aof");
+        let rewrite_policy = RewritePolicy {
+            percentage: 100,
+            min_size: 0,
+        };
+
+        let (aof, _) = Aof::open(&path, FsyncPolicy::Always, rewrite_policy)
+            .await
+            .unwrap();
+        drop(aof.append_all(&[entry("first", 1)]).await.unwrap());
+        // The file has grown from nothing, so it is due for a rewrite
+        assert!(aof
+            .append_all(&[entry("first", 2)])
+            .await
+            .unwrap()
+            .needs_rewrite());
+
+        let guard = aof.begin_rewrite().await.unwrap();
+        drop(guard);
+        assert!(matches!(
+            aof.begin_rewrite().await,
+            Err(Error::RewriteInProgress)
+        ));
+        // Appends made during the rewrite must survive it
+        let guard = aof.append_all(&[entry("second", 3)]).await.unwrap();
+        assert!(!guard.needs_rewrite());
+        drop(guard);
+        let (sender, entries) = mpsc::channel(1);
+        sender.try_send(vec![entry("first", 2)]).unwrap();
+        drop(sender);
+        aof.finish_rewrite(entries).await.unwrap();
+
+        drop(aof.append_all(&[entry("third", 4)]).await.unwrap());
+        drop(aof);
+
+        let (_, entries) = Aof::open(&path, FsyncPolicy::Always, NEVER_REWRITE)
+            .await
+            .unwrap();
+        let keys: Vec<&str> = entries.iter().map(key).collect();
+        assert_eq!(keys, vec!["first", "second", "third"]);
+    }
+
+    #[tokio::test]
+    async fn test_aof_corrupt_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("appendonly.aof");
+        tokio::fs::write(&path, b"garbage\n{}\n").await.unwrap();
+
+        let result = Aof::open(&path, FsyncPolicy::Always, NEVER_REWRITE).await;
+        assert!(matches!(result, Err(Error::CorruptLog { offset: 0, .. })));
+    }
+}
@@ -0,0 +1,121 @@
+use std::io::{BufRead, Write};
+
+use aether_common::db::Value;
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use super::Error;
+use crate::pattern::matches_any;
+
+/// A single key as written by an export, one per line
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct Record {
+    pub key: String,
+    pub value: Value,
+}
+
+/// Writes every entry with a key matching one of `patterns` as JSON Lines, returning how many were written
+///
+/// Every entry is written when there are no patterns.
+pub fn write_records(
+    mut writer: impl Write,
+    entries: impl IntoIterator<Item = (String, Value)>,
+    patterns: &[String],
+) -> Result<usize, Error> {
+    let mut written = 0;
+    for (key, value) in entries {
+        if !matches_any(patterns, &key) {
+            continue;
+        }
+        serde_json::to_writer(&mut writer, &Record { key, value })?;
+        writer.write_all(b"\n")?;
+        written += 1;
+    }
+    writer.flush()?;
+    Ok(written)
+}
+
+/// Reads JSON Lines records with a key matching one of `patterns`
+///
+/// Blank lines and records that have already expired are skipped.
+pub fn read_records(reader: impl BufRead, patterns: &[String]) -> Result<Vec<Record>, Error> {
+    let now = OffsetDateTime::now_utc();
+    let mut records = Vec::new();
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: Record =
+            serde_json::from_str(&line).map_err(|source| Error::InvalidRecord {
+                line: index + 1,
+                source,
+            })?;
+        if matches_any(patterns, &record.key)
+            && record.value.expiry.is_none_or(|expiry| now <= expiry)
+        {
+            records.push(record);
+        }
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use aether_common::db::Data;
+    use time::Duration;
+
+    #[test]
+    fn test_export_round_trip() {
+        let expiry = OffsetDateTime::now_utc().checked_add(Duration::hours(1));
+        let entries = vec![
+            (
+                "users/1".to_string(),
+                Value {
+                    data: Data::Json(serde_json::json!({ "name": "Ada" })),
+                    expiry,
+                },
+            ),
+            (
+                "orders/1".to_string(),
+                Value {
+                    data: Data::Int(1),
+                    expiry: None,
+                },
+            ),
+        ];
+
+        let mut output = Vec::new();
+        let written = write_records(&mut output, entries, &["users/*".to_string()]).unwrap();
+        assert_eq!(written, 1);
+
+        let records = read_records(output.as_slice(), &[]).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].key, "users/1");
+        assert_eq!(records[0].value.expiry, expiry);
+    }
+
+    #[test]
+    fn test_import_skips_expired() {
+        let input = format!(
+            "{}\n\n{}\n",
+            r#"{"key":"live","value":{"data":{"int":1},"expiry":null}}"#,
+            r#"{"key":"dead","value":{"data":{"int":1},"expiry":[2000,1,0,0,0,0,0,0,0]}}"#
+        );
+        let records = read_records(input.as_bytes(), &[]).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].key, "live");
+    }
+
+    #[test]
+    fn test_import_invalid_record() {
+        let input = "{\"key\":\"live\",\"value\":{\"data\":{\"int\":1}}}\nnot json\n";
+        assert!(matches!(
+            read_records(input.as_bytes(), &[]),
+            Err(Error::InvalidRecord { line: 2, .. })
+        ));
+    }
+}
@@ -0,0 +1,135 @@
+use aether_common::db::{
+    ChannelPattern, IndexDefinition, SearchIndexDefinition, TableOptions, TriggerDefinition, Value,
+    VectorIndexDefinition,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::db::SubscriptionOptions;
+
+pub mod aof;
+pub mod export;
+pub mod rdb;
+pub mod snapshot;
+
+/// A single mutation to the database
+///
+/// These are written to the append-only file and replayed on startup.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Entry {
+    Set {
+        table: String,
+        key: String,
+        value: Value,
+    },
+    Delete {
+        table: String,
+        key: String,
+    },
+    CreateTable {
+        name: String,
+        options: TableOptions,
+    },
+    DropTable {
+        name: String,
+    },
+    CreateIndex {
+        table: String,
+        index: IndexDefinition,
+    },
+    DropIndex {
+        table: String,
+        name: String,
+    },
+    CreateSearchIndex {
+        table: String,
+        index: SearchIndexDefinition,
+    },
+    DropSearchIndex {
+        table: String,
+        name: String,
+    },
+    CreateVectorIndex {
+        table: String,
+        index: VectorIndexDefinition,
+    },
+    DropVectorIndex {
+        table: String,
+        name: String,
+    },
+    CreateTrigger {
+        table: String,
+        trigger: TriggerDefinition,
+    },
+    DropTrigger {
+        table: String,
+        name: String,
+    },
+    Subscribe {
+        client_id: String,
+        channel: String,
+        options: SubscriptionOptions,
+    },
+    Unsubscribe {
+        client_id: String,
+        channel: String,
+    },
+    PSubscribe {
+        client_id: String,
+        pattern: ChannelPattern,
+        options: SubscriptionOptions,
+    },
+    PUnsubscribe {
+        client_id: String,
+        pattern: ChannelPattern,
+    },
+    /// Removes a client's pattern subscriptions as well as its channel subscriptions
+    ClearSubscriptions {
+        client_id: String,
+    },
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("persistence io error")]
+    Io(#[from] std::io::Error),
+
+    #[error("could not serialize entry")]
+    Serialize(#[from] serde_json::Error),
+
+    #[error("persistence task failed")]
+    Task(#[from] tokio::task::JoinError),
+
+    #[error("an append-only file rewrite is already in progress")]
+    RewriteInProgress,
+
+    #[error("a save is already in progress")]
+    SaveInProgress,
+
+    #[error("snapshot is corrupt: {0}")]
+    CorruptSnapshot(&'static str),
+
+    #[error("snapshot version {0} is not supported")]
+    UnsupportedSnapshotVersion(u16),
+
+    #[error("invalid record on line {line}")]
+    InvalidRecord {
+        line: usize,
+        source: serde_json::Error,
+    },
+
+    #[error("rdb file is corrupt: {0}")]
+    CorruptRdb(&'static str),
+
+    #[error("rdb version {0} is not supported")]
+    UnsupportedRdbVersion(u32),
+
+    #[error("rdb value type {0} is not supported")]
+    UnsupportedRdbType(u8),
+
+    #[error("append-only file is corrupt at byte {offset}")]
+    CorruptLog {
+        offset: usize,
+        source: serde_json::Error,
+    },
+}
@@ -0,0 +1,1014 @@
+use std::{
+    collections::BTreeMap,
+    io::{ErrorKind, Read},
+};
+
+use aether_common::db::{Data, Value};
+use serde::Serialize;
+use time::OffsetDateTime;
+use tracing::debug;
+
+use super::Error;
+
+// The newest RDB version this reader understands (Redis 7.4)
+const MAX_VERSION: u32 = 12;
+
+const OPCODE_SLOT_INFO: u8 = 0xF4;
+const OPCODE_FUNCTION2: u8 = 0xF5;
+const OPCODE_FREQ: u8 = 0xF7;
+const OPCODE_IDLE: u8 = 0xF8;
+const OPCODE_MODULE_AUX: u8 = 0xF9;
+const OPCODE_AUX: u8 = 0xFA;
+const OPCODE_RESIZEDB: u8 = 0xFB;
+const OPCODE_EXPIRETIME_MS: u8 = 0xFC;
+const OPCODE_EXPIRETIME: u8 = 0xFD;
+const OPCODE_SELECTDB: u8 = 0xFE;
+const OPCODE_EOF: u8 = 0xFF;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_ZSET: u8 = 3;
+const TYPE_HASH: u8 = 4;
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_MODULE_2: u8 = 7;
+const TYPE_HASH_ZIPMAP: u8 = 9;
+const TYPE_LIST_ZIPLIST: u8 = 10;
+const TYPE_SET_INTSET: u8 = 11;
+const TYPE_ZSET_ZIPLIST: u8 = 12;
+const TYPE_HASH_ZIPLIST: u8 = 13;
+const TYPE_LIST_QUICKLIST: u8 = 14;
+const TYPE_STREAM_LISTPACKS: u8 = 15;
+const TYPE_HASH_LISTPACK: u8 = 16;
+const TYPE_ZSET_LISTPACK: u8 = 17;
+const TYPE_LIST_QUICKLIST_2: u8 = 18;
+const TYPE_STREAM_LISTPACKS_2: u8 = 19;
+const TYPE_SET_LISTPACK: u8 = 20;
+const TYPE_STREAM_LISTPACKS_3: u8 = 21;
+
+const ENCODING_INT8: u8 = 0;
+const ENCODING_INT16: u8 = 1;
+const ENCODING_INT32: u8 = 2;
+const ENCODING_LZF: u8 = 3;
+
+const QUICKLIST_NODE_PLAIN: u64 = 1;
+
+/// Everything read from a Redis RDB file
+pub struct RdbImport {
+    /// The entries of each Redis database, by its number
+    pub entries: BTreeMap<u64, Vec<(String, Value)>>,
+    pub report: RdbReport,
+}
+
+/// What happened to the keys in a Redis RDB file
+#[derive(Debug, Default, Serialize)]
+pub struct RdbReport {
+    pub version: u32,
+    pub imported: usize,
+    pub expired: usize,
+    pub skipped: Vec<SkippedKey>,
+
+    /// The table each Redis database's keys are imported into, by the database's number
+    pub tables: BTreeMap<u64, String>,
+}
+
+/// A key that could not be imported
+#[derive(Debug, Serialize)]
+pub struct SkippedKey {
+    pub db: u64,
+    /// The key, with any invalid UTF-8 replaced
+    pub key: String,
+    pub kind: &'static str,
+    pub reason: String,
+}
+
+type Pairs = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// A value as Redis stores it, before it is mapped onto `Data`
+#[derive(Debug)]
+enum RdbValue {
+    String(Vec<u8>),
+    List(Vec<Vec<u8>>),
+    Set(Vec<Vec<u8>>),
+    SortedSet(Vec<(Vec<u8>, f64)>),
+    Hash(Pairs),
+    /// A type that was read past but has no equivalent
+    Unsupported(&'static str),
+}
+
+/// The table a Redis database is imported into, which is `table` for database 0 and `table-N` for database N
+///
+/// Each database gets its own table, so a key in several of them isn't overwritten.
+pub fn table_name(table: &str, db: u64) -> String {
+    match db {
+        0 => table.to_string(),
+        db => format!("{table}-{db}"),
+    }
+}
+
+/// Reads a Redis RDB file, mapping every key onto `Data`
+///
+/// Strings that are integers become `Data::Int`, other strings become `Data::String`,
+/// and lists, sets, sorted sets and hashes become `Data::Json`.
+/// Keys that can't be mapped are listed in the report instead.
+pub fn read(reader: impl Read) -> Result<RdbImport, Error> {
+    let mut reader = Reader::new(reader);
+    let magic = reader.read_array::<9>()?;
+    if &magic[..5] != b"REDIS" {
+        return Err(Error::CorruptRdb("bad magic"));
+    }
+    let version = std::str::from_utf8(&magic[5..])
+        .ok()
+        .and_then(|version| version.parse::<u32>().ok())
+        .ok_or(Error::CorruptRdb("bad version"))?;
+    if version > MAX_VERSION {
+        return Err(Error::UnsupportedRdbVersion(version));
+    }
+
+    let now = OffsetDateTime::now_utc();
+    let mut report = RdbReport {
+        version,
+        ..RdbReport::default()
+    };
+    let mut entries: BTreeMap<u64, Vec<(String, Value)>> = BTreeMap::new();
+    let mut db = 0;
+    let mut expiry_ms: Option<i64> = None;
+    loop {
+        match reader.read_u8()? {
+            OPCODE_AUX => {
+                reader.read_string()?;
+                reader.read_string()?;
+            }
+            OPCODE_RESIZEDB => {
+                reader.read_len()?;
+                reader.read_len()?;
+            }
+            OPCODE_EXPIRETIME_MS => expiry_ms = Some(i64::from_le_bytes(reader.read_array()?)),
+            OPCODE_EXPIRETIME => {
+                expiry_ms = Some(u32::from_le_bytes(reader.read_array()?) as i64 * 1000)
+            }
+            OPCODE_SELECTDB => db = reader.read_len()?,
+            OPCODE_IDLE => {
+                reader.read_len()?;
+            }
+            OPCODE_FREQ => {
+                reader.read_u8()?;
+            }
+            OPCODE_FUNCTION2 => {
+                reader.read_string()?;
+            }
+            OPCODE_SLOT_INFO => {
+                for _ in 0..3 {
+                    reader.read_len()?;
+                }
+            }
+            OPCODE_MODULE_AUX => {
+                // Module id, when opcode and when, followed by the module's own fields
+                for _ in 0..3 {
+                    reader.read_len()?;
+                }
+                reader.skip_module_fields()?;
+            }
+            OPCODE_EOF => break,
+            value_type => {
+                let key = reader.read_string()?;
+                let value = reader.read_value(value_type)?;
+                let expiry = expiry_ms.take().map(|millis| {
+                    OffsetDateTime::from_unix_timestamp_nanos(millis as i128 * 1_000_000)
+                        .unwrap_or(OffsetDateTime::UNIX_EPOCH)
+                });
+                if expiry.is_some_and(|expiry| expiry < now) {
+                    report.expired += 1;
+                    continue;
+                }
+
+                let kind = value.kind();
+                let data = String::from_utf8(key.clone())
+                    .map_err(|_| "key is not valid UTF-8".to_string())
+                    .and_then(|key| Ok((key, value.into_data()?)));
+                match data {
+                    Ok((key, data)) => {
+                        entries
+                            .entry(db)
+                            .or_default()
+                            .push((key, Value { data, expiry }));
+                        report.imported += 1;
+                    }
+                    Err(reason) => report.skipped.push(SkippedKey {
+                        db,
+                        key: String::from_utf8_lossy(&key).into_owned(),
+                        kind,
+                        reason,
+                    }),
+                }
+            }
+        }
+    }
+
+    // Files from before version 5 have no checksum, and a zero checksum means it was disabled
+    if version >= 5 {
+        let expected = reader.crc;
+        let checksum = u64::from_le_bytes(reader.read_array()?);
+        if checksum != 0 && checksum != expected {
+            return Err(Error::CorruptRdb("checksum mismatch"));
+        }
+    }
+
+    debug!(
+        imported = report.imported,
+        expired = report.expired,
+        skipped = report.skipped.len(),
+        "Read RDB file"
+    );
+    Ok(RdbImport { entries, report })
+}
+
+impl RdbValue {
+    fn kind(&self) -> &'static str {
+        match self {
+            RdbValue::String(_) => "string",
+            RdbValue::List(_) => "list",
+            RdbValue::Set(_) => "set",
+            RdbValue::SortedSet(_) => "zset",
+            RdbValue::Hash(_) => "hash",
+            RdbValue::Unsupported(kind) => kind,
+        }
+    }
+
+    fn into_data(self) -> Result<Data, String> {
+        let data = match self {
+            RdbValue::String(bytes) => {
+                let string = utf8(bytes)?;
+                match string.parse::<i64>() {
+                    // Only map integers that survive the round trip, so "007" stays a string
+                    Ok(int) if int.to_string() == string => Data::Int(int),
+                    _ => Data::String(string),
+                }
+            }
+            RdbValue::List(items) | RdbValue::Set(items) => Data::Json(
+                items
+                    .into_iter()
+                    .map(|item| utf8(item).map(serde_json::Value::String))
+                    .collect::<Result<_, _>>()?,
+            ),
+            RdbValue::SortedSet(items) => Data::Json(
+                items
+                    .into_iter()
+                    .map(|(member, score)| {
+                        Ok(serde_json::json!({ "member": utf8(member)?, "score": score }))
+                    })
+                    .collect::<Result<_, String>>()?,
+            ),
+            RdbValue::Hash(fields) => Data::Json(serde_json::Value::Object(
+                fields
+                    .into_iter()
+                    .map(|(field, value)| {
+                        Ok((utf8(field)?, serde_json::Value::String(utf8(value)?)))
+                    })
+                    .collect::<Result<_, String>>()?,
+            )),
+            RdbValue::Unsupported(kind) => return Err(format!("{kind} has no equivalent")),
+        };
+        Ok(data)
+    }
+}
+
+fn utf8(bytes: Vec<u8>) -> Result<String, String> {
+    String::from_utf8(bytes).map_err(|_| "value is not valid UTF-8".to_string())
+}
+
+enum Length {
+    Length(u64),
+    Encoded(u8),
+}
+
+/// Reads RDB fields while keeping a running checksum
+struct Reader<R> {
+    reader: R,
+    crc: u64,
+}
+
+impl<R: Read> Reader<R> {
+    fn new(reader: R) -> Self {
+        Self { reader, crc: 0 }
+    }
+
+    fn read_exact(&mut self, buffer: &mut [u8]) -> Result<(), Error> {
+        self.reader
+            .read_exact(buffer)
+            .map_err(|err| match err.kind() {
+                ErrorKind::UnexpectedEof => Error::CorruptRdb("unexpected end of file"),
+                _ => err.into(),
+            })?;
+        self.crc = crc64(self.crc, buffer);
+        Ok(())
+    }
+
+    fn read_array<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        let mut buffer = [0; N];
+        self.read_exact(&mut buffer)?;
+        Ok(buffer)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, Error> {
+        Ok(self.read_array::<1>()?[0])
+    }
+
+    fn read_bytes(&mut self, length: u64) -> Result<Vec<u8>, Error> {
+        let mut buffer = Vec::new();
+        // Read through `take` so a corrupt length can't make us allocate everything up front
+        (&mut self.reader).take(length).read_to_end(&mut buffer)?;
+        if buffer.len() as u64 != length {
+            return Err(Error::CorruptRdb("unexpected end of file"));
+        }
+        self.crc = crc64(self.crc, &buffer);
+        Ok(buffer)
+    }
+
+    fn read_length(&mut self) -> Result<Length, Error> {
+        let first = self.read_u8()?;
+        let length = match first >> 6 {
+            0 => Length::Length((first & 0x3F) as u64),
+            1 => Length::Length((((first & 0x3F) as u64) << 8) | self.read_u8()? as u64),
+            2 => match first {
+                0x80 => Length::Length(u32::from_be_bytes(self.read_array()?) as u64),
+                0x81 => Length::Length(u64::from_be_bytes(self.read_array()?)),
+                _ => return Err(Error::CorruptRdb("bad length encoding")),
+            },
+            _ => Length::Encoded(first & 0x3F),
+        };
+        Ok(length)
+    }
+
+    fn read_len(&mut self) -> Result<u64, Error> {
+        match self.read_length()? {
+            Length::Length(length) => Ok(length),
+            Length::Encoded(_) => Err(Error::CorruptRdb("expected a length")),
+        }
+    }
+
+    fn read_string(&mut self) -> Result<Vec<u8>, Error> {
+        match self.read_length()? {
+            Length::Length(length) => self.read_bytes(length),
+            Length::Encoded(ENCODING_INT8) => Ok((self.read_u8()? as i8).to_string().into_bytes()),
+            Length::Encoded(ENCODING_INT16) => Ok(i16::from_le_bytes(self.read_array()?)
+                .to_string()
+                .into_bytes()),
+            Length::Encoded(ENCODING_INT32) => Ok(i32::from_le_bytes(self.read_array()?)
+                .to_string()
+                .into_bytes()),
+            Length::Encoded(ENCODING_LZF) => {
+                let compressed_length = self.read_len()?;
+                let length = self.read_len()?;
+                let compressed = self.read_bytes(compressed_length)?;
+                lzf_decompress(&compressed, length as usize)
+            }
+            Length::Encoded(_) => Err(Error::CorruptRdb("unknown string encoding")),
+        }
+    }
+
+    // Scores in the original sorted set encoding are stored as text
+    fn read_double_string(&mut self) -> Result<f64, Error> {
+        match self.read_u8()? {
+            253 => Ok(f64::NAN),
+            254 => Ok(f64::INFINITY),
+            255 => Ok(f64::NEG_INFINITY),
+            length => parse_score(&self.read_bytes(length as u64)?),
+        }
+    }
+
+    fn read_strings(&mut self) -> Result<Vec<Vec<u8>>, Error> {
+        let length = self.read_len()?;
+        (0..length).map(|_| self.read_string()).collect()
+    }
+
+    fn read_value(&mut self, value_type: u8) -> Result<RdbValue, Error> {
+        let value = match value_type {
+            TYPE_STRING => RdbValue::String(self.read_string()?),
+            TYPE_LIST => RdbValue::List(self.read_strings()?),
+            TYPE_SET => RdbValue::Set(self.read_strings()?),
+            TYPE_ZSET | TYPE_ZSET_2 => {
+                let length = self.read_len()?;
+                let mut items = Vec::new();
+                for _ in 0..length {
+                    let member = self.read_string()?;
+                    let score = match value_type {
+                        TYPE_ZSET => self.read_double_string()?,
+                        _ => f64::from_le_bytes(self.read_array()?),
+                    };
+                    items.push((member, score));
+                }
+                RdbValue::SortedSet(items)
+            }
+            TYPE_HASH => {
+                let length = self.read_len()?;
+                let mut fields = Vec::new();
+                for _ in 0..length {
+                    fields.push((self.read_string()?, self.read_string()?));
+                }
+                RdbValue::Hash(fields)
+            }
+            TYPE_HASH_ZIPMAP => RdbValue::Hash(pairs(zipmap_entries(&self.read_string()?)?)?),
+            TYPE_LIST_ZIPLIST => RdbValue::List(ziplist_entries(&self.read_string()?)?),
+            TYPE_SET_INTSET => RdbValue::Set(intset_entries(&self.read_string()?)?),
+            TYPE_SET_LISTPACK => RdbValue::Set(listpack_entries(&self.read_string()?)?),
+            TYPE_ZSET_ZIPLIST => {
+                RdbValue::SortedSet(scores(ziplist_entries(&self.read_string()?)?)?)
+            }
+            TYPE_ZSET_LISTPACK => {
+                RdbValue::SortedSet(scores(listpack_entries(&self.read_string()?)?)?)
+            }
+            TYPE_HASH_ZIPLIST => RdbValue::Hash(pairs(ziplist_entries(&self.read_string()?)?)?),
+            TYPE_HASH_LISTPACK => RdbValue::Hash(pairs(listpack_entries(&self.read_string()?)?)?),
+            TYPE_LIST_QUICKLIST => {
+                let mut items = Vec::new();
+                for ziplist in self.read_strings()? {
+                    items.extend(ziplist_entries(&ziplist)?);
+                }
+                RdbValue::List(items)
+            }
+            TYPE_LIST_QUICKLIST_2 => {
+                let length = self.read_len()?;
+                let mut items = Vec::new();
+                for _ in 0..length {
+                    let container = self.read_len()?;
+                    let node = self.read_string()?;
+                    if container == QUICKLIST_NODE_PLAIN {
+                        items.push(node);
+                    } else {
+                        items.extend(listpack_entries(&node)?);
+                    }
+                }
+                RdbValue::List(items)
+            }
+            TYPE_STREAM_LISTPACKS | TYPE_STREAM_LISTPACKS_2 | TYPE_STREAM_LISTPACKS_3 => {
+                self.skip_stream(value_type)?;
+                RdbValue::Unsupported("stream")
+            }
+            TYPE_MODULE_2 => {
+                self.read_len()?;
+                self.skip_module_fields()?;
+                RdbValue::Unsupported("module")
+            }
+            // Anything else (including version 1 modules) can't be skipped without understanding it
+            _ => return Err(Error::UnsupportedRdbType(value_type)),
+        };
+        Ok(value)
+    }
+
+    fn skip_stream(&mut self, value_type: u8) -> Result<(), Error> {
+        // Listpacks keyed by their master entry id
+        let listpacks = self.read_len()?;
+        for _ in 0..listpacks {
+            self.read_string()?;
+            self.read_string()?;
+        }
+        // Length, then the last id
+        for _ in 0..3 {
+            self.read_len()?;
+        }
+        if value_type >= TYPE_STREAM_LISTPACKS_2 {
+            // First id, max deleted id and entries added
+            for _ in 0..5 {
+                self.read_len()?;
+            }
+        }
+
+        let groups = self.read_len()?;
+        for _ in 0..groups {
+            self.read_string()?;
+            // Last delivered id
+            self.read_len()?;
+            self.read_len()?;
+            if value_type >= TYPE_STREAM_LISTPACKS_2 {
+                // Entries read
+                self.read_len()?;
+            }
+
+            let pending = self.read_len()?;
+            for _ in 0..pending {
+                // Raw id, delivery time and delivery count
+                self.read_array::<16>()?;
+                self.read_array::<8>()?;
+                self.read_len()?;
+            }
+
+            let consumers = self.read_len()?;
+            for _ in 0..consumers {
+                self.read_string()?;
+                // Seen time, and active time from version 3
+                self.read_array::<8>()?;
+                if value_type >= TYPE_STREAM_LISTPACKS_3 {
+                    self.read_array::<8>()?;
+                }
+                let pending = self.read_len()?;
+                for _ in 0..pending {
+                    self.read_array::<16>()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn skip_module_fields(&mut self) -> Result<(), Error> {
+        loop {
+            match self.read_len()? {
+                // EOF
+                0 => return Ok(()),
+                // Signed and unsigned integers
+                1 | 2 => {
+                    self.read_len()?;
+                }
+                // Float
+                3 => {
+                    self.read_array::<4>()?;
+                }
+                // Double
+                4 => {
+                    self.read_array::<8>()?;
+                }
+                // String
+                5 => {
+                    self.read_string()?;
+                }
+                _ => return Err(Error::CorruptRdb("unknown module opcode")),
+            }
+        }
+    }
+}
+
+fn parse_score(bytes: &[u8]) -> Result<f64, Error> {
+    std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|score| match score {
+            "inf" | "+inf" => Some(f64::INFINITY),
+            "-inf" => Some(f64::NEG_INFINITY),
+            "nan" => Some(f64::NAN),
+            score => score.parse().ok(),
+        })
+        .ok_or(Error::CorruptRdb("bad sorted set score"))
+}
+
+fn pairs(items: Vec<Vec<u8>>) -> Result<Pairs, Error> {
+    if !items.len().is_multiple_of(2) {
+        return Err(Error::CorruptRdb("odd number of hash entries"));
+    }
+    let mut items = items.into_iter();
+    let mut pairs = Vec::new();
+    while let (Some(field), Some(value)) = (items.next(), items.next()) {
+        pairs.push((field, value));
+    }
+    Ok(pairs)
+}
+
+fn scores(items: Vec<Vec<u8>>) -> Result<Vec<(Vec<u8>, f64)>, Error> {
+    pairs(items)?
+        .into_iter()
+        .map(|(member, score)| Ok((member, parse_score(&score)?)))
+        .collect()
+}
+
+/// Walks an in-memory encoding such as a ziplist or listpack
+struct Cursor<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, position: 0 }
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], Error> {
+        let end = self
+            .position
+            .checked_add(length)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or(Error::CorruptRdb("encoded value is truncated"))?;
+        let bytes = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn take_array<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+
+    fn take_u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take_array::<1>()?[0])
+    }
+}
+
+fn ziplist_entries(bytes: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+    let mut cursor = Cursor::new(bytes);
+    // Total bytes, tail offset and entry count
+    cursor.take(10)?;
+
+    let mut entries = Vec::new();
+    loop {
+        let previous_length = cursor.take_u8()?;
+        if previous_length == 0xFF {
+            return Ok(entries);
+        }
+        if previous_length == 0xFE {
+            cursor.take(4)?;
+        }
+
+        let encoding = cursor.take_u8()?;
+        let entry = match encoding >> 6 {
+            0 => cursor.take((encoding & 0x3F) as usize)?.to_vec(),
+            1 => {
+                let length = (((encoding & 0x3F) as usize) << 8) | cursor.take_u8()? as usize;
+                cursor.take(length)?.to_vec()
+            }
+            2 => {
+                let length = u32::from_be_bytes(cursor.take_array()?) as usize;
+                cursor.take(length)?.to_vec()
+            }
+            _ => {
+                let int = match encoding {
+                    0xC0 => i16::from_le_bytes(cursor.take_array()?) as i64,
+                    0xD0 => i32::from_le_bytes(cursor.take_array()?) as i64,
+                    0xE0 => i64::from_le_bytes(cursor.take_array()?),
+                    0xF0 => {
+                        let [a, b, c] = cursor.take_array()?;
+                        // Shift up and back down to sign extend the 24 bit integer
+                        (i32::from_le_bytes([0, a, b, c]) >> 8) as i64
+                    }
+                    0xFE => cursor.take_u8()? as i8 as i64,
+                    0xF1..=0xFD => (encoding & 0x0F) as i64 - 1,
+                    _ => return Err(Error::CorruptRdb("bad ziplist encoding")),
+                };
+                int.to_string().into_bytes()
+            }
+        };
+        entries.push(entry);
+    }
+}
+
+fn listpack_entries(bytes: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+    let mut cursor = Cursor::new(bytes);
+    // Total bytes and element count
+    cursor.take(6)?;
+
+    let mut entries = Vec::new();
+    loop {
+        let start = cursor.position;
+        let encoding = cursor.take_u8()?;
+        let entry = if encoding & 0x80 == 0 {
+            (encoding as i64).to_string().into_bytes()
+        } else if encoding & 0xC0 == 0x80 {
+            cursor.take((encoding & 0x3F) as usize)?.to_vec()
+        } else if encoding & 0xE0 == 0xC0 {
+            let int = (((encoding & 0x1F) as i64) << 8) | cursor.take_u8()? as i64;
+            // Sign extend the 13 bit integer
+            let int = if int >= 1 << 12 { int - (1 << 13) } else { int };
+            int.to_string().into_bytes()
+        } else if encoding & 0xF0 == 0xE0 {
+            let length = (((encoding & 0x0F) as usize) << 8) | cursor.take_u8()? as usize;
+            cursor.take(length)?.to_vec()
+        } else {
+            match encoding {
+                0xF0 => {
+                    let length = u32::from_le_bytes(cursor.take_array()?) as usize;
+                    cursor.take(length)?.to_vec()
+                }
+                0xF1 => i16::from_le_bytes(cursor.take_array()?)
+                    .to_string()
+                    .into_bytes(),
+                0xF2 => {
+                    let [a, b, c] = cursor.take_array()?;
+                    (i32::from_le_bytes([0, a, b, c]) >> 8)
+                        .to_string()
+                        .into_bytes()
+                }
+                0xF3 => i32::from_le_bytes(cursor.take_array()?)
+                    .to_string()
+                    .into_bytes(),
+                0xF4 => i64::from_le_bytes(cursor.take_array()?)
+                    .to_string()
+                    .into_bytes(),
+                0xFF => return Ok(entries),
+                _ => return Err(Error::CorruptRdb("bad listpack encoding")),
+            }
+        };
+
+        // Every entry ends with its own length, written in as few 7 bit groups as possible
+        let length = cursor.position - start;
+        let backlength_size = match length {
+            0..=127 => 1,
+            128..=16382 => 2,
+            16383..=2097150 => 3,
+            2097151..=268435454 => 4,
+            _ => 5,
+        };
+        cursor.take(backlength_size)?;
+        entries.push(entry);
+    }
+}
+
+fn intset_entries(bytes: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+    let mut cursor = Cursor::new(bytes);
+    let encoding = u32::from_le_bytes(cursor.take_array()?);
+    let length = u32::from_le_bytes(cursor.take_array()?);
+    (0..length)
+        .map(|_| {
+            let int = match encoding {
+                2 => i16::from_le_bytes(cursor.take_array()?) as i64,
+                4 => i32::from_le_bytes(cursor.take_array()?) as i64,
+                8 => i64::from_le_bytes(cursor.take_array()?),
+                _ => return Err(Error::CorruptRdb("bad intset encoding")),
+            };
+            Ok(int.to_string().into_bytes())
+        })
+        .collect()
+}
+
+fn zipmap_entries(bytes: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+    let mut cursor = Cursor::new(bytes);
+    // Entry count, which is unreliable past 253
+    cursor.take_u8()?;
+
+    let mut entries = Vec::new();
+    let read_length = |cursor: &mut Cursor| -> Result<Option<usize>, Error> {
+        match cursor.take_u8()? {
+            0xFF => Ok(None),
+            0xFE => Ok(Some(u32::from_le_bytes(cursor.take_array()?) as usize)),
+            length => Ok(Some(length as usize)),
+        }
+    };
+    while let Some(length) = read_length(&mut cursor)? {
+        entries.push(cursor.take(length)?.to_vec());
+        let length =
+            read_length(&mut cursor)?.ok_or(Error::CorruptRdb("zipmap key has no value"))?;
+        let free = cursor.take_u8()? as usize;
+        entries.push(cursor.take(length)?.to_vec());
+        cursor.take(free)?;
+    }
+    Ok(entries)
+}
+
+/// The most bytes LZF can decompress each compressed byte to, from a three byte back reference copying 264 bytes
+const LZF_MAX_EXPANSION: usize = 88;
+
+fn lzf_decompress(input: &[u8], length: usize) -> Result<Vec<u8>, Error> {
+    let corrupt = || Error::CorruptRdb("bad lzf compressed string");
+    // The declared length comes from the file, so don't trust it further than the input could decompress to
+    if length > input.len().saturating_mul(LZF_MAX_EXPANSION) {
+        return Err(corrupt());
+    }
+    let mut output = Vec::with_capacity(length);
+    let mut position = 0;
+    while position < input.len() {
+        let control = input[position] as usize;
+        position += 1;
+        if control < 32 {
+            // A run of literal bytes
+            let literal = input
+                .get(position..position + control + 1)
+                .ok_or_else(corrupt)?;
+            if output.len() + literal.len() > length {
+                return Err(corrupt());
+            }
+            output.extend_from_slice(literal);
+            position += control + 1;
+        } else {
+            // A back reference into what has already been decompressed
+            let mut run = control >> 5;
+            if run == 7 {
+                run += *input.get(position).ok_or_else(corrupt)? as usize;
+                position += 1;
+            }
+            let offset =
+                ((control & 0x1F) << 8) + *input.get(position).ok_or_else(corrupt)? as usize + 1;
+            position += 1;
+            let start = output.len().checked_sub(offset).ok_or_else(corrupt)?;
+            if output.len() + run + 2 > length {
+                return Err(corrupt());
+            }
+            // Copy byte by byte since the reference may overlap what it is producing
+            for index in start..start + run + 2 {
+                output.push(output[index]);
+            }
+        }
+    }
+
+    if output.len() != length {
+        return Err(corrupt());
+    }
+    Ok(output)
+}
+
+// CRC-64/Jones, as used by Redis, with the polynomial reflected
+const CRC64_POLYNOMIAL: u64 = 0x95AC_9329_AC4B_C9B5;
+
+const CRC64_TABLE: [u64; 256] = {
+    let mut table = [0; 256];
+    let mut index = 0;
+    while index < 256 {
+        let mut crc = index as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ CRC64_POLYNOMIAL
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[index] = crc;
+        index += 1;
+    }
+    table
+};
+
+fn crc64(mut crc: u64, bytes: &[u8]) -> u64 {
+    for byte in bytes {
+        crc = CRC64_TABLE[((crc ^ *byte as u64) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    fn fixture(name: &str) -> RdbImport {
+        let path = format!("{}/fixtures/rdb/{name}", env!("CARGO_MANIFEST_DIR"));
+        read(std::fs::File::open(path).unwrap()).unwrap()
+    }
+
+    fn json(data: &Data) -> &serde_json::Value {
+        match data {
+            Data::Json(json) => json,
+            other => panic!("expected json, got {other:?}"),
+        }
+    }
+
+    fn database(mut import: RdbImport, db: u64) -> Vec<(String, Value)> {
+        assert_eq!(import.entries.keys().collect::<Vec<_>>(), [&db]);
+        import.entries.remove(&db).unwrap()
+    }
+
+    #[test]
+    fn test_read_databases() {
+        // The same key in databases 0 and 1, with checksums disabled
+        let mut bytes = b"REDIS0009".to_vec();
+        for (db, value) in [(0, b"zero"), (1, b"once")] {
+            bytes.extend([OPCODE_SELECTDB, db, TYPE_STRING, 3]);
+            bytes.extend(b"key");
+            bytes.push(4);
+            bytes.extend(value);
+        }
+        bytes.push(OPCODE_EOF);
+        bytes.extend([0; 8]);
+
+        let import = read(bytes.as_slice()).unwrap();
+        assert_eq!(import.report.imported, 2);
+        let values: Vec<(u64, &str, &Data)> = import
+            .entries
+            .iter()
+            .flat_map(|(db, entries)| {
+                entries
+                    .iter()
+                    .map(|(key, value)| (*db, key.as_str(), &value.data))
+            })
+            .collect();
+        assert!(matches!(
+            values.as_slice(),
+            [(0, "key", Data::String(zero)), (1, "key", Data::String(one))] if zero == "zero" && one == "once"
+        ));
+        assert_eq!(table_name("cache", 0), "cache");
+        assert_eq!(table_name("cache", 1), "cache-1");
+    }
+
+    #[test]
+    fn test_crc64() {
+        assert_eq!(crc64(0, b"123456789"), 0xE9C6_D914_C4B8_D9CA);
+    }
+
+    #[test]
+    fn test_lzf_decompress() {
+        // A literal run of "abc" followed by a back reference repeating it three times
+        let compressed = [0x02, b'a', b'b', b'c', 0xE0, 0x00, 0x02];
+        assert_eq!(lzf_decompress(&compressed, 12).unwrap(), b"abcabcabcabc");
+        assert!(lzf_decompress(&compressed, 11).is_err());
+        // A declared length the input can't possibly decompress to is rejected before allocating
+        assert!(lzf_decompress(&compressed, usize::MAX).is_err());
+        assert!(lzf_decompress(&compressed, compressed.len() * LZF_MAX_EXPANSION + 1).is_err());
+    }
+
+    #[test]
+    fn test_read_plain_types() {
+        let import = fixture("plain.rdb");
+        assert_eq!(import.report.version, 9);
+        assert_eq!(import.report.imported, 9);
+        assert_eq!(import.report.expired, 1);
+        assert_eq!(import.report.skipped.len(), 2);
+        assert_eq!(import.report.skipped[0].key, "events");
+        assert_eq!(import.report.skipped[0].kind, "stream");
+        assert_eq!(import.report.skipped[1].key, "binary");
+
+        let entries: HashMap<String, Value> = database(import, 0).into_iter().collect();
+        assert!(matches!(&entries["greeting"].data, Data::String(string) if string == "hello"));
+        assert!(matches!(entries["counter"].data, Data::Int(42)));
+        assert!(matches!(entries["big"].data, Data::Int(-100000)));
+        assert!(matches!(&entries["padded"].data, Data::String(string) if string == "007"));
+        assert!(
+            matches!(&entries["compressed"].data, Data::String(string) if string == "abcabcabcabc")
+        );
+        assert_eq!(
+            json(&entries["list"].data),
+            &serde_json::json!(["a", "b", "c"])
+        );
+        assert_eq!(json(&entries["set"].data), &serde_json::json!(["x", "y"]));
+        assert_eq!(
+            json(&entries["hash"].data),
+            &serde_json::json!({ "name": "Ada", "lang": "en" })
+        );
+        // JSON has no infinity, so infinite scores become null
+        assert_eq!(
+            json(&entries["zset"].data),
+            &serde_json::json!([
+                { "member": "a", "score": 1.5 },
+                { "member": "b", "score": null }
+            ])
+        );
+        assert!(entries["greeting"].expiry.is_some());
+        assert!(entries["counter"].expiry.is_none());
+        assert!(!entries.contains_key("stale"));
+    }
+
+    #[test]
+    fn test_read_encoded_types() {
+        let import = fixture("encoded.rdb");
+        assert_eq!(import.report.version, 11);
+        assert!(import.report.skipped.is_empty());
+
+        let entries: HashMap<String, Value> = database(import, 0).into_iter().collect();
+        assert_eq!(entries.len(), 11);
+        assert_eq!(
+            json(&entries["ziplist"].data),
+            &serde_json::json!(["one", "12", "-2", "300", "-70000", "5000000000"])
+        );
+        assert_eq!(
+            json(&entries["intset"].data),
+            &serde_json::json!(["1", "2", "-3"])
+        );
+        assert_eq!(
+            json(&entries["zset_ziplist"].data),
+            &serde_json::json!([
+                { "member": "low", "score": 1.0 },
+                { "member": "high", "score": 2.5 }
+            ])
+        );
+        assert_eq!(
+            json(&entries["hash_ziplist"].data),
+            &serde_json::json!({ "field": "value" })
+        );
+        assert_eq!(
+            json(&entries["quicklist"].data),
+            &serde_json::json!(["a", "b", "c"])
+        );
+        assert_eq!(
+            json(&entries["zipmap"].data),
+            &serde_json::json!({ "k": "v" })
+        );
+        assert_eq!(
+            json(&entries["hash_listpack"].data),
+            &serde_json::json!({ "field": "value", "count": "-5" })
+        );
+        assert_eq!(
+            json(&entries["zset_listpack"].data),
+            &serde_json::json!([{ "member": "m", "score": 3.0 }])
+        );
+        assert_eq!(
+            json(&entries["quicklist2"].data),
+            &serde_json::json!(["packed", "100", "-1000", "plain node"])
+        );
+        assert_eq!(
+            json(&entries["set_listpack"].data),
+            &serde_json::json!(["s", "70000"])
+        );
+        assert_eq!(
+            json(&entries["zset2"].data),
+            &serde_json::json!([{ "member": "pi", "score": 3.25 }])
+        );
+    }
+
+    #[test]
+    fn test_read_bad_checksum() {
+        let path = format!("{}/fixtures/rdb/plain.rdb", env!("CARGO_MANIFEST_DIR"));
+        let mut bytes = std::fs::read(path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xFF;
+        assert!(matches!(
+            read(bytes.as_slice()),
+            Err(Error::CorruptRdb("checksum mismatch"))
+        ));
+    }
+}
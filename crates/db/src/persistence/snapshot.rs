@@ -0,0 +1,870 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, BufWriter, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use aether_common::db::{
+    Data, IndexDefinition, Metric, SearchIndexDefinition, TableOptions, TriggerDefinition, Value,
+    VectorIndexDefinition, DEFAULT_TABLE,
+};
+use time::OffsetDateTime;
+use tokio::sync::mpsc;
+use tracing::{debug, info};
+
+use super::Error;
+use crate::db::{PatternSubscriptions, SubscriptionOptions, Subscriptions};
+
+// File layout:
+//
+// magic | version (u16) | (table | entry | index | search index | vector index | trigger | subscription | pattern subscription)* | EOF opcode | crc32 of everything before it (u32)
+//
+// table: TABLE opcode | name | options, with every following entry and index belonging to it
+// entry: ENTRY opcode | key | expiry | data
+// index: INDEX opcode | name | pattern | pointer
+// search index: SEARCH INDEX opcode | name | pattern | field count | field*
+// vector index: VECTOR INDEX opcode | name | pattern | dimensions | metric (u8)
+// trigger: TRIGGER opcode | definition as JSON
+// subscription: SUBSCRIPTION opcode | client id | channel | options
+// pattern subscription: PATTERN SUBSCRIPTION opcode | client id | pattern as JSON | options
+//
+// Integers are little endian and lengths are LEB128 varints.
+// Entries before the first table belong to the default table.
+const MAGIC: &[u8; 6] = b"AETHER";
+const VERSION: u16 = 1;
+
+const OP_ENTRY: u8 = 0x01;
+const OP_SUBSCRIPTION: u8 = 0x02;
+const OP_TABLE: u8 = 0x03;
+const OP_INDEX: u8 = 0x04;
+const OP_SEARCH_INDEX: u8 = 0x05;
+const OP_VECTOR_INDEX: u8 = 0x06;
+const OP_TRIGGER: u8 = 0x07;
+const OP_PATTERN_SUBSCRIPTION: u8 = 0x08;
+const OP_EOF: u8 = 0xFF;
+
+const SUBSCRIBE_TO_SELF: u8 = 0x01;
+
+const HAS_DEFAULT_TTL: u8 = 0x01;
+const HAS_MAX_MEMORY: u8 = 0x02;
+const ORDERED: u8 = 0x04;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_JSON: u8 = 1;
+const TYPE_INT: u8 = 2;
+const TYPE_VECTOR: u8 = 3;
+const TYPE_CUSTOM: u8 = 4;
+
+const METRIC_COSINE: u8 = 0;
+const METRIC_DOT: u8 = 1;
+const METRIC_L2: u8 = 2;
+
+/// Batches of a table's entries, received as they are copied
+pub type EntryBatches = mpsc::Receiver<Vec<(String, Value)>>;
+
+/// Everything stored in a snapshot
+///
+/// Loaded snapshots hold every entry at once, while saved ones stream their entries in as [`EntryBatches`].
+#[derive(Default)]
+pub struct Contents<E = HashMap<String, Value>> {
+    pub tables: HashMap<String, TableContents<E>>,
+    pub subscriptions: Subscriptions,
+    pub pattern_subscriptions: PatternSubscriptions,
+}
+
+/// A table's settings, entries and index definitions
+#[derive(Default)]
+pub struct TableContents<E = HashMap<String, Value>> {
+    pub options: TableOptions,
+    pub entries: E,
+    pub indexes: Vec<IndexDefinition>,
+    pub search_indexes: Vec<SearchIndexDefinition>,
+    pub vector_indexes: Vec<VectorIndexDefinition>,
+    pub triggers: Vec<TriggerDefinition>,
+}
+
+/// Reads and writes point-in-time snapshots of the tables and subscriptions
+#[derive(Clone)]
+pub struct Snapshotter {
+    path: Arc<PathBuf>,
+    saving: Arc<AtomicBool>,
+}
+
+/// Marks a save as in progress until dropped
+pub struct SaveGuard {
+    saving: Arc<AtomicBool>,
+}
+
+impl Drop for SaveGuard {
+    fn drop(&mut self) {
+        self.saving.store(false, Ordering::Release);
+    }
+}
+
+impl Snapshotter {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path: Arc::new(path),
+            saving: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Claims the right to save, failing if another save is already running
+    pub fn begin_save(&self) -> Result<SaveGuard, Error> {
+        if self.saving.swap(true, Ordering::AcqRel) {
+            return Err(Error::SaveInProgress);
+        }
+        Ok(SaveGuard {
+            saving: self.saving.clone(),
+        })
+    }
+
+    /// Writes `contents` to the snapshot file as its entries arrive, replacing the file atomically
+    pub async fn save(
+        &self,
+        _guard: SaveGuard,
+        contents: Contents<EntryBatches>,
+    ) -> Result<(), Error> {
+        let path = self.path.clone();
+        tokio::task::spawn_blocking(move || write(&path, contents)).await??;
+        Ok(())
+    }
+
+    /// Loads every unexpired entry and every subscription from the snapshot file, if there is one
+    pub async fn load(&self) -> Result<Contents, Error> {
+        let path = self.path.clone();
+        tokio::task::spawn_blocking(move || match File::open(path.as_path()) {
+            Ok(file) => read(BufReader::new(file)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(Contents::default()),
+            Err(err) => Err(err.into()),
+        })
+        .await?
+    }
+}
+
+fn write(path: &Path, contents: Contents<EntryBatches>) -> Result<(), Error> {
+    // Write to a temporary file and rename it over the old snapshot so a crash never leaves a partial file
+    let mut temporary_path = path.as_os_str().to_owned();
+    temporary_path.push(".tmp");
+    let temporary_path = PathBuf::from(temporary_path);
+
+    let file = File::create(&temporary_path)?;
+    let mut encoder = Encoder::new(BufWriter::new(file));
+    encoder.write_bytes(MAGIC)?;
+    encoder.write_bytes(&VERSION.to_le_bytes())?;
+    let tables = contents.tables.len();
+    let mut entries = 0;
+    for (name, mut table) in contents.tables {
+        encoder.write_bytes(&[OP_TABLE])?;
+        encoder.write_table(&name, &table.options)?;
+        while let Some(batch) = table.entries.blocking_recv() {
+            for (key, value) in &batch {
+                encoder.write_bytes(&[OP_ENTRY])?;
+                encoder.write_entry(key, value)?;
+            }
+            entries += batch.len();
+        }
+        for index in &table.indexes {
+            encoder.write_bytes(&[OP_INDEX])?;
+            encoder.write_index(index)?;
+        }
+        for index in &table.search_indexes {
+            encoder.write_bytes(&[OP_SEARCH_INDEX])?;
+            encoder.write_search_index(index)?;
+        }
+        for index in &table.vector_indexes {
+            encoder.write_bytes(&[OP_VECTOR_INDEX])?;
+            encoder.write_vector_index(index)?;
+        }
+        for trigger in &table.triggers {
+            encoder.write_bytes(&[OP_TRIGGER])?;
+            encoder.write_string(&serde_json::to_vec(trigger)?)?;
+        }
+    }
+    for (client_id, subscriptions) in &contents.subscriptions {
+        for (channel, options) in subscriptions {
+            encoder.write_bytes(&[OP_SUBSCRIPTION])?;
+            encoder.write_subscription(client_id, channel, options)?;
+        }
+    }
+    for (client_id, patterns) in &contents.pattern_subscriptions {
+        for (pattern, options) in patterns {
+            encoder.write_bytes(&[OP_PATTERN_SUBSCRIPTION])?;
+            encoder.write_subscription(client_id, &serde_json::to_string(pattern)?, options)?;
+        }
+    }
+    encoder.write_bytes(&[OP_EOF])?;
+    let file = encoder
+        .finish()?
+        .into_inner()
+        .map_err(|err| err.into_error())?;
+    file.sync_all()?;
+
+    std::fs::rename(&temporary_path, path)?;
+    info!(?path, tables, entries, "Saved snapshot");
+    Ok(())
+}
+
+fn read(reader: impl Read) -> Result<Contents, Error> {
+    let mut decoder = Decoder::new(reader);
+    if &decoder.read_array::<6>()? != MAGIC {
+        return Err(Error::CorruptSnapshot("bad magic"));
+    }
+    let version = u16::from_le_bytes(decoder.read_array()?);
+    if version != VERSION {
+        return Err(Error::UnsupportedSnapshotVersion(version));
+    }
+
+    let now = OffsetDateTime::now_utc();
+    let mut contents: Contents = Contents::default();
+    let mut table = DEFAULT_TABLE.to_string();
+    loop {
+        match decoder.read_array::<1>()?[0] {
+            OP_ENTRY => {
+                let (key, value) = decoder.read_entry()?;
+                // Skip anything that expired while the server was down
+                if value.expiry.is_none_or(|expiry| now <= expiry) {
+                    contents
+                        .tables
+                        .entry(table.clone())
+                        .or_default()
+                        .entries
+                        .insert(key, value);
+                }
+            }
+            OP_TABLE => {
+                let (name, options) = decoder.read_table()?;
+                contents.tables.entry(name.clone()).or_default().options = options;
+                table = name;
+            }
+            OP_INDEX => {
+                let index = decoder.read_index()?;
+                contents
+                    .tables
+                    .entry(table.clone())
+                    .or_default()
+                    .indexes
+                    .push(index);
+            }
+            OP_SEARCH_INDEX => {
+                let index = decoder.read_search_index()?;
+                contents
+                    .tables
+                    .entry(table.clone())
+                    .or_default()
+                    .search_indexes
+                    .push(index);
+            }
+            OP_VECTOR_INDEX => {
+                let index = decoder.read_vector_index()?;
+                contents
+                    .tables
+                    .entry(table.clone())
+                    .or_default()
+                    .vector_indexes
+                    .push(index);
+            }
+            OP_TRIGGER => {
+                let trigger = serde_json::from_slice(&decoder.read_string()?)
+                    .map_err(|_| Error::CorruptSnapshot("invalid trigger"))?;
+                contents
+                    .tables
+                    .entry(table.clone())
+                    .or_default()
+                    .triggers
+                    .push(trigger);
+            }
+            OP_SUBSCRIPTION => {
+                let (client_id, channel, options) = decoder.read_subscription()?;
+                contents
+                    .subscriptions
+                    .entry(client_id)
+                    .or_default()
+                    .insert(channel, options);
+            }
+            OP_PATTERN_SUBSCRIPTION => {
+                let (client_id, pattern, options) = decoder.read_subscription()?;
+                let pattern = serde_json::from_str(&pattern)
+                    .map_err(|_| Error::CorruptSnapshot("invalid pattern"))?;
+                contents
+                    .pattern_subscriptions
+                    .entry(client_id)
+                    .or_default()
+                    .insert(pattern, options);
+            }
+            OP_EOF => break,
+            _ => return Err(Error::CorruptSnapshot("unknown opcode")),
+        }
+    }
+    decoder.verify_checksum()?;
+
+    debug!(tables = contents.tables.len(), "Loaded snapshot");
+    Ok(contents)
+}
+
+/// Writes snapshot fields while keeping a running checksum
+struct Encoder<W> {
+    writer: W,
+    hasher: crc32fast::Hasher,
+}
+
+impl<W: Write> Encoder<W> {
+    fn new(writer: W) -> Self {
+        Self {
+            writer,
+            hasher: crc32fast::Hasher::new(),
+        }
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        self.hasher.update(bytes);
+        self.writer.write_all(bytes)
+    }
+
+    fn write_length(&mut self, mut length: usize) -> std::io::Result<()> {
+        loop {
+            let byte = (length & 0x7F) as u8;
+            length >>= 7;
+            if length == 0 {
+                return self.write_bytes(&[byte]);
+            }
+            self.write_bytes(&[byte | 0x80])?;
+        }
+    }
+
+    fn write_string(&mut self, string: &[u8]) -> std::io::Result<()> {
+        self.write_length(string.len())?;
+        self.write_bytes(string)
+    }
+
+    fn write_entry(&mut self, key: &str, value: &Value) -> Result<(), Error> {
+        self.write_string(key.as_bytes())?;
+        match value.expiry {
+            Some(expiry) => {
+                let millis = (expiry.unix_timestamp_nanos() / 1_000_000) as i64;
+                self.write_bytes(&[1])?;
+                self.write_bytes(&millis.to_le_bytes())?;
+            }
+            None => self.write_bytes(&[0])?,
+        }
+        match &value.data {
+            Data::String(string) => {
+                self.write_bytes(&[TYPE_STRING])?;
+                self.write_string(string.as_bytes())?;
+            }
+            Data::Json(json) => {
+                self.write_bytes(&[TYPE_JSON])?;
+                self.write_string(&serde_json::to_vec(json)?)?;
+            }
+            Data::Int(int) => {
+                self.write_bytes(&[TYPE_INT])?;
+                self.write_bytes(&int.to_le_bytes())?;
+            }
+            Data::Vector(vector) => {
+                self.write_bytes(&[TYPE_VECTOR])?;
+                self.write_length(vector.len())?;
+                for component in vector {
+                    self.write_bytes(&component.to_le_bytes())?;
+                }
+            }
+            Data::Custom { kind, data } => {
+                self.write_bytes(&[TYPE_CUSTOM])?;
+                self.write_string(kind.as_bytes())?;
+                self.write_string(&serde_json::to_vec(data)?)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_table(&mut self, name: &str, options: &TableOptions) -> std::io::Result<()> {
+        self.write_string(name.as_bytes())?;
+        let mut flags = 0;
+        if options.default_ttl.is_some() {
+            flags |= HAS_DEFAULT_TTL;
+        }
+        if options.max_memory.is_some() {
+            flags |= HAS_MAX_MEMORY;
+        }
+        if options.ordered {
+            flags |= ORDERED;
+        }
+        self.write_bytes(&[flags])?;
+        if let Some(default_ttl) = options.default_ttl {
+            self.write_bytes(&default_ttl.to_le_bytes())?;
+        }
+        if let Some(max_memory) = options.max_memory {
+            self.write_bytes(&max_memory.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn write_index(&mut self, index: &IndexDefinition) -> std::io::Result<()> {
+        self.write_string(index.name.as_bytes())?;
+        self.write_string(index.pattern.as_bytes())?;
+        self.write_string(index.pointer.as_bytes())
+    }
+
+    fn write_vector_index(&mut self, index: &VectorIndexDefinition) -> std::io::Result<()> {
+        self.write_string(index.name.as_bytes())?;
+        self.write_string(index.pattern.as_bytes())?;
+        self.write_length(index.dimensions)?;
+        let metric = match index.metric {
+            Metric::Cosine => METRIC_COSINE,
+            Metric::Dot => METRIC_DOT,
+            Metric::L2 => METRIC_L2,
+        };
+        self.write_bytes(&[metric])
+    }
+
+    fn write_search_index(&mut self, index: &SearchIndexDefinition) -> std::io::Result<()> {
+        self.write_string(index.name.as_bytes())?;
+        self.write_string(index.pattern.as_bytes())?;
+        self.write_length(index.fields.len())?;
+        for field in &index.fields {
+            self.write_string(field.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn write_subscription(
+        &mut self,
+        client_id: &str,
+        channel: &str,
+        options: &SubscriptionOptions,
+    ) -> std::io::Result<()> {
+        self.write_string(client_id.as_bytes())?;
+        self.write_string(channel.as_bytes())?;
+        let mut flags = 0;
+        if options.subscribe_to_self {
+            flags |= SUBSCRIBE_TO_SELF;
+        }
+        self.write_bytes(&[flags])
+    }
+
+    fn finish(mut self) -> std::io::Result<W> {
+        let checksum = self.hasher.finalize();
+        self.writer.write_all(&checksum.to_le_bytes())?;
+        Ok(self.writer)
+    }
+}
+
+/// Reads snapshot fields while keeping a running checksum
+struct Decoder<R> {
+    reader: R,
+    hasher: crc32fast::Hasher,
+}
+
+impl<R: Read> Decoder<R> {
+    fn new(reader: R) -> Self {
+        Self {
+            reader,
+            hasher: crc32fast::Hasher::new(),
+        }
+    }
+
+    fn read_exact(&mut self, buffer: &mut [u8]) -> Result<(), Error> {
+        self.reader
+            .read_exact(buffer)
+            .map_err(|err| match err.kind() {
+                ErrorKind::UnexpectedEof => Error::CorruptSnapshot("unexpected end of file"),
+                _ => err.into(),
+            })?;
+        self.hasher.update(buffer);
+        Ok(())
+    }
+
+    fn read_array<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        let mut buffer = [0; N];
+        self.read_exact(&mut buffer)?;
+        Ok(buffer)
+    }
+
+    fn read_length(&mut self) -> Result<usize, Error> {
+        let mut length = 0usize;
+        for shift in (0..usize::BITS).step_by(7) {
+            let byte = self.read_array::<1>()?[0];
+            length |= ((byte & 0x7F) as usize) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(length);
+            }
+        }
+        Err(Error::CorruptSnapshot("length overflow"))
+    }
+
+    fn read_string(&mut self) -> Result<Vec<u8>, Error> {
+        let length = self.read_length()?;
+        let mut buffer = Vec::new();
+        // Read through `take` so a corrupt length can't make us allocate everything up front
+        (&mut self.reader)
+            .take(length as u64)
+            .read_to_end(&mut buffer)?;
+        if buffer.len() != length {
+            return Err(Error::CorruptSnapshot("unexpected end of file"));
+        }
+        self.hasher.update(&buffer);
+        Ok(buffer)
+    }
+
+    fn read_entry(&mut self) -> Result<(String, Value), Error> {
+        let key = String::from_utf8(self.read_string()?)
+            .map_err(|_| Error::CorruptSnapshot("key is not utf-8"))?;
+        let expiry = match self.read_array::<1>()?[0] {
+            0 => None,
+            1 => {
+                let millis = i64::from_le_bytes(self.read_array()?);
+                let expiry = OffsetDateTime::from_unix_timestamp_nanos(millis as i128 * 1_000_000)
+                    .map_err(|_| Error::CorruptSnapshot("expiry out of range"))?;
+                Some(expiry)
+            }
+            _ => return Err(Error::CorruptSnapshot("bad expiry flag")),
+        };
+        let data = match self.read_array::<1>()?[0] {
+            TYPE_STRING => Data::String(
+                String::from_utf8(self.read_string()?)
+                    .map_err(|_| Error::CorruptSnapshot("string is not utf-8"))?,
+            ),
+            TYPE_JSON => Data::Json(
+                serde_json::from_slice(&self.read_string()?)
+                    .map_err(|_| Error::CorruptSnapshot("invalid json"))?,
+            ),
+            TYPE_INT => Data::Int(i64::from_le_bytes(self.read_array()?)),
+            TYPE_VECTOR => {
+                let length = self.read_length()?;
+                Data::Vector(
+                    (0..length)
+                        .map(|_| Ok(f32::from_le_bytes(self.read_array()?)))
+                        .collect::<Result<_, Error>>()?,
+                )
+            }
+            TYPE_CUSTOM => Data::Custom {
+                kind: String::from_utf8(self.read_string()?)
+                    .map_err(|_| Error::CorruptSnapshot("data kind is not utf-8"))?,
+                data: serde_json::from_slice(&self.read_string()?)
+                    .map_err(|_| Error::CorruptSnapshot("invalid json"))?,
+            },
+            _ => return Err(Error::CorruptSnapshot("unknown data type")),
+        };
+        Ok((key, Value { data, expiry }))
+    }
+
+    fn read_table(&mut self) -> Result<(String, TableOptions), Error> {
+        let name = String::from_utf8(self.read_string()?)
+            .map_err(|_| Error::CorruptSnapshot("table name is not utf-8"))?;
+        let flags = self.read_array::<1>()?[0];
+        let default_ttl = match flags & HAS_DEFAULT_TTL {
+            0 => None,
+            _ => Some(u32::from_le_bytes(self.read_array()?)),
+        };
+        let max_memory = match flags & HAS_MAX_MEMORY {
+            0 => None,
+            _ => Some(u64::from_le_bytes(self.read_array()?)),
+        };
+        let options = TableOptions {
+            default_ttl,
+            max_memory,
+            ordered: flags & ORDERED != 0,
+        };
+        Ok((name, options))
+    }
+
+    fn read_index(&mut self) -> Result<IndexDefinition, Error> {
+        let mut read_utf8 =
+            |what| String::from_utf8(self.read_string()?).map_err(|_| Error::CorruptSnapshot(what));
+        Ok(IndexDefinition {
+            name: read_utf8("index name is not utf-8")?,
+            pattern: read_utf8("index pattern is not utf-8")?,
+            pointer: read_utf8("index pointer is not utf-8")?,
+        })
+    }
+
+    fn read_vector_index(&mut self) -> Result<VectorIndexDefinition, Error> {
+        let mut read_utf8 =
+            |what| String::from_utf8(self.read_string()?).map_err(|_| Error::CorruptSnapshot(what));
+        let name = read_utf8("vector index name is not utf-8")?;
+        let pattern = read_utf8("vector index pattern is not utf-8")?;
+        let dimensions = self.read_length()?;
+        let metric = match self.read_array::<1>()?[0] {
+            METRIC_COSINE => Metric::Cosine,
+            METRIC_DOT => Metric::Dot,
+            METRIC_L2 => Metric::L2,
+            _ => return Err(Error::CorruptSnapshot("unknown vector metric")),
+        };
+        Ok(VectorIndexDefinition {
+            name,
+            pattern,
+            dimensions,
+            metric,
+        })
+    }
+
+    fn read_search_index(&mut self) -> Result<SearchIndexDefinition, Error> {
+        let mut read_utf8 =
+            |what| String::from_utf8(self.read_string()?).map_err(|_| Error::CorruptSnapshot(what));
+        let name = read_utf8("search index name is not utf-8")?;
+        let pattern = read_utf8("search index pattern is not utf-8")?;
+        let count = self.read_length()?;
+        let fields = (0..count)
+            .map(|_| {
+                String::from_utf8(self.read_string()?)
+                    .map_err(|_| Error::CorruptSnapshot("search index field is not utf-8"))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(SearchIndexDefinition {
+            name,
+            pattern,
+            fields,
+        })
+    }
+
+    fn read_subscription(&mut self) -> Result<(String, String, SubscriptionOptions), Error> {
+        let client_id = String::from_utf8(self.read_string()?)
+            .map_err(|_| Error::CorruptSnapshot("client id is not utf-8"))?;
+        let channel = String::from_utf8(self.read_string()?)
+            .map_err(|_| Error::CorruptSnapshot("channel is not utf-8"))?;
+        let flags = self.read_array::<1>()?[0];
+        let options = SubscriptionOptions {
+            subscribe_to_self: flags & SUBSCRIBE_TO_SELF != 0,
+        };
+        Ok((client_id, channel, options))
+    }
+
+    fn verify_checksum(mut self) -> Result<(), Error> {
+        let expected = self.hasher.clone().finalize();
+        let checksum = self.read_array::<4>()?;
+        if u32::from_le_bytes(checksum) != expected {
+            return Err(Error::CorruptSnapshot("checksum mismatch"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use aether_common::db::{ChannelPattern, KeyEvent, TriggerAction};
+    use time::Duration;
+
+    fn sample() -> Contents {
+        let expiry = OffsetDateTime::now_utc().checked_add(Duration::hours(1));
+        let entries = HashMap::from([
+            (
+                "string".to_string(),
+                Value {
+                    data: Data::String("value".to_string()),
+                    expiry,
+                },
+            ),
+            (
+                "json".to_string(),
+                Value {
+                    data: Data::Json(serde_json::json!({ "nested": [1, 2, 3] })),
+                    expiry: None,
+                },
+            ),
+            (
+                "int".to_string(),
+                Value {
+                    data: Data::Int(-42),
+                    expiry: None,
+                },
+            ),
+            (
+                "vector".to_string(),
+                Value {
+                    data: Data::Vector(vec![0.5, -1.25, 3.0]),
+                    expiry: None,
+                },
+            ),
+            (
+                "custom".to_string(),
+                Value {
+                    data: Data::Custom {
+                        kind: "point".to_string(),
+                        data: serde_json::json!([1, 2]),
+                    },
+                    expiry: None,
+                },
+            ),
+        ]);
+        let subscriptions = HashMap::from([(
+            "client".to_string(),
+            HashMap::from([(
+                "channel".to_string(),
+                SubscriptionOptions {
+                    subscribe_to_self: true,
+                },
+            )]),
+        )]);
+        let options = TableOptions {
+            default_ttl: Some(60),
+            max_memory: None,
+            ordered: true,
+        };
+        let tables = HashMap::from([
+            (
+                DEFAULT_TABLE.to_string(),
+                TableContents {
+                    options: TableOptions::default(),
+                    entries,
+                    indexes: vec![IndexDefinition {
+                        name: "nested".to_string(),
+                        pattern: "*".to_string(),
+                        pointer: "/nested/0".to_string(),
+                    }],
+                    search_indexes: vec![SearchIndexDefinition {
+                        name: "text".to_string(),
+                        pattern: "*".to_string(),
+                        fields: vec!["/title".to_string(), "/body".to_string()],
+                    }],
+                    vector_indexes: vec![VectorIndexDefinition {
+                        name: "embeddings".to_string(),
+                        pattern: "*".to_string(),
+                        dimensions: 3,
+                        metric: Metric::Dot,
+                    }],
+                    triggers: vec![TriggerDefinition {
+                        name: "audit".to_string(),
+                        pattern: "*".to_string(),
+                        events: vec![KeyEvent::Delete],
+                        action: TriggerAction::Publish {
+                            channel: "audit".to_string(),
+                            message: "{key} deleted".to_string(),
+                        },
+                    }],
+                },
+            ),
+            (
+                "empty".to_string(),
+                TableContents {
+                    options,
+                    ..TableContents::default()
+                },
+            ),
+        ]);
+        let pattern_subscriptions = HashMap::from([(
+            "client".to_string(),
+            HashMap::from([(
+                ChannelPattern::Nats("orders.>".to_string()),
+                SubscriptionOptions {
+                    subscribe_to_self: false,
+                },
+            )]),
+        )]);
+        Contents {
+            tables,
+            subscriptions,
+            pattern_subscriptions,
+        }
+    }
+
+    /// Streams the entries of `contents` the way a save receives them
+    fn batches(contents: Contents) -> Contents<EntryBatches> {
+        let tables = contents
+            .tables
+            .into_iter()
+            .map(|(name, table)| {
+                let (sender, entries) = mpsc::channel(1);
+                sender
+                    .try_send(table.entries.into_iter().collect())
+                    .unwrap();
+                let table = TableContents {
+                    options: table.options,
+                    entries,
+                    indexes: table.indexes,
+                    search_indexes: table.search_indexes,
+                    vector_indexes: table.vector_indexes,
+                    triggers: table.triggers,
+                };
+                (name, table)
+            })
+            .collect();
+        Contents {
+            tables,
+            subscriptions: contents.subscriptions,
+            pattern_subscriptions: contents.pattern_subscriptions,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let snapshotter = Snapshotter::new(dir.path().join("dump.adb"));
+        assert!(snapshotter.load().await.unwrap().tables.is_empty());
+
+        let contents = sample();
+        let entries = contents.tables[DEFAULT_TABLE].entries.clone();
+        let indexes = contents.tables[DEFAULT_TABLE].indexes.clone();
+        let search_indexes = contents.tables[DEFAULT_TABLE].search_indexes.clone();
+        let vector_indexes = contents.tables[DEFAULT_TABLE].vector_indexes.clone();
+        let triggers = contents.tables[DEFAULT_TABLE].triggers.clone();
+        let subscriptions = contents.subscriptions.clone();
+        let pattern_subscriptions = contents.pattern_subscriptions.clone();
+        let guard = snapshotter.begin_save().unwrap();
+        snapshotter.save(guard, batches(contents)).await.unwrap();
+
+        let mut loaded = snapshotter.load().await.unwrap();
+        assert_eq!(loaded.subscriptions, subscriptions);
+        assert_eq!(loaded.pattern_subscriptions, pattern_subscriptions);
+        let empty = &loaded.tables["empty"];
+        assert!(empty.entries.is_empty());
+        assert_eq!(empty.options.default_ttl, Some(60));
+        assert!(empty.options.ordered);
+        let loaded = loaded.tables.remove(DEFAULT_TABLE).unwrap();
+        assert_eq!(loaded.indexes, indexes);
+        assert_eq!(loaded.search_indexes, search_indexes);
+        assert_eq!(loaded.vector_indexes, vector_indexes);
+        assert_eq!(loaded.triggers, triggers);
+        let loaded = loaded.entries;
+        assert_eq!(loaded.len(), entries.len());
+        assert!(matches!(loaded["int"].data, Data::Int(-42)));
+        assert!(
+            matches!(&loaded["vector"].data, Data::Vector(vector) if vector == &[0.5, -1.25, 3.0])
+        );
+        assert!(matches!(&loaded["string"].data, Data::String(string) if string == "value"));
+        assert!(matches!(
+            &loaded["custom"].data,
+            Data::Custom { kind, data } if kind == "point" && data == &serde_json::json!([1, 2])
+        ));
+        let expiry = entries["string"].expiry.unwrap();
+        let loaded_expiry = loaded["string"].expiry.unwrap();
+        assert!((expiry - loaded_expiry).abs() < Duration::milliseconds(1));
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_checksum() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dump.adb");
+        let snapshotter = Snapshotter::new(path.clone());
+        let guard = snapshotter.begin_save().unwrap();
+        snapshotter.save(guard, batches(sample())).await.unwrap();
+
+        // Flip a bit in the middle of the file
+        let mut bytes = std::fs::read(&path).unwrap();
+        let middle = bytes.len() / 2;
+        bytes[middle] ^= 0x01;
+        std::fs::write(&path, bytes).unwrap();
+
+        assert!(matches!(
+            snapshotter.load().await,
+            Err(Error::CorruptSnapshot(_))
+        ));
+    }
+
+    #[test]
+    fn test_snapshot_single_save() {
+        let snapshotter = Snapshotter::new(PathBuf::from("dump.adb"));
+        let guard = snapshotter.begin_save().unwrap();
+        assert!(matches!(
+            snapshotter.begin_save(),
+            Err(Error::SaveInProgress)
+        ));
+        drop(guard);
+        assert!(snapshotter.begin_save().is_ok());
+    }
+}
@@ -0,0 +1,614 @@
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
+
+use aether_common::db::{BroadcastMessage, Data, Value};
+use anyhow::{anyhow, Context as _};
+use serde::Deserialize;
+use tracing::info;
+use wasmtime::{
+    AsContext, AsContextMut, Caller, Engine, Extern, Instance, Linker, Memory, Module, Store,
+    StoreLimits, StoreLimitsBuilder, Trap, TypedFunc, Val,
+};
+
+use crate::db::Database;
+
+/// How much fuel a plugin burns between yielding to other tasks
+const YIELD_INTERVAL: u64 = 10_000;
+
+/// How much each plugin call may use
+#[derive(Clone, Copy, Debug)]
+pub struct PluginLimits {
+    /// The most bytes of linear memory a call may grow to
+    pub max_memory: usize,
+
+    /// The fuel a call starts with, roughly the number of instructions it may run
+    pub fuel: u64,
+}
+
+/// WebAssembly plugin modules, by the command names and data kinds they registered
+///
+/// Plugins exchange JSON with the server through their linear memory, with pointers and lengths packed into an
+/// `i64` as `pointer << 32 | length`. A plugin exports:
+///
+/// - `memory`, and `aether_alloc(length: i32) -> i32` to allocate the bytes the server passes in
+/// - `aether_manifest() -> i64`, returning `{"commands": [...], "types": [...]}`
+/// - `aether_command(name, name_length, args, args_length: i32) -> i64`, returning the reply to one of its commands
+/// - `aether_data(kind, kind_length, data, data_length: i32) -> i64`, returning the payload to store for one of its
+///   data types, which may be the payload it was given
+///
+/// and may import from `aether`:
+///
+/// - `get(key, key_length: i32) -> i64`, returning the key's data, or zero if it is missing
+/// - `set(key, key_length, data, data_length: i32)` and `delete(key, key_length: i32)`
+/// - `publish(channel, channel_length, message, message_length: i32)`
+/// - `error(message, message_length: i32)`, failing the call with the message once it returns
+///
+/// Data is passed as the JSON of [`Data`], like `{"int": 1}`. Keys are read and written in the table the command
+/// was sent to, and only commands may use them. Every call runs in a fresh instance, so nothing is kept between calls.
+#[derive(Clone, Default)]
+pub struct Plugins {
+    commands: Arc<HashMap<String, Arc<Plugin>>>,
+    kinds: Arc<HashMap<String, Arc<Plugin>>>,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("could not load plugin {plugin}: {message}")]
+    Load { plugin: String, message: String },
+
+    #[error("{0} is registered by more than one plugin")]
+    Conflict(String),
+
+    #[error("no plugin registered the command {0}")]
+    NoSuchCommand(String),
+
+    #[error("no plugin handles data of kind {0}")]
+    NoSuchKind(String),
+
+    #[error("plugin {0} ran out of fuel")]
+    OutOfFuel(String),
+
+    #[error("plugin {plugin} failed: {message}")]
+    Failed { plugin: String, message: String },
+}
+
+impl Plugins {
+    /// Compiles the plugin modules at `paths`, which may be binary or text WebAssembly, and registers what they declare
+    pub async fn load(paths: &[PathBuf], limits: PluginLimits) -> Result<Self, Error> {
+        let mut sources = Vec::new();
+        for path in paths {
+            let plugin = path.display().to_string();
+            match tokio::fs::read(path).await {
+                Ok(bytes) => sources.push((plugin, bytes)),
+                Err(err) => {
+                    let message = err.to_string();
+                    return Err(Error::Load { plugin, message });
+                }
+            }
+        }
+        Self::compile(sources, limits).await
+    }
+
+    async fn compile(sources: Vec<(String, Vec<u8>)>, limits: PluginLimits) -> Result<Self, Error> {
+        if sources.is_empty() {
+            return Ok(Self::default());
+        }
+        let mut config = wasmtime::Config::new();
+        config.async_support(true).consume_fuel(true);
+        let linker = Engine::new(&config)
+            .and_then(|engine| linker(&engine))
+            .map_err(|err| Error::Load {
+                plugin: "engine".to_string(),
+                message: format!("{err:#}"),
+            })?;
+        let linker = Arc::new(linker);
+
+        let mut commands = HashMap::new();
+        let mut kinds = HashMap::new();
+        for (name, bytes) in sources {
+            let module = Module::new(linker.engine(), &bytes).map_err(|err| Error::Load {
+                plugin: name.clone(),
+                message: format!("{err:#}"),
+            })?;
+            let plugin = Arc::new(Plugin {
+                name,
+                module,
+                linker: linker.clone(),
+                limits,
+            });
+            let manifest = plugin.call(None, "aether_manifest", &[]).await?;
+            let manifest: Manifest =
+                serde_json::from_value(manifest).map_err(|err| Error::Load {
+                    plugin: plugin.name.clone(),
+                    message: format!("invalid manifest: {err}"),
+                })?;
+            for command in &manifest.commands {
+                if commands.insert(command.clone(), plugin.clone()).is_some() {
+                    return Err(Error::Conflict(command.clone()));
+                }
+            }
+            for kind in &manifest.types {
+                if kinds.insert(kind.clone(), plugin.clone()).is_some() {
+                    return Err(Error::Conflict(kind.clone()));
+                }
+            }
+            info!(
+                plugin = plugin.name,
+                commands = ?manifest.commands,
+                types = ?manifest.types,
+                "Loaded plugin"
+            );
+        }
+        Ok(Self {
+            commands: Arc::new(commands),
+            kinds: Arc::new(kinds),
+        })
+    }
+
+    /// Runs a plugin command against `table`, broadcasting what it publishes as `client_id`
+    pub async fn command(
+        &self,
+        database: &Database,
+        table: &str,
+        name: &str,
+        args: &serde_json::Value,
+        client_id: &str,
+    ) -> Result<serde_json::Value, Error> {
+        let plugin = self
+            .commands
+            .get(name)
+            .ok_or_else(|| Error::NoSuchCommand(name.to_string()))?;
+        let context = Context {
+            database: database.clone(),
+            table: table.to_string(),
+            client_id: client_id.to_string(),
+        };
+        let args = serde_json::to_vec(args).expect("JSON values always serialize");
+        plugin
+            .call(Some(context), "aether_command", &[name.as_bytes(), &args])
+            .await
+    }
+
+    /// Has the plugin that registered a custom data kind check its payload, returning the data to store
+    ///
+    /// Data that isn't custom is returned as it is.
+    pub async fn check(&self, data: Data) -> Result<Data, Error> {
+        let Data::Custom { kind, data } = data else {
+            return Ok(data);
+        };
+        let plugin = self
+            .kinds
+            .get(&kind)
+            .ok_or_else(|| Error::NoSuchKind(kind.clone()))?;
+        let payload = serde_json::to_vec(&data).expect("JSON values always serialize");
+        let data = plugin
+            .call(None, "aether_data", &[kind.as_bytes(), &payload])
+            .await?;
+        Ok(Data::Custom { kind, data })
+    }
+}
+
+#[derive(Deserialize)]
+struct Manifest {
+    #[serde(default)]
+    commands: Vec<String>,
+
+    #[serde(default)]
+    types: Vec<String>,
+}
+
+struct Plugin {
+    name: String,
+    module: Module,
+    linker: Arc<Linker<Host>>,
+    limits: PluginLimits,
+}
+
+impl Plugin {
+    /// Calls an export of a fresh instance with `inputs` written into its memory, returning the JSON it points to
+    async fn call(
+        &self,
+        context: Option<Context>,
+        function: &str,
+        inputs: &[&[u8]],
+    ) -> Result<serde_json::Value, Error> {
+        let host = Host {
+            context,
+            limits: StoreLimitsBuilder::new()
+                .memory_size(self.limits.max_memory)
+                .build(),
+            error: None,
+        };
+        let mut store = Store::new(self.linker.engine(), host);
+        store.limiter(|host| &mut host.limits);
+
+        let result = async {
+            store.set_fuel(self.limits.fuel)?;
+            store.fuel_async_yield_interval(Some(YIELD_INTERVAL))?;
+            let instance = self
+                .linker
+                .instantiate_async(&mut store, &self.module)
+                .await?;
+            let guest = Guest::of_instance(&mut store, instance)?;
+            let mut params = Vec::new();
+            for input in inputs {
+                let (pointer, length) = guest.write(&mut store, input).await?;
+                params.extend([Val::I32(pointer), Val::I32(length)]);
+            }
+            let func = instance
+                .get_func(&mut store, function)
+                .with_context(|| format!("plugin does not export {function}"))?;
+            let mut results = [Val::I64(0)];
+            func.call_async(&mut store, &params, &mut results).await?;
+            let packed = results[0].i64().context("expected an i64 result")?;
+            guest.read(&store, unpack(packed))
+        }
+        .await;
+
+        if let Some(message) = store.into_data().error {
+            return Err(self.failed(message));
+        }
+        let bytes = result.map_err(|err| match err.downcast_ref::<Trap>() {
+            Some(Trap::OutOfFuel) => Error::OutOfFuel(self.name.clone()),
+            _ => self.failed(format!("{err:#}")),
+        })?;
+        serde_json::from_slice(&bytes)
+            .map_err(|err| self.failed(format!("returned invalid JSON: {err}")))
+    }
+
+    fn failed(&self, message: String) -> Error {
+        Error::Failed {
+            plugin: self.name.clone(),
+            message,
+        }
+    }
+}
+
+/// What a plugin instance's host functions can reach
+struct Host {
+    // Only commands can read and write keys
+    context: Option<Context>,
+    limits: StoreLimits,
+    error: Option<String>,
+}
+
+#[derive(Clone)]
+struct Context {
+    database: Database,
+    table: String,
+    client_id: String,
+}
+
+impl Host {
+    fn context(&self) -> anyhow::Result<Context> {
+        self.context
+            .clone()
+            .ok_or_else(|| anyhow!("only commands can use keys"))
+    }
+}
+
+/// A plugin instance's memory and allocator, to pass bytes in and out of it
+struct Guest {
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+}
+
+impl Guest {
+    fn of_instance(store: &mut Store<Host>, instance: Instance) -> anyhow::Result<Self> {
+        let memory = instance
+            .get_memory(&mut *store, "memory")
+            .context("plugin does not export memory")?;
+        let alloc = instance.get_typed_func(&mut *store, "aether_alloc")?;
+        Ok(Self { memory, alloc })
+    }
+
+    fn of_caller(caller: &mut Caller<'_, Host>) -> anyhow::Result<Self> {
+        let memory = caller
+            .get_export("memory")
+            .and_then(Extern::into_memory)
+            .context("plugin does not export memory")?;
+        let alloc = caller
+            .get_export("aether_alloc")
+            .and_then(Extern::into_func)
+            .context("plugin does not export aether_alloc")?
+            .typed(&*caller)?;
+        Ok(Self { memory, alloc })
+    }
+
+    async fn write(
+        &self,
+        mut store: impl AsContextMut<Data = Host>,
+        bytes: &[u8],
+    ) -> anyhow::Result<(i32, i32)> {
+        let length = i32::try_from(bytes.len())?;
+        let pointer = self.alloc.call_async(&mut store, length).await?;
+        self.memory
+            .write(&mut store, pointer as u32 as usize, bytes)?;
+        Ok((pointer, length))
+    }
+
+    // The range is checked against the memory before anything is copied, so a plugin can't make the server allocate
+    // more than the memory it has
+    fn read(
+        &self,
+        store: impl AsContext,
+        (pointer, length): (i32, i32),
+    ) -> anyhow::Result<Vec<u8>> {
+        let start = pointer as u32 as usize;
+        let end = start + length as u32 as usize;
+        let bytes = self
+            .memory
+            .data(store.as_context())
+            .get(start..end)
+            .context("plugin passed bytes outside its memory")?;
+        Ok(bytes.to_vec())
+    }
+
+    fn read_string(&self, store: impl AsContext, slice: (i32, i32)) -> anyhow::Result<String> {
+        Ok(String::from_utf8(self.read(store, slice)?)?)
+    }
+}
+
+fn pack((pointer, length): (i32, i32)) -> i64 {
+    (i64::from(pointer as u32) << 32) | i64::from(length as u32)
+}
+
+fn unpack(packed: i64) -> (i32, i32) {
+    ((packed as u64 >> 32) as u32 as i32, packed as u32 as i32)
+}
+
+fn linker(engine: &Engine) -> anyhow::Result<Linker<Host>> {
+    let mut linker = Linker::new(engine);
+    linker
+        .func_wrap_async(
+            "aether",
+            "get",
+            |mut caller: Caller<'_, Host>, key: (i32, i32)| {
+                Box::new(async move {
+                    let guest = Guest::of_caller(&mut caller)?;
+                    let key = guest.read_string(&caller, key)?;
+                    let context = caller.data().context()?;
+                    let Some(value) = context.database.get(&context.table, &key).await? else {
+                        return Ok(0);
+                    };
+                    let data = serde_json::to_vec(&value.data)?;
+                    Ok(pack(guest.write(&mut caller, &data).await?))
+                })
+            },
+        )?
+        .func_wrap_async(
+            "aether",
+            "set",
+            |mut caller: Caller<'_, Host>,
+             (key, key_length, data, data_length): (i32, i32, i32, i32)| {
+                Box::new(async move {
+                    let guest = Guest::of_caller(&mut caller)?;
+                    let key = guest.read_string(&caller, (key, key_length))?;
+                    let data: Data =
+                        serde_json::from_slice(&guest.read(&caller, (data, data_length))?)?;
+                    let context = caller.data().context()?;
+                    let value = Value { data, expiry: None };
+                    context.database.set(&context.table, key, value).await?;
+                    Ok(())
+                })
+            },
+        )?
+        .func_wrap_async(
+            "aether",
+            "delete",
+            |mut caller: Caller<'_, Host>, key: (i32, i32)| {
+                Box::new(async move {
+                    let guest = Guest::of_caller(&mut caller)?;
+                    let key = guest.read_string(&caller, key)?;
+                    let context = caller.data().context()?;
+                    context.database.delete(&context.table, key).await?;
+                    Ok(())
+                })
+            },
+        )?
+        .func_wrap(
+            "aether",
+            "publish",
+            |mut caller: Caller<'_, Host>,
+             channel: i32,
+             channel_length: i32,
+             message: i32,
+             message_length: i32|
+             -> anyhow::Result<()> {
+                let guest = Guest::of_caller(&mut caller)?;
+                let channel = guest.read_string(&caller, (channel, channel_length))?;
+                let message = guest.read_string(&caller, (message, message_length))?;
+                let context = caller.data().context()?;
+                let message = BroadcastMessage::new(context.client_id, channel, message);
+                context.database.broker.publish(message);
+                Ok(())
+            },
+        )?
+        .func_wrap(
+            "aether",
+            "error",
+            |mut caller: Caller<'_, Host>, message: i32, length: i32| -> anyhow::Result<()> {
+                let guest = Guest::of_caller(&mut caller)?;
+                let message = guest.read_string(&caller, (message, length))?;
+                caller.data_mut().error = Some(message);
+                Ok(())
+            },
+        )?;
+    Ok(linker)
+}
+
+#[cfg(test)]
+mod tests {
+    use aether_common::db::DEFAULT_TABLE;
+
+    use super::*;
+    use crate::{
+        config::Config,
+        db::{self, SubscriptionOptions},
+    };
+
+    // Registers `greet`, which sets `greeting` once, `spin`, which never returns, and `upper`,
+    // a string data type stored in upper case
+    const PLUGIN: &str = r#"
+        (module
+          (import "aether" "get" (func $get (param i32 i32) (result i64)))
+          (import "aether" "set" (func $set (param i32 i32 i32 i32)))
+          (import "aether" "publish" (func $publish (param i32 i32 i32 i32)))
+          (import "aether" "error" (func $error (param i32 i32)))
+          (memory (export "memory") 1)
+          (global $next (mut i32) (i32.const 1024))
+          (data (i32.const 0) "{\"commands\":[\"greet\",\"spin\"],\"types\":[\"upper\"]}")
+          (data (i32.const 64) "greeting")
+          (data (i32.const 80) "{\"string\":\"hi\"}")
+          (data (i32.const 96) "greetings")
+          (data (i32.const 112) "hi")
+          (data (i32.const 128) "\"greeted\"")
+          (data (i32.const 144) "already greeted")
+          (data (i32.const 160) "upper takes a string")
+          (func (export "aether_alloc") (param $length i32) (result i32)
+            (local $pointer i32)
+            (local.set $pointer (global.get $next))
+            (global.set $next (i32.add (global.get $next) (local.get $length)))
+            (local.get $pointer))
+          (func (export "aether_manifest") (result i64)
+            (i64.const 47))
+          (func (export "aether_command") (param $name i32) (param $name_length i32)
+            (param $args i32) (param $args_length i32) (result i64)
+            (if (i32.eq (local.get $name_length) (i32.const 4))
+              (then (loop $spin (br $spin))))
+            (if (i64.ne (call $get (i32.const 64) (i32.const 8)) (i64.const 0))
+              (then
+                (call $error (i32.const 144) (i32.const 15))
+                (return (i64.const 0))))
+            (call $set (i32.const 64) (i32.const 8) (i32.const 80) (i32.const 15))
+            (call $publish (i32.const 96) (i32.const 9) (i32.const 112) (i32.const 2))
+            (i64.or (i64.shl (i64.const 128) (i64.const 32)) (i64.const 9)))
+          (func (export "aether_data") (param $kind i32) (param $kind_length i32)
+            (param $data i32) (param $data_length i32) (result i64)
+            (local $i i32)
+            (local $byte i32)
+            (if (i32.ne (i32.load8_u (local.get $data)) (i32.const 34))
+              (then
+                (call $error (i32.const 160) (i32.const 20))
+                (return (i64.const 0))))
+            (block $done
+              (loop $next
+                (br_if $done (i32.ge_u (local.get $i) (local.get $data_length)))
+                (local.set $byte (i32.load8_u (i32.add (local.get $data) (local.get $i))))
+                (if (i32.and
+                      (i32.ge_u (local.get $byte) (i32.const 97))
+                      (i32.le_u (local.get $byte) (i32.const 122)))
+                  (then
+                    (i32.store8
+                      (i32.add (local.get $data) (local.get $i))
+                      (i32.sub (local.get $byte) (i32.const 32)))))
+                (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                (br $next)))
+            (i64.or
+              (i64.shl (i64.extend_i32_u (local.get $data)) (i64.const 32))
+              (i64.extend_i32_u (local.get $data_length)))))
+    "#;
+
+    #[tokio::test]
+    async fn test_plugin_out_of_bounds() {
+        // Claims its manifest is 4 GiB long
+        const PLUGIN: &str = r#"
+            (module
+              (memory (export "memory") 1)
+              (func (export "aether_alloc") (param i32) (result i32) (i32.const 0))
+              (func (export "aether_manifest") (result i64) (i64.const 0xffffffff)))
+        "#;
+        let sources = vec![("huge".to_string(), PLUGIN.as_bytes().to_vec())];
+        let limits = PluginLimits {
+            max_memory: 1024 * 1024,
+            fuel: 100_000,
+        };
+        assert!(matches!(
+            Plugins::compile(sources, limits).await,
+            Err(Error::Failed { message, .. }) if message.contains("outside its memory")
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_plugins() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("greeter.wat");
+        std::fs::write(&path, PLUGIN).unwrap();
+        let config = Config {
+            dir: dir.path().to_path_buf(),
+            plugins: vec![path],
+            plugin_fuel: 100_000,
+            ..Config::default()
+        };
+        let database = Database::open(&config).await.unwrap();
+        let mut receiver = database.broker.mailbox("listener".to_string());
+        receiver.subscribe("greetings".to_string(), SubscriptionOptions::default());
+        let command = |name: &'static str| {
+            let database = database.clone();
+            async move {
+                database
+                    .plugin_command(DEFAULT_TABLE, name, &serde_json::Value::Null, "client")
+                    .await
+            }
+        };
+
+        assert_eq!(command("greet").await.unwrap(), "greeted");
+        let greeting = database.get(DEFAULT_TABLE, "greeting").await.unwrap();
+        assert!(matches!(greeting.unwrap().data, Data::String(string) if string == "hi"));
+        let delivery = receiver.recv().await.unwrap();
+        let message = delivery.message().unwrap();
+        assert_eq!(
+            (message.channel.as_str(), message.message.as_str()),
+            ("greetings", "hi")
+        );
+        assert!(matches!(
+            command("greet").await,
+            Err(db::Error::Plugin(Error::Failed { message, .. })) if message == "already greeted"
+        ));
+        assert!(matches!(
+            command("spin").await,
+            Err(db::Error::Plugin(Error::OutOfFuel(_)))
+        ));
+        assert!(matches!(
+            command("missing").await,
+            Err(db::Error::Plugin(Error::NoSuchCommand(_)))
+        ));
+
+        let custom = |kind: &str, data| Value {
+            data: Data::Custom {
+                kind: kind.to_string(),
+                data,
+            },
+            expiry: None,
+        };
+        database
+            .set(
+                DEFAULT_TABLE,
+                "shout".to_string(),
+                custom("upper", serde_json::json!("hello")),
+            )
+            .await
+            .unwrap();
+        let shout = database.get(DEFAULT_TABLE, "shout").await.unwrap();
+        assert!(matches!(shout.unwrap().data, Data::Custom { data, .. } if data == "HELLO"));
+        assert!(matches!(
+            database
+                .set(
+                    DEFAULT_TABLE,
+                    "shout".to_string(),
+                    custom("upper", serde_json::json!(1))
+                )
+                .await,
+            Err(db::Error::Plugin(Error::Failed { .. }))
+        ));
+        assert!(matches!(
+            database
+                .set(
+                    DEFAULT_TABLE,
+                    "shout".to_string(),
+                    custom("lower", serde_json::json!("hello"))
+                )
+                .await,
+            Err(db::Error::Plugin(Error::NoSuchKind(_)))
+        ));
+    }
+}
@@ -0,0 +1,432 @@
+use std::cmp::Ordering;
+
+use aether_common::{
+    command::IndexQuery,
+    db::{Data, IndexDefinition, Value},
+};
+use serde_json::Value as Json;
+
+use crate::pattern::glob_match;
+
+mod parser;
+
+pub use parser::{parse, parse_filter};
+
+/// A parsed query over the JSON values of a table
+///
+/// Only `Data::Json` values are considered. Every other value is skipped.
+#[derive(Clone, Debug, Default)]
+pub struct Query {
+    /// Only keys matching this glob pattern are considered
+    pub pattern: Option<String>,
+
+    /// The fields to return, or the whole value if empty
+    pub projection: Vec<Path>,
+
+    pub filter: Option<Expr>,
+
+    pub order: Vec<(Path, Direction)>,
+
+    pub limit: Option<usize>,
+}
+
+/// A field within a JSON value, written `.address.city`
+#[derive(Clone, Debug)]
+pub struct Path {
+    /// The field names as written, joined with `.`
+    pub name: String,
+
+    /// The same field as a JSON pointer, like `/address/city`
+    pub pointer: String,
+}
+
+#[derive(Clone, Debug)]
+pub enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Compare {
+        left: Operand,
+        op: CompareOp,
+        right: Operand,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub enum Operand {
+    Path(Path),
+    Literal(Json),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Direction {
+    Ascending,
+    Descending,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("{message} at position {position}")]
+    Syntax { message: String, position: usize },
+}
+
+impl Error {
+    fn syntax(message: impl Into<String>, position: usize) -> Self {
+        Self::Syntax {
+            message: message.into(),
+            position,
+        }
+    }
+}
+
+impl Path {
+    fn new(segments: Vec<String>) -> Self {
+        let pointer = segments
+            .iter()
+            .map(|segment| format!("/{}", segment.replace('~', "~0").replace('/', "~1")))
+            .collect();
+        Self {
+            name: segments.join("."),
+            pointer,
+        }
+    }
+
+    fn get<'a>(&self, json: &'a Json) -> Option<&'a Json> {
+        json.pointer(&self.pointer)
+    }
+}
+
+impl CompareOp {
+    // The same comparison with its operands swapped
+    fn flip(self) -> Self {
+        match self {
+            Self::Lt => Self::Gt,
+            Self::Le => Self::Ge,
+            Self::Gt => Self::Lt,
+            Self::Ge => Self::Le,
+            op => op,
+        }
+    }
+}
+
+impl Query {
+    /// Finds an index that can narrow down the keys to look at
+    ///
+    /// An index can be used when a comparison joined to the rest of the filter by `and` compares its pointer
+    /// with a literal and the index covers every key the query is over.
+    /// Whatever the index returns must still be checked against the whole filter.
+    pub fn index_lookup<'a>(
+        &self,
+        indexes: impl IntoIterator<Item = &'a IndexDefinition>,
+    ) -> Option<(String, IndexQuery)> {
+        let filter = self.filter.as_ref()?;
+        let mut comparisons = Vec::new();
+        conjuncts(filter, &mut comparisons);
+        let pattern = self.pattern.as_deref().unwrap_or("*");
+        let indexes: Vec<&IndexDefinition> = indexes
+            .into_iter()
+            .filter(|index| index.pattern == pattern)
+            .collect();
+
+        comparisons.into_iter().find_map(|(path, op, literal)| {
+            let index = indexes.iter().find(|index| index.pointer == path.pointer)?;
+            let query = match op {
+                CompareOp::Eq => IndexQuery::Value(literal.clone()),
+                CompareOp::Gt | CompareOp::Ge => IndexQuery::Range {
+                    start: Some(literal.clone()),
+                    end: None,
+                },
+                CompareOp::Lt => IndexQuery::Range {
+                    start: None,
+                    end: Some(literal.clone()),
+                },
+                // Index ranges exclude their end, so these would need the whole index anyway
+                CompareOp::Le | CompareOp::Ne => return None,
+            };
+            Some((index.name.clone(), query))
+        })
+    }
+
+    /// Whether an entry is one the query returns, before it is limited
+    pub fn matches(&self, key: &str, value: &Value) -> bool {
+        match &value.data {
+            Data::Json(json) => self.matches_json(key, json),
+            _ => false,
+        }
+    }
+
+    fn matches_json(&self, key: &str, json: &Json) -> bool {
+        self.pattern
+            .as_deref()
+            .is_none_or(|pattern| glob_match(pattern, key))
+            && self.filter.as_ref().is_none_or(|expr| expr.matches(json))
+    }
+
+    /// Filters, sorts, limits and projects `entries`, which must include every key the query could match
+    pub fn execute<'a>(
+        &self,
+        entries: impl IntoIterator<Item = (&'a str, &'a Value)>,
+    ) -> Vec<(String, Value)> {
+        let mut matches: Vec<(&str, &Value, &Json)> = entries
+            .into_iter()
+            .filter_map(|(key, value)| match &value.data {
+                Data::Json(json) => Some((key, value, json)),
+                _ => None,
+            })
+            .filter(|(key, _, json)| self.matches_json(key, json))
+            .collect();
+
+        matches.sort_by(|(a_key, _, a), (b_key, _, b)| {
+            self.order
+                .iter()
+                .map(|(path, direction)| {
+                    let ordering = compare_json(path.get(a), path.get(b));
+                    match direction {
+                        Direction::Ascending => ordering,
+                        Direction::Descending => ordering.reverse(),
+                    }
+                })
+                .find(|ordering| ordering.is_ne())
+                // Break ties by key so results don't depend on how the table is stored
+                .unwrap_or_else(|| a_key.cmp(b_key))
+        });
+
+        matches
+            .into_iter()
+            .take(self.limit.unwrap_or(usize::MAX))
+            .map(|(key, value, json)| {
+                let data = match self.projection.is_empty() {
+                    true => json.clone(),
+                    false => Json::Object(
+                        self.projection
+                            .iter()
+                            .map(|path| {
+                                let field = path.get(json).cloned().unwrap_or(Json::Null);
+                                (path.name.clone(), field)
+                            })
+                            .collect(),
+                    ),
+                };
+                let value = Value {
+                    data: Data::Json(data),
+                    expiry: value.expiry,
+                };
+                (key.to_string(), value)
+            })
+            .collect()
+    }
+}
+
+impl Expr {
+    fn matches(&self, json: &Json) -> bool {
+        match self {
+            Self::And(left, right) => left.matches(json) && right.matches(json),
+            Self::Or(left, right) => left.matches(json) || right.matches(json),
+            Self::Not(expr) => !expr.matches(json),
+            Self::Compare { left, op, right } => {
+                let (Some(left), Some(right)) = (left.resolve(json), right.resolve(json)) else {
+                    // Missing fields never compare, like SQL's null
+                    return false;
+                };
+                compare(left, *op, right)
+            }
+        }
+    }
+}
+
+impl Operand {
+    fn resolve<'a>(&'a self, json: &'a Json) -> Option<&'a Json> {
+        match self {
+            Self::Path(path) => path.get(json),
+            Self::Literal(literal) => Some(literal),
+        }
+    }
+}
+
+// Collects the comparisons of a path with a literal that must all hold for `expr` to match
+fn conjuncts<'a>(expr: &'a Expr, comparisons: &mut Vec<(&'a Path, CompareOp, &'a Json)>) {
+    match expr {
+        Expr::And(left, right) => {
+            conjuncts(left, comparisons);
+            conjuncts(right, comparisons);
+        }
+        Expr::Compare {
+            left: Operand::Path(path),
+            op,
+            right: Operand::Literal(literal),
+        } => comparisons.push((path, *op, literal)),
+        Expr::Compare {
+            left: Operand::Literal(literal),
+            op,
+            right: Operand::Path(path),
+        } => comparisons.push((path, op.flip(), literal)),
+        _ => {}
+    }
+}
+
+// Values of different types are never equal and never ordered
+fn compare(left: &Json, op: CompareOp, right: &Json) -> bool {
+    let ordering = match (left, right) {
+        (Json::Null, Json::Null) => Some(Ordering::Equal),
+        (Json::Bool(left), Json::Bool(right)) => Some(left.cmp(right)),
+        (Json::Number(left), Json::Number(right)) => left
+            .as_f64()
+            .zip(right.as_f64())
+            .and_then(|(left, right)| left.partial_cmp(&right)),
+        (Json::String(left), Json::String(right)) => Some(left.cmp(right)),
+        (left, right) => (left == right).then_some(Ordering::Equal),
+    };
+    match (op, ordering) {
+        (CompareOp::Eq, ordering) => ordering == Some(Ordering::Equal),
+        (CompareOp::Ne, ordering) => ordering != Some(Ordering::Equal),
+        (_, None) => false,
+        (CompareOp::Lt, Some(ordering)) => ordering.is_lt(),
+        (CompareOp::Le, Some(ordering)) => ordering.is_le(),
+        (CompareOp::Gt, Some(ordering)) => ordering.is_gt(),
+        (CompareOp::Ge, Some(ordering)) => ordering.is_ge(),
+    }
+}
+
+/// Orders any two JSON values for sorting
+///
+/// Missing fields sort first, then `null`, booleans, numbers, strings, arrays and objects.
+fn compare_json(left: Option<&Json>, right: Option<&Json>) -> Ordering {
+    fn rank(json: Option<&Json>) -> u8 {
+        match json {
+            None => 0,
+            Some(Json::Null) => 1,
+            Some(Json::Bool(_)) => 2,
+            Some(Json::Number(_)) => 3,
+            Some(Json::String(_)) => 4,
+            Some(Json::Array(_)) => 5,
+            Some(Json::Object(_)) => 6,
+        }
+    }
+    match (left, right) {
+        (Some(Json::Bool(left)), Some(Json::Bool(right))) => left.cmp(right),
+        (Some(Json::Number(left)), Some(Json::Number(right))) => {
+            let left = left.as_f64().unwrap_or(f64::NAN);
+            let right = right.as_f64().unwrap_or(f64::NAN);
+            left.total_cmp(&right)
+        }
+        (Some(Json::String(left)), Some(Json::String(right))) => left.cmp(right),
+        // Arrays and objects only need a consistent order
+        (Some(left @ (Json::Array(_) | Json::Object(_))), Some(right))
+            if rank(Some(left)) == rank(Some(right)) =>
+        {
+            left.to_string().cmp(&right.to_string())
+        }
+        (left, right) => rank(left).cmp(&rank(right)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    fn entries() -> Vec<(String, Value)> {
+        [
+            (
+                "users/1",
+                json!({ "name": "Ada", "age": 36, "city": "Oslo" }),
+            ),
+            (
+                "users/2",
+                json!({ "name": "Bo", "age": 31, "city": "Oslo" }),
+            ),
+            (
+                "users/3",
+                json!({ "name": "Cy", "age": 45, "city": "Bergen" }),
+            ),
+            (
+                "users/4",
+                json!({ "name": "Di", "age": 29, "city": "Oslo" }),
+            ),
+            (
+                "orders/1",
+                json!({ "name": "Ed", "age": 50, "city": "Oslo" }),
+            ),
+        ]
+        .into_iter()
+        .map(|(key, json)| {
+            let value = Value {
+                data: Data::Json(json),
+                expiry: None,
+            };
+            (key.to_string(), value)
+        })
+        .chain([(
+            "users/5".to_string(),
+            Value {
+                data: Data::Int(1),
+                expiry: None,
+            },
+        )])
+        .collect()
+    }
+
+    fn run(query: &str) -> Vec<(String, Value)> {
+        let entries = entries();
+        parse(query)
+            .unwrap()
+            .execute(entries.iter().map(|(key, value)| (key.as_str(), value)))
+    }
+
+    fn keys(results: &[(String, Value)]) -> Vec<&str> {
+        results.iter().map(|(key, _)| key.as_str()).collect()
+    }
+
+    #[test]
+    fn test_execute() {
+        let results = run(r#"from "users/*" where .age > 30 and .city == "Oslo" order by .age"#);
+        assert_eq!(keys(&results), ["users/2", "users/1"]);
+
+        let results = run(r#"where .city != "Oslo" or .age < 30 order by .age desc limit 1"#);
+        assert_eq!(keys(&results), ["users/3"]);
+
+        let results = run(r#"select .name where 40 < .age order by .name"#);
+        assert_eq!(keys(&results), ["users/3", "orders/1"]);
+        assert!(matches!(&results[0].1.data, Data::Json(json) if *json == json!({ "name": "Cy" })));
+
+        // Missing fields and mismatched types never match
+        assert!(run("where .missing == null").is_empty());
+        assert!(run(r#"where .age > "30""#).is_empty());
+    }
+
+    #[test]
+    fn test_index_lookup() {
+        let index = IndexDefinition {
+            name: "age".to_string(),
+            pattern: "users/*".to_string(),
+            pointer: "/age".to_string(),
+        };
+        let lookup = |query: &str| parse(query).unwrap().index_lookup([&index]);
+
+        assert!(matches!(
+            lookup(r#"from "users/*" where .city == "Oslo" and 30 < .age"#),
+            Some((
+                _,
+                IndexQuery::Range {
+                    start: Some(_),
+                    end: None
+                }
+            ))
+        ));
+        // The index doesn't cover every key the query is over
+        assert!(lookup("where .age > 30").is_none());
+        // Either side of an `or` might match without the indexed field
+        assert!(lookup(r#"from "users/*" where .age > 30 or .city == "Oslo""#).is_none());
+    }
+}
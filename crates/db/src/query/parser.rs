@@ -0,0 +1,388 @@
+use super::{CompareOp, Direction, Error, Expr, Operand, Path, Query};
+
+/// Parses the text of a query
+///
+/// ```text
+/// query      := ["from" string] ["select" path ("," path)*] ["where" expr]
+///               ["order" "by" path ["asc" | "desc"] ("," path ["asc" | "desc"])*] ["limit" integer]
+/// expr       := and ("or" and)*
+/// and        := not ("and" not)*
+/// not        := "not" not | "(" expr ")" | operand [op operand]
+/// operand    := path | string | number | "true" | "false" | "null"
+/// path       := ("." name)+
+/// op         := "==" | "!=" | "<" | "<=" | ">" | ">="
+/// ```
+///
+/// Keywords are case insensitive.
+pub fn parse(text: &str) -> Result<Query, Error> {
+    let tokens = tokenize(text)?;
+    let mut parser = Parser {
+        tokens,
+        position: 0,
+        end: text.len(),
+    };
+    let query = parser.query()?;
+    match parser.peek() {
+        None => Ok(query),
+        Some((_, position)) => Err(Error::syntax("unexpected input", position)),
+    }
+}
+
+/// Parses the text of a filter on its own, the `expr` after `where` in a query
+pub fn parse_filter(text: &str) -> Result<Expr, Error> {
+    let tokens = tokenize(text)?;
+    let mut parser = Parser {
+        tokens,
+        position: 0,
+        end: text.len(),
+    };
+    let expr = parser.or()?;
+    match parser.peek() {
+        None => Ok(expr),
+        Some((_, position)) => Err(Error::syntax("unexpected input", position)),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Path(Vec<String>),
+    String(String),
+    Number(serde_json::Number),
+    Op(CompareOp),
+    LeftParen,
+    RightParen,
+    Comma,
+}
+
+fn tokenize(text: &str) -> Result<Vec<(Token, usize)>, Error> {
+    let mut tokens = Vec::new();
+    let mut chars = text.char_indices().peekable();
+    while let Some(&(start, c)) = chars.peek() {
+        let token = match c {
+            c if c.is_whitespace() => {
+                chars.next();
+                continue;
+            }
+            '(' => {
+                chars.next();
+                Token::LeftParen
+            }
+            ')' => {
+                chars.next();
+                Token::RightParen
+            }
+            ',' => {
+                chars.next();
+                Token::Comma
+            }
+            '.' => {
+                let mut segments = Vec::new();
+                while chars.next_if(|&(_, c)| c == '.').is_some() {
+                    let segment = take_while(&mut chars, is_name);
+                    if segment.is_empty() {
+                        return Err(Error::syntax("expected a field name", start));
+                    }
+                    segments.push(segment);
+                }
+                Token::Path(segments)
+            }
+            '"' | '\'' => {
+                chars.next();
+                Token::String(string(&mut chars, c, start)?)
+            }
+            '=' | '!' | '<' | '>' => {
+                chars.next();
+                let equals = chars.next_if(|&(_, c)| c == '=').is_some();
+                let op = match (c, equals) {
+                    ('=', true) => CompareOp::Eq,
+                    ('!', true) => CompareOp::Ne,
+                    ('<', false) => CompareOp::Lt,
+                    ('<', true) => CompareOp::Le,
+                    ('>', false) => CompareOp::Gt,
+                    ('>', true) => CompareOp::Ge,
+                    _ => return Err(Error::syntax("expected a comparison", start)),
+                };
+                Token::Op(op)
+            }
+            c if c == '-' || c.is_ascii_digit() => {
+                chars.next();
+                let rest = take_while(&mut chars, |c| {
+                    c.is_ascii_alphanumeric() || matches!(c, '.' | '+' | '-')
+                });
+                let number = format!("{c}{rest}");
+                match serde_json::from_str::<serde_json::Number>(&number) {
+                    Ok(number) => Token::Number(number),
+                    Err(_) => return Err(Error::syntax("invalid number", start)),
+                }
+            }
+            c if is_name(c) => Token::Word(take_while(&mut chars, is_name).to_lowercase()),
+            _ => return Err(Error::syntax("unexpected character", start)),
+        };
+        tokens.push((token, start));
+    }
+    Ok(tokens)
+}
+
+fn is_name(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+fn take_while(
+    chars: &mut std::iter::Peekable<std::str::CharIndices>,
+    predicate: impl Fn(char) -> bool,
+) -> String {
+    let mut taken = String::new();
+    while let Some((_, c)) = chars.next_if(|&(_, c)| predicate(c)) {
+        taken.push(c);
+    }
+    taken
+}
+
+// Reads the rest of a string after its opening quote, handling backslash escapes
+fn string(
+    chars: &mut std::iter::Peekable<std::str::CharIndices>,
+    quote: char,
+    start: usize,
+) -> Result<String, Error> {
+    let mut string = String::new();
+    loop {
+        match chars.next() {
+            None => return Err(Error::syntax("unterminated string", start)),
+            Some((_, c)) if c == quote => return Ok(string),
+            Some((position, '\\')) => match chars.next() {
+                Some((_, 'n')) => string.push('\n'),
+                Some((_, 't')) => string.push('\t'),
+                Some((_, c)) => string.push(c),
+                None => return Err(Error::syntax("unterminated string", position)),
+            },
+            Some((_, c)) => string.push(c),
+        }
+    }
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    position: usize,
+    // Where errors at the end of the input are reported
+    end: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<(&Token, usize)> {
+        self.tokens
+            .get(self.position)
+            .map(|(token, position)| (token, *position))
+    }
+
+    fn next(&mut self) -> Option<(Token, usize)> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn here(&self) -> usize {
+        self.peek().map_or(self.end, |(_, position)| position)
+    }
+
+    // Consumes the next token if it is the keyword `word`
+    fn keyword(&mut self, word: &str) -> bool {
+        let matches = matches!(self.peek(), Some((Token::Word(next), _)) if next == word);
+        if matches {
+            self.position += 1;
+        }
+        matches
+    }
+
+    fn expect_keyword(&mut self, word: &'static str) -> Result<(), Error> {
+        match self.keyword(word) {
+            true => Ok(()),
+            false => Err(Error::syntax(format!("expected `{word}`"), self.here())),
+        }
+    }
+
+    fn query(&mut self) -> Result<Query, Error> {
+        let mut query = Query::default();
+        if self.keyword("from") {
+            match self.next() {
+                Some((Token::String(pattern), _)) => query.pattern = Some(pattern),
+                _ => return Err(Error::syntax("expected a quoted key pattern", self.here())),
+            }
+        }
+        if self.keyword("select") {
+            query.projection = self.list(Self::path)?;
+        }
+        if self.keyword("where") {
+            query.filter = Some(self.or()?);
+        }
+        if self.keyword("order") {
+            self.expect_keyword("by")?;
+            query.order = self.list(|parser| {
+                let path = parser.path()?;
+                let direction = if parser.keyword("desc") {
+                    Direction::Descending
+                } else {
+                    parser.keyword("asc");
+                    Direction::Ascending
+                };
+                Ok((path, direction))
+            })?;
+        }
+        if self.keyword("limit") {
+            let position = self.here();
+            match self.next() {
+                Some((Token::Number(number), _)) if number.is_u64() => {
+                    query.limit = number.as_u64().map(|limit| limit as usize);
+                }
+                _ => return Err(Error::syntax("expected a whole number", position)),
+            }
+        }
+        Ok(query)
+    }
+
+    fn list<T>(
+        &mut self,
+        mut item: impl FnMut(&mut Self) -> Result<T, Error>,
+    ) -> Result<Vec<T>, Error> {
+        let mut items = vec![item(self)?];
+        while matches!(self.peek(), Some((Token::Comma, _))) {
+            self.position += 1;
+            items.push(item(self)?);
+        }
+        Ok(items)
+    }
+
+    fn path(&mut self) -> Result<Path, Error> {
+        let position = self.here();
+        match self.next() {
+            Some((Token::Path(segments), _)) => Ok(Path::new(segments)),
+            _ => Err(Error::syntax(
+                "expected a field path like `.name`",
+                position,
+            )),
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, Error> {
+        let mut expr = self.and()?;
+        while self.keyword("or") {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, Error> {
+        let mut expr = self.not()?;
+        while self.keyword("and") {
+            expr = Expr::And(Box::new(expr), Box::new(self.not()?));
+        }
+        Ok(expr)
+    }
+
+    fn not(&mut self) -> Result<Expr, Error> {
+        if self.keyword("not") {
+            return Ok(Expr::Not(Box::new(self.not()?)));
+        }
+        if matches!(self.peek(), Some((Token::LeftParen, _))) {
+            self.position += 1;
+            let expr = self.or()?;
+            return match self.next() {
+                Some((Token::RightParen, _)) => Ok(expr),
+                _ => Err(Error::syntax("expected `)`", self.here())),
+            };
+        }
+
+        let left = self.operand()?;
+        match self.peek() {
+            Some((Token::Op(op), _)) => {
+                let op = *op;
+                self.position += 1;
+                let right = self.operand()?;
+                Ok(Expr::Compare { left, op, right })
+            }
+            // A lone operand is true when it is the boolean `true`
+            _ => Ok(Expr::Compare {
+                left,
+                op: CompareOp::Eq,
+                right: Operand::Literal(serde_json::Value::Bool(true)),
+            }),
+        }
+    }
+
+    fn operand(&mut self) -> Result<Operand, Error> {
+        let position = self.here();
+        let operand = match self.next() {
+            Some((Token::Path(segments), _)) => Operand::Path(Path::new(segments)),
+            Some((Token::String(string), _)) => Operand::Literal(string.into()),
+            Some((Token::Number(number), _)) => Operand::Literal(number.into()),
+            Some((Token::Word(word), _)) => match word.as_str() {
+                "true" => Operand::Literal(true.into()),
+                "false" => Operand::Literal(false.into()),
+                "null" => Operand::Literal(serde_json::Value::Null),
+                _ => return Err(Error::syntax("expected a value or field path", position)),
+            },
+            _ => return Err(Error::syntax("expected a value or field path", position)),
+        };
+        Ok(operand)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let query = parse(
+            r#"FROM "users/*" select .name, .address.city where .age > 30 and not (.city == "Oslo" or .retired) order by .age desc, .name limit 10"#,
+        )
+        .unwrap();
+        assert_eq!(query.pattern.as_deref(), Some("users/*"));
+        assert_eq!(query.projection[1].pointer, "/address/city");
+        assert_eq!(query.order.len(), 2);
+        assert!(matches!(query.order[0].1, Direction::Descending));
+        assert!(matches!(query.order[1].1, Direction::Ascending));
+        assert_eq!(query.limit, Some(10));
+        let Some(Expr::And(left, right)) = query.filter else {
+            panic!("expected a conjunction");
+        };
+        assert!(matches!(
+            *left,
+            Expr::Compare {
+                op: CompareOp::Gt,
+                ..
+            }
+        ));
+        assert!(matches!(*right, Expr::Not(_)));
+
+        assert!(matches!(
+            parse_filter(".age >= 18 or .admin"),
+            Ok(Expr::Or(_, _))
+        ));
+        assert!(parse_filter(".age > 18 limit 1").is_err());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(matches!(
+            parse("where .age >"),
+            Err(Error::Syntax { position: 12, .. })
+        ));
+        assert!(matches!(
+            parse("where .name == \"unterminated"),
+            Err(Error::Syntax { position: 15, .. })
+        ));
+        assert!(matches!(
+            parse("limit -1"),
+            Err(Error::Syntax { position: 6, .. })
+        ));
+        assert!(matches!(
+            parse("order .age"),
+            Err(Error::Syntax { position: 6, .. })
+        ));
+        assert!(matches!(
+            parse("where .a == 1 extra"),
+            Err(Error::Syntax { position: 14, .. })
+        ));
+    }
+}
@@ -0,0 +1,419 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::{Arc, Mutex, PoisonError, RwLock},
+    time::{Duration, Instant},
+};
+
+use aether_common::db::{Data, Value};
+use rhai::{
+    packages::{Package, StandardPackage},
+    Array, Dynamic, Engine, EvalAltResult, Module, Scope, Shared, AST,
+};
+use sha1::{Digest, Sha1};
+use time::OffsetDateTime;
+
+/// How much work a script may do before it is stopped
+#[derive(Clone, Copy, Debug)]
+pub struct ScriptLimits {
+    /// The most Rhai operations a script may run
+    pub max_operations: u64,
+
+    /// How long a script may run for
+    pub timeout: Duration,
+}
+
+// How large the strings, arrays and maps a script builds may grow, so a runaway script can't exhaust memory
+// before it runs out of operations
+const MAX_STRING_SIZE: usize = 4 * 1024 * 1024;
+const MAX_ARRAY_SIZE: usize = 64 * 1024;
+const MAX_MAP_SIZE: usize = 64 * 1024;
+
+// How deeply a script's functions may call each other
+const MAX_CALL_LEVELS: usize = 64;
+
+/// Compiled Rhai scripts, cached by the SHA-1 of their source
+///
+/// Scripts read and write the keys they are given through `db.get`, `db.set` and `db.delete`,
+/// and broadcast through `db.publish`. They see `keys` and `args` as arrays.
+#[derive(Clone)]
+pub struct Scripts {
+    cache: Arc<RwLock<HashMap<String, Arc<AST>>>>,
+    // The standard library, shared by the engine made for each run
+    package: Shared<Module>,
+    limits: ScriptLimits,
+}
+
+/// What running a script did
+#[derive(Debug)]
+pub struct Outcome {
+    /// The value the script evaluated to
+    pub result: serde_json::Value,
+
+    /// The new value of each key the script wrote to, or `None` if it deleted it
+    pub writes: Vec<(String, Option<Value>)>,
+
+    /// The messages the script broadcast, as channels and messages
+    pub broadcasts: Vec<(String, String)>,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("could not compile script: {0}")]
+    Compile(String),
+
+    #[error("script failed: {0}")]
+    Run(String),
+
+    #[error("script ran more than {0} operations")]
+    TooManyOperations(u64),
+
+    #[error("script ran for more than {0:?}")]
+    TimedOut(Duration),
+
+    #[error("script built a value that is too large: {0}")]
+    TooLarge(String),
+}
+
+impl Scripts {
+    pub fn new(limits: ScriptLimits) -> Self {
+        Self {
+            cache: Arc::default(),
+            package: StandardPackage::new().as_shared_module(),
+            limits,
+        }
+    }
+
+    /// Compiles and caches a script, returning the SHA-1 it can be run by
+    pub fn load(&self, source: &str) -> Result<String, Error> {
+        let sha = hex::encode(Sha1::digest(source.as_bytes()));
+        if self.get(&sha).is_none() {
+            let ast = self
+                .engine()
+                .compile(source)
+                .map_err(|err| Error::Compile(err.to_string()))?;
+            let mut cache = self.cache.write().unwrap_or_else(PoisonError::into_inner);
+            cache.insert(sha.clone(), Arc::new(ast));
+        }
+        Ok(sha)
+    }
+
+    /// Gets a cached script by its SHA-1
+    pub fn get(&self, sha: &str) -> Option<Arc<AST>> {
+        let cache = self.cache.read().unwrap_or_else(PoisonError::into_inner);
+        cache.get(sha).cloned()
+    }
+
+    /// Runs a script over the current values of its keys
+    ///
+    /// Nothing is written here. The writes the script made are returned to be applied while the keys are still locked.
+    pub fn run(
+        &self,
+        ast: &AST,
+        values: HashMap<String, Option<Value>>,
+        keys: &[String],
+        args: &[serde_json::Value],
+    ) -> Result<Outcome, Error> {
+        let db = Db {
+            state: Arc::new(Mutex::new(State {
+                values,
+                written: BTreeSet::new(),
+                broadcasts: Vec::new(),
+            })),
+        };
+        let args = args
+            .iter()
+            .map(rhai::serde::to_dynamic)
+            .collect::<Result<Array, _>>()
+            .map_err(|err| Error::Run(err.to_string()))?;
+        let mut scope = Scope::new();
+        scope.push_constant("db", db.clone());
+        scope.push_constant(
+            "keys",
+            keys.iter().cloned().map(Dynamic::from).collect::<Array>(),
+        );
+        scope.push_constant("args", args);
+
+        let mut engine = self.engine();
+        let ScriptLimits {
+            max_operations,
+            timeout,
+        } = self.limits;
+        engine.set_max_operations(max_operations);
+        engine.set_max_string_size(MAX_STRING_SIZE);
+        engine.set_max_array_size(MAX_ARRAY_SIZE);
+        engine.set_max_map_size(MAX_MAP_SIZE);
+        engine.set_max_call_levels(MAX_CALL_LEVELS);
+        let deadline = Instant::now() + timeout;
+        engine.on_progress(move |_| (Instant::now() > deadline).then_some(Dynamic::UNIT));
+        register_db(&mut engine);
+
+        let result = engine
+            .eval_ast_with_scope::<Dynamic>(&mut scope, ast)
+            .map_err(|err| match *err {
+                EvalAltResult::ErrorTooManyOperations(_) => {
+                    Error::TooManyOperations(max_operations)
+                }
+                EvalAltResult::ErrorTerminated(..) => Error::TimedOut(timeout),
+                EvalAltResult::ErrorDataTooLarge(what, _) => Error::TooLarge(what),
+                err => Error::Run(err.to_string()),
+            })?;
+        let result =
+            rhai::serde::from_dynamic(&result).map_err(|err| Error::Run(err.to_string()))?;
+
+        drop(scope);
+        let mut state = db.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let state = &mut *state;
+        let writes = std::mem::take(&mut state.written)
+            .into_iter()
+            .map(|key| {
+                let value = state.values.remove(&key).flatten();
+                (key, value)
+            })
+            .collect();
+        Ok(Outcome {
+            result,
+            writes,
+            broadcasts: std::mem::take(&mut state.broadcasts),
+        })
+    }
+
+    fn engine(&self) -> Engine {
+        let mut engine = Engine::new_raw();
+        engine.register_global_module(self.package.clone());
+        engine
+    }
+}
+
+/// The `db` object scripts read and write through
+#[derive(Clone)]
+struct Db {
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    // The values of the script's keys as it has left them, `None` for missing keys
+    values: HashMap<String, Option<Value>>,
+    written: BTreeSet<String>,
+    broadcasts: Vec<(String, String)>,
+}
+
+impl Db {
+    fn with_key<T>(
+        &self,
+        key: &str,
+        f: impl FnOnce(&mut Option<Value>) -> T,
+    ) -> Result<T, Box<EvalAltResult>> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        let value = state
+            .values
+            .get_mut(key)
+            .ok_or_else(|| format!("key {key} was not passed to the script in keys"))?;
+        Ok(f(value))
+    }
+
+    fn get(&mut self, key: &str) -> Result<Dynamic, Box<EvalAltResult>> {
+        let value = self.with_key(key, |value| value.clone())?;
+        value.map_or(Ok(Dynamic::UNIT), |value| to_dynamic(&value.data))
+    }
+
+    fn set(
+        &mut self,
+        key: &str,
+        data: Dynamic,
+        expiry: Option<OffsetDateTime>,
+    ) -> Result<(), Box<EvalAltResult>> {
+        let data = from_dynamic(data)?;
+        self.with_key(key, |value| *value = Some(Value { data, expiry }))?;
+        self.written(key);
+        Ok(())
+    }
+
+    fn delete(&mut self, key: &str) -> Result<bool, Box<EvalAltResult>> {
+        let existed = self.with_key(key, |value| value.take().is_some())?;
+        self.written(key);
+        Ok(existed)
+    }
+
+    fn written(&self, key: &str) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.written.insert(key.to_string());
+    }
+
+    fn publish(&mut self, channel: &str, message: &str) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state
+            .broadcasts
+            .push((channel.to_string(), message.to_string()));
+    }
+}
+
+fn register_db(engine: &mut Engine) {
+    engine
+        .register_type_with_name::<Db>("Db")
+        .register_fn("get", Db::get)
+        .register_fn("set", |db: &mut Db, key: &str, data: Dynamic| {
+            db.set(key, data, None)
+        })
+        .register_fn(
+            "set",
+            |db: &mut Db, key: &str, data: Dynamic, seconds: i64| {
+                if seconds <= 0 {
+                    return Err(format!("expiry must be positive, got {seconds}").into());
+                }
+                let expiry = OffsetDateTime::now_utc() + time::Duration::seconds(seconds);
+                db.set(key, data, Some(expiry))
+            },
+        )
+        .register_fn("delete", Db::delete)
+        .register_fn("publish", Db::publish);
+}
+
+/// Converts stored data into a script value, with JSON becoming maps and arrays
+fn to_dynamic(data: &Data) -> Result<Dynamic, Box<EvalAltResult>> {
+    Ok(match data {
+        Data::String(string) => string.clone().into(),
+        Data::Int(int) => (*int).into(),
+        Data::Json(json) | Data::Custom { data: json, .. } => rhai::serde::to_dynamic(json)?,
+        Data::Vector(vector) => vector
+            .iter()
+            .map(|&value| Dynamic::from_float(value.into()))
+            .collect::<Array>()
+            .into(),
+    })
+}
+
+/// Converts a script value into data to store, with anything other than strings and integers stored as JSON
+fn from_dynamic(value: Dynamic) -> Result<Data, Box<EvalAltResult>> {
+    if value.is_string() {
+        return Ok(Data::String(value.into_string()?));
+    }
+    if value.is_int() {
+        return Ok(Data::Int(value.as_int()?));
+    }
+    Ok(Data::Json(rhai::serde::from_dynamic(&value)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scripts() -> Scripts {
+        Scripts::new(ScriptLimits {
+            max_operations: 10_000,
+            timeout: Duration::from_secs(1),
+        })
+    }
+
+    #[test]
+    fn test_run() {
+        let scripts = scripts();
+        let sha = scripts
+            .load(
+                r#"
+                let stock = db.get(keys[0]);
+                if stock < args[0] {
+                    return #{ reserved: false, stock: stock };
+                }
+                db.set(keys[0], stock - args[0]);
+                db.set(keys[1], #{ quantity: args[0] }, 60);
+                db.delete(keys[2]);
+                db.publish("inventory", "reserved");
+                #{ reserved: true, stock: stock - args[0] }
+                "#,
+            )
+            .unwrap();
+        assert_eq!(sha.len(), 40);
+        let keys = ["stock", "reservation", "cart"].map(String::from);
+        let values = HashMap::from([
+            (
+                "stock".to_string(),
+                Some(Value {
+                    data: Data::Int(5),
+                    expiry: None,
+                }),
+            ),
+            ("reservation".to_string(), None),
+            (
+                "cart".to_string(),
+                Some(Value {
+                    data: Data::String("items".to_string()),
+                    expiry: None,
+                }),
+            ),
+        ]);
+
+        let ast = scripts.get(&sha).unwrap();
+        let outcome = scripts
+            .run(&ast, values.clone(), &keys, &[serde_json::json!(3)])
+            .unwrap();
+        assert_eq!(
+            outcome.result,
+            serde_json::json!({ "reserved": true, "stock": 2 })
+        );
+        let writes: Vec<_> = outcome
+            .writes
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_ref().map(|value| &value.data)))
+            .collect();
+        assert!(matches!(
+            writes.as_slice(),
+            [
+                ("cart", None),
+                ("reservation", Some(Data::Json(_))),
+                ("stock", Some(Data::Int(2))),
+            ]
+        ));
+        assert!(outcome.writes[1].1.as_ref().unwrap().expiry.is_some());
+        assert_eq!(
+            outcome.broadcasts,
+            [("inventory".to_string(), "reserved".to_string())]
+        );
+
+        let outcome = scripts
+            .run(&ast, values, &keys, &[serde_json::json!(6)])
+            .unwrap();
+        assert_eq!(
+            outcome.result,
+            serde_json::json!({ "reserved": false, "stock": 5 })
+        );
+        assert!(outcome.writes.is_empty());
+    }
+
+    #[test]
+    fn test_limits() {
+        let scripts = scripts();
+        let run = |source: &str| {
+            let ast = scripts.get(&scripts.load(source)?).unwrap();
+            scripts.run(&ast, HashMap::new(), &[], &[])
+        };
+
+        assert!(matches!(
+            run("loop {}"),
+            Err(Error::TooManyOperations(10_000))
+        ));
+        assert!(matches!(
+            run("let s = \"x\"; loop { s += s }"),
+            Err(Error::TooLarge(_))
+        ));
+        assert!(matches!(
+            run("let a = [0]; loop { a += a }"),
+            Err(Error::TooLarge(_))
+        ));
+        assert!(matches!(
+            run("fn f() { f() } f()"),
+            Err(Error::Run(err)) if err.contains("Stack overflow")
+        ));
+        assert!(matches!(run("db.get(\"other\")"), Err(Error::Run(_))));
+        assert!(matches!(run("let x = ;"), Err(Error::Compile(_))));
+
+        let slow = Scripts::new(ScriptLimits {
+            max_operations: 0,
+            timeout: Duration::from_millis(50),
+        });
+        let ast = slow.get(&slow.load("loop {}").unwrap()).unwrap();
+        assert!(matches!(
+            slow.run(&ast, HashMap::new(), &[], &[]),
+            Err(Error::TimedOut(_))
+        ));
+    }
+}
@@ -1,6 +1,6 @@
 use aether_common::{
-    command::Command,
-    db::{BroadcastMessage, Value},
+    command::{Command, WatchTarget},
+    db::{BroadcastMessage, KeyEvent, Value, DEFAULT_TABLE},
     message::{Message, StatusMessage},
 };
 use axum::{
@@ -10,12 +10,33 @@ use axum::{
     },
     response::IntoResponse,
 };
-use futures::{SinkExt, StreamExt};
-use std::{borrow::Cow, collections::HashMap, net::SocketAddr, ops::ControlFlow, sync::Arc};
-use tokio::{select, sync::mpsc};
-use tracing::{debug, error, info, instrument};
+use futures::{stream::SplitSink, SinkExt, StreamExt};
+use std::{
+    borrow::Cow, collections::HashMap, net::SocketAddr, ops::ControlFlow, sync::Arc, time::Duration,
+};
+use tokio::{
+    select,
+    sync::{broadcast, mpsc},
+    time::{sleep_until, Instant},
+};
+use tokio_stream::{
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream},
+    StreamMap,
+};
+use tracing::{debug, error, info, instrument, warn};
+
+use crate::{
+    broker::{Delivery, Mailbox},
+    db::{self, SubscriptionOptions},
+    pattern::glob_match,
+    AppState, ClientID,
+};
+
+/// How long a snapshot is kept when the Snapshot command doesn't say
+const DEFAULT_SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(60);
 
-use crate::{db::SubscriptionOptions, AppState, ClientID};
+/// The snapshots a socket has open, keyed by id, with when each one times out
+type Snapshots = HashMap<String, (db::Snapshot, Instant)>;
 
 pub async fn ws_handler(
     ws: WebSocketUpgrade,
@@ -23,18 +44,21 @@ pub async fn ws_handler(
     Query(client_id): Query<ClientID>,
     State(state): State<Arc<AppState>>,
 ) -> impl IntoResponse {
+    // Only clients that chose their id can reconnect as the same client, so only their subscriptions are kept
+    let persistent = client_id.client_id.is_some();
     let client_id = client_id
         .client_id
         .unwrap_or(uuid::Uuid::new_v4().to_string());
     info!(?addr, ?client_id, "Connected on websocket");
     // finalize the upgrade process by returning upgrade callback.
     // we can customize the callback by sending additional info such as address.
-    ws.on_upgrade(move |socket| handle_socket(client_id, addr, state, socket))
+    ws.on_upgrade(move |socket| handle_socket(client_id, persistent, addr, state, socket))
 }
 
 #[instrument(skip(state, socket))]
 async fn handle_socket(
     client_id: String,
+    persistent: bool,
     socket_address: SocketAddr,
     state: Arc<AppState>,
     socket: WebSocket,
@@ -45,12 +69,28 @@ async fn handle_socket(
     let (mut socket_sender, mut socket_receiver) = socket.split();
     let (command_tx, mut command_rx) = mpsc::channel(crate::CHANNEL_SIZE);
     let (status_tx, mut status_rx) = mpsc::channel(crate::CHANNEL_SIZE);
-    let broadcast_sender = state.data_store.broadcast_channel.clone();
-    let mut broadcast_receiver = state.data_store.broadcast_channel.subscribe();
+    // Route the client's subscriptions, loaded from the database, to this socket
+    let mailbox = state.data_store.broker.mailbox(client_id.clone());
+    for (channel, options) in state.data_store.get_subscriptions(&client_id).await {
+        mailbox.subscribe(channel, options);
+    }
+    for (pattern, options) in state.data_store.get_pattern_subscriptions(&client_id).await {
+        mailbox.psubscribe(pattern, options);
+    }
 
-    // Load subscriptions from the database
-    let mut subscriptions: HashMap<String, SubscriptionOptions> =
-        state.data_store.get_subscriptions(&client_id).await;
+    let mut session = Session {
+        mailbox,
+        selected_table: DEFAULT_TABLE.to_string(),
+        snapshots: Snapshots::new(),
+        transaction: None,
+        watch: db::Watch::default(),
+        key_watches: Vec::new(),
+        live_queries: HashMap::new(),
+        changes: StreamMap::new(),
+        client_id,
+        persistent,
+        state,
+    };
 
     // Spawn a task that will push several messages to the client (does not matter what client does)
     let mut send_task = tokio::spawn(async move {
@@ -64,7 +104,7 @@ async fn handle_socket(
             debug!("Sent Ping");
         }
 
-        let client_id_json = serde_json::to_string(&Message::ClientId(client_id.clone()));
+        let client_id_json = serde_json::to_string(&Message::ClientId(session.client_id.clone()));
 
         match client_id_json {
             Ok(json) => {
@@ -80,78 +120,75 @@ async fn handle_socket(
             }
         };
 
+        let mut close = CloseFrame {
+            code: axum::extract::ws::close_code::NORMAL,
+            reason: Cow::from("Goodbye"),
+        };
+
         // Handle messages
         loop {
             select! {
                 possible_command = command_rx.recv() => {
                     match possible_command {
                         Some(command) => {
-                        match command {
-                            Command::SubscribeBroadcast{ channel, subscribe_to_self } => {
-                                let subscription = SubscriptionOptions { subscribe_to_self };
-                                subscriptions.insert(channel.clone(), subscription.clone());
-                                state.data_store.add_subscription(client_id.clone(), channel, subscription).await;
-                            },
-                            Command::UnsubscribeBroadcast(channel) => {
-                                subscriptions.remove(&channel);
-                                state.data_store.remove_subscription(client_id.clone(), &channel).await;
-
-                            },
-                            Command::SendBroadcast { channel, message } => match broadcast_sender.send(BroadcastMessage{ client_id: client_id.clone(), channel, message }) {
-                                Ok(_) => info!("Sent broadcast"),
-                                Err(err) => error!(?err, "Could not send broadcast"),
-                            },
-                            Command::Set { key, value } => {
-                                let db_value = Value::from(value.clone());
-                                state.data_store.db.set(key, db_value).await;
-
-                                // Return Ok
-                                let text = serde_json::to_string(&Message::Status(StatusMessage::Ok));
-                                // TODO: Handle this result beyond logging if possible
-                                match text {
-                                    Ok(text) => {
-                                        // TODO: Handle this result beyond logging if possible
-                                        let _ = socket_sender.send(WSMessage::Text(text)).await.inspect_err(|err| error!(?err, "Could not send set string message"));
-                                    },
-                                    Err(err) => error!(?err, "Could not serialize set string message"),
-                                }
-
-                            },
-                            Command::Get { key } => {
-                                let value = state.data_store.db.get(&key).await;
-                                let text = serde_json::to_string(&Message::Get(value));
-                                match text {
-                                    Ok(text) => {
-                                        // TODO: Handle this result beyond logging if possible
-                                        let _ = socket_sender.send(WSMessage::Text(text)).await.inspect_err(|err| error!(?err, "Could not send get string message"));
-                                    },
-                                    Err(err) => error!(?err, "Could not serialize broadcast message"),
-                                }
-
-                            },
-                        }},
+                            if let Some(message) = session.run(command).await {
+                                send_message(&mut socket_sender, &message).await;
+                            }
+                        },
                         None => {
-                            info!(?socket_address, client_id, "WS receiver closed");
+                            info!(?socket_address, session.client_id, "WS receiver closed");
                             break;
                         },
                     }
 
                 }
-                Ok(message) = broadcast_receiver.recv() => {
-                    // Write message
-                    if should_send_message(&client_id, &message, &subscriptions) {
-                        debug!(?message, "Sending message");
-                        let client_message = Message::BroadcastMessage(message);
-                        let text = serde_json::to_string(&client_message);
-                        match text {
-                            Ok(text) => {
-                                // TODO: Handle this result beyond logging if possible
-                                let _ = socket_sender.send(WSMessage::Text(text)).await.inspect_err(|err| error!(?err, "Could not send broadcast message"));
-                            },
-                            Err(err) => error!(?err, "Could not serialize broadcast message"),
+                delivery = session.mailbox.recv() => {
+                    let Some(delivery) = delivery else {
+                        warn!(?socket_address, session.client_id, "Disconnecting slow consumer");
+                        close = CloseFrame {
+                            code: axum::extract::ws::close_code::POLICY,
+                            reason: Cow::from("Too slow to keep up with broadcasts"),
+                        };
+                        break;
+                    };
+                    let message = match delivery {
+                        Delivery::Channel(message) => Message::BroadcastMessage(BroadcastMessage::clone(&message)),
+                        Delivery::Pattern(pattern, message) => Message::PatternMessage {
+                            pattern,
+                            message: BroadcastMessage::clone(&message),
+                        },
+                        Delivery::Lagged(skipped) => {
+                            warn!(session.client_id, skipped, "Client fell behind and skipped broadcasts");
+                            Message::Lagged { skipped }
+                        }
+                    };
+                    send_message(&mut socket_sender, &message).await;
+                }
+                Some((table, change)) = session.changes.next(), if !session.changes.is_empty() => {
+                    match change {
+                        Ok(change) => {
+                            for message in session.changed(table, change) {
+                                send_message(&mut socket_sender, &message).await;
+                            }
+                        }
+                        Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+                            warn!(session.client_id, table, skipped, "Watched keys and live queries fell behind and skipped changes");
+                            for message in session.lagged(table, skipped).await {
+                                send_message(&mut socket_sender, &message).await;
+                            }
                         }
                     }
                 }
+                _ = sleep_until(next_deadline(&session.snapshots)), if !session.snapshots.is_empty() => {
+                    let now = Instant::now();
+                    session.snapshots.retain(|snapshot_id, (_, deadline)| {
+                        let open = now < *deadline;
+                        if !open {
+                            debug!(snapshot_id, "Snapshot timed out");
+                        }
+                        open
+                    });
+                }
                 Some(error) = status_rx.recv() => {
                     debug!(?error, "Sending error");
                     let client_error = Message::Status(error);
@@ -169,13 +206,7 @@ async fn handle_socket(
 
         // TODO: Add close send for sanely closing clients on early returns above
         info!("Sending close");
-        if let Err(e) = socket_sender
-            .send(WSMessage::Close(Some(CloseFrame {
-                code: axum::extract::ws::close_code::NORMAL,
-                reason: Cow::from("Goodbye"),
-            })))
-            .await
-        {
+        if let Err(e) = socket_sender.send(WSMessage::Close(Some(close))).await {
             error!(?e, "Could not send Close, most likely okay");
         }
     });
@@ -215,6 +246,1113 @@ async fn handle_socket(
     info!("Websocket context destroyed");
 }
 
+/// The state of one socket that its commands read and change
+struct Session {
+    client_id: String,
+    // Whether the client's subscriptions are saved in the database for when it reconnects
+    persistent: bool,
+    state: Arc<AppState>,
+    // Receives broadcasts to the channels and patterns the client is subscribed to
+    mailbox: Mailbox,
+    // The table used by commands that don't name one
+    selected_table: String,
+    // Snapshots are released when they time out or the socket closes, if the client doesn't release them first
+    snapshots: Snapshots,
+    // The commands queued since Multi, and the keys watched for the next transaction
+    transaction: Option<Transaction>,
+    watch: db::Watch,
+    // The keys watched with WatchKey and the live queries by id, with the changes to every table they are in
+    key_watches: Vec<KeyWatch>,
+    live_queries: HashMap<String, db::LiveQuery>,
+    changes: StreamMap<String, BroadcastStream<db::Change>>,
+}
+
+impl Session {
+    /// The messages for a change to a table this socket watches keys or runs live queries in
+    fn changed(&mut self, table: String, change: db::Change) -> Vec<Message> {
+        let mut messages = Vec::new();
+        for (query_id, live_query) in &mut self.live_queries {
+            if live_query.table() != table {
+                continue;
+            }
+            let Some(delta) = live_query.apply(&change) else {
+                continue;
+            };
+            let (mut added, mut updated, mut removed) = (Vec::new(), Vec::new(), Vec::new());
+            match delta {
+                db::Delta::Added(entry) => added.push(entry),
+                db::Delta::Updated(entry) => updated.push(entry),
+                db::Delta::Removed(key) => removed.push(key),
+            }
+            messages.push(Message::LiveQueryDiff {
+                query_id: query_id.clone(),
+                added,
+                updated,
+                removed,
+            });
+        }
+
+        let mut watches = self
+            .key_watches
+            .iter()
+            .filter(|watch| watch.matches(&table, &change.key))
+            .peekable();
+        if watches.peek().is_some() {
+            let include_value = watches.any(|watch| watch.include_value);
+            messages.push(Message::KeyChanged {
+                table,
+                key: change.key,
+                op: change.event,
+                value: (include_value && change.event == KeyEvent::Set)
+                    .then(|| Value::clone(&change.value)),
+                version: change.version,
+            });
+        }
+        messages
+    }
+
+    /// The messages for changes to a table that were dropped because this socket fell behind
+    ///
+    /// Watches in the table are told how many changes were dropped. Live queries in the table can't tell which of
+    /// their results the changes affected, so they are run again and their full results sent.
+    async fn lagged(&mut self, table: String, skipped: u64) -> Vec<Message> {
+        let mut messages = Vec::new();
+        if self.key_watches.iter().any(|watch| watch.table == table) {
+            messages.push(Message::WatchLagged {
+                table: table.clone(),
+                skipped,
+            });
+        }
+        let mut failed = Vec::new();
+        for (query_id, live_query) in &mut self.live_queries {
+            if live_query.table() != table {
+                continue;
+            }
+            match self.state.data_store.refresh_live_query(live_query).await {
+                Ok(entries) => messages.push(Message::LiveQuery {
+                    query_id: query_id.clone(),
+                    entries,
+                }),
+                Err(err) => {
+                    error!(?err, query_id, "Could not refresh live query");
+                    messages.push(Message::Status(StatusMessage::Error {
+                        message: err.to_string(),
+                        operation: None,
+                    }));
+                    failed.push(query_id.clone());
+                }
+            }
+        }
+        for query_id in failed {
+            self.live_queries.remove(&query_id);
+        }
+        self.stop_listening(&table);
+        messages
+    }
+
+    /// Listens for changes to a table through `changes`, unless it already is
+    fn listen(&mut self, table: &str, changes: broadcast::Receiver<db::Change>) {
+        if !self.changes.contains_key(table) {
+            self.changes
+                .insert(table.to_string(), BroadcastStream::new(changes));
+        }
+    }
+
+    /// Stops listening for changes to a table once no watch or live query needs them
+    fn stop_listening(&mut self, table: &str) {
+        let watched = self.key_watches.iter().any(|watch| watch.table == table);
+        let queried = self
+            .live_queries
+            .values()
+            .any(|live_query| live_query.table() == table);
+        if !watched && !queried {
+            self.changes.remove(table);
+        }
+    }
+
+    /// Runs a command, returning its reply if it has one
+    async fn run(&mut self, command: Command) -> Option<Message> {
+        // Inside a transaction, everything but the transaction commands themselves is queued for Exec
+        if let Some(queued) = self
+            .transaction
+            .as_mut()
+            .filter(|_| !is_transaction_command(&command))
+        {
+            let message = match operation(command, &self.selected_table) {
+                Ok(operation) => {
+                    queued.operations.push(operation);
+                    Message::Queued
+                }
+                Err(command) => {
+                    queued.failed = true;
+                    Message::Status(status(Err(db::Error::NotTransactional), command))
+                }
+            };
+            return Some(message);
+        }
+
+        match command {
+            Command::SubscribeBroadcast {
+                channel,
+                subscribe_to_self,
+                replay,
+            } => {
+                let subscription = SubscriptionOptions { subscribe_to_self };
+                match &replay {
+                    Some(replay) => {
+                        let replayed = self.mailbox.subscribe_with_replay(
+                            channel.clone(),
+                            subscription.clone(),
+                            replay,
+                        );
+                        debug!(channel, replayed, "Replayed channel history");
+                    }
+                    None => self
+                        .mailbox
+                        .subscribe(channel.clone(), subscription.clone()),
+                }
+                if !self.persistent {
+                    return None;
+                }
+                let result = self
+                    .state
+                    .data_store
+                    .add_subscription(self.client_id.clone(), channel.clone(), subscription)
+                    .await;
+                // Subscribing only has a reply if it fails
+                result.err().map(|err| {
+                    Message::Status(status(
+                        Err(err),
+                        Command::SubscribeBroadcast {
+                            channel,
+                            subscribe_to_self,
+                            replay,
+                        },
+                    ))
+                })
+            }
+            Command::UnsubscribeBroadcast(channel) => {
+                self.mailbox.unsubscribe(&channel);
+                if !self.persistent {
+                    return None;
+                }
+                let result = self
+                    .state
+                    .data_store
+                    .remove_subscription(self.client_id.clone(), &channel)
+                    .await;
+                result.err().map(|err| {
+                    Message::Status(status(Err(err), Command::UnsubscribeBroadcast(channel)))
+                })
+            }
+            Command::PSubscribe {
+                pattern,
+                subscribe_to_self,
+            } => {
+                let subscription = SubscriptionOptions { subscribe_to_self };
+                self.mailbox
+                    .psubscribe(pattern.clone(), subscription.clone());
+                if !self.persistent {
+                    return None;
+                }
+                let result = self
+                    .state
+                    .data_store
+                    .add_pattern_subscription(self.client_id.clone(), pattern.clone(), subscription)
+                    .await;
+                result.err().map(|err| {
+                    Message::Status(status(
+                        Err(err),
+                        Command::PSubscribe {
+                            pattern,
+                            subscribe_to_self,
+                        },
+                    ))
+                })
+            }
+            Command::PUnsubscribe(pattern) => {
+                self.mailbox.punsubscribe(&pattern);
+                if !self.persistent {
+                    return None;
+                }
+                let result = self
+                    .state
+                    .data_store
+                    .remove_pattern_subscription(self.client_id.clone(), pattern.clone())
+                    .await;
+                result
+                    .err()
+                    .map(|err| Message::Status(status(Err(err), Command::PUnsubscribe(pattern))))
+            }
+            Command::ClearSubscriptions => {
+                self.mailbox.unsubscribe_all();
+                if !self.persistent {
+                    return None;
+                }
+                let result = self
+                    .state
+                    .data_store
+                    .clear_subscriptions(&self.client_id)
+                    .await;
+                result
+                    .err()
+                    .map(|err| Message::Status(status(Err(err), Command::ClearSubscriptions)))
+            }
+            Command::SendBroadcast { channel, message } => {
+                let message = BroadcastMessage::new(self.client_id.clone(), channel, message);
+                let delivered = self.state.data_store.broker.publish(message);
+                info!(delivered, "Sent broadcast");
+                None
+            }
+            Command::PubSubStats => {
+                Some(Message::PubSubStats(self.state.data_store.broker.stats()))
+            }
+            Command::Set { key, value, table } => {
+                let db_value = Value::from(value.clone());
+                let table_name = table.as_deref().unwrap_or(&self.selected_table);
+                let result = self
+                    .state
+                    .data_store
+                    .set(table_name, key.clone(), db_value)
+                    .await;
+                let status = status(result, Command::Set { key, value, table });
+                Some(Message::Status(status))
+            }
+            Command::Get {
+                key,
+                table,
+                snapshot_id,
+            } => {
+                let table_name = table.as_deref().unwrap_or(&self.selected_table);
+                let result = match snapshot_id
+                    .as_deref()
+                    .map(|id| find_snapshot(&self.snapshots, id))
+                {
+                    Some(Ok(snapshot)) => Ok(snapshot.get(&key).await),
+                    Some(Err(err)) => Err(err),
+                    None => self.state.data_store.get(table_name, &key).await,
+                };
+                match result {
+                    Ok(value) => Some(Message::Get(value)),
+                    Err(err) => {
+                        let status = status(
+                            Err(err),
+                            Command::Get {
+                                key,
+                                table,
+                                snapshot_id,
+                            },
+                        );
+                        Some(Message::Status(status))
+                    }
+                }
+            }
+            Command::Range {
+                start,
+                end,
+                limit,
+                reverse,
+                table,
+                snapshot_id,
+            } => {
+                let table_name = table.as_deref().unwrap_or(&self.selected_table);
+                let result = match snapshot_id
+                    .as_deref()
+                    .map(|id| find_snapshot(&self.snapshots, id))
+                {
+                    Some(Ok(snapshot)) => {
+                        snapshot
+                            .range(start.as_deref(), end.as_deref(), limit, reverse)
+                            .await
+                    }
+                    Some(Err(err)) => Err(err),
+                    None => {
+                        self.state
+                            .data_store
+                            .range(table_name, start.as_deref(), end.as_deref(), limit, reverse)
+                            .await
+                    }
+                };
+                match result {
+                    Ok(entries) => Some(Message::Entries(entries)),
+                    Err(err) => {
+                        let status = status(
+                            Err(err),
+                            Command::Range {
+                                start,
+                                end,
+                                limit,
+                                reverse,
+                                table,
+                                snapshot_id,
+                            },
+                        );
+                        Some(Message::Status(status))
+                    }
+                }
+            }
+            Command::PrefixScan {
+                prefix,
+                table,
+                snapshot_id,
+            } => {
+                let table_name = table.as_deref().unwrap_or(&self.selected_table);
+                let result = match snapshot_id
+                    .as_deref()
+                    .map(|id| find_snapshot(&self.snapshots, id))
+                {
+                    Some(Ok(snapshot)) => snapshot.prefix_scan(&prefix).await,
+                    Some(Err(err)) => Err(err),
+                    None => self.state.data_store.prefix_scan(table_name, &prefix).await,
+                };
+                match result {
+                    Ok(entries) => Some(Message::Entries(entries)),
+                    Err(err) => {
+                        let status = status(
+                            Err(err),
+                            Command::PrefixScan {
+                                prefix,
+                                table,
+                                snapshot_id,
+                            },
+                        );
+                        Some(Message::Status(status))
+                    }
+                }
+            }
+            Command::Delete { key, table } => {
+                let table_name = table.as_deref().unwrap_or(&self.selected_table);
+                let result = self.state.data_store.delete(table_name, key.clone()).await;
+                let status = status(result, Command::Delete { key, table });
+                Some(Message::Status(status))
+            }
+            Command::CreateIndex { index, table } => {
+                let table_name = table.as_deref().unwrap_or(&self.selected_table);
+                let result = self
+                    .state
+                    .data_store
+                    .create_index(table_name, index.clone())
+                    .await;
+                let status = status(result, Command::CreateIndex { index, table });
+                Some(Message::Status(status))
+            }
+            Command::DropIndex { name, table } => {
+                let table_name = table.as_deref().unwrap_or(&self.selected_table);
+                let result = self
+                    .state
+                    .data_store
+                    .drop_index(table_name, name.clone())
+                    .await;
+                let status = status(result, Command::DropIndex { name, table });
+                Some(Message::Status(status))
+            }
+            Command::FindBy {
+                index,
+                query,
+                table,
+            } => {
+                let table_name = table.as_deref().unwrap_or(&self.selected_table);
+                match self
+                    .state
+                    .data_store
+                    .find_by(table_name, &index, &query)
+                    .await
+                {
+                    Ok(entries) => Some(Message::Entries(entries)),
+                    Err(err) => {
+                        let status = status(
+                            Err(err),
+                            Command::FindBy {
+                                index,
+                                query,
+                                table,
+                            },
+                        );
+                        Some(Message::Status(status))
+                    }
+                }
+            }
+            Command::CreateSearchIndex { index, table } => {
+                let table_name = table.as_deref().unwrap_or(&self.selected_table);
+                let result = self
+                    .state
+                    .data_store
+                    .create_search_index(table_name, index.clone())
+                    .await;
+                let status = status(result, Command::CreateSearchIndex { index, table });
+                Some(Message::Status(status))
+            }
+            Command::DropSearchIndex { name, table } => {
+                let table_name = table.as_deref().unwrap_or(&self.selected_table);
+                let result = self
+                    .state
+                    .data_store
+                    .drop_search_index(table_name, name.clone())
+                    .await;
+                let status = status(result, Command::DropSearchIndex { name, table });
+                Some(Message::Status(status))
+            }
+            Command::Search {
+                index,
+                query,
+                limit,
+                table,
+            } => {
+                let table_name = table.as_deref().unwrap_or(&self.selected_table);
+                match self
+                    .state
+                    .data_store
+                    .search(table_name, &index, &query, limit)
+                    .await
+                {
+                    Ok(hits) => Some(Message::SearchResults(hits)),
+                    Err(err) => {
+                        let status = status(
+                            Err(err),
+                            Command::Search {
+                                index,
+                                query,
+                                limit,
+                                table,
+                            },
+                        );
+                        Some(Message::Status(status))
+                    }
+                }
+            }
+            Command::CreateVectorIndex { index, table } => {
+                let table_name = table.as_deref().unwrap_or(&self.selected_table);
+                let result = self
+                    .state
+                    .data_store
+                    .create_vector_index(table_name, index.clone())
+                    .await;
+                let status = status(result, Command::CreateVectorIndex { index, table });
+                Some(Message::Status(status))
+            }
+            Command::DropVectorIndex { name, table } => {
+                let table_name = table.as_deref().unwrap_or(&self.selected_table);
+                let result = self
+                    .state
+                    .data_store
+                    .drop_vector_index(table_name, name.clone())
+                    .await;
+                let status = status(result, Command::DropVectorIndex { name, table });
+                Some(Message::Status(status))
+            }
+            Command::CreateTrigger { trigger, table } => {
+                let table_name = table.as_deref().unwrap_or(&self.selected_table);
+                let result = self
+                    .state
+                    .data_store
+                    .create_trigger(table_name, (*trigger).clone())
+                    .await;
+                let status = status(result, Command::CreateTrigger { trigger, table });
+                Some(Message::Status(status))
+            }
+            Command::DropTrigger { name, table } => {
+                let table_name = table.as_deref().unwrap_or(&self.selected_table);
+                let result = self
+                    .state
+                    .data_store
+                    .drop_trigger(table_name, name.clone())
+                    .await;
+                let status = status(result, Command::DropTrigger { name, table });
+                Some(Message::Status(status))
+            }
+            Command::Triggers { table } => {
+                let table_name = table.as_deref().unwrap_or(&self.selected_table);
+                match self.state.data_store.triggers(table_name).await {
+                    Ok(triggers) => Some(Message::Triggers(triggers)),
+                    Err(err) => Some(Message::Status(status(
+                        Err(err),
+                        Command::Triggers { table },
+                    ))),
+                }
+            }
+            Command::VectorSearch {
+                index,
+                vector,
+                k,
+                filter,
+                table,
+            } => {
+                let table_name = table.as_deref().unwrap_or(&self.selected_table);
+                match self
+                    .state
+                    .data_store
+                    .vector_search(table_name, &index, &vector, k, filter.as_deref())
+                    .await
+                {
+                    Ok(hits) => Some(Message::VectorResults(hits)),
+                    Err(err) => {
+                        let status = status(
+                            Err(err),
+                            Command::VectorSearch {
+                                index,
+                                vector,
+                                k,
+                                filter,
+                                table,
+                            },
+                        );
+                        Some(Message::Status(status))
+                    }
+                }
+            }
+            Command::Query {
+                query,
+                table,
+                snapshot_id,
+            } => {
+                let table_name = table.as_deref().unwrap_or(&self.selected_table);
+                let result = match snapshot_id
+                    .as_deref()
+                    .map(|id| find_snapshot(&self.snapshots, id))
+                {
+                    Some(Ok(snapshot)) => snapshot.query(&query).await,
+                    Some(Err(err)) => Err(err),
+                    None => self.state.data_store.query(table_name, &query).await,
+                };
+                match result {
+                    Ok(entries) => Some(Message::Entries(entries)),
+                    Err(err) => {
+                        let status = status(
+                            Err(err),
+                            Command::Query {
+                                query,
+                                table,
+                                snapshot_id,
+                            },
+                        );
+                        Some(Message::Status(status))
+                    }
+                }
+            }
+            Command::Snapshot { table, timeout } => {
+                let table_name = table.as_deref().unwrap_or(&self.selected_table);
+                match self.state.data_store.snapshot(table_name).await {
+                    Ok(snapshot) => {
+                        let snapshot_id = uuid::Uuid::new_v4().to_string();
+                        let timeout = timeout.map_or(DEFAULT_SNAPSHOT_TIMEOUT, Duration::from_secs);
+                        let deadline = Instant::now()
+                            .checked_add(timeout)
+                            .unwrap_or_else(far_future);
+                        self.snapshots
+                            .insert(snapshot_id.clone(), (snapshot, deadline));
+                        Some(Message::SnapshotId(snapshot_id))
+                    }
+                    Err(err) => {
+                        let status = status(Err(err), Command::Snapshot { table, timeout });
+                        Some(Message::Status(status))
+                    }
+                }
+            }
+            Command::ReleaseSnapshot { snapshot_id } => {
+                let result = match self.snapshots.remove(&snapshot_id) {
+                    Some(_) => Ok(()),
+                    None => Err(db::Error::NoSuchSnapshot(snapshot_id.clone())),
+                };
+                let status = status(result, Command::ReleaseSnapshot { snapshot_id });
+                Some(Message::Status(status))
+            }
+            Command::Multi => {
+                let result = match self.transaction {
+                    Some(_) => Err(db::Error::TransactionStarted),
+                    None => {
+                        self.transaction = Some(Transaction::default());
+                        Ok(())
+                    }
+                };
+                let status = status(result, Command::Multi);
+                Some(Message::Status(status))
+            }
+            Command::Exec => {
+                // A watch only covers one transaction, whether it runs or not
+                let watched = std::mem::take(&mut self.watch);
+                let message = match self.transaction.take() {
+                    None => Message::Status(status(Err(db::Error::NoTransaction), Command::Exec)),
+                    Some(Transaction { failed: true, .. }) => {
+                        Message::Status(status(Err(db::Error::TransactionDiscarded), Command::Exec))
+                    }
+                    Some(Transaction { operations, .. }) => {
+                        let reads = reads(&operations);
+                        match self.state.data_store.exec(operations, &watched).await {
+                            Ok(Some(values)) => Message::Exec(Some(replies(reads, values))),
+                            Ok(None) => Message::Exec(None),
+                            Err(err) => Message::Status(status(Err(err), Command::Exec)),
+                        }
+                    }
+                };
+                Some(message)
+            }
+            Command::Discard => {
+                self.watch = db::Watch::default();
+                let result = self
+                    .transaction
+                    .take()
+                    .map(|_| ())
+                    .ok_or(db::Error::NoTransaction);
+                let status = status(result, Command::Discard);
+                Some(Message::Status(status))
+            }
+            Command::Watch { keys, table } => {
+                let table_name = table.as_deref().unwrap_or(&self.selected_table);
+                let result = match self.transaction {
+                    Some(_) => Err(db::Error::TransactionStarted),
+                    None => {
+                        self.state
+                            .data_store
+                            .watch(table_name, &keys, &self.watch)
+                            .await
+                    }
+                };
+                let status = status(result, Command::Watch { keys, table });
+                Some(Message::Status(status))
+            }
+            Command::Unwatch => {
+                self.watch = db::Watch::default();
+                let status = status(Ok(()), Command::Unwatch);
+                Some(Message::Status(status))
+            }
+            Command::WatchKey {
+                target,
+                include_value,
+                table,
+            } => {
+                let table_name = table.as_deref().unwrap_or(&self.selected_table).to_string();
+                let result = match self.changes.contains_key(&table_name) {
+                    true => Ok(()),
+                    false => self
+                        .state
+                        .data_store
+                        .changes(&table_name)
+                        .await
+                        .map(|changes| self.listen(&table_name, changes)),
+                };
+                if result.is_ok() {
+                    let table = table_name;
+                    self.key_watches
+                        .retain(|watch| watch.table != table || watch.target != target);
+                    self.key_watches.push(KeyWatch {
+                        table,
+                        target: target.clone(),
+                        include_value,
+                    });
+                }
+                let status = status(
+                    result,
+                    Command::WatchKey {
+                        target,
+                        include_value,
+                        table,
+                    },
+                );
+                Some(Message::Status(status))
+            }
+            Command::UnwatchKey { target, table } => {
+                let table_name = table.as_deref().unwrap_or(&self.selected_table).to_string();
+                self.key_watches
+                    .retain(|watch| watch.table != table_name || watch.target != target);
+                self.stop_listening(&table_name);
+                let status = status(Ok(()), Command::UnwatchKey { target, table });
+                Some(Message::Status(status))
+            }
+            Command::LiveQuery {
+                prefix,
+                filter,
+                table,
+            } => {
+                let table_name = table.as_deref().unwrap_or(&self.selected_table).to_string();
+                let result = self
+                    .state
+                    .data_store
+                    .live_query(&table_name, prefix.clone(), filter.as_deref())
+                    .await;
+                match result {
+                    Ok((live_query, entries, changes)) => {
+                        // Changes from a listener started earlier include all of these, so it can be kept
+                        self.listen(&table_name, changes);
+                        let query_id = uuid::Uuid::new_v4().to_string();
+                        self.live_queries.insert(query_id.clone(), live_query);
+                        Some(Message::LiveQuery { query_id, entries })
+                    }
+                    Err(err) => {
+                        let command = Command::LiveQuery {
+                            prefix,
+                            filter,
+                            table,
+                        };
+                        Some(Message::Status(status(Err(err), command)))
+                    }
+                }
+            }
+            Command::StopLiveQuery { query_id } => {
+                let result = match self.live_queries.remove(&query_id) {
+                    Some(live_query) => {
+                        self.stop_listening(live_query.table());
+                        Ok(())
+                    }
+                    None => Err(db::Error::NoSuchLiveQuery(query_id.clone())),
+                };
+                let status = status(result, Command::StopLiveQuery { query_id });
+                Some(Message::Status(status))
+            }
+            Command::Eval {
+                script,
+                keys,
+                args,
+                table,
+            } => {
+                let table_name = table.as_deref().unwrap_or(&self.selected_table);
+                let result = self
+                    .state
+                    .data_store
+                    .eval(table_name, &script, &keys, &args, &self.client_id)
+                    .await;
+                match result {
+                    Ok(result) => Some(Message::ScriptResult(result)),
+                    Err(err) => {
+                        let status = status(
+                            Err(err),
+                            Command::Eval {
+                                script,
+                                keys,
+                                args,
+                                table,
+                            },
+                        );
+                        Some(Message::Status(status))
+                    }
+                }
+            }
+            Command::EvalSha {
+                sha,
+                keys,
+                args,
+                table,
+            } => {
+                let table_name = table.as_deref().unwrap_or(&self.selected_table);
+                let result = self
+                    .state
+                    .data_store
+                    .eval_sha(table_name, &sha, &keys, &args, &self.client_id)
+                    .await;
+                match result {
+                    Ok(result) => Some(Message::ScriptResult(result)),
+                    Err(err) => {
+                        let status = status(
+                            Err(err),
+                            Command::EvalSha {
+                                sha,
+                                keys,
+                                args,
+                                table,
+                            },
+                        );
+                        Some(Message::Status(status))
+                    }
+                }
+            }
+            Command::ScriptLoad { script } => match self.state.data_store.script_load(&script) {
+                Ok(sha) => Some(Message::ScriptSha(sha)),
+                Err(err) => Some(Message::Status(status(
+                    Err(err),
+                    Command::ScriptLoad { script },
+                ))),
+            },
+            Command::Plugin(command) => {
+                let table_name = command.table.as_deref().unwrap_or(&self.selected_table);
+                let result = self
+                    .state
+                    .data_store
+                    .plugin_command(table_name, &command.name, &command.args, &self.client_id)
+                    .await;
+                match result {
+                    Ok(result) => Some(Message::PluginResult(result)),
+                    Err(err) => Some(Message::Status(status(Err(err), Command::Plugin(command)))),
+                }
+            }
+            Command::Select { table } => {
+                let result = self.state.data_store.table(&table).await.map(|_| ());
+                if result.is_ok() {
+                    self.selected_table.clone_from(&table);
+                }
+                let status = status(result, Command::Select { table });
+                Some(Message::Status(status))
+            }
+            Command::CreateTable { name, options } => {
+                let result = self
+                    .state
+                    .data_store
+                    .create_table(name.clone(), options.clone())
+                    .await;
+                let status = status(result, Command::CreateTable { name, options });
+                Some(Message::Status(status))
+            }
+            Command::DropTable { name } => {
+                let result = self.state.data_store.drop_table(name.clone()).await;
+                let status = status(result, Command::DropTable { name });
+                Some(Message::Status(status))
+            }
+            Command::Save => {
+                let status = status(self.state.data_store.save().await, Command::Save);
+                Some(Message::Status(status))
+            }
+            Command::BgSave => {
+                let status = status(
+                    self.state.data_store.background_save().await,
+                    Command::BgSave,
+                );
+                Some(Message::Status(status))
+            }
+            Command::BgRewriteAof => {
+                let status = status(
+                    self.state.data_store.background_rewrite_aof().await,
+                    Command::BgRewriteAof,
+                );
+                Some(Message::Status(status))
+            }
+            Command::Export {
+                path,
+                patterns,
+                table,
+            } => {
+                let table_name = table.as_deref().unwrap_or(&self.selected_table);
+                let result = self
+                    .state
+                    .data_store
+                    .export(table_name, &path, patterns.clone())
+                    .await;
+                if let Ok(count) = result {
+                    info!(count, path, "Exported keys");
+                }
+                let status = status(
+                    result.map(|_| ()),
+                    Command::Export {
+                        path,
+                        patterns,
+                        table,
+                    },
+                );
+                Some(Message::Status(status))
+            }
+            Command::Import {
+                path,
+                patterns,
+                table,
+            } => {
+                let table_name = table.as_deref().unwrap_or(&self.selected_table);
+                let result = self
+                    .state
+                    .data_store
+                    .import(table_name, &path, patterns.clone())
+                    .await;
+                if let Ok(count) = result {
+                    info!(count, path, "Imported keys");
+                }
+                let status = status(
+                    result.map(|_| ()),
+                    Command::Import {
+                        path,
+                        patterns,
+                        table,
+                    },
+                );
+                Some(Message::Status(status))
+            }
+            Command::Batch {
+                commands,
+                atomic: false,
+            } => {
+                let mut replies = Vec::with_capacity(commands.len());
+                for command in commands {
+                    // Commands that only reply on failure are given one, so replies line up with commands
+                    let reply = Box::pin(self.run(command)).await;
+                    replies.push(reply.unwrap_or(Message::Status(StatusMessage::Ok)));
+                }
+                Some(Message::Batch(replies))
+            }
+            Command::Batch {
+                commands,
+                atomic: true,
+            } => {
+                let operations: Result<Vec<db::Operation>, Command> = commands
+                    .iter()
+                    .cloned()
+                    .map(|command| operation(command, &self.selected_table))
+                    .collect();
+                let message = match operations {
+                    Ok(operations) => {
+                        let reads = reads(&operations);
+                        match self.state.data_store.exec_all(operations).await {
+                            Ok(values) => Message::Batch(replies(reads, values)),
+                            Err(err) => Message::Status(status(
+                                Err(err),
+                                Command::Batch {
+                                    commands,
+                                    atomic: true,
+                                },
+                            )),
+                        }
+                    }
+                    Err(command) => {
+                        Message::Status(status(Err(db::Error::NotTransactional), command))
+                    }
+                };
+                Some(message)
+            }
+        }
+    }
+}
+
+async fn send_message(socket_sender: &mut SplitSink<WebSocket, WSMessage>, message: &Message) {
+    match serde_json::to_string(message) {
+        Ok(text) => {
+            // TODO: Handle this result beyond logging if possible
+            let _ = socket_sender
+                .send(WSMessage::Text(text))
+                .await
+                .inspect_err(|err| error!(?err, "Could not send message"));
+        }
+        Err(err) => error!(?err, "Could not serialize message"),
+    }
+}
+
+/// A key or pattern watched with `WatchKey`
+struct KeyWatch {
+    table: String,
+    target: WatchTarget,
+    include_value: bool,
+}
+
+impl KeyWatch {
+    fn matches(&self, table: &str, key: &str) -> bool {
+        self.table == table
+            && match &self.target {
+                WatchTarget::Key(watched) => watched == key,
+                WatchTarget::Pattern(pattern) => glob_match(pattern, key),
+            }
+    }
+}
+
+/// The commands a socket has queued since `Multi`
+#[derive(Default)]
+struct Transaction {
+    operations: Vec<db::Operation>,
+    // Set when a command couldn't be queued, so Exec discards the transaction instead of running part of it
+    failed: bool,
+}
+
+/// Which operations are reads, whose replies carry the value read
+fn reads(operations: &[db::Operation]) -> Vec<bool> {
+    operations
+        .iter()
+        .map(|operation| matches!(operation, db::Operation::Get { .. }))
+        .collect()
+}
+
+/// The reply to each operation run by a transaction or atomic batch
+fn replies(reads: Vec<bool>, values: Vec<Option<Value>>) -> Vec<Message> {
+    reads
+        .into_iter()
+        .zip(values)
+        .map(|(read, value)| match read {
+            true => Message::Get(value),
+            false => Message::Status(StatusMessage::Ok),
+        })
+        .collect()
+}
+
+fn is_transaction_command(command: &Command) -> bool {
+    matches!(
+        command,
+        Command::Multi
+            | Command::Exec
+            | Command::Discard
+            | Command::Watch { .. }
+            | Command::Unwatch
+    )
+}
+
+/// Converts a command to queue in a transaction into the operation it runs, giving it back if it can't be queued
+fn operation(command: Command, selected_table: &str) -> Result<db::Operation, Command> {
+    let table_or_selected =
+        |table: Option<String>| table.unwrap_or_else(|| selected_table.to_string());
+    match command {
+        Command::Set { key, value, table } => Ok(db::Operation::Set {
+            table: table_or_selected(table),
+            key,
+            value: Value::from(value),
+        }),
+        Command::Get {
+            key,
+            table,
+            snapshot_id: None,
+        } => Ok(db::Operation::Get {
+            table: table_or_selected(table),
+            key,
+        }),
+        Command::Delete { key, table } => Ok(db::Operation::Delete {
+            table: table_or_selected(table),
+            key,
+        }),
+        command => Err(command),
+    }
+}
+
+/// Looks up an open snapshot, treating one that has timed out but not been released yet as gone
+fn find_snapshot<'a>(
+    snapshots: &'a Snapshots,
+    snapshot_id: &str,
+) -> Result<&'a db::Snapshot, db::Error> {
+    snapshots
+        .get(snapshot_id)
+        .filter(|(_, deadline)| Instant::now() < *deadline)
+        .map(|(snapshot, _)| snapshot)
+        .ok_or_else(|| db::Error::NoSuchSnapshot(snapshot_id.to_string()))
+}
+
+/// When the next snapshot times out
+fn next_deadline(snapshots: &Snapshots) -> Instant {
+    snapshots
+        .values()
+        .map(|(_, deadline)| *deadline)
+        .min()
+        .unwrap_or_else(far_future)
+}
+
+/// An instant that won't be reached, for timeouts too long to represent
+fn far_future() -> Instant {
+    Instant::now() + Duration::from_secs(60 * 60 * 24 * 365 * 30)
+}
+
+/// Converts the result of an operation into a status for the client
+fn status(result: Result<(), db::Error>, operation: Command) -> StatusMessage {
+    match result {
+        Ok(()) => StatusMessage::Ok,
+        Err(err) => {
+            error!(?err, ?operation, "Operation failed");
+            StatusMessage::Error {
+                message: err.to_string(),
+                operation: Some(operation),
+            }
+        }
+    }
+}
+
+/// Parses a command, treating a bare array of commands as a batch that isn't atomic
+fn parse_command(text: &[u8]) -> serde_json::Result<Command> {
+    if text.trim_ascii_start().starts_with(b"[") {
+        let commands = serde_json::from_slice(text)?;
+        return Ok(Command::Batch {
+            commands,
+            atomic: false,
+        });
+    }
+    serde_json::from_slice(text)
+}
+
 #[instrument(skip(msg, command_tx, status_tx))]
 async fn process_message(
     msg: WSMessage,
@@ -224,7 +1362,7 @@ async fn process_message(
 ) -> ControlFlow<(), ()> {
     match msg {
         WSMessage::Text(t) => {
-            let message = serde_json::from_str::<Command>(&t);
+            let message = parse_command(t.as_bytes());
             match message {
                 Ok(message) => match command_tx.send(message).await {
                     Ok(_) => debug!(?socket_address, "Sent message to receive task"),
@@ -250,7 +1388,7 @@ async fn process_message(
             ControlFlow::Continue(())
         }
         WSMessage::Binary(d) => {
-            let message = serde_json::from_slice::<Command>(&d);
+            let message = parse_command(&d);
             match message {
                 Ok(message) => match command_tx.send(message).await {
                     Ok(_) => debug!(?socket_address, "Sent message to receive task"),
@@ -301,22 +1439,235 @@ async fn process_message(
     }
 }
 
-fn should_send_message(
-    client_id: &String,
-    message: &BroadcastMessage,
-    subscriptions: &HashMap<String, SubscriptionOptions>,
-) -> bool {
-    // This is some nasty logic
-    // If the channel is `global`, just send the message
-    // Otherwise, check if you're subscribed
-    // If you're subscribed and it's not from you, send it
-    // If you're subscribed and it is from you, check to see if you subscribed to self messages
-    // If you're not subscribed and the channel is not `global`, ignore it
-    message.channel == "global"
-        || (subscriptions.contains_key(&message.channel)
-            && (message.client_id != *client_id
-                || *subscriptions
-                    .get(&message.channel)
-                    .map(|sub| &sub.subscribe_to_self)
-                    .unwrap_or(&false)))
+#[cfg(test)]
+mod tests {
+    use aether_common::db::{ChannelPattern, Replay};
+
+    use super::*;
+
+    #[test]
+    fn test_parse_batches() {
+        let command = parse_command(
+            br#"{"batch": {"commands": [{"get": {"key": "a"}}, "exec"], "atomic": true}}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            command,
+            Command::Batch { commands, atomic: true } if commands.len() == 2
+        ));
+
+        let command =
+            parse_command(br#" [{"get": {"key": "a"}}, {"delete": {"key": "b"}}]"#).unwrap();
+        assert!(matches!(
+            command,
+            Command::Batch { commands, atomic: false } if commands.len() == 2
+        ));
+
+        assert!(matches!(
+            parse_command(br#""multi""#).unwrap(),
+            Command::Multi
+        ));
+        assert!(parse_command(br#"[{"get": {}, "delete": {}}]"#).is_err());
+
+        assert!(parse_command(br#"{"set": {"key": "a"}}"#).is_err());
+        assert!(parse_command(br#"{"geo_add": {"lat": 51.5}}"#).is_err());
+        assert!(matches!(
+            parse_command(br#"{"plugin": {"name": "geo_add", "args": {"lat": 51.5}}}"#).unwrap(),
+            Command::Plugin(command) if command.name == "geo_add" && command.args["lat"] == 51.5
+                && command.table.is_none()
+        ));
+        assert!(matches!(
+            parse_command(br#"{"plugin": {"name": "geo_add", "table": "stores"}}"#).unwrap(),
+            Command::Plugin(command) if command.args.is_null() && command.table.as_deref() == Some("stores")
+        ));
+    }
+
+    fn session(state: &Arc<AppState>, client_id: &str, persistent: bool) -> Session {
+        Session {
+            client_id: client_id.to_string(),
+            persistent,
+            mailbox: state.data_store.broker.mailbox(client_id.to_string()),
+            selected_table: DEFAULT_TABLE.to_string(),
+            snapshots: Snapshots::new(),
+            transaction: None,
+            watch: db::Watch::default(),
+            key_watches: Vec::new(),
+            live_queries: HashMap::new(),
+            changes: StreamMap::new(),
+            state: state.clone(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_persisted_subscriptions() {
+        let state = Arc::new(AppState {
+            data_store: db::Database::default(),
+        });
+        for (client_id, persistent) in [("chosen", true), ("generated", false)] {
+            let mut session = session(&state, client_id, persistent);
+            for command in [
+                br#"{"subscribe_broadcast": {"channel": "chat"}}"#.as_slice(),
+                br#"{"psubscribe": {"glob": "chat.*"}}"#,
+            ] {
+                assert!(session.run(parse_command(command).unwrap()).await.is_none());
+            }
+        }
+
+        let subscriptions = state.data_store.get_subscriptions("chosen").await;
+        assert!(subscriptions.contains_key("chat"));
+        let patterns = state.data_store.get_pattern_subscriptions("chosen").await;
+        assert_eq!(patterns.len(), 1);
+        assert!(state
+            .data_store
+            .get_subscriptions("generated")
+            .await
+            .is_empty());
+        let patterns = state
+            .data_store
+            .get_pattern_subscriptions("generated")
+            .await;
+        assert!(patterns.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_clear_subscriptions() {
+        let state = Arc::new(AppState {
+            data_store: db::Database::default(),
+        });
+        let mut session = session(&state, "client", true);
+        for command in [
+            br#"{"subscribe_broadcast": {"channel": "chat"}}"#.as_slice(),
+            br#"{"psubscribe": {"glob": "news.*"}}"#,
+            br#""clear_subscriptions""#,
+        ] {
+            assert!(session.run(parse_command(command).unwrap()).await.is_none());
+        }
+
+        assert!(state
+            .data_store
+            .get_subscriptions("client")
+            .await
+            .is_empty());
+        let patterns = state.data_store.get_pattern_subscriptions("client").await;
+        assert!(patterns.is_empty());
+        for channel in ["chat", "news.today"] {
+            let message =
+                BroadcastMessage::new("other".to_string(), channel.to_string(), "hi".to_string());
+            assert_eq!(state.data_store.broker.publish(message), 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_watch_keys() {
+        let state = Arc::new(AppState {
+            data_store: db::Database::default(),
+        });
+        let mut session = session(&state, "client", true);
+        let command =
+            parse_command(br#"{"watch_key": {"pattern": "users/*", "include_value": true}}"#);
+        assert!(matches!(
+            session.run(command.unwrap()).await,
+            Some(Message::Status(StatusMessage::Ok))
+        ));
+        let command = parse_command(br#"{"watch_key": {"key": "posts/1"}}"#);
+        session.run(command.unwrap()).await;
+
+        let value = |s: &str| Value {
+            data: aether_common::db::Data::String(s.to_string()),
+            expiry: None,
+        };
+        for key in ["users/ada", "other", "posts/1"] {
+            state
+                .data_store
+                .set(DEFAULT_TABLE, key.to_string(), value(key))
+                .await
+                .unwrap();
+        }
+        state
+            .data_store
+            .delete(DEFAULT_TABLE, "users/ada".to_string())
+            .await
+            .unwrap();
+
+        let mut messages = Vec::new();
+        while messages.len() < 3 {
+            let (table, change) = session.changes.next().await.unwrap();
+            messages.extend(session.changed(table, change.unwrap()));
+        }
+        assert!(matches!(
+            &messages[0],
+            Message::KeyChanged { key, op: KeyEvent::Set, value: Some(_), .. } if key == "users/ada"
+        ));
+        assert!(matches!(
+            &messages[1],
+            Message::KeyChanged { key, op: KeyEvent::Set, value: None, .. } if key == "posts/1"
+        ));
+        assert!(matches!(
+            &messages[2],
+            Message::KeyChanged { key, op: KeyEvent::Delete, value: None, .. } if key == "users/ada"
+        ));
+        assert!(matches!(
+            session.lagged(DEFAULT_TABLE.to_string(), 3).await.as_slice(),
+            [Message::WatchLagged { table, skipped: 3 }] if table == DEFAULT_TABLE
+        ));
+
+        let command = parse_command(br#"{"unwatch_key": {"pattern": "users/*"}}"#);
+        session.run(command.unwrap()).await;
+        let command = parse_command(br#"{"unwatch_key": {"key": "posts/1"}}"#);
+        session.run(command.unwrap()).await;
+        assert!(session.changes.is_empty());
+    }
+
+    #[test]
+    fn test_parse_pattern_subscriptions() {
+        assert!(matches!(
+            parse_command(
+                br#"{"psubscribe": {"nats": "orders.*.created", "subscribe_to_self": true}}"#
+            )
+            .unwrap(),
+            Command::PSubscribe {
+                pattern: ChannelPattern::Nats(_),
+                subscribe_to_self: true,
+            }
+        ));
+        assert!(matches!(
+            parse_command(br#"{"punsubscribe": {"mqtt": "sensors/#"}}"#).unwrap(),
+            Command::PUnsubscribe(ChannelPattern::Mqtt(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_replay() {
+        assert!(matches!(
+            parse_command(br#"{"subscribe_broadcast": {"channel": "chat", "since_seq": 41}}"#)
+                .unwrap(),
+            Command::SubscribeBroadcast {
+                replay: Some(Replay::SinceSeq(41)),
+                ..
+            }
+        ));
+        assert!(matches!(
+            parse_command(
+                br#"{"subscribe_broadcast": {"channel": "chat", "since_time": 1700000000000}}"#
+            )
+            .unwrap(),
+            Command::SubscribeBroadcast {
+                replay: Some(Replay::SinceTime(1_700_000_000_000)),
+                ..
+            }
+        ));
+        assert!(matches!(
+            parse_command(br#"{"subscribe_broadcast": {"channel": "chat", "last_n": 10, "subscribe_to_self": true}}"#)
+                .unwrap(),
+            Command::SubscribeBroadcast {
+                replay: Some(Replay::LastN(10)),
+                subscribe_to_self: true,
+                ..
+            }
+        ));
+        assert!(matches!(
+            parse_command(br#"{"subscribe_broadcast": {"channel": "chat"}}"#).unwrap(),
+            Command::SubscribeBroadcast { replay: None, .. }
+        ));
+    }
 }